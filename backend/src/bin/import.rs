@@ -0,0 +1,79 @@
+//! Imports notes from the command line.
+//!
+//! Usage: `cargo run --bin import -- <file> [--dry-run] [--duplicates skip|overwrite|keep_both] [--notebook <id>]`
+//!
+//! Connects to `MONGO_URI` and prints the import report as JSON.
+
+use dotenv::dotenv;
+use mongodb::Client;
+use std::env;
+use std::process::ExitCode;
+
+use backend::models::import::{DuplicateStrategy, ImportOptions};
+use backend::services::import_service;
+
+const USAGE: &str = "Usage: import <file> [--dry-run] [--duplicates skip|overwrite|keep_both] [--notebook <id>]";
+
+#[actix_web::main]
+async fn main() -> ExitCode {
+    dotenv().ok();
+
+    let (path, options) = match parse_args(env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let archive = match import_service::parse_archive(&path, &data) {
+        Ok(archive) => archive,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mongo_uri = env::var("MONGO_URI").expect("MONGO_URI must be set");
+    let client = Client::with_uri_str(&mongo_uri).await.expect("Failed to initialize MongoDB client");
+    match import_service::import_archive(&client, archive, &options).await {
+        Ok(report) => {
+            println!("{}", serde_json::to_string_pretty(&report).unwrap_or_default());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Import failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<(String, ImportOptions), String> {
+    let mut path = None;
+    let mut options = ImportOptions::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => options.dry_run = true,
+            "--duplicates" => {
+                options.duplicates = match args.next().as_deref() {
+                    Some("skip") => DuplicateStrategy::Skip,
+                    Some("overwrite") => DuplicateStrategy::Overwrite,
+                    Some("keep_both" | "keep-both") => DuplicateStrategy::KeepBoth,
+                    other => return Err(format!("Invalid --duplicates value: {}", other.unwrap_or(""))),
+                }
+            }
+            "--notebook" => options.notebook_id = Some(args.next().ok_or("--notebook needs a notebook id")?),
+            flag if flag.starts_with("--") => return Err(format!("Unknown option: {}", flag)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err("Only one file can be imported at a time".to_string()),
+        }
+    }
+    Ok((path.ok_or("Missing file to import")?, options))
+}
@@ -0,0 +1,65 @@
+use object_store::{aws::AmazonS3Builder, local::LocalFileSystem, path::Path, ObjectStore, PutPayload};
+use bytes::Bytes;
+use std::env;
+use std::sync::Arc;
+
+/// Storage for attachment bytes, backed by the local filesystem or an
+/// S3-compatible bucket.
+#[derive(Clone)]
+pub struct BlobStore {
+    store: Arc<dyn ObjectStore>,
+}
+
+impl BlobStore {
+    /// Wraps an existing object store.
+    pub fn new(store: Arc<dyn ObjectStore>) -> Self {
+        BlobStore { store }
+    }
+
+    /// Builds the blob store selected by the BLOB_STORE environment variable.
+    ///
+    /// `local` (the default) stores files under ATTACHMENTS_DIR. `s3` uses the
+    /// bucket in S3_BUCKET; credentials, region and a custom endpoint (e.g. a
+    /// local MinIO) are read from the standard AWS_* variables such as
+    /// AWS_ENDPOINT and AWS_ALLOW_HTTP.
+    pub fn from_env() -> Result<Self, object_store::Error> {
+        let backend = env::var("BLOB_STORE").unwrap_or_else(|_| "local".to_string());
+        let store: Arc<dyn ObjectStore> = match backend.as_str() {
+            "s3" => {
+                let bucket = env::var("S3_BUCKET").map_err(|_| object_store::Error::Generic {
+                    store: "S3",
+                    source: "S3_BUCKET must be set when BLOB_STORE=s3".into(),
+                })?;
+                Arc::new(AmazonS3Builder::from_env().with_bucket_name(bucket).build()?)
+            }
+            _ => {
+                let dir = env::var("ATTACHMENTS_DIR").unwrap_or_else(|_| "data/attachments".to_string());
+                std::fs::create_dir_all(&dir).map_err(|e| object_store::Error::Generic {
+                    store: "LocalFileSystem",
+                    source: Box::new(e),
+                })?;
+                Arc::new(LocalFileSystem::new_with_prefix(dir)?)
+            }
+        };
+        Ok(BlobStore { store })
+    }
+
+    /// Writes the bytes under the given key, replacing any existing object.
+    pub async fn put(&self, key: &str, data: Bytes) -> Result<(), object_store::Error> {
+        self.store.put(&Path::from(key), PutPayload::from(data)).await?;
+        Ok(())
+    }
+
+    /// Reads the full object stored under the given key.
+    pub async fn get(&self, key: &str) -> Result<Bytes, object_store::Error> {
+        self.store.get(&Path::from(key)).await?.bytes().await
+    }
+
+    /// Deletes the object under the given key; missing objects are ignored.
+    pub async fn delete(&self, key: &str) -> Result<(), object_store::Error> {
+        match self.store.delete(&Path::from(key)).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
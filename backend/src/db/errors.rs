@@ -0,0 +1,11 @@
+use mongodb::error::{Error, ErrorKind, WriteFailure};
+
+/// Whether the error is a unique index violation, from a write or from a
+/// command such as `findAndModify`.
+pub fn is_duplicate_key(error: &Error) -> bool {
+    match error.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == 11000,
+        ErrorKind::Command(e) => e.code == 11000,
+        _ => false,
+    }
+}
@@ -0,0 +1,165 @@
+use mongodb::{Client, IndexModel, bson::{doc, Document}, options::IndexOptions};
+use crate::services::notes_service;
+
+/// Creates the indexes the services rely on. Safe to run on every startup.
+pub async fn ensure_indexes(client: &Client) -> mongodb::error::Result<()> {
+    let db = client.database("organise");
+
+    // One daily note per date; other notes don't have the field
+    let daily_note = IndexModel::builder()
+        .keys(doc! { "daily_date": 1 })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .partial_filter_expression(doc! { "daily_date": { "$type": "string" } })
+                .build(),
+        )
+        .build();
+    db.collection::<Document>("notes").create_index(daily_note).await?;
+
+    // At most one default notebook, even when it is created by concurrent requests
+    let default_notebook = IndexModel::builder()
+        .keys(doc! { "is_default": 1 })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .partial_filter_expression(doc! { "is_default": true })
+                .build(),
+        )
+        .build();
+    db.collection::<Document>("notebooks").create_index(default_notebook).await?;
+
+    // Keyset pagination of the notes list: pinned first, then the sort field
+    let mut list_indexes: Vec<IndexModel> = ["updated_at", "created_at", "position"]
+        .into_iter()
+        .map(|field| {
+            IndexModel::builder()
+                .keys(doc! { "is_pinned": -1, field: -1, "_id": -1 })
+                .build()
+        })
+        .collect();
+    list_indexes.push(
+        IndexModel::builder()
+            .keys(doc! { "is_pinned": -1, "title": 1, "_id": 1 })
+            .options(IndexOptions::builder().collation(notes_service::title_collation()).build())
+            .build(),
+    );
+    db.collection::<Document>("notes").create_indexes(list_indexes).await?;
+
+    // Finding the encrypted notes still wrapped with a key that is being rotated
+    let encryption_key = IndexModel::builder()
+        .keys(doc! { "encryption.key_id": 1 })
+        .options(IndexOptions::builder().sparse(true).build())
+        .build();
+    db.collection::<Document>("notes").create_index(encryption_key).await?;
+
+    // Listing the trash and purging what is past its retention period
+    for collection in ["notes", "todos", "calendar_events"] {
+        let trash = IndexModel::builder()
+            .keys(doc! { "deleted_at": 1 })
+            .options(IndexOptions::builder().sparse(true).build())
+            .build();
+        db.collection::<Document>(collection).create_index(trash).await?;
+    }
+
+    // Date-range queries: events starting before the end of the range and ending after its start
+    let event_range = IndexModel::builder()
+        .keys(doc! { "start_time": 1, "end_time": 1 })
+        .build();
+    db.collection::<Document>("calendar_events").create_index(event_range).await?;
+
+    // One live exception per occurrence of a recurring event; other events don't have the
+    // fields. Trashed exceptions differ in `deleted_at`, which live ones don't have.
+    let event_exception = IndexModel::builder()
+        .keys(doc! { "recurring_event_id": 1, "recurrence_id": 1, "deleted_at": 1 })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .partial_filter_expression(doc! { "recurring_event_id": { "$exists": true } })
+                .build(),
+        )
+        .build();
+    db.collection::<Document>("calendar_events").create_index(event_exception).await?;
+
+    // Matching imported events to the ones already stored. A `UID` stands for
+    // one live series per calendar; its exceptions differ in `recurrence_id`.
+    let ical_uid = IndexModel::builder()
+        .keys(doc! { "calendar_id": 1, "ical_uid": 1, "recurrence_id": 1, "deleted_at": 1 })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .partial_filter_expression(doc! { "ical_uid": { "$exists": true } })
+                .build(),
+        )
+        .build();
+    db.collection::<Document>("calendar_events").create_index(ical_uid).await?;
+
+    // Looking up events by the name CalDAV clients gave their resource
+    let dav_name = IndexModel::builder()
+        .keys(doc! { "dav_name": 1 })
+        .options(IndexOptions::builder().sparse(true).build())
+        .build();
+    db.collection::<Document>("calendar_events").create_index(dav_name).await?;
+
+    // Finding the changes to a calendar since a CalDAV sync token
+    let event_change = IndexModel::builder()
+        .keys(doc! { "calendar_id": 1, "change": 1 })
+        .options(IndexOptions::builder().sparse(true).build())
+        .build();
+    db.collection::<Document>("calendar_events").create_index(event_change).await?;
+
+    // Matching events to their copies in external calendars. A provider id stands
+    // for one live event per calendar; trashed copies differ in `deleted_at`.
+    let external_id = IndexModel::builder()
+        .keys(doc! { "calendar_id": 1, "external_id": 1, "deleted_at": 1 })
+        .options(
+            IndexOptions::builder()
+                .unique(true)
+                .partial_filter_expression(doc! { "external_id": { "$exists": true } })
+                .build(),
+        )
+        .build();
+    db.collection::<Document>("calendar_events").create_index(external_id).await?;
+
+    // One sync state per external calendar of each user
+    let sync_state = IndexModel::builder()
+        .keys(doc! { "user": 1, "provider": 1, "remote_calendar_id": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    db.collection::<Document>("calendar_sync_states").create_index(sync_state).await?;
+
+    // Looking up calendar feeds by the hash of the token in their URL
+    let feed_token = IndexModel::builder()
+        .keys(doc! { "token_hash": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    db.collection::<Document>("calendar_feeds").create_index(feed_token).await?;
+
+    // One connected account per user and calendar provider
+    let connection_provider = IndexModel::builder()
+        .keys(doc! { "user": 1, "provider": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    db.collection::<Document>("calendar_connections").create_index(connection_provider).await?;
+
+    // Looking up OAuth authorizations by the hash of their state, which are dropped once they expire
+    let oauth_state = IndexModel::builder()
+        .keys(doc! { "state_hash": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    db.collection::<Document>("oauth_states").create_index(oauth_state).await?;
+    let oauth_state_expiry = IndexModel::builder()
+        .keys(doc! { "expires_at": 1 })
+        .options(IndexOptions::builder().expire_after(std::time::Duration::ZERO).build())
+        .build();
+    db.collection::<Document>("oauth_states").create_index(oauth_state_expiry).await?;
+
+    // Stored responses for idempotency keys are dropped once they expire
+    let idempotency_expiry = IndexModel::builder()
+        .keys(doc! { "expires_at": 1 })
+        .options(IndexOptions::builder().expire_after(std::time::Duration::ZERO).build())
+        .build();
+    db.collection::<Document>("idempotency_keys").create_index(idempotency_expiry).await?;
+
+    Ok(())
+}
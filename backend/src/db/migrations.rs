@@ -0,0 +1,31 @@
+use mongodb::{Client, bson::{doc, Document}};
+
+/// Brings existing documents up to the current schema. Safe to run on every startup.
+pub async fn run_migrations(client: &Client) -> mongodb::error::Result<()> {
+    let notes = client.database("organise").collection::<Document>("notes");
+
+    // Note timestamps used to be stored as RFC 3339 strings, which sort incorrectly
+    for field in ["created_at", "updated_at"] {
+        let filter = doc! { field: { "$type": "string" } };
+        let update = vec![doc! {
+            "$set": { field: { "$dateFromString": { "dateString": format!("${}", field), "onError": null } } }
+        }];
+        notes.update_many(filter, update).await?;
+    }
+
+    // Pinned notes sort first, so the flag must be present on every note
+    for field in ["is_pinned", "is_favourite"] {
+        notes.update_many(doc! { field: { "$exists": false } }, doc! { "$set": { field: false } }).await?;
+    }
+
+    // Optimistic concurrency control compares against the document version
+    for collection in ["notes", "todos"] {
+        client
+            .database("organise")
+            .collection::<Document>(collection)
+            .update_many(doc! { "version": { "$exists": false } }, doc! { "$set": { "version": 1_i64 } })
+            .await?;
+    }
+
+    Ok(())
+}
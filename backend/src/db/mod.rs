@@ -1 +1,5 @@
-pub mod connection;
\ No newline at end of file
+pub mod connection;
+pub mod errors;
+pub mod blob_store;
+pub mod indexes;
+pub mod migrations;
\ No newline at end of file
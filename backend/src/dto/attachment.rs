@@ -0,0 +1,39 @@
+use serde::Serialize;
+use crate::dto::id_string;
+use crate::models::attachment::{Attachment, AttachmentOwner};
+
+#[derive(Debug, Serialize)]
+pub struct AttachmentResponse {
+    pub id: String,
+    pub owner_type: AttachmentOwner,
+    pub owner_id: String,
+    pub filename: String,
+    /// Content type sniffed from the uploaded bytes.
+    pub content_type: String,
+    pub size: i64,
+    pub sha256: String,
+    /// Where the file can be downloaded.
+    pub url: String,
+    /// Where the thumbnail can be downloaded, for images that have one.
+    pub thumbnail_url: Option<String>,
+    pub created_at: String,
+}
+
+impl From<Attachment> for AttachmentResponse {
+    fn from(attachment: Attachment) -> Self {
+        let id = id_string(attachment.id);
+        let url = format!("/api/attachments/{}", id);
+        AttachmentResponse {
+            thumbnail_url: attachment.has_thumbnail.then(|| format!("{}/thumbnail", url)),
+            url,
+            id,
+            owner_type: attachment.owner_type,
+            owner_id: attachment.owner_id.to_hex(),
+            filename: attachment.filename,
+            content_type: attachment.content_type,
+            size: attachment.size,
+            sha256: attachment.sha256,
+            created_at: attachment.created_at,
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! CalDAV multistatus and error bodies.
+
+use actix_web::http::header::HttpDate;
+use std::time::SystemTime;
+use crate::models::caldav::{
+    calendar_href, event_href, EventResource, PropName, PropRequest, SyncToken, CALDAV_NS, CALENDAR_HOME_PATH,
+    CALENDAR_SERVER_NS, DAV_NS, PRINCIPAL_PATH,
+};
+use crate::models::calendar::LOCAL_CALENDAR_ID;
+
+/// Properties listed for `allprop` and `propname`. `calendar-data` is only
+/// returned when asked for by name.
+const ALL_PROPS: &[(&str, &str)] = &[
+    (DAV_NS, "resourcetype"),
+    (DAV_NS, "displayname"),
+    (DAV_NS, "current-user-principal"),
+    (DAV_NS, "principal-URL"),
+    (CALDAV_NS, "calendar-home-set"),
+    (DAV_NS, "supported-report-set"),
+    (CALDAV_NS, "supported-calendar-component-set"),
+    (DAV_NS, "sync-token"),
+    (CALENDAR_SERVER_NS, "getctag"),
+    (DAV_NS, "current-user-privilege-set"),
+    (DAV_NS, "getetag"),
+    (DAV_NS, "getcontenttype"),
+    (DAV_NS, "getlastmodified"),
+];
+
+/// A resource of the CalDAV tree, as listed in a multistatus.
+pub enum DavResource<'a> {
+    Principal,
+    CalendarHome,
+    Calendar { id: &'a str, sync_token: SyncToken },
+    Event { calendar_id: &'a str, resource: &'a EventResource },
+}
+
+impl DavResource<'_> {
+    pub fn href(&self) -> String {
+        match self {
+            DavResource::Principal => PRINCIPAL_PATH.to_string(),
+            DavResource::CalendarHome => CALENDAR_HOME_PATH.to_string(),
+            DavResource::Calendar { id, .. } => calendar_href(id),
+            DavResource::Event { calendar_id, resource } => event_href(calendar_id, &resource.name),
+        }
+    }
+
+    /// Value of a property as XML, or `None` when the resource doesn't have it.
+    fn prop(&self, name: &PropName) -> Option<String> {
+        use DavResource::*;
+        let value = match (name.namespace.as_str(), name.name.as_str(), self) {
+            (DAV_NS, "resourcetype", Principal) => "<D:collection/><D:principal/>".to_string(),
+            (DAV_NS, "resourcetype", CalendarHome) => "<D:collection/>".to_string(),
+            (DAV_NS, "resourcetype", Calendar { .. }) => "<D:collection/><C:calendar/>".to_string(),
+            (DAV_NS, "resourcetype", Event { .. }) => String::new(),
+            (DAV_NS, "displayname", Principal | Calendar { id: LOCAL_CALENDAR_ID, .. }) => "Organise".to_string(),
+            (DAV_NS, "displayname", CalendarHome) => "Calendars".to_string(),
+            (DAV_NS, "displayname", Calendar { id, .. }) => escape_xml(id),
+            (DAV_NS, "current-user-principal", _) | (DAV_NS, "principal-URL", Principal) => href(PRINCIPAL_PATH),
+            (CALDAV_NS, "calendar-home-set", Principal) => href(CALENDAR_HOME_PATH),
+            (DAV_NS, "supported-report-set", Calendar { .. }) => [("C", "calendar-query"), ("C", "calendar-multiget"), ("D", "sync-collection")]
+                .iter()
+                .map(|(prefix, report)| format!("<D:supported-report><D:report><{}:{}/></D:report></D:supported-report>", prefix, report))
+                .collect(),
+            (CALDAV_NS, "supported-calendar-component-set", Calendar { .. }) => "<C:comp name=\"VEVENT\"/>".to_string(),
+            (DAV_NS, "sync-token", Calendar { sync_token, .. }) | (CALENDAR_SERVER_NS, "getctag", Calendar { sync_token, .. }) => {
+                escape_xml(&sync_token.to_string())
+            }
+            (DAV_NS, "current-user-privilege-set", Calendar { .. } | Event { .. }) => ["read", "write-content", "bind", "unbind"]
+                .iter()
+                .map(|privilege| format!("<D:privilege><D:{}/></D:privilege>", privilege))
+                .collect(),
+            (DAV_NS, "getetag", Event { resource, .. }) => escape_xml(&format!("\"{}\"", resource.etag)),
+            (DAV_NS, "getcontenttype", Event { .. }) => "text/calendar; charset=utf-8; component=vevent".to_string(),
+            (DAV_NS, "getlastmodified", Event { resource, .. }) => {
+                HttpDate::from(SystemTime::from(resource.last_modified)).to_string()
+            }
+            (CALDAV_NS, "calendar-data", Event { resource, .. }) => escape_xml(&resource.body),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+/// A `207 Multi-Status` body.
+#[derive(Debug, Default)]
+pub struct Multistatus {
+    responses: String,
+    sync_token: Option<SyncToken>,
+}
+
+impl Multistatus {
+    /// Adds a resource with the requested properties; those it doesn't have
+    /// are listed as not found.
+    pub fn add(&mut self, resource: &DavResource, props: &PropRequest) {
+        let all = || ALL_PROPS.iter().map(|(namespace, name)| PropName::new(namespace, name));
+        let (found, missing): (Vec<_>, Vec<_>) = match props {
+            PropRequest::All => (all().filter_map(|name| Some((resource.prop(&name)?, name))).collect(), Vec::new()),
+            PropRequest::Names => (
+                all().filter(|name| resource.prop(name).is_some()).map(|name| (String::new(), name)).collect(),
+                Vec::new(),
+            ),
+            PropRequest::Props(names) => {
+                let (mut found, mut missing) = (Vec::new(), Vec::new());
+                for name in names {
+                    match resource.prop(name) {
+                        Some(value) => found.push((value, name.clone())),
+                        None => missing.push(name.clone()),
+                    }
+                }
+                (found, missing)
+            }
+        };
+
+        self.responses.push_str(&format!("<D:response>{}", href(&resource.href())));
+        for (status, props) in [("200 OK", found), ("404 Not Found", missing.into_iter().map(|name| (String::new(), name)).collect())] {
+            if props.is_empty() {
+                continue;
+            }
+            self.responses.push_str("<D:propstat><D:prop>");
+            for (value, name) in props {
+                self.responses.push_str(&element(&name, &value));
+            }
+            self.responses.push_str(&format!("</D:prop><D:status>HTTP/1.1 {}</D:status></D:propstat>", status));
+        }
+        self.responses.push_str("</D:response>");
+    }
+
+    /// Adds an href with no resource behind it: a removed resource in a sync
+    /// report, or a missing one in a multiget.
+    pub fn add_missing(&mut self, path: &str) {
+        self.responses
+            .push_str(&format!("<D:response>{}<D:status>HTTP/1.1 404 Not Found</D:status></D:response>", href(path)));
+    }
+
+    pub fn set_sync_token(&mut self, token: SyncToken) {
+        self.sync_token = Some(token);
+    }
+
+    pub fn into_xml(self) -> String {
+        let sync_token = self
+            .sync_token
+            .map(|token| format!("<D:sync-token>{}</D:sync-token>", escape_xml(&token.to_string())))
+            .unwrap_or_default();
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus {}>{}{}</D:multistatus>",
+            NAMESPACES, self.responses, sync_token
+        )
+    }
+}
+
+/// Body of a `403 Forbidden` naming the precondition a request failed, with
+/// the href of the resource it conflicts with when there is one.
+pub fn error_body(namespace: &str, condition: &str, conflict: Option<&str>) -> String {
+    let content = conflict.map(href).unwrap_or_default();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error {}>{}</D:error>",
+        NAMESPACES,
+        element(&PropName::new(namespace, condition), &content)
+    )
+}
+
+const NAMESPACES: &str = "xmlns:D=\"DAV:\" xmlns:C=\"urn:ietf:params:xml:ns:caldav\" xmlns:CS=\"http://calendarserver.org/ns/\"";
+
+/// An element holding `content`, with the prefix declared by `NAMESPACES`
+/// or its own namespace declaration.
+fn element(name: &PropName, content: &str) -> String {
+    let (tag, declaration) = match name.namespace.as_str() {
+        DAV_NS => (format!("D:{}", name.name), String::new()),
+        CALDAV_NS => (format!("C:{}", name.name), String::new()),
+        CALENDAR_SERVER_NS => (format!("CS:{}", name.name), String::new()),
+        "" => (name.name.clone(), " xmlns=\"\"".to_string()),
+        namespace => (format!("X:{}", name.name), format!(" xmlns:X=\"{}\"", escape_xml(namespace))),
+    };
+    match content.is_empty() {
+        true => format!("<{}{}/>", tag, declaration),
+        false => format!("<{}{}>{}</{}>", tag, declaration, content, tag),
+    }
+}
+
+fn href(path: &str) -> String {
+    format!("<D:href>{}</D:href>", escape_xml(path))
+}
+
+/// Escapes text and attribute values. Carriage returns are escaped too, as
+/// XML parsers would otherwise drop them from the CRLF line ends of iCalendar.
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\r', "&#13;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use roxmltree::Document;
+
+    /// Text of the first element with a name in the DAV namespace.
+    fn text<'a>(document: &'a Document, name: &str) -> Option<&'a str> {
+        document.descendants().find(|node| node.has_tag_name((DAV_NS, name))).and_then(|node| node.text())
+    }
+
+    fn status<'a>(propstat: roxmltree::Node<'a, 'a>) -> Option<&'a str> {
+        propstat.children().find(|node| node.has_tag_name((DAV_NS, "status"))).and_then(|node| node.text())
+    }
+
+    #[test]
+    fn lists_found_and_missing_props_apart() {
+        let mut multistatus = Multistatus::default();
+        let props = PropRequest::Props(vec![PropName::new(DAV_NS, "sync-token"), PropName::new(DAV_NS, "getetag")]);
+        multistatus.add(&DavResource::Calendar { id: "work", sync_token: SyncToken(9) }, &props);
+        let xml = multistatus.into_xml();
+        let document = Document::parse(&xml).unwrap();
+
+        assert_eq!(text(&document, "href"), Some("/caldav/calendars/work/"));
+        let propstats: Vec<_> = document.descendants().filter(|node| node.has_tag_name((DAV_NS, "propstat"))).collect();
+        assert_eq!(propstats.len(), 2);
+        assert_eq!(status(propstats[0]), Some("HTTP/1.1 200 OK"));
+        assert!(propstats[0].descendants().any(|node| node.has_tag_name((DAV_NS, "sync-token"))));
+        assert_eq!(status(propstats[1]), Some("HTTP/1.1 404 Not Found"));
+        assert!(propstats[1].descendants().any(|node| node.has_tag_name((DAV_NS, "getetag"))));
+        assert_eq!(text(&document, "sync-token"), Some("urn:organise:caldav:change:9"));
+    }
+
+    #[test]
+    fn sync_reports_end_with_the_new_token_and_list_removals() {
+        let mut multistatus = Multistatus::default();
+        multistatus.add_missing("/caldav/calendars/local/gone.ics");
+        multistatus.set_sync_token(SyncToken(12));
+        let xml = multistatus.into_xml();
+        let document = Document::parse(&xml).unwrap();
+        let root = document.root_element();
+
+        assert!(root.has_tag_name((DAV_NS, "multistatus")));
+        let last = root.children().rfind(|node| node.is_element()).unwrap();
+        assert!(last.has_tag_name((DAV_NS, "sync-token")));
+        assert_eq!(last.text(), Some("urn:organise:caldav:change:12"));
+        assert_eq!(text(&document, "status"), Some("HTTP/1.1 404 Not Found"));
+    }
+
+    #[test]
+    fn calendar_data_keeps_its_line_ends() {
+        let resource = EventResource {
+            name: "a&b.ics".to_string(),
+            events: Vec::new(),
+            body: "BEGIN:VCALENDAR\r\nSUMMARY:<Lunch>\r\nEND:VCALENDAR\r\n".to_string(),
+            etag: "abc".to_string(),
+            last_modified: chrono::Utc::now(),
+        };
+        let mut multistatus = Multistatus::default();
+        let props = PropRequest::Props(vec![PropName::new(CALDAV_NS, "calendar-data"), PropName::new(DAV_NS, "getetag")]);
+        multistatus.add(&DavResource::Event { calendar_id: "local", resource: &resource }, &props);
+        let xml = multistatus.into_xml();
+        let document = Document::parse(&xml).unwrap();
+
+        let data = document.descendants().find(|node| node.has_tag_name((CALDAV_NS, "calendar-data"))).unwrap();
+        assert_eq!(data.text(), Some(resource.body.as_str()));
+        assert_eq!(text(&document, "getetag"), Some("\"abc\""));
+        assert_eq!(text(&document, "href"), Some("/caldav/calendars/local/a%26b.ics"));
+    }
+
+    #[test]
+    fn unknown_namespaces_are_declared_on_the_element() {
+        let mut multistatus = Multistatus::default();
+        multistatus.add(&DavResource::Principal, &PropRequest::Props(vec![PropName::new("urn:example", "color")]));
+        let xml = multistatus.into_xml();
+        let document = Document::parse(&xml).unwrap();
+        assert!(document.descendants().any(|node| node.has_tag_name(("urn:example", "color"))));
+    }
+}
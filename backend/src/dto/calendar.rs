@@ -0,0 +1,69 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::dto::id_string;
+use crate::models::calendar::{instance_id, CalendarEvent, EventOccurrence, LOCAL_CALENDAR_ID};
+
+#[derive(Debug, Serialize)]
+pub struct CalendarEventResponse {
+    /// Event id, or `{series id}_{original start}` for an occurrence of a series.
+    pub id: String,
+    pub title: String,
+    pub description: Option<String>,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub location: Option<String>,
+    pub is_all_day: bool,
+    pub time_zone: Option<String>,
+    /// RFC 5545 `RRULE`, `RDATE` and `EXDATE` lines.
+    pub recurrence: Vec<String>,
+    /// Series this occurrence belongs to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recurring_event_id: Option<String>,
+    /// Start time the series assigned to this occurrence.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original_start_time: Option<DateTime<Utc>>,
+    pub attendees: Vec<String>,
+    pub color: Option<String>,
+    pub calendar_id: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<CalendarEvent> for CalendarEventResponse {
+    /// Exceptions take the id of the occurrence they replace.
+    fn from(event: CalendarEvent) -> Self {
+        let id = match (event.recurring_event_id, event.recurrence_id) {
+            (Some(series_id), Some(start)) => instance_id(series_id, start),
+            _ => id_string(event.id),
+        };
+        CalendarEventResponse {
+            id,
+            recurrence: event.recurrence_lines(),
+            title: event.title,
+            description: event.description,
+            start_time: event.start_time,
+            end_time: event.end_time,
+            location: event.location,
+            is_all_day: event.is_all_day,
+            time_zone: event.time_zone,
+            recurring_event_id: event.recurring_event_id.map(|id| id.to_hex()),
+            original_start_time: event.recurrence_id,
+            attendees: event.attendees,
+            color: event.color,
+            calendar_id: event.calendar_id.unwrap_or_else(|| LOCAL_CALENDAR_ID.to_string()),
+            created_at: event.created_at,
+            updated_at: event.updated_at,
+        }
+    }
+}
+
+impl From<EventOccurrence> for CalendarEventResponse {
+    fn from(occurrence: EventOccurrence) -> Self {
+        CalendarEventResponse {
+            id: occurrence.instance_id,
+            recurring_event_id: occurrence.recurring_event_id.map(|id| id.to_hex()),
+            original_start_time: occurrence.original_start_time,
+            ..occurrence.event.into()
+        }
+    }
+}
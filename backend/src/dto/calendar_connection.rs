@@ -0,0 +1,25 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::models::calendar_connection::CalendarConnection;
+
+/// A connected account, without its tokens.
+#[derive(Debug, Serialize)]
+pub struct CalendarConnectionResponse {
+    pub provider: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    /// When the current access token expires; it is refreshed before then.
+    pub expires_at: DateTime<Utc>,
+}
+
+impl From<CalendarConnection> for CalendarConnectionResponse {
+    fn from(connection: CalendarConnection) -> Self {
+        CalendarConnectionResponse {
+            provider: connection.provider,
+            scope: connection.scope,
+            connected_at: connection.connected_at,
+            expires_at: connection.expires_at,
+        }
+    }
+}
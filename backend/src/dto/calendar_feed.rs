@@ -0,0 +1,29 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::dto::id_string;
+use crate::models::calendar_feed::CalendarFeed;
+
+#[derive(Debug, Serialize)]
+pub struct CalendarFeedResponse {
+    pub id: String,
+    /// Calendar served by the feed; every calendar when omitted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub calendar_id: Option<String>,
+    /// Secret feed URL; only returned when the feed is created or its token regenerated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub token_issued_at: DateTime<Utc>,
+}
+
+impl From<CalendarFeed> for CalendarFeedResponse {
+    fn from(feed: CalendarFeed) -> Self {
+        CalendarFeedResponse {
+            id: id_string(feed.id),
+            calendar_id: feed.calendar_id,
+            url: None,
+            created_at: feed.created_at,
+            token_issued_at: feed.token_issued_at,
+        }
+    }
+}
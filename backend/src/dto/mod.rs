@@ -0,0 +1,23 @@
+//! Response bodies of the HTTP API.
+//!
+//! These are kept separate from the storage models so that list, detail,
+//! create and update routes all return the same shape, with ids as plain
+//! strings instead of `{"$oid": ...}`.
+
+pub mod todo;
+pub mod note;
+pub mod calendar;
+pub mod calendar_feed;
+pub mod calendar_connection;
+pub mod caldav;
+pub mod notebook;
+pub mod attachment;
+pub mod template;
+pub(crate) mod preconditions;
+
+use mongodb::bson::oid::ObjectId;
+
+/// Hex string form of a document id; empty for documents that were never stored.
+fn id_string(id: Option<ObjectId>) -> String {
+    id.map(|id| id.to_hex()).unwrap_or_default()
+}
@@ -0,0 +1,69 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::dto::id_string;
+use crate::models::note::{Note, NoteEncryption, NoteHeading, RenderedNote};
+
+#[derive(Debug, Serialize)]
+pub struct NoteResponse {
+    pub id: String,
+    /// Plain text, or base64 ciphertext for encrypted notes.
+    pub title: String,
+    /// Markdown, or base64 ciphertext for encrypted notes.
+    pub content: String,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub tags: Vec<String>,
+    pub is_archived: bool,
+    pub is_pinned: bool,
+    pub is_favourite: bool,
+    pub extract_todos: bool,
+    pub notebook_id: Option<String>,
+    pub position: Option<i32>,
+    pub daily_date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<NoteEncryption>,
+    pub version: i64,
+}
+
+impl From<Note> for NoteResponse {
+    fn from(note: Note) -> Self {
+        NoteResponse {
+            id: id_string(note.id),
+            title: note.title,
+            content: note.content,
+            created_at: note.created_at,
+            updated_at: note.updated_at,
+            tags: note.tags.unwrap_or_default(),
+            is_archived: note.is_archived.unwrap_or(false),
+            is_pinned: note.is_pinned.unwrap_or(false),
+            is_favourite: note.is_favourite.unwrap_or(false),
+            extract_todos: note.extract_todos.unwrap_or(false),
+            notebook_id: note.notebook_id.map(|id| id.to_hex()),
+            position: note.position,
+            daily_date: note.daily_date,
+            encryption: note.encryption,
+            version: note.version,
+        }
+    }
+}
+
+/// A note together with its server-side Markdown rendering.
+#[derive(Debug, Serialize)]
+pub struct RenderedNoteResponse {
+    #[serde(flatten)]
+    pub note: NoteResponse,
+    pub html: String,
+    pub excerpt: String,
+    pub outline: Vec<NoteHeading>,
+}
+
+impl From<RenderedNote> for RenderedNoteResponse {
+    fn from(rendered: RenderedNote) -> Self {
+        RenderedNoteResponse {
+            note: rendered.note.into(),
+            html: rendered.html,
+            excerpt: rendered.excerpt,
+            outline: rendered.outline,
+        }
+    }
+}
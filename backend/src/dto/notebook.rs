@@ -0,0 +1,36 @@
+use serde::Serialize;
+use crate::dto::id_string;
+use crate::models::notebook::NotebookSummary;
+
+#[derive(Debug, Serialize)]
+pub struct NotebookResponse {
+    pub id: String,
+    pub name: String,
+    /// Parent notebook; `None` for top-level notebooks.
+    pub parent_id: Option<String>,
+    /// Sort position among the notebook's siblings.
+    pub position: i32,
+    /// The notebook that receives notes from deleted notebooks.
+    pub is_default: bool,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub note_count: u64,
+    pub notebook_count: u64,
+}
+
+impl From<NotebookSummary> for NotebookResponse {
+    fn from(summary: NotebookSummary) -> Self {
+        let notebook = summary.notebook;
+        NotebookResponse {
+            id: id_string(notebook.id),
+            name: notebook.name,
+            parent_id: notebook.parent_id.map(|id| id.to_hex()),
+            position: notebook.position,
+            is_default: notebook.is_default,
+            created_at: notebook.created_at,
+            updated_at: notebook.updated_at,
+            note_count: summary.note_count,
+            notebook_count: summary.notebook_count,
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! `ETag` and `If-Match` handling for documents carrying a `version`.
+
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+
+/// Strong entity tag for a document version.
+pub(crate) fn etag(version: i64) -> (header::HeaderName, String) {
+    (header::ETAG, format!("\"{}\"", version))
+}
+
+/// Versions listed in the request's `If-Match` header.
+///
+/// Returns `None` when the header is absent or `*`, in which case the write is
+/// unconditional. Weak or malformed tags never match, so a header made only of
+/// those yields an empty list and the write fails its precondition.
+pub(crate) fn if_match(request: &HttpRequest) -> Option<Vec<i64>> {
+    let values: Vec<&HeaderValue> = request.headers().get_all(header::IF_MATCH).collect();
+    if values.is_empty() {
+        return None;
+    }
+
+    let mut versions = Vec::new();
+    for tag in values.iter().filter_map(|value| value.to_str().ok()).flat_map(|value| value.split(',')) {
+        let tag = tag.trim();
+        if tag == "*" {
+            return None;
+        }
+        if let Some(version) = tag.strip_prefix('"').and_then(|t| t.strip_suffix('"')).and_then(|t| t.parse().ok()) {
+            versions.push(version);
+        }
+    }
+    Some(versions)
+}
+
+/// 200 response tagged with the document's new version.
+pub(crate) fn ok_with_etag(version: i64) -> HttpResponseBuilder {
+    let mut response = HttpResponse::Ok();
+    response.insert_header(etag(version));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn versions(values: &[&str]) -> Option<Vec<i64>> {
+        let request = values
+            .iter()
+            .fold(TestRequest::default(), |request, value| request.append_header((header::IF_MATCH, *value)))
+            .to_http_request();
+        if_match(&request)
+    }
+
+    #[test]
+    fn missing_or_wildcard_header_is_unconditional() {
+        assert_eq!(versions(&[]), None);
+        assert_eq!(versions(&["\"3\", *"]), None);
+    }
+
+    #[test]
+    fn lists_every_strong_tag() {
+        assert_eq!(versions(&["\"3\", \"4\"", "\"7\""]), Some(vec![3, 4, 7]));
+    }
+
+    #[test]
+    fn weak_or_malformed_tags_never_match() {
+        assert_eq!(versions(&["W/\"3\", 4, \"x\""]), Some(vec![]));
+    }
+
+    #[test]
+    fn etag_quotes_the_version() {
+        assert_eq!(etag(12).1, "\"12\"");
+    }
+}
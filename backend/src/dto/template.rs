@@ -0,0 +1,34 @@
+use serde::Serialize;
+use crate::dto::id_string;
+use crate::models::template::{NoteTemplate, TemplatePrompt};
+
+#[derive(Debug, Serialize)]
+pub struct TemplateResponse {
+    pub id: String,
+    pub name: String,
+    /// Title of created notes; may contain placeholders.
+    pub title: String,
+    pub content: String,
+    pub tags: Vec<String>,
+    pub prompts: Vec<TemplatePrompt>,
+    /// Whether this template is used for daily notes.
+    pub is_daily: bool,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+}
+
+impl From<NoteTemplate> for TemplateResponse {
+    fn from(template: NoteTemplate) -> Self {
+        TemplateResponse {
+            id: id_string(template.id),
+            name: template.name,
+            title: template.title,
+            content: template.content,
+            tags: template.tags.unwrap_or_default(),
+            prompts: template.prompts,
+            is_daily: template.is_daily,
+            created_at: template.created_at,
+            updated_at: template.updated_at,
+        }
+    }
+}
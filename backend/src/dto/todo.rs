@@ -0,0 +1,39 @@
+use serde::Serialize;
+use crate::dto::id_string;
+use crate::models::todo::Todo;
+
+#[derive(Debug, Serialize)]
+pub struct TodoResponse {
+    pub id: String,
+    pub title: String,
+    pub description: String,
+    pub completed: bool,
+    pub priority: String,
+    pub created_at: String,
+    pub updated_at: Option<String>,
+    /// Note this todo was extracted from, if any.
+    pub note_id: Option<String>,
+    pub project: Option<String>,
+    pub tags: Vec<String>,
+    pub is_archived: bool,
+    pub version: i64,
+}
+
+impl From<Todo> for TodoResponse {
+    fn from(todo: Todo) -> Self {
+        TodoResponse {
+            id: id_string(todo.id),
+            title: todo.title,
+            description: todo.description,
+            completed: todo.completed,
+            priority: todo.priority,
+            created_at: todo.created_at,
+            updated_at: todo.updated_at,
+            note_id: todo.note_id.map(|id| id.to_hex()),
+            project: todo.project,
+            tags: todo.tags.unwrap_or_default(),
+            is_archived: todo.is_archived.unwrap_or(false),
+            version: todo.version,
+        }
+    }
+}
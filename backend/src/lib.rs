@@ -1,5 +1,6 @@
 pub mod config;
 pub mod models;
 pub mod db;
+pub mod dto;
 pub mod routes;
 pub mod services;
@@ -1,11 +1,12 @@
 use actix_cors::Cors;
 use actix_web::{App, HttpServer};
 use dotenv::dotenv;
-use env_logger;
 use std::env;
 use mongodb::Client;
 
-use backend::routes;
+use backend::db::{blob_store::BlobStore, indexes, migrations};
+use backend::routes::{self, auth::ApiUsers};
+use backend::services::{attachment_service, collab_service::CollabHub, trash_service};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -18,6 +19,45 @@ async fn main() -> std::io::Result<()> {
     let mongo_uri = env::var("MONGO_URI").expect("MONGO_URI must be set");
     let mongo_client = Client::with_uri_str(&mongo_uri).await.expect("Failed to initialize MongoDB client");
 
+    indexes::ensure_indexes(&mongo_client).await.expect("Failed to create MongoDB indexes");
+    migrations::run_migrations(&mongo_client).await.expect("Failed to migrate MongoDB documents");
+
+    let blob_store = BlobStore::from_env().expect("Failed to initialize attachment storage");
+
+    // Purge trash past its retention period, then sweep the attachment blobs
+    // that are no longer referenced, including those a failed request left behind
+    let gc_client = mongo_client.clone();
+    let gc_store = blob_store.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = trash_service::purge_expired(&gc_client).await {
+                eprintln!("Trash purge failed: {}", e);
+            }
+            if let Err(e) = attachment_service::collect_garbage(&gc_client, &gc_store).await {
+                eprintln!("Attachment garbage collection failed: {}", e);
+            }
+        }
+    });
+
+    // Write collaboratively edited notes back to the database while rooms are open
+    let collab_hub = actix_web::web::Data::new(CollabHub::new());
+    let persist_client = mongo_client.clone();
+    let persist_hub = collab_hub.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            persist_hub.persist_all(&persist_client).await;
+        }
+    });
+
+    let api_users = actix_web::web::Data::new(ApiUsers::from_env());
+    if api_users.is_empty() {
+        eprintln!("API_USERS is not set; CalDAV refuses every request");
+    }
+
     println!("Starting server at {}", server_address);
     println!("Connected to MongoDB at {}", mongo_uri);
 
@@ -25,10 +65,15 @@ async fn main() -> std::io::Result<()> {
         let cors = Cors::default()
             .allow_any_origin()
             .allow_any_method()
-            .allow_any_header();
+            .allow_any_header()
+            // Browsers only let scripts read ETag and the pagination cursor when exposed
+            .expose_any_header();
 
         App::new()
             .app_data(actix_web::web::Data::new(mongo_client.clone()))
+            .app_data(actix_web::web::Data::new(blob_store.clone()))
+            .app_data(collab_hub.clone())
+            .app_data(api_users.clone())
             .configure(routes::init_routes)
             .wrap(cors)
     })
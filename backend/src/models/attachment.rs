@@ -0,0 +1,46 @@
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+/// Kind of document an attachment belongs to.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttachmentOwner {
+    Note,
+    Todo,
+}
+
+/// A file attached to a note or todo.
+///
+/// The bytes live in the blob store and are shared between attachments with
+/// identical content; see `Blob`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Attachment {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub owner_type: AttachmentOwner,
+    pub owner_id: ObjectId,
+    pub filename: String,
+    /// Content type sniffed from the uploaded bytes.
+    pub content_type: String,
+    pub size: i64,
+    /// SHA-256 of the content, referencing the `Blob` document.
+    pub sha256: String,
+    pub has_thumbnail: bool,
+    pub created_at: String,
+}
+
+/// Reference-counted, content-addressed blob metadata.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Blob {
+    /// SHA-256 of the content.
+    #[serde(rename = "_id")]
+    pub id: String,
+    /// Key of the object in the blob store.
+    pub key: String,
+    pub thumbnail_key: Option<String>,
+    pub content_type: String,
+    pub size: i64,
+    /// Number of attachments referencing this blob.
+    pub ref_count: i64,
+    pub created_at: String,
+}
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+
+/// Operations to apply in order, each to a single document.
+#[derive(Debug, Deserialize)]
+pub struct BulkRequest<A> {
+    pub operations: Vec<BulkOperation<A>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkOperation<A> {
+    pub id: String,
+    /// Only apply the operation to this version of the document, like `If-Match`.
+    pub version: Option<i64>,
+    #[serde(flatten)]
+    pub action: A,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TodoBulkAction {
+    Complete {
+        #[serde(default = "default_true")]
+        completed: bool,
+    },
+    Archive {
+        #[serde(default = "default_true")]
+        archived: bool,
+    },
+    /// Files the todo under a project, or under none.
+    Move { project: Option<String> },
+    AddTag { tag: String },
+    RemoveTag { tag: String },
+    /// Moves the todo to the trash.
+    Delete,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum NoteBulkAction {
+    Archive {
+        #[serde(default = "default_true")]
+        archived: bool,
+    },
+    /// Moves the note to the end of a notebook, or out of any notebook.
+    Move { notebook_id: Option<String> },
+    AddTag { tag: String },
+    RemoveTag { tag: String },
+    /// Moves the note to the trash.
+    Delete,
+}
+
+/// Outcome of a single operation.
+#[derive(Debug, Serialize)]
+pub struct BulkItemResult {
+    /// Position of the operation in the request.
+    pub index: usize,
+    pub id: String,
+    /// HTTP status the operation would have had as a single request.
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Version of the document after the operation, or its current version
+    /// when the operation failed its precondition.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkReport {
+    /// Whether the operations ran in a single transaction.
+    pub transactional: bool,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub results: Vec<BulkItemResult>,
+}
@@ -0,0 +1,402 @@
+//! Resources and requests of the CalDAV (RFC 4791) subset served under `/caldav`.
+//!
+//! The principal lives at `/caldav/`, its calendars at
+//! `/caldav/calendars/{calendar id}/`, and each series or single event, with
+//! the exceptions to it, is one `.ics` resource inside its calendar.
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use roxmltree::{Document, Node};
+use std::fmt;
+use std::str::FromStr;
+use crate::models::calendar::CalendarEvent;
+
+pub const DAV_NS: &str = "DAV:";
+pub const CALDAV_NS: &str = "urn:ietf:params:xml:ns:caldav";
+/// Namespace of `getctag`, which clients that predate sync tokens poll.
+pub const CALENDAR_SERVER_NS: &str = "http://calendarserver.org/ns/";
+
+/// Path of the principal, which is also the root of the CalDAV tree.
+pub const PRINCIPAL_PATH: &str = "/caldav/";
+
+/// Path of the collection holding the calendars.
+pub const CALENDAR_HOME_PATH: &str = "/caldav/calendars/";
+
+/// Sync tokens are this prefix followed by the number of the calendar's last
+/// change.
+const SYNC_TOKEN_PREFIX: &str = "urn:organise:caldav:change:";
+
+/// Characters escaped in a path segment.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~').remove(b'@');
+
+/// Path of a calendar collection.
+pub fn calendar_href(calendar_id: &str) -> String {
+    format!("{}{}/", CALENDAR_HOME_PATH, utf8_percent_encode(calendar_id, SEGMENT))
+}
+
+/// Path of an event resource.
+pub fn event_href(calendar_id: &str, name: &str) -> String {
+    format!("{}{}", calendar_href(calendar_id), utf8_percent_encode(name, SEGMENT))
+}
+
+/// Name of the resource an href points at, if it is inside the calendar.
+/// Hrefs may be absolute URLs.
+pub fn event_name_from_href(href: &str, calendar_id: &str) -> Option<String> {
+    let href = href.trim();
+    let path = match href.split_once("://") {
+        Some((_, rest)) => &rest[rest.find('/')?..],
+        None => href,
+    };
+    let path = percent_decode_str(path).decode_utf8().ok()?;
+    let collection = format!("{}{}/", CALENDAR_HOME_PATH, calendar_id);
+    let name = path.strip_prefix(&collection)?;
+    match name.is_empty() || name.contains('/') {
+        true => None,
+        false => Some(name.to_string()),
+    }
+}
+
+/// A WebDAV property, by namespace and local name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PropName {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl PropName {
+    pub fn new(namespace: &str, name: &str) -> Self {
+        PropName { namespace: namespace.to_string(), name: name.to_string() }
+    }
+
+    fn from_node(node: Node) -> Self {
+        PropName::new(node.tag_name().namespace().unwrap_or_default(), node.tag_name().name())
+    }
+}
+
+/// Properties asked for by a PROPFIND or REPORT.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropRequest {
+    /// `allprop`, or an empty PROPFIND body.
+    All,
+    /// `propname`: the names of the properties, without values.
+    Names,
+    Props(Vec<PropName>),
+}
+
+impl PropRequest {
+    /// Parses a PROPFIND body; an empty body asks for every property.
+    pub fn parse_propfind(body: &str) -> Result<Self, String> {
+        if body.trim().is_empty() {
+            return Ok(PropRequest::All);
+        }
+        let document = Document::parse(body).map_err(|e| format!("Invalid XML: {}", e))?;
+        let root = document.root_element();
+        if !is(root, DAV_NS, "propfind") {
+            return Err("Expected a propfind element".to_string());
+        }
+        Ok(PropRequest::from_parent(root).unwrap_or(PropRequest::All))
+    }
+
+    /// The `prop`, `allprop` or `propname` child of an element.
+    fn from_parent(parent: Node) -> Option<Self> {
+        parent.children().filter(Node::is_element).find_map(|child| match child.tag_name().namespace() {
+            Some(DAV_NS) => match child.tag_name().name() {
+                "allprop" => Some(PropRequest::All),
+                "propname" => Some(PropRequest::Names),
+                "prop" => Some(PropRequest::Props(
+                    child.children().filter(Node::is_element).map(PropName::from_node).collect(),
+                )),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+}
+
+/// The `Depth` header of a PROPFIND. `infinity`, the default, is answered
+/// as depth 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Depth {
+    Zero,
+    One,
+}
+
+impl Depth {
+    pub fn parse(header: Option<&str>) -> Self {
+        match header.map(str::trim) {
+            Some("0") => Depth::Zero,
+            _ => Depth::One,
+        }
+    }
+}
+
+/// A REPORT on a calendar collection.
+#[derive(Debug)]
+pub enum CalendarReport {
+    /// `calendar-query`: the events, optionally those with an occurrence in
+    /// a time range.
+    Query {
+        props: PropRequest,
+        /// `false` when the filter asks for components other than `VEVENT`s.
+        events: bool,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    },
+    /// `calendar-multiget`: the resources at some hrefs.
+    Multiget { props: PropRequest, hrefs: Vec<String> },
+    /// `sync-collection`: what changed since a sync token, or every resource
+    /// without one.
+    SyncCollection { props: PropRequest, token: Option<String> },
+}
+
+/// Why a REPORT body can't be answered.
+#[derive(Debug)]
+pub enum ReportError {
+    Invalid(String),
+    UnsupportedReport(String),
+    UnsupportedFilter(String),
+}
+
+impl CalendarReport {
+    pub fn parse(body: &str) -> Result<Self, ReportError> {
+        let document = Document::parse(body).map_err(|e| ReportError::Invalid(format!("Invalid XML: {}", e)))?;
+        let root = document.root_element();
+        let props = PropRequest::from_parent(root).unwrap_or(PropRequest::All);
+        match (root.tag_name().namespace().unwrap_or_default(), root.tag_name().name()) {
+            (CALDAV_NS, "calendar-query") => {
+                let filter = child(root, CALDAV_NS, "filter")
+                    .ok_or_else(|| ReportError::Invalid("calendar-query needs a filter".to_string()))?;
+                parse_query(props, filter)
+            }
+            (CALDAV_NS, "calendar-multiget") => {
+                let hrefs = root
+                    .children()
+                    .filter(|node| is(*node, DAV_NS, "href"))
+                    .filter_map(|node| node.text())
+                    .map(|href| href.trim().to_string())
+                    .collect();
+                Ok(CalendarReport::Multiget { props, hrefs })
+            }
+            (DAV_NS, "sync-collection") => {
+                let token = child(root, DAV_NS, "sync-token")
+                    .and_then(|node| node.text())
+                    .map(str::trim)
+                    .filter(|token| !token.is_empty())
+                    .map(str::to_string);
+                Ok(CalendarReport::SyncCollection { props, token })
+            }
+            (_, name) => Err(ReportError::UnsupportedReport(name.to_string())),
+        }
+    }
+}
+
+/// Reads a `calendar-query` filter: a `VCALENDAR` comp-filter, optionally
+/// narrowed to `VEVENT`s with a time range. Property and parameter filters
+/// aren't supported.
+fn parse_query(props: PropRequest, filter: Node) -> Result<CalendarReport, ReportError> {
+    if let Some(unsupported) = filter
+        .descendants()
+        .find(|node| is(*node, CALDAV_NS, "prop-filter") || is(*node, CALDAV_NS, "param-filter"))
+    {
+        return Err(ReportError::UnsupportedFilter(unsupported.tag_name().name().to_string()));
+    }
+    let Some(calendar) = child(filter, CALDAV_NS, "comp-filter").filter(|node| node.attribute("name") == Some("VCALENDAR")) else {
+        return Err(ReportError::Invalid("The filter must start with a VCALENDAR comp-filter".to_string()));
+    };
+    let Some(component) = child(calendar, CALDAV_NS, "comp-filter") else {
+        return Ok(CalendarReport::Query { props, events: true, start: None, end: None });
+    };
+    if component.attribute("name") != Some("VEVENT") || child(component, CALDAV_NS, "is-not-defined").is_some() {
+        return Ok(CalendarReport::Query { props, events: false, start: None, end: None });
+    }
+    let Some(range) = child(component, CALDAV_NS, "time-range") else {
+        return Ok(CalendarReport::Query { props, events: true, start: None, end: None });
+    };
+    let bound = |name: &str| -> Result<Option<DateTime<Utc>>, ReportError> {
+        range
+            .attribute(name)
+            .map(|value| {
+                NaiveDateTime::parse_from_str(value.trim(), "%Y%m%dT%H%M%SZ")
+                    .map(|date| date.and_utc())
+                    .map_err(|_| ReportError::Invalid(format!("Invalid time-range {}: {}", name, value)))
+            })
+            .transpose()
+    };
+    Ok(CalendarReport::Query { props, events: true, start: bound("start")?, end: bound("end")? })
+}
+
+fn is(node: Node, namespace: &str, name: &str) -> bool {
+    node.is_element() && node.tag_name().namespace() == Some(namespace) && node.tag_name().name() == name
+}
+
+fn child<'a, 'input>(parent: Node<'a, 'input>, namespace: &str, name: &str) -> Option<Node<'a, 'input>> {
+    parent.children().find(|node| is(*node, namespace, name))
+}
+
+/// Position in a calendar's history that a client has synced up to: the
+/// number of the calendar's last change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SyncToken(pub i64);
+
+impl fmt::Display for SyncToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", SYNC_TOKEN_PREFIX, self.0)
+    }
+}
+
+impl FromStr for SyncToken {
+    type Err = String;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        token
+            .strip_prefix(SYNC_TOKEN_PREFIX)
+            .and_then(|number| number.parse().ok())
+            .filter(|number| *number >= 0)
+            .map(SyncToken)
+            .ok_or_else(|| format!("Invalid sync token: {}", token))
+    }
+}
+
+/// `If-Match` and `If-None-Match` of a PUT or DELETE.
+#[derive(Debug, Default)]
+pub struct Precondition {
+    /// Strong entity tags, one of which the resource must have; `*` for any
+    /// existing resource. `None` when there is no condition.
+    pub if_match: Option<Vec<String>>,
+    /// `If-None-Match: *`: the resource must not exist yet.
+    pub if_none_match: bool,
+}
+
+impl Precondition {
+    /// Whether a write to a resource with the given entity tag, or to a
+    /// missing one, may go ahead.
+    pub fn allows(&self, etag: Option<&str>) -> bool {
+        if self.if_none_match && etag.is_some() {
+            return false;
+        }
+        match (&self.if_match, etag) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(tags), Some(etag)) => tags.iter().any(|tag| tag == "*" || tag == etag),
+        }
+    }
+}
+
+/// A calendar object resource: a series or single event with the exceptions
+/// to it, serialised as iCalendar.
+#[derive(Debug)]
+pub struct EventResource {
+    pub name: String,
+    /// The series first, then its exceptions.
+    pub events: Vec<CalendarEvent>,
+    pub body: String,
+    pub etag: String,
+    pub last_modified: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn sync_tokens_round_trip_and_reject_other_forms() {
+        let token = SyncToken(42);
+        assert_eq!(token.to_string().parse::<SyncToken>(), Ok(token));
+        assert!("urn:organise:caldav:change:-1".parse::<SyncToken>().is_err());
+        assert!("urn:organise:caldav:1700000000000".parse::<SyncToken>().is_err());
+        assert!("42".parse::<SyncToken>().is_err());
+    }
+
+    #[test]
+    fn propfind_reads_named_props_and_defaults_to_all() {
+        let body = r#"<?xml version="1.0"?>
+            <D:propfind xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+              <D:prop><D:getetag/><C:calendar-data/><X:color xmlns:X="urn:example"/></D:prop>
+            </D:propfind>"#;
+        assert_eq!(
+            PropRequest::parse_propfind(body),
+            Ok(PropRequest::Props(vec![
+                PropName::new(DAV_NS, "getetag"),
+                PropName::new(CALDAV_NS, "calendar-data"),
+                PropName::new("urn:example", "color"),
+            ]))
+        );
+        assert_eq!(PropRequest::parse_propfind(""), Ok(PropRequest::All));
+        assert_eq!(PropRequest::parse_propfind(r#"<propfind xmlns="DAV:"><propname/></propfind>"#), Ok(PropRequest::Names));
+        assert!(PropRequest::parse_propfind(r#"<prop xmlns="DAV:"/>"#).is_err());
+        assert!(PropRequest::parse_propfind("<propfind").is_err());
+    }
+
+    #[test]
+    fn calendar_query_reads_the_time_range() {
+        let body = r#"<C:calendar-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+              <D:prop><D:getetag/></D:prop>
+              <C:filter><C:comp-filter name="VCALENDAR"><C:comp-filter name="VEVENT">
+                <C:time-range start="20240101T000000Z" end="20240201T000000Z"/>
+              </C:comp-filter></C:comp-filter></C:filter>
+            </C:calendar-query>"#;
+        let Ok(CalendarReport::Query { props, events, start, end }) = CalendarReport::parse(body) else {
+            panic!("expected a calendar-query");
+        };
+        assert_eq!(props, PropRequest::Props(vec![PropName::new(DAV_NS, "getetag")]));
+        assert!(events);
+        assert_eq!(start, Some(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()));
+        assert_eq!(end, Some(Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn calendar_query_refuses_property_filters() {
+        let body = r#"<C:calendar-query xmlns:C="urn:ietf:params:xml:ns:caldav">
+              <C:filter><C:comp-filter name="VCALENDAR"><C:comp-filter name="VEVENT">
+                <C:prop-filter name="SUMMARY"/>
+              </C:comp-filter></C:comp-filter></C:filter>
+            </C:calendar-query>"#;
+        assert!(matches!(CalendarReport::parse(body), Err(ReportError::UnsupportedFilter(name)) if name == "prop-filter"));
+    }
+
+    #[test]
+    fn multiget_and_sync_collection_read_their_arguments() {
+        let multiget = r#"<C:calendar-multiget xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+              <D:prop><D:getetag/></D:prop>
+              <D:href> /caldav/calendars/local/a.ics </D:href><D:href>/caldav/calendars/local/b.ics</D:href>
+            </C:calendar-multiget>"#;
+        let Ok(CalendarReport::Multiget { hrefs, .. }) = CalendarReport::parse(multiget) else {
+            panic!("expected a calendar-multiget");
+        };
+        assert_eq!(hrefs, ["/caldav/calendars/local/a.ics", "/caldav/calendars/local/b.ics"]);
+
+        let sync = r#"<D:sync-collection xmlns:D="DAV:"><D:sync-token/><D:sync-level>1</D:sync-level><D:prop><D:getetag/></D:prop></D:sync-collection>"#;
+        assert!(matches!(CalendarReport::parse(sync), Ok(CalendarReport::SyncCollection { token: None, .. })));
+        let sync = sync.replace("<D:sync-token/>", "<D:sync-token>urn:organise:caldav:change:7</D:sync-token>");
+        let Ok(CalendarReport::SyncCollection { token, .. }) = CalendarReport::parse(&sync) else {
+            panic!("expected a sync-collection");
+        };
+        assert_eq!(token.as_deref(), Some("urn:organise:caldav:change:7"));
+        assert!(matches!(
+            CalendarReport::parse(r#"<D:expand-property xmlns:D="DAV:"/>"#),
+            Err(ReportError::UnsupportedReport(_))
+        ));
+    }
+
+    #[test]
+    fn hrefs_round_trip_through_escaping() {
+        let href = event_href("work", "my event@home.ics");
+        assert_eq!(href, "/caldav/calendars/work/my%20event@home.ics");
+        assert_eq!(event_name_from_href(&href, "work").as_deref(), Some("my event@home.ics"));
+        assert_eq!(event_name_from_href(&format!("https://example.com{}", href), "work").as_deref(), Some("my event@home.ics"));
+        assert_eq!(event_name_from_href(&href, "home"), None);
+        assert_eq!(event_name_from_href("/caldav/calendars/work/", "work"), None);
+    }
+
+    #[test]
+    fn preconditions_compare_entity_tags() {
+        let if_match = Precondition { if_match: Some(vec!["\"a\"".to_string()]), if_none_match: false };
+        assert!(if_match.allows(Some("\"a\"")));
+        assert!(!if_match.allows(Some("\"b\"")));
+        assert!(!if_match.allows(None));
+        let create = Precondition { if_match: None, if_none_match: true };
+        assert!(create.allows(None));
+        assert!(!create.allows(Some("\"a\"")));
+    }
+}
@@ -1,11 +1,20 @@
 use serde::{Deserialize, Serialize};
 use mongodb::bson::oid::ObjectId;
 use validator::Validate;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 use mongodb::bson::{DateTime as BsonDateTime};
 use std::time::SystemTime;
+use chrono_tz::Tz;
+use crate::models::datetime;
+use crate::models::recurrence::{local_to_utc, Recurrence, RecurrenceRule};
 
-#[derive(Debug, Serialize, Deserialize, Validate)]
+/// Id of the calendar holding events created in the app.
+pub const LOCAL_CALENDAR_ID: &str = "local";
+
+/// Longest span the date-range endpoint returns events for.
+pub const MAX_RANGE_DAYS: i64 = 366;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
 pub struct CalendarEvent {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
@@ -13,15 +22,57 @@ pub struct CalendarEvent {
     pub title: String,
     #[validate(length(min = 1, max = 500))]
     pub description: Option<String>,
+    #[serde(with = "datetime::required")]
     pub start_time: DateTime<Utc>,
+    #[serde(with = "datetime::required")]
     pub end_time: DateTime<Utc>,
     pub location: Option<String>,
     pub is_all_day: bool,
+    /// IANA time zone the event's recurrence is expanded in; UTC when unset.
+    #[serde(default)]
+    pub time_zone: Option<String>,
+    /// RFC 5545 `RRULE` value, e.g. `FREQ=WEEKLY;BYDAY=MO,WE`.
     pub recurrence_rule: Option<String>,
+    /// Extra occurrences of the series (`RDATE`), by start time.
+    #[serde(default, with = "datetime::list")]
+    pub recurrence_dates: Vec<DateTime<Utc>>,
+    /// Occurrences left out of the series (`EXDATE`), by start time.
+    #[serde(default, with = "datetime::list")]
+    pub excluded_dates: Vec<DateTime<Utc>>,
+    /// Series whose occurrence this event replaces; `None` unless the event
+    /// is an exception to a series.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurring_event_id: Option<ObjectId>,
+    /// Original start of the replaced occurrence (`RECURRENCE-ID`).
+    #[serde(default, with = "datetime::optional", skip_serializing_if = "Option::is_none")]
+    pub recurrence_id: Option<DateTime<Utc>>,
     pub attendees: Vec<String>,
     pub color: Option<String>,
+    /// Calendar the event belongs to; `None` for the local calendar.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calendar_id: Option<String>,
+    /// iCalendar `UID` the event was imported with; exceptions share the
+    /// `UID` of their series.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ical_uid: Option<String>,
+    /// Name of the event's CalDAV resource when a CalDAV client created it;
+    /// other events are named after their id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dav_name: Option<String>,
+    /// Id of the event in the external calendar it is synced with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    /// When the event last matched its copy in the external calendar; it has
+    /// changes to push when it was updated or trashed since.
+    #[serde(default, with = "datetime::optional", skip_serializing_if = "Option::is_none")]
+    pub synced_at: Option<DateTime<Utc>>,
+    #[serde(with = "datetime::required")]
     pub created_at: DateTime<Utc>,
+    #[serde(with = "datetime::required")]
     pub updated_at: DateTime<Utc>,
+    /// When the event was moved to the trash; `None` while it is live.
+    #[serde(default, with = "datetime::optional", skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Validate)]
@@ -35,13 +86,23 @@ pub struct CalendarEventSchema {
     pub location: Option<String>,
     pub color_id: Option<String>,
     pub is_all_day: bool,
+    /// IANA time zone of the event, used to expand its recurrence.
+    pub time_zone: Option<String>,
+    /// RFC 5545 `RRULE`, `RDATE` and `EXDATE` lines.
     pub recurrence: Option<Vec<String>>,
     pub attendees: Option<Vec<String>>,
 }
 
-impl From<CalendarEventSchema> for CalendarEvent {
-    fn from(schema: CalendarEventSchema) -> Self {
-        CalendarEvent {
+impl TryFrom<CalendarEventSchema> for CalendarEvent {
+    type Error = String;
+
+    /// Fails when the time zone, the recurrence lines or an attendee's email
+    /// address are invalid.
+    fn try_from(schema: CalendarEventSchema) -> Result<Self, Self::Error> {
+        let time_zone = parse_time_zone(schema.time_zone.as_deref())?;
+        let recurrence = Recurrence::parse(&schema.recurrence.unwrap_or_default(), schema.start_time, time_zone)?;
+        let attendees = parse_attendees(schema.attendees.unwrap_or_default())?;
+        Ok(CalendarEvent {
             id: None,
             title: schema.title,
             description: Some(schema.description),
@@ -49,35 +110,209 @@ impl From<CalendarEventSchema> for CalendarEvent {
             end_time: schema.end_time,
             location: schema.location,
             is_all_day: schema.is_all_day,
-            recurrence_rule: None,
-            attendees: schema.attendees.unwrap_or_default(),
+            time_zone: schema.time_zone,
+            recurrence_rule: recurrence.rule.map(|rule| rule.to_string()),
+            recurrence_dates: recurrence.dates,
+            excluded_dates: recurrence.excluded_dates,
+            recurring_event_id: None,
+            recurrence_id: None,
+            attendees,
             color: schema.color_id,
+            calendar_id: None,
+            ical_uid: None,
+            dav_name: None,
+            external_id: None,
+            synced_at: None,
             created_at: Utc::now(),
             updated_at: Utc::now(),
+            deleted_at: None,
+        })
+    }
+}
+
+/// Attendees as bare email addresses; a `mailto:` prefix is dropped.
+fn parse_attendees(attendees: Vec<String>) -> Result<Vec<String>, String> {
+    attendees
+        .iter()
+        .map(|attendee| attendee.trim())
+        .map(|attendee| attendee.strip_prefix("mailto:").unwrap_or(attendee))
+        .filter(|attendee| !attendee.is_empty())
+        .map(|attendee| match validator::validate_email(attendee) {
+            true => Ok(attendee.to_string()),
+            false => Err(format!("Invalid attendee email address: {}", attendee.escape_debug())),
+        })
+        .collect()
+}
+
+fn parse_time_zone(time_zone: Option<&str>) -> Result<Tz, String> {
+    match time_zone {
+        Some(name) => name.parse().map_err(|_| format!("Unknown time zone: {}", name)),
+        None => Ok(Tz::UTC),
+    }
+}
+
+/// Which occurrences of a recurring event an update or removal applies to.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EditScope {
+    /// Only the addressed occurrence.
+    Instance,
+    /// The addressed occurrence and every later one; splits the series.
+    Following,
+    /// The whole series.
+    #[default]
+    All,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditScopeQuery {
+    #[serde(default)]
+    pub scope: EditScope,
+}
+
+/// Query string of `GET /api/calendar/events`.
+#[derive(Debug, Deserialize)]
+pub struct EventRangeQuery {
+    /// RFC 3339 date-time, or a date or local date-time in `tz`.
+    pub start: Option<String>,
+    pub end: Option<String>,
+    /// IANA time zone for dates and local date-times; UTC when unset.
+    pub tz: Option<String>,
+    /// Comma-separated calendar ids; every calendar when unset.
+    pub calendar_ids: Option<String>,
+}
+
+/// A validated date range, `[start, end)`, over some calendars.
+#[derive(Debug)]
+pub struct EventRange {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Calendars to include; empty for every calendar.
+    pub calendar_ids: Vec<String>,
+}
+
+impl EventRangeQuery {
+    /// Whether the query asks for a date range rather than every event.
+    pub fn is_range(&self) -> bool {
+        self.start.is_some() || self.end.is_some()
+    }
+}
+
+impl TryFrom<EventRangeQuery> for EventRange {
+    type Error = String;
+
+    /// Fails unless both bounds parse, `start` is before `end`, and the range
+    /// spans at most `MAX_RANGE_DAYS`.
+    fn try_from(query: EventRangeQuery) -> Result<Self, Self::Error> {
+        let tz = parse_time_zone(query.tz.as_deref())?;
+        let (Some(start), Some(end)) = (query.start.as_deref(), query.end.as_deref()) else {
+            return Err("start and end must be given together".to_string());
+        };
+        let (start, end) = (parse_range_bound(start, tz)?, parse_range_bound(end, tz)?);
+        if start >= end {
+            return Err("start must be before end".to_string());
         }
+        if end - start > Duration::days(MAX_RANGE_DAYS) {
+            return Err(format!("The range can span at most {} days", MAX_RANGE_DAYS));
+        }
+        Ok(EventRange { start, end, calendar_ids: parse_calendar_ids(query.calendar_ids.as_deref()) })
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Validate)]
-pub struct GoogleCalendarCredentials {
-    #[validate(length(min = 1))]
-    pub client_id: String,
-    #[validate(length(min = 1))]
-    pub client_secret: String,
-    #[validate(length(min = 1))]
-    pub redirect_uri: String,
+/// Query string of `GET /api/calendar/export.ics`.
+#[derive(Debug, Deserialize)]
+pub struct CalendarExportQuery {
+    /// Comma-separated calendar ids; every calendar when unset.
+    pub calendar_ids: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Validate)]
-pub struct GoogleCalendarToken {
-    #[validate(length(min = 1))]
-    pub access_token: String,
-    #[validate(length(min = 1))]
-    pub refresh_token: String,
-    pub expires_at: DateTime<Utc>,
+/// Options for an iCalendar import.
+#[derive(Debug, Default, Deserialize)]
+pub struct CalendarImportOptions {
+    /// Only report what would be imported.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Calendar new events are added to; the local calendar when unset.
+    pub calendar_id: Option<String>,
+}
+
+/// What an import does with a single `VEVENT`.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CalendarImportAction {
+    Create,
+    Update,
+    Skip,
+}
+
+/// Outcome of importing one `VEVENT`.
+#[derive(Debug, Serialize)]
+pub struct CalendarImportItem {
+    pub uid: String,
+    /// Occurrence replaced by an exception.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recurrence_id: Option<DateTime<Utc>>,
+    pub title: String,
+    pub action: CalendarImportAction,
+    /// Id of the created or updated event; omitted on dry runs of new events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Summary of an iCalendar import.
+#[derive(Debug, Default, Serialize)]
+pub struct CalendarImportReport {
+    pub dry_run: bool,
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub items: Vec<CalendarImportItem>,
+    pub warnings: Vec<String>,
+}
+
+impl CalendarImportReport {
+    /// Adds an item and counts it.
+    pub fn record(&mut self, item: CalendarImportItem) {
+        match item.action {
+            CalendarImportAction::Create => self.created += 1,
+            CalendarImportAction::Update => self.updated += 1,
+            CalendarImportAction::Skip => self.skipped += 1,
+        }
+        self.items.push(item);
+    }
+}
+
+/// Splits a comma-separated list of calendar ids.
+pub fn parse_calendar_ids(calendar_ids: Option<&str>) -> Vec<String> {
+    calendar_ids
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parses a bound of a date range; dates stand for midnight in `tz`.
+fn parse_range_bound(value: &str, tz: Tz) -> Result<DateTime<Utc>, String> {
+    // An unencoded `+` in a query string arrives as a space
+    let value = value.trim().replace(' ', "+");
+    if let Ok(date) = DateTime::parse_from_rfc3339(&value) {
+        return Ok(date.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDateTime::parse_from_str(&value, "%Y-%m-%dT%H:%M:%S") {
+        return Ok(local_to_utc(date, tz));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(&value, "%Y-%m-%d") {
+        return Ok(local_to_utc(date.and_time(NaiveTime::MIN), tz));
+    }
+    Err(format!("Invalid date: {}", value))
 }
 
 impl CalendarEvent {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         title: String,
         description: Option<String>,
@@ -98,11 +333,22 @@ impl CalendarEvent {
             end_time,
             location,
             is_all_day,
+            time_zone: None,
             recurrence_rule,
+            recurrence_dates: Vec::new(),
+            excluded_dates: Vec::new(),
+            recurring_event_id: None,
+            recurrence_id: None,
             attendees,
             color,
+            calendar_id: None,
+            ical_uid: None,
+            dav_name: None,
+            external_id: None,
+            synced_at: None,
             created_at: now,
             updated_at: now,
+            deleted_at: None,
         }
     }
 
@@ -110,7 +356,44 @@ impl CalendarEvent {
         if self.start_time >= self.end_time {
             return Err("End time must be after start time".to_string());
         }
-        self.validate().map_err(|e| e.to_string())
+        parse_time_zone(self.time_zone.as_deref())?;
+        self.rule()?;
+        Validate::validate(self).map_err(|e| e.to_string())
+    }
+
+    /// Time zone the recurrence is expanded in.
+    pub fn tz(&self) -> Tz {
+        parse_time_zone(self.time_zone.as_deref()).unwrap_or(Tz::UTC)
+    }
+
+    /// The parsed recurrence rule, if the event has one.
+    pub fn rule(&self) -> Result<Option<RecurrenceRule>, String> {
+        self.recurrence_rule.as_deref().map(str::parse).transpose()
+    }
+
+    /// Whether the event replaces an occurrence of a series.
+    pub fn is_exception(&self) -> bool {
+        self.recurring_event_id.is_some() && self.recurrence_id.is_some()
+    }
+
+    /// Whether the event is a series rather than a single occurrence.
+    pub fn is_recurring(&self) -> bool {
+        self.recurrence_rule.is_some() || !self.recurrence_dates.is_empty()
+    }
+
+    /// The recurrence as RFC 5545 lines, in the form accepted by `CalendarEventSchema`.
+    pub fn recurrence_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(rule) = &self.recurrence_rule {
+            lines.push(format!("RRULE:{}", rule));
+        }
+        for (name, dates) in [("RDATE", &self.recurrence_dates), ("EXDATE", &self.excluded_dates)] {
+            if !dates.is_empty() {
+                let dates: Vec<String> = dates.iter().map(|date| date.format("%Y%m%dT%H%M%SZ").to_string()).collect();
+                lines.push(format!("{}:{}", name, dates.join(",")));
+            }
+        }
+        lines
     }
 }
 
@@ -138,15 +421,46 @@ impl From<CalendarEvent> for mongodb::bson::Document {
             doc.insert("location", location);
         }
         doc.insert("is_all_day", event.is_all_day);
+        if let Some(time_zone) = event.time_zone {
+            doc.insert("time_zone", time_zone);
+        }
         if let Some(recurrence_rule) = event.recurrence_rule {
             doc.insert("recurrence_rule", recurrence_rule);
         }
+        let recurrence_dates: Vec<BsonDateTime> = event.recurrence_dates.into_iter().map(datetime::to_bson).collect();
+        doc.insert("recurrence_dates", recurrence_dates);
+        let excluded_dates: Vec<BsonDateTime> = event.excluded_dates.into_iter().map(datetime::to_bson).collect();
+        doc.insert("excluded_dates", excluded_dates);
+        if let Some(recurring_event_id) = event.recurring_event_id {
+            doc.insert("recurring_event_id", recurring_event_id);
+        }
+        if let Some(recurrence_id) = event.recurrence_id {
+            doc.insert("recurrence_id", datetime::to_bson(recurrence_id));
+        }
         doc.insert("attendees", event.attendees);
         if let Some(color) = event.color {
             doc.insert("color", color);
         }
+        if let Some(calendar_id) = event.calendar_id {
+            doc.insert("calendar_id", calendar_id);
+        }
+        if let Some(ical_uid) = event.ical_uid {
+            doc.insert("ical_uid", ical_uid);
+        }
+        if let Some(dav_name) = event.dav_name {
+            doc.insert("dav_name", dav_name);
+        }
+        if let Some(external_id) = event.external_id {
+            doc.insert("external_id", external_id);
+        }
+        if let Some(synced_at) = event.synced_at {
+            doc.insert("synced_at", datetime::to_bson(synced_at));
+        }
         doc.insert("created_at", BsonDateTime::from(created_at));
         doc.insert("updated_at", BsonDateTime::from(updated_at));
+        if let Some(deleted_at) = event.deleted_at {
+            doc.insert("deleted_at", datetime::to_bson(deleted_at));
+        }
         doc
     }
 }
@@ -162,18 +476,107 @@ impl TryFrom<mongodb::bson::Document> for CalendarEvent {
         let updated_at = DateTime::<Utc>::from_timestamp_millis(doc.get_datetime("updated_at")?.timestamp_millis()).unwrap_or_default();
         
         Ok(CalendarEvent {
-            id: doc.get_object_id("_id").ok().map(|id| id.clone()),
+            id: doc.get_object_id("_id").ok(),
             title: doc.get_str("title")?.to_string(),
             description: doc.get_str("description").ok().map(|s| s.to_string()),
             start_time,
             end_time,
             location: doc.get_str("location").ok().map(|s| s.to_string()),
             is_all_day: doc.get_bool("is_all_day")?,
+            time_zone: doc.get_str("time_zone").ok().map(|s| s.to_string()),
             recurrence_rule: doc.get_str("recurrence_rule").ok().map(|s| s.to_string()),
+            recurrence_dates: date_list(&doc, "recurrence_dates"),
+            excluded_dates: date_list(&doc, "excluded_dates"),
+            recurring_event_id: doc.get_object_id("recurring_event_id").ok(),
+            recurrence_id: doc
+                .get_datetime("recurrence_id")
+                .ok()
+                .and_then(|date| DateTime::<Utc>::from_timestamp_millis(date.timestamp_millis())),
             attendees: doc.get_array("attendees")?.iter().map(|v| v.as_str().unwrap_or_default().to_string()).collect(),
             color: doc.get_str("color").ok().map(|s| s.to_string()),
+            calendar_id: doc.get_str("calendar_id").ok().map(|s| s.to_string()),
+            ical_uid: doc.get_str("ical_uid").ok().map(|s| s.to_string()),
+            dav_name: doc.get_str("dav_name").ok().map(|s| s.to_string()),
+            external_id: doc.get_str("external_id").ok().map(|s| s.to_string()),
+            synced_at: doc
+                .get_datetime("synced_at")
+                .ok()
+                .and_then(|date| DateTime::<Utc>::from_timestamp_millis(date.timestamp_millis())),
             created_at,
             updated_at,
+            deleted_at: doc
+                .get_datetime("deleted_at")
+                .ok()
+                .and_then(|date| DateTime::<Utc>::from_timestamp_millis(date.timestamp_millis())),
         })
     }
-} 
\ No newline at end of file
+} 
+
+/// Reads an optional array of BSON datetimes.
+fn date_list(doc: &mongodb::bson::Document, key: &str) -> Vec<DateTime<Utc>> {
+    doc.get_array(key)
+        .map(|dates| {
+            dates
+                .iter()
+                .filter_map(|date| date.as_datetime())
+                .filter_map(|date| DateTime::<Utc>::from_timestamp_millis(date.timestamp_millis()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// A single occurrence of an event, as returned for a date range.
+#[derive(Debug)]
+pub struct EventOccurrence {
+    /// The event, with the start and end time of this occurrence.
+    pub event: CalendarEvent,
+    /// `{series id}_{original start}` for occurrences of a series; the event
+    /// id for single events. Stable as long as the series' rule is unchanged.
+    pub instance_id: String,
+    /// Series the occurrence belongs to; `None` for single events.
+    pub recurring_event_id: Option<ObjectId>,
+    /// Start time the recurrence assigned to this occurrence.
+    pub original_start_time: Option<DateTime<Utc>>,
+}
+
+/// Id of the occurrence of a series that originally started at `start`.
+pub fn instance_id(series_id: ObjectId, start: DateTime<Utc>) -> String {
+    format!("{}_{}", series_id.to_hex(), start.format("%Y%m%dT%H%M%SZ"))
+}
+
+/// Splits an id made by `instance_id` into the series id and original start.
+pub fn parse_instance_id(id: &str) -> Option<(ObjectId, DateTime<Utc>)> {
+    let (series_id, start) = id.split_once('_')?;
+    let start = NaiveDateTime::parse_from_str(start, "%Y%m%dT%H%M%SZ").ok()?;
+    Some((ObjectId::parse_str(series_id).ok()?, start.and_utc()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema(attendees: &[&str]) -> CalendarEventSchema {
+        CalendarEventSchema {
+            title: "Call".to_string(),
+            description: "Weekly call".to_string(),
+            start_time: Utc::now(),
+            end_time: Utc::now() + Duration::hours(1),
+            location: None,
+            color_id: None,
+            is_all_day: false,
+            time_zone: None,
+            recurrence: None,
+            attendees: Some(attendees.iter().map(|attendee| attendee.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn attendees_must_be_email_addresses() {
+        let event = CalendarEvent::try_from(schema(&[" mailto:ann@example.com", "bob@example.org", " "])).unwrap();
+        assert_eq!(event.attendees, vec!["ann@example.com", "bob@example.org"]);
+
+        for attendee in ["Bob", "ann@example.com\r\nX-INJECTED:1", "ann@example.com;ROLE=CHAIR"] {
+            assert!(CalendarEvent::try_from(schema(&[attendee])).is_err(), "{:?}", attendee);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Accounts of external calendar services, connected through OAuth 2.0.
+
+use chrono::{DateTime, Utc};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use crate::models::datetime;
+
+/// A connected account. Each user has at most one connection per provider.
+/// Tokens are stored encrypted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalendarConnection {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// User who connected the account.
+    pub user: String,
+    /// External service, e.g. `google`.
+    pub provider: String,
+    /// Encrypted access token.
+    pub access_token: String,
+    /// Encrypted refresh token.
+    pub refresh_token: String,
+    /// When the access token expires.
+    #[serde(with = "datetime::required")]
+    pub expires_at: DateTime<Utc>,
+    /// Scopes the provider granted.
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(with = "datetime::required")]
+    pub connected_at: DateTime<Utc>,
+}
+
+/// An authorization in progress, from the redirect to the provider until its
+/// callback.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OAuthState {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// Hex SHA-256 of the `state` sent to the provider.
+    pub state_hash: String,
+    /// User the account is connected for.
+    pub user: String,
+    pub provider: String,
+    /// PKCE verifier, which the token request proves the authorization with.
+    pub code_verifier: String,
+    /// When the authorization is abandoned; MongoDB drops it then.
+    #[serde(with = "datetime::required")]
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Query string the provider redirects back to the callback with.
+#[derive(Debug, Deserialize)]
+pub struct OAuthCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    /// Set instead of `code` when the user denied access.
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+/// Response of an OAuth token endpoint.
+#[derive(Debug, Deserialize)]
+pub struct OAuthTokenResponse {
+    pub access_token: String,
+    /// Seconds until the access token expires.
+    pub expires_in: Option<i64>,
+    /// Sent with the first token, and by providers that rotate it on refresh.
+    pub refresh_token: Option<String>,
+    pub scope: Option<String>,
+}
+
+/// Error body of an OAuth token endpoint.
+#[derive(Debug, Deserialize)]
+pub struct OAuthErrorResponse {
+    pub error: String,
+    pub error_description: Option<String>,
+}
@@ -0,0 +1,31 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use crate::models::datetime;
+
+/// A secret link serving a read-only iCalendar feed of a calendar.
+///
+/// Only a hash of the token in the link is stored, so links are shown once,
+/// when they are created or regenerated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalendarFeed {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// Calendar served by the feed; every calendar when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calendar_id: Option<String>,
+    /// Hex SHA-256 of the token.
+    pub token_hash: String,
+    #[serde(with = "datetime::required")]
+    pub created_at: DateTime<Utc>,
+    /// When the current token was issued; earlier links no longer work.
+    #[serde(with = "datetime::required")]
+    pub token_issued_at: DateTime<Utc>,
+}
+
+/// Body of `POST /api/calendar/feeds`.
+#[derive(Debug, Default, Deserialize)]
+pub struct CalendarFeedSchema {
+    /// Calendar to serve; every calendar when unset.
+    pub calendar_id: Option<String>,
+}
@@ -0,0 +1,109 @@
+//! State and results of syncing a local calendar with an external one.
+
+use chrono::{DateTime, Utc};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use crate::models::datetime;
+use crate::routes::auth::LOCAL_USER;
+
+/// External calendar services events are synced with.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CalendarProvider {
+    Google,
+    /// Outlook, through Microsoft Graph.
+    Microsoft,
+}
+
+impl CalendarProvider {
+    /// Name connections and sync states are stored under.
+    pub fn name(self) -> &'static str {
+        match self {
+            CalendarProvider::Google => "google",
+            CalendarProvider::Microsoft => "microsoft",
+        }
+    }
+}
+
+/// Body of `POST /api/calendar/sync/{provider}`.
+#[derive(Debug, Deserialize)]
+pub struct CalendarSyncRequest {
+    /// Calendar of the connected account to sync; its default calendar when
+    /// unset.
+    #[serde(default = "primary_calendar")]
+    pub calendar: String,
+}
+
+/// Stands for the default calendar of a connected account.
+pub const PRIMARY_CALENDAR: &str = "primary";
+
+fn primary_calendar() -> String {
+    PRIMARY_CALENDAR.to_string()
+}
+
+/// Where the sync of an external calendar left off.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalendarSyncState {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// User whose connected account the calendar belongs to.
+    pub user: String,
+    /// External service, e.g. `google`.
+    pub provider: String,
+    /// Id of the calendar in the external service.
+    pub remote_calendar_id: String,
+    /// Local calendar holding the synced events.
+    pub calendar_id: String,
+    /// Token, or for Microsoft Graph the delta link, for fetching the changes
+    /// since the last sync; `None` before the first sync, or after the
+    /// service expired it.
+    pub sync_token: Option<String>,
+    #[serde(default, with = "datetime::optional", skip_serializing_if = "Option::is_none")]
+    pub last_synced_at: Option<DateTime<Utc>>,
+}
+
+impl CalendarSyncState {
+    /// State of a calendar that hasn't been synced yet. Its events go to the
+    /// calendar `{user}:{provider}:{remote calendar id}`, or for the local
+    /// user `{provider}:{remote calendar id}`.
+    pub fn new(user: &str, provider: &str, remote_calendar_id: &str) -> Self {
+        let calendar_id = match user {
+            LOCAL_USER => format!("{}:{}", provider, remote_calendar_id),
+            user => format!("{}:{}:{}", user, provider, remote_calendar_id),
+        };
+        CalendarSyncState {
+            id: None,
+            user: user.to_string(),
+            provider: provider.to_string(),
+            remote_calendar_id: remote_calendar_id.to_string(),
+            calendar_id,
+            sync_token: None,
+            last_synced_at: None,
+        }
+    }
+}
+
+/// Events created, updated and deleted on one side of a sync.
+#[derive(Debug, Default, Serialize)]
+pub struct SyncCounts {
+    pub created: usize,
+    pub updated: usize,
+    pub deleted: usize,
+}
+
+/// Summary of a sync.
+#[derive(Debug, Default, Serialize)]
+pub struct CalendarSyncReport {
+    /// Local calendar the events were synced into.
+    pub calendar_id: String,
+    /// Whether every event was fetched, rather than the changes since the
+    /// last sync.
+    pub full_sync: bool,
+    /// Changes applied to the local calendar.
+    pub pulled: SyncCounts,
+    /// Changes sent to the external calendar.
+    pub pushed: SyncCounts,
+    /// Events changed on both sides, resolved in favour of the later change.
+    pub conflicts: usize,
+    pub warnings: Vec<String>,
+}
@@ -0,0 +1,80 @@
+//! Serde helpers for timestamps that are stored as BSON datetimes but exposed
+//! as RFC 3339 strings in JSON.
+
+use chrono::{DateTime, Utc};
+use mongodb::bson::{Bson, DateTime as BsonDateTime};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Converts a chrono timestamp to a BSON datetime for use in queries and updates.
+pub fn to_bson(date: DateTime<Utc>) -> BsonDateTime {
+    BsonDateTime::from_millis(date.timestamp_millis())
+}
+
+/// (De)serialises `Option<DateTime<Utc>>`.
+///
+/// The MongoDB driver serialises in non-human-readable mode, where the value is
+/// written as a BSON datetime; JSON gets an RFC 3339 string. Both BSON
+/// datetimes and legacy RFC 3339 strings are accepted when reading.
+pub mod optional {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(date) if !serializer.is_human_readable() => to_bson(*date).serialize(serializer),
+            Some(date) => date.serialize(serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+        match Option::<Bson>::deserialize(deserializer)? {
+            None | Some(Bson::Null) => Ok(None),
+            Some(Bson::DateTime(date)) => Ok(DateTime::<Utc>::from_timestamp_millis(date.timestamp_millis())),
+            Some(Bson::String(text)) => DateTime::parse_from_rfc3339(&text)
+                .map(|date| Some(date.with_timezone(&Utc)))
+                .map_err(D::Error::custom),
+            Some(other) => Err(D::Error::custom(format!("expected a datetime, found {}", other))),
+        }
+    }
+}
+
+/// (De)serialises `DateTime<Utc>` like `optional`, for required timestamps.
+pub mod required {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+        optional::serialize(&Some(*value), serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+        optional::deserialize(deserializer)?.ok_or_else(|| D::Error::custom("expected a datetime, found null"))
+    }
+}
+
+/// (De)serialises `Vec<DateTime<Utc>>` like `optional`, for lists of timestamps.
+pub mod list {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(values: &[DateTime<Utc>], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            values.serialize(serializer)
+        } else {
+            values.iter().map(|date| to_bson(*date)).collect::<Vec<_>>().serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<DateTime<Utc>>, D::Error> {
+        let values = Option::<Vec<Bson>>::deserialize(deserializer)?.unwrap_or_default();
+        values
+            .into_iter()
+            .map(|value| match value {
+                Bson::DateTime(date) => DateTime::<Utc>::from_timestamp_millis(date.timestamp_millis())
+                    .ok_or_else(|| D::Error::custom("datetime out of range")),
+                Bson::String(text) => DateTime::parse_from_rfc3339(&text)
+                    .map(|date| date.with_timezone(&Utc))
+                    .map_err(D::Error::custom),
+                other => Err(D::Error::custom(format!("expected a datetime, found {}", other))),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,92 @@
+//! Resources of the Google Calendar API (v3), as far as the sync reads and
+//! writes them.
+
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Status of events deleted in Google Calendar, and of cancelled occurrences.
+pub const CANCELLED: &str = "cancelled";
+
+/// An event resource.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GoogleEvent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<GoogleEventTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<GoogleEventTime>,
+    /// `RRULE`, `RDATE` and `EXDATE` lines of a series.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recurrence: Vec<String>,
+    /// Series whose occurrence this event is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurring_event_id: Option<String>,
+    /// Start the occurrence has in its series.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_start_time: Option<GoogleEventTime>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attendees: Vec<GoogleAttendee>,
+    #[serde(default, skip_serializing)]
+    pub updated: Option<DateTime<Utc>>,
+}
+
+impl GoogleEvent {
+    pub fn is_cancelled(&self) -> bool {
+        self.status.as_deref() == Some(CANCELLED)
+    }
+}
+
+/// Start or end of an event: a `date` for all-day events, a `dateTime`
+/// otherwise.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GoogleEventTime {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<NaiveDate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date_time: Option<DateTime<Utc>>,
+    /// IANA time zone the recurrence of a series is expanded in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_zone: Option<String>,
+}
+
+impl GoogleEventTime {
+    /// The time as stored locally, and whether it is a date. Dates stand for
+    /// midnight UTC, as all-day events do.
+    pub fn instant(&self) -> Result<(DateTime<Utc>, bool), String> {
+        match (self.date_time, self.date) {
+            (Some(date_time), _) => Ok((date_time, false)),
+            (None, Some(date)) => Ok((date.and_time(NaiveTime::MIN).and_utc(), true)),
+            (None, None) => Err("Missing date".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GoogleAttendee {
+    #[serde(default)]
+    pub email: String,
+}
+
+/// A page of `events.list`. The last page has a `nextSyncToken` instead of a
+/// `nextPageToken`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GoogleEventList {
+    #[serde(default)]
+    pub items: Vec<GoogleEvent>,
+    pub next_page_token: Option<String>,
+    pub next_sync_token: Option<String>,
+}
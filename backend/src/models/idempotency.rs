@@ -0,0 +1,25 @@
+use mongodb::bson::{Binary, DateTime as BsonDateTime};
+use serde::{Deserialize, Serialize};
+
+/// A request made with an `Idempotency-Key`, and its response once known.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    /// The client-supplied key.
+    #[serde(rename = "_id")]
+    pub key: String,
+    /// SHA-256 of the method, path, query and body of the first request.
+    pub fingerprint: String,
+    /// `None` while the first request is still being handled.
+    pub response: Option<StoredResponse>,
+    pub created_at: BsonDateTime,
+    /// Removed by a TTL index after this time.
+    pub expires_at: BsonDateTime,
+}
+
+/// A response as it is replayed to retries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Binary,
+}
@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+
+/// What happens to an imported note whose title already exists in its notebook.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateStrategy {
+    /// Leave the existing note alone.
+    #[default]
+    Skip,
+    /// Replace the existing note's content and tags.
+    Overwrite,
+    /// Import the note next to the existing one.
+    KeepBoth,
+}
+
+/// Options for an import run.
+#[derive(Debug, Default, Deserialize)]
+pub struct ImportOptions {
+    /// Only report what would be imported.
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub duplicates: DuplicateStrategy,
+    /// Notebook the imported folders are created under; top level when omitted.
+    pub notebook_id: Option<String>,
+}
+
+/// Source format of an import archive.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    /// A zip of Markdown files, such as an Obsidian vault.
+    Markdown,
+    /// An Evernote export, on its own or inside a zip.
+    Enex,
+}
+
+/// What the import does with a single note.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportAction {
+    Create,
+    Overwrite,
+    Skip,
+}
+
+/// Outcome of importing one note.
+#[derive(Debug, Serialize)]
+pub struct ImportItem {
+    /// Path of the note inside the archive.
+    pub path: String,
+    pub title: String,
+    /// Notebook path, folders separated by `/`.
+    pub notebook: Option<String>,
+    pub action: ImportAction,
+    /// Id of the created or overwritten note; omitted on dry runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// A `[[wiki link]]` that didn't match any imported or existing note.
+#[derive(Debug, Serialize)]
+pub struct UnresolvedLink {
+    pub path: String,
+    pub target: String,
+}
+
+/// Summary of an import run.
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub format: ImportFormat,
+    pub notes_created: usize,
+    pub notes_overwritten: usize,
+    pub notes_skipped: usize,
+    pub notebooks_created: usize,
+    pub items: Vec<ImportItem>,
+    pub unresolved_links: Vec<UnresolvedLink>,
+    pub warnings: Vec<String>,
+}
@@ -0,0 +1,163 @@
+//! Resources of Microsoft Graph (v1.0) calendars, as far as the sync reads and
+//! writes them.
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// `type` of an occurrence that still matches its series.
+pub const OCCURRENCE: &str = "occurrence";
+
+/// `type` of an occurrence changed on its own.
+pub const EXCEPTION: &str = "exception";
+
+/// `type` of a series.
+pub const SERIES_MASTER: &str = "seriesMaster";
+
+/// An event resource.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphEvent {
+    #[serde(default, skip_serializing)]
+    pub id: Option<String>,
+    /// Set instead of the event's fields when delta lists it as deleted.
+    #[serde(default, rename = "@removed", skip_serializing)]
+    pub removed: Option<serde_json::Value>,
+    /// `singleInstance`, `occurrence`, `exception` or `seriesMaster`.
+    #[serde(default, rename = "type", skip_serializing)]
+    pub event_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<GraphItemBody>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<GraphDateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<GraphDateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_all_day: Option<bool>,
+    #[serde(default, skip_serializing)]
+    pub is_cancelled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<GraphLocation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attendees: Option<Vec<GraphAttendee>>,
+    /// Recurrence of a series; `Some(None)` clears it, `None` leaves it out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurrence: Option<Option<PatternedRecurrence>>,
+    /// Series whose occurrence this event is.
+    #[serde(default, skip_serializing)]
+    pub series_master_id: Option<String>,
+    /// Start the occurrence has in its series.
+    #[serde(default, skip_serializing)]
+    pub original_start: Option<DateTime<Utc>>,
+    /// Time zone the event was created in, which its series is expanded in.
+    /// Windows or IANA name.
+    #[serde(default, skip_serializing)]
+    pub original_start_time_zone: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub last_modified_date_time: Option<DateTime<Utc>>,
+}
+
+impl GraphEvent {
+    pub fn is_cancelled(&self) -> bool {
+        self.removed.is_some() || self.is_cancelled == Some(true)
+    }
+
+    pub fn is_type(&self, event_type: &str) -> bool {
+        self.event_type.as_deref() == Some(event_type)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphItemBody {
+    /// `text` or `html`.
+    pub content_type: String,
+    #[serde(default)]
+    pub content: String,
+}
+
+/// Local date and time in a time zone, which may be a Windows or IANA name.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphDateTime {
+    pub date_time: NaiveDateTime,
+    pub time_zone: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphLocation {
+    #[serde(default)]
+    pub display_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphAttendee {
+    pub email_address: GraphEmailAddress,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GraphEmailAddress {
+    #[serde(default)]
+    pub address: Option<String>,
+}
+
+/// Recurrence of a series: how it repeats and until when.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PatternedRecurrence {
+    pub pattern: RecurrencePattern,
+    pub range: RecurrenceRange,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecurrencePattern {
+    /// `daily`, `weekly`, `absoluteMonthly`, `relativeMonthly`,
+    /// `absoluteYearly` or `relativeYearly`.
+    #[serde(rename = "type")]
+    pub pattern_type: String,
+    pub interval: u32,
+    /// Lowercase English day names.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days_of_week: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub day_of_month: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub month: Option<u32>,
+    /// Which of the `days_of_week` in the month of a relative pattern:
+    /// `first` to `fourth`, or `last`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_day_of_week: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecurrenceRange {
+    /// `noEnd`, `endDate` or `numbered`.
+    #[serde(rename = "type")]
+    pub range_type: String,
+    pub start_date: NaiveDate,
+    /// Last day of an `endDate` range, inclusive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_date: Option<NaiveDate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub number_of_occurrences: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurrence_time_zone: Option<String>,
+}
+
+/// A page of events. The last page of a delta query has a `@odata.deltaLink`
+/// for the next changes instead of a `@odata.nextLink`.
+#[derive(Debug, Deserialize)]
+pub struct GraphEventPage {
+    #[serde(default)]
+    pub value: Vec<GraphEvent>,
+    #[serde(rename = "@odata.nextLink")]
+    pub next_link: Option<String>,
+    #[serde(rename = "@odata.deltaLink")]
+    pub delta_link: Option<String>,
+}
@@ -1,3 +1,18 @@
 pub mod note;
 pub mod todo;
-pub mod calendar;
\ No newline at end of file
+pub mod calendar;
+pub mod recurrence;
+pub mod calendar_feed;
+pub mod calendar_connection;
+pub mod calendar_sync;
+pub mod google_calendar;
+pub mod microsoft_calendar;
+pub mod caldav;
+pub mod notebook;
+pub mod attachment;
+pub mod template;
+pub mod datetime;
+pub mod import;
+pub mod trash;
+pub mod bulk;
+pub mod idempotency;
\ No newline at end of file
@@ -1,20 +1,53 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, Utc};
 use mongodb::bson::{doc, oid::ObjectId};
 use serde::{Deserialize, Serialize};
-use validator::Validate;
+use std::borrow::Cow;
+use crate::models::datetime;
+use validator::{Validate, ValidationError, ValidationErrors};
 
-#[derive(Debug, Serialize, Deserialize, Validate)]
+/// Longest encrypted title accepted, in base64 characters.
+const MAX_ENCRYPTED_TITLE_LENGTH: u64 = 1024;
+
+/// Longest encrypted content accepted, in base64 characters.
+const MAX_ENCRYPTED_CONTENT_LENGTH: u64 = 8 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Note {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
-    #[validate(length(min = 1, max = 100, message = "Title must be between 1 and 100 characters"))]
+    /// Plain text, or base64 ciphertext for encrypted notes.
     pub title: String,
-    #[validate(length(min = 1, message = "Content cannot be empty"))]
+    /// Markdown, or base64 ciphertext for encrypted notes.
     pub content: String,
-    pub created_at: Option<String>,
-    pub updated_at: Option<String>,
+    #[serde(default, with = "datetime::optional")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default, with = "datetime::optional")]
+    pub updated_at: Option<DateTime<Utc>>,
     pub tags: Option<Vec<String>>,
     pub is_archived: Option<bool>,
+    /// Pinned notes are listed before all others.
+    pub is_pinned: Option<bool>,
+    pub is_favourite: Option<bool>,
     pub user_id: Option<String>,
+    /// Whether unchecked task-list items are turned into linked todos.
+    pub extract_todos: Option<bool>,
+    /// Notebook the note is filed in; `None` for unfiled notes.
+    pub notebook_id: Option<ObjectId>,
+    /// Manual sort position within the notebook.
+    pub position: Option<i32>,
+    /// Calendar date (YYYY-MM-DD) for daily notes; unique across notes.
+    pub daily_date: Option<String>,
+    /// Set for end-to-end encrypted notes, whose title and content are
+    /// ciphertext the server cannot read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<NoteEncryption>,
+    /// When the note was moved to the trash; `None` while it is live.
+    #[serde(default, with = "datetime::optional", skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Incremented on every write; exposed as the note's `ETag`.
+    #[serde(default)]
+    pub version: i64,
 }
 
 impl Note {
@@ -27,7 +60,198 @@ impl Note {
             updated_at: None,
             tags: Some(Vec::new()),
             is_archived: Some(false),
+            is_pinned: Some(false),
+            is_favourite: Some(false),
             user_id: None,
+            extract_todos: None,
+            notebook_id: None,
+            position: None,
+            daily_date: None,
+            encryption: None,
+            deleted_at: None,
+            version: 1,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+}
+
+impl Validate for Note {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        match &self.encryption {
+            None => {
+                check_length(&mut errors, "title", &self.title, 1, 100, "Title must be between 1 and 100 characters");
+                check_length(&mut errors, "content", &self.content, 1, u64::MAX, "Content cannot be empty");
+            }
+            Some(encryption) => {
+                check_ciphertext(&mut errors, "title", &self.title, MAX_ENCRYPTED_TITLE_LENGTH);
+                check_ciphertext(&mut errors, "content", &self.content, MAX_ENCRYPTED_CONTENT_LENGTH);
+                if let Err(e) = encryption.validate() {
+                    errors.add("encryption", validation_error("encryption", Cow::Owned(e)));
+                }
+            }
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+fn validation_error(code: &'static str, message: Cow<'static, str>) -> ValidationError {
+    let mut error = ValidationError::new(code);
+    error.message = Some(message);
+    error
+}
+
+fn check_length(errors: &mut ValidationErrors, field: &'static str, value: &str, min: u64, max: u64, message: &'static str) {
+    let length = value.chars().count() as u64;
+    if length < min || length > max {
+        let mut error = validation_error("length", Cow::Borrowed(message));
+        error.add_param(Cow::Borrowed("min"), &min);
+        if max != u64::MAX {
+            error.add_param(Cow::Borrowed("max"), &max);
+        }
+        error.add_param(Cow::Borrowed("value"), &value);
+        errors.add(field, error);
+    }
+}
+
+fn check_ciphertext(errors: &mut ValidationErrors, field: &'static str, value: &str, max: u64) {
+    if value.is_empty() || value.len() as u64 > max {
+        let mut error = validation_error(
+            "length",
+            Cow::Owned(format!("Encrypted {} must be between 1 and {} characters", field, max)),
+        );
+        error.add_param(Cow::Borrowed("min"), &1);
+        error.add_param(Cow::Borrowed("max"), &max);
+        errors.add(field, error);
+    } else if BASE64.decode(value).is_err() {
+        errors.add(field, validation_error("ciphertext", Cow::Borrowed("Ciphertext must be base64 encoded")));
+    }
+}
+
+/// Cipher used by the client to encrypt a note.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NoteCipher {
+    Aes256Gcm,
+    Xchacha20Poly1305,
+}
+
+impl NoteCipher {
+    /// Nonce length in bytes.
+    pub fn nonce_length(self) -> usize {
+        match self {
+            NoteCipher::Aes256Gcm => 12,
+            NoteCipher::Xchacha20Poly1305 => 24,
         }
     }
 }
+
+/// Metadata needed by clients to decrypt a note.
+///
+/// Each note is encrypted with its own data key, which is stored wrapped
+/// (encrypted) with a key that never reaches the server. Rotating that key
+/// only rewraps the data key; the ciphertext stays the same.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct NoteEncryption {
+    pub cipher: NoteCipher,
+    /// Base64 nonce used to encrypt the content.
+    pub nonce: String,
+    /// Base64 nonce used to encrypt the title.
+    pub title_nonce: String,
+    /// Client-chosen id of the key that wraps the data key.
+    pub key_id: String,
+    /// Base64 data key, encrypted with the key named by `key_id`.
+    pub wrapped_key: String,
+}
+
+impl NoteEncryption {
+    /// Checks that the nonces fit the cipher and the key fields are well formed.
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, nonce) in [("nonce", &self.nonce), ("title_nonce", &self.title_nonce)] {
+            match BASE64.decode(nonce) {
+                Ok(bytes) if bytes.len() == self.cipher.nonce_length() => {}
+                _ => return Err(format!("{} must be {} base64-encoded bytes", name, self.cipher.nonce_length())),
+            }
+        }
+        if self.key_id.is_empty() || self.key_id.len() > 100 {
+            return Err("key_id must be between 1 and 100 characters".to_string());
+        }
+        if BASE64.decode(&self.wrapped_key).map(|key| key.is_empty()).unwrap_or(true) {
+            return Err("wrapped_key must be base64 encoded".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Request to rewrap an encrypted note's data key with a new key.
+#[derive(Debug, Deserialize)]
+pub struct KeyRotation {
+    /// Key the data key is currently wrapped with; guards against lost updates.
+    pub from_key_id: String,
+    pub key_id: String,
+    pub wrapped_key: String,
+}
+
+/// A single entry in a note's heading outline.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NoteHeading {
+    pub level: u8,
+    pub text: String,
+}
+
+/// A note together with its server-side Markdown rendering.
+#[derive(Debug, Serialize)]
+pub struct RenderedNote {
+    #[serde(flatten)]
+    pub note: Note,
+    pub html: String,
+    pub excerpt: String,
+    pub outline: Vec<NoteHeading>,
+}
+
+/// Field the notes list is ordered by.
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NoteSort {
+    #[default]
+    Updated,
+    Created,
+    Title,
+    /// The manual position set by moving notes.
+    Manual,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// Sorting, filtering and pagination options for listing notes.
+#[derive(Debug, Default, Deserialize)]
+pub struct NoteListQuery {
+    #[serde(default)]
+    pub sort: NoteSort,
+    /// Defaults to newest first for dates and A-Z for titles and manual order.
+    pub order: Option<SortOrder>,
+    /// Page size; all notes are returned when omitted.
+    pub limit: Option<u32>,
+    /// Opaque cursor from a previous page's `X-Next-Cursor` header.
+    pub cursor: Option<String>,
+    pub pinned: Option<bool>,
+    pub favourite: Option<bool>,
+    /// Only encrypted notes whose data key is wrapped with this key.
+    pub key_id: Option<String>,
+}
+
+/// One page of notes.
+#[derive(Debug)]
+pub struct NotePage {
+    pub notes: Vec<Note>,
+    /// Cursor for the following page, if there is one.
+    pub next_cursor: Option<String>,
+}
@@ -0,0 +1,54 @@
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct Notebook {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    #[validate(length(min = 1, max = 100, message = "Name must be between 1 and 100 characters"))]
+    pub name: String,
+    /// Parent notebook; `None` for top-level notebooks.
+    pub parent_id: Option<ObjectId>,
+    /// Sort position among the notebook's siblings.
+    pub position: i32,
+    /// The notebook that receives notes from deleted notebooks.
+    #[serde(default)]
+    pub is_default: bool,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+}
+
+impl Notebook {
+    pub fn new(name: String, parent_id: Option<ObjectId>) -> Self {
+        Notebook {
+            id: None,
+            name,
+            parent_id,
+            position: 0,
+            is_default: false,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+}
+
+/// A notebook together with the number of notes and child notebooks it holds.
+#[derive(Debug, Serialize)]
+pub struct NotebookSummary {
+    #[serde(flatten)]
+    pub notebook: Notebook,
+    pub note_count: u64,
+    pub notebook_count: u64,
+}
+
+/// What happens to the contents of a notebook when it is deleted.
+#[derive(Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotebookDeleteMode {
+    /// Move notes to the default notebook and child notebooks up one level.
+    #[default]
+    Move,
+    /// Delete the notebook, its descendants and every note inside them.
+    Recursive,
+}
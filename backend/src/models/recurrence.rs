@@ -0,0 +1,320 @@
+//! RFC 5545 recurrence rules (`RRULE`) and recurrence date lists (`RDATE`,
+//! `EXDATE`) as sent by calendar clients.
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// A `BYDAY` entry such as `MO`, `2TU` or `-1FR`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByDay {
+    /// Which occurrence of the weekday within the month or year; `None` for every one.
+    pub ordinal: Option<i32>,
+    pub weekday: Weekday,
+}
+
+/// A parsed `RRULE` value.
+///
+/// Supports `FREQ` (daily to yearly), `INTERVAL`, `COUNT`, `UNTIL`, `BYDAY`,
+/// `BYMONTHDAY`, `BYMONTH`, `BYSETPOS` and `WKST`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecurrenceRule {
+    pub frequency: Frequency,
+    pub interval: u32,
+    pub count: Option<u32>,
+    pub until: Option<Until>,
+    pub by_day: Vec<ByDay>,
+    pub by_month_day: Vec<i32>,
+    pub by_month: Vec<u32>,
+    pub by_set_pos: Vec<i32>,
+    pub week_start: Weekday,
+}
+
+/// End of a rule, inclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Until {
+    /// `UNTIL=20241231T235959Z`
+    Utc(DateTime<Utc>),
+    /// `UNTIL=20241231T235959`, in the event's time zone.
+    Floating(NaiveDateTime),
+    /// `UNTIL=20241231`, the whole day in the event's time zone.
+    Date(NaiveDate),
+}
+
+impl FromStr for RecurrenceRule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let value = value.strip_prefix("RRULE:").unwrap_or(value);
+
+        let mut frequency = None;
+        let mut rule = RecurrenceRule {
+            frequency: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: Vec::new(),
+            by_month_day: Vec::new(),
+            by_month: Vec::new(),
+            by_set_pos: Vec::new(),
+            week_start: Weekday::Mon,
+        };
+        for part in value.split(';').filter(|part| !part.is_empty()) {
+            let (name, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid RRULE part: {}", part))?;
+            match name.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    frequency = Some(match value.to_ascii_uppercase().as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        other => return Err(format!("Unsupported FREQ: {}", other)),
+                    })
+                }
+                "INTERVAL" => {
+                    rule.interval = value
+                        .parse()
+                        .ok()
+                        .filter(|interval| *interval > 0)
+                        .ok_or_else(|| format!("Invalid INTERVAL: {}", value))?
+                }
+                "COUNT" => {
+                    rule.count = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|count| *count > 0)
+                            .ok_or_else(|| format!("Invalid COUNT: {}", value))?,
+                    )
+                }
+                "UNTIL" => rule.until = Some(parse_until(value)?),
+                "BYDAY" => rule.by_day = parse_list(value, parse_by_day)?,
+                "BYMONTHDAY" => rule.by_month_day = parse_list(value, |v| parse_ranged(v, "BYMONTHDAY", 31))?,
+                "BYMONTH" => {
+                    rule.by_month = parse_list(value, |v| {
+                        v.parse().ok().filter(|month| (1..=12).contains(month)).ok_or_else(|| format!("Invalid BYMONTH: {}", v))
+                    })?
+                }
+                "BYSETPOS" => rule.by_set_pos = parse_list(value, |v| parse_ranged(v, "BYSETPOS", 366))?,
+                "WKST" => rule.week_start = parse_weekday(value)?,
+                other => return Err(format!("Unsupported RRULE part: {}", other)),
+            }
+        }
+
+        rule.frequency = frequency.ok_or("RRULE must have a FREQ")?;
+        if rule.count.is_some() && rule.until.is_some() {
+            return Err("RRULE cannot have both COUNT and UNTIL".to_string());
+        }
+        let has_ordinals = rule.by_day.iter().any(|day| day.ordinal.is_some());
+        if has_ordinals && matches!(rule.frequency, Frequency::Daily | Frequency::Weekly) {
+            return Err("Numbered BYDAY values are only allowed with FREQ=MONTHLY or FREQ=YEARLY".to_string());
+        }
+        if !rule.by_month_day.is_empty() && rule.frequency == Frequency::Weekly {
+            return Err("BYMONTHDAY is not allowed with FREQ=WEEKLY".to_string());
+        }
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for RecurrenceRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frequency = match self.frequency {
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+            Frequency::Monthly => "MONTHLY",
+            Frequency::Yearly => "YEARLY",
+        };
+        write!(f, "FREQ={}", frequency)?;
+        if self.interval != 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        if let Some(count) = self.count {
+            write!(f, ";COUNT={}", count)?;
+        }
+        match self.until {
+            Some(Until::Utc(until)) => write!(f, ";UNTIL={}", until.format("%Y%m%dT%H%M%SZ"))?,
+            Some(Until::Floating(until)) => write!(f, ";UNTIL={}", until.format("%Y%m%dT%H%M%S"))?,
+            Some(Until::Date(until)) => write!(f, ";UNTIL={}", until.format("%Y%m%d"))?,
+            None => {}
+        }
+        if !self.by_day.is_empty() {
+            let days: Vec<String> = self
+                .by_day
+                .iter()
+                .map(|day| format!("{}{}", day.ordinal.map(|n| n.to_string()).unwrap_or_default(), weekday_code(day.weekday)))
+                .collect();
+            write!(f, ";BYDAY={}", days.join(","))?;
+        }
+        write_list(f, "BYMONTHDAY", &self.by_month_day)?;
+        write_list(f, "BYMONTH", &self.by_month)?;
+        write_list(f, "BYSETPOS", &self.by_set_pos)?;
+        if self.week_start != Weekday::Mon {
+            write!(f, ";WKST={}", weekday_code(self.week_start))?;
+        }
+        Ok(())
+    }
+}
+
+/// Recurrence of an event, split out of the `RRULE`, `RDATE` and `EXDATE`
+/// lines of a client request.
+#[derive(Debug, Default, PartialEq)]
+pub struct Recurrence {
+    pub rule: Option<RecurrenceRule>,
+    /// Extra occurrences, by start time.
+    pub dates: Vec<DateTime<Utc>>,
+    /// Occurrences to leave out, by start time.
+    pub excluded_dates: Vec<DateTime<Utc>>,
+}
+
+impl Recurrence {
+    /// Parses recurrence lines such as `RRULE:FREQ=WEEKLY;BYDAY=MO`,
+    /// `RDATE;TZID=Europe/Paris:20240105T090000` or `EXDATE;VALUE=DATE:20240112`.
+    ///
+    /// Times without a `Z` suffix or `TZID` are in `time_zone`; dates without a
+    /// time take the time of day of `start`.
+    pub fn parse(lines: &[String], start: DateTime<Utc>, time_zone: Tz) -> Result<Self, String> {
+        let mut recurrence = Recurrence::default();
+        for line in lines.iter().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+            let (head, value) = line.split_once(':').ok_or_else(|| format!("Invalid recurrence line: {}", line))?;
+            let mut params = head.split(';');
+            let name = params.next().unwrap_or_default().to_ascii_uppercase();
+            match name.as_str() {
+                "RRULE" => {
+                    if recurrence.rule.is_some() {
+                        return Err("Only one RRULE is supported".to_string());
+                    }
+                    recurrence.rule = Some(value.parse()?);
+                }
+                "RDATE" | "EXDATE" => {
+                    let mut zone = time_zone;
+                    for param in params {
+                        if let Some(tzid) = param.strip_prefix("TZID=") {
+                            zone = tzid.parse().map_err(|_| format!("Unknown time zone: {}", tzid))?;
+                        }
+                    }
+                    let dates = value
+                        .split(',')
+                        .map(|date| parse_date_time(date.trim(), start, zone))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if name == "RDATE" {
+                        recurrence.dates.extend(dates);
+                    } else {
+                        recurrence.excluded_dates.extend(dates);
+                    }
+                }
+                other => return Err(format!("Unsupported recurrence line: {}", other)),
+            }
+        }
+        recurrence.dates.sort();
+        recurrence.dates.dedup();
+        recurrence.excluded_dates.sort();
+        recurrence.excluded_dates.dedup();
+        Ok(recurrence)
+    }
+}
+
+/// Converts a local time to UTC. Times skipped by a DST change move forward
+/// by the length of the gap; repeated times resolve to the first of the two.
+pub fn local_to_utc(local: NaiveDateTime, time_zone: Tz) -> DateTime<Utc> {
+    time_zone
+        .from_local_datetime(&local)
+        .earliest()
+        .or_else(|| time_zone.from_local_datetime(&(local + chrono::Duration::hours(1))).earliest())
+        .map(|date| date.with_timezone(&Utc))
+        .unwrap_or_else(|| Utc.from_utc_datetime(&local))
+}
+
+fn parse_date_time(value: &str, start: DateTime<Utc>, time_zone: Tz) -> Result<DateTime<Utc>, String> {
+    if let Some(utc) = value.strip_suffix('Z') {
+        return NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S")
+            .map(|date| Utc.from_utc_datetime(&date))
+            .map_err(|_| format!("Invalid date: {}", value));
+    }
+    if let Ok(local) = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S") {
+        return Ok(local_to_utc(local, time_zone));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y%m%d").map_err(|_| format!("Invalid date: {}", value))?;
+    let time = start.with_timezone(&time_zone).time();
+    Ok(local_to_utc(date.and_time(time), time_zone))
+}
+
+fn parse_until(value: &str) -> Result<Until, String> {
+    if let Some(utc) = value.strip_suffix('Z') {
+        if let Ok(date) = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S") {
+            return Ok(Until::Utc(Utc.from_utc_datetime(&date)));
+        }
+    } else if let Ok(date) = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S") {
+        return Ok(Until::Floating(date));
+    } else if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+        return Ok(Until::Date(date));
+    }
+    Err(format!("Invalid UNTIL: {}", value))
+}
+
+fn parse_list<T>(value: &str, parse: impl Fn(&str) -> Result<T, String>) -> Result<Vec<T>, String> {
+    value.split(',').map(|item| parse(item.trim())).collect()
+}
+
+/// Parses a non-zero number between `-max` and `max`.
+fn parse_ranged(value: &str, name: &str, max: i32) -> Result<i32, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|n: &i32| *n != 0 && n.abs() <= max)
+        .ok_or_else(|| format!("Invalid {}: {}", name, value))
+}
+
+fn parse_by_day(value: &str) -> Result<ByDay, String> {
+    let split = value.len().saturating_sub(2);
+    let (ordinal, weekday) = value.split_at(split);
+    let ordinal = match ordinal {
+        "" => None,
+        ordinal => Some(parse_ranged(ordinal.trim_start_matches('+'), "BYDAY", 53)?),
+    };
+    Ok(ByDay { ordinal, weekday: parse_weekday(weekday)? })
+}
+
+fn parse_weekday(value: &str) -> Result<Weekday, String> {
+    match value.to_ascii_uppercase().as_str() {
+        "MO" => Ok(Weekday::Mon),
+        "TU" => Ok(Weekday::Tue),
+        "WE" => Ok(Weekday::Wed),
+        "TH" => Ok(Weekday::Thu),
+        "FR" => Ok(Weekday::Fri),
+        "SA" => Ok(Weekday::Sat),
+        "SU" => Ok(Weekday::Sun),
+        _ => Err(format!("Invalid weekday: {}", value)),
+    }
+}
+
+fn weekday_code(weekday: Weekday) -> &'static str {
+    match weekday {
+        Weekday::Mon => "MO",
+        Weekday::Tue => "TU",
+        Weekday::Wed => "WE",
+        Weekday::Thu => "TH",
+        Weekday::Fri => "FR",
+        Weekday::Sat => "SA",
+        Weekday::Sun => "SU",
+    }
+}
+
+fn write_list<T: fmt::Display>(f: &mut fmt::Formatter<'_>, name: &str, values: &[T]) -> fmt::Result {
+    if values.is_empty() {
+        return Ok(());
+    }
+    let values: Vec<String> = values.iter().map(T::to_string).collect();
+    write!(f, ";{}={}", name, values.join(","))
+}
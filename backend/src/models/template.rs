@@ -0,0 +1,86 @@
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use validator::Validate;
+
+/// A custom value asked from the user when a template is applied.
+#[derive(Debug, Clone, Serialize, Deserialize, Validate)]
+pub struct TemplatePrompt {
+    /// Placeholder name, used as `{{name}}` in the template.
+    #[validate(length(min = 1, max = 50, message = "Prompt name must be between 1 and 50 characters"))]
+    pub name: String,
+    /// Question shown to the user.
+    pub label: Option<String>,
+    /// Value used when the user leaves the prompt empty.
+    pub default: Option<String>,
+}
+
+/// A reusable note structure with `{{placeholder}}` variables.
+///
+/// Built-in placeholders are `{{date}}`, `{{time}}`, `{{datetime}}`,
+/// `{{weekday}}` and `{{title}}`; custom ones are declared as prompts.
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct NoteTemplate {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    #[validate(length(min = 1, max = 100, message = "Name must be between 1 and 100 characters"))]
+    pub name: String,
+    /// Title of created notes; may contain placeholders.
+    #[validate(length(min = 1, max = 100, message = "Title must be between 1 and 100 characters"))]
+    pub title: String,
+    #[validate(length(min = 1, message = "Content cannot be empty"))]
+    pub content: String,
+    pub tags: Option<Vec<String>>,
+    #[validate]
+    #[serde(default)]
+    pub prompts: Vec<TemplatePrompt>,
+    /// Whether this template is used for daily notes.
+    #[serde(default)]
+    pub is_daily: bool,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct NoteTemplateSchema {
+    #[validate(length(min = 1, max = 100, message = "Name must be between 1 and 100 characters"))]
+    pub name: String,
+    #[validate(length(min = 1, max = 100, message = "Title must be between 1 and 100 characters"))]
+    pub title: String,
+    #[validate(length(min = 1, message = "Content cannot be empty"))]
+    pub content: String,
+    pub tags: Option<Vec<String>>,
+    #[validate]
+    pub prompts: Option<Vec<TemplatePrompt>>,
+    pub is_daily: Option<bool>,
+}
+
+impl From<NoteTemplateSchema> for NoteTemplate {
+    fn from(schema: NoteTemplateSchema) -> Self {
+        NoteTemplate {
+            id: None,
+            name: schema.name,
+            title: schema.title,
+            content: schema.content,
+            tags: schema.tags,
+            prompts: schema.prompts.unwrap_or_default(),
+            is_daily: schema.is_daily.unwrap_or(false),
+            created_at: None,
+            updated_at: None,
+        }
+    }
+}
+
+/// Values supplied when creating a note from a template. Every field is
+/// optional, so `{}` takes all the defaults.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TemplateValues {
+    /// Value for `{{title}}`; defaults to the template name.
+    pub title: Option<String>,
+    /// Answers to the template's prompts, keyed by prompt name.
+    pub values: HashMap<String, String>,
+    /// IANA time zone used for date and time placeholders; defaults to UTC.
+    pub tz: Option<String>,
+    pub notebook_id: Option<String>,
+}
@@ -1,5 +1,7 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use mongodb::bson::oid::ObjectId;
+use crate::models::datetime;
 use validator::Validate;
 
 #[derive(Debug, Serialize, Deserialize, Validate)]
@@ -15,6 +17,20 @@ pub struct Todo {
     pub priority: String,
     pub created_at: String,
     pub updated_at: Option<String>,
+    /// Note this todo was extracted from, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note_id: Option<ObjectId>,
+    /// Name of the project the todo is filed under, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub is_archived: Option<bool>,
+    /// When the todo was moved to the trash; `None` while it is live.
+    #[serde(default, with = "datetime::optional", skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Incremented on every write; exposed as the todo's `ETag`.
+    #[serde(default)]
+    pub version: i64,
 }
 
 impl From<TodoSchema> for Todo {
@@ -27,6 +43,12 @@ impl From<TodoSchema> for Todo {
             priority: schema.priority,
             created_at: schema.created_at,
             updated_at: None,
+            note_id: None,
+            project: schema.project.map(|project| project.trim().to_string()).filter(|project| !project.is_empty()),
+            tags: schema.tags,
+            is_archived: Some(false),
+            deleted_at: None,
+            version: 1,
         }
     }
 }
@@ -41,4 +63,7 @@ pub struct TodoSchema {
     #[validate(length(min = 1, message = "Priority cannot be empty"))]
     pub priority: String,
     pub created_at: String,
+    #[validate(length(max = 100, message = "Project must be at most 100 characters"))]
+    pub project: Option<String>,
+    pub tags: Option<Vec<String>>,
 }
@@ -0,0 +1,38 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Kind of document that can be moved to the trash.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TrashKind {
+    Note,
+    Todo,
+    Event,
+}
+
+/// A trashed document, as listed in the trash bin.
+#[derive(Debug, Serialize)]
+pub struct TrashItem {
+    #[serde(rename = "type")]
+    pub kind: TrashKind,
+    pub id: String,
+    pub title: String,
+    pub deleted_at: DateTime<Utc>,
+    /// When the retention period ends and the item is purged for good.
+    pub purge_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TrashQuery {
+    /// Only list trashed documents of this kind.
+    #[serde(rename = "type")]
+    pub kind: Option<TrashKind>,
+}
+
+/// Number of documents permanently deleted, per kind.
+#[derive(Debug, Default, Serialize)]
+pub struct PurgeSummary {
+    pub notes: u64,
+    pub todos: u64,
+    pub events: u64,
+}
@@ -0,0 +1,162 @@
+use actix_multipart::Multipart;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{get, post, delete, web, HttpResponse, Responder};
+use bytes::{Bytes, BytesMut};
+use futures_util::StreamExt;
+use mongodb::Client;
+use crate::db::blob_store::BlobStore;
+use crate::dto::attachment::AttachmentResponse;
+use crate::models::attachment::AttachmentOwner;
+use crate::routes;
+use crate::services::attachment_service;
+
+/// Reads the `file` field of a multipart upload, enforcing the size limit
+/// while streaming.
+pub(crate) async fn read_upload(mut payload: Multipart, limit: usize) -> Result<(String, Bytes), HttpResponse> {
+    while let Some(field) = payload.next().await {
+        let mut field = field.map_err(|e| HttpResponse::BadRequest().body(format!("Invalid upload: {}", e)))?;
+        if field.name() != Some("file") {
+            continue;
+        }
+
+        let filename = field
+            .content_disposition()
+            .and_then(|cd| cd.get_filename())
+            .unwrap_or("attachment")
+            .to_string();
+        let mut data = BytesMut::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|e| HttpResponse::BadRequest().body(format!("Invalid upload: {}", e)))?;
+            if data.len() + chunk.len() > limit {
+                return Err(HttpResponse::PayloadTooLarge()
+                    .body(format!("Upload exceeds the maximum size of {} bytes", limit)));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        return Ok((filename, data.freeze()));
+    }
+    Err(HttpResponse::BadRequest().body("Missing file field"))
+}
+
+async fn upload(
+    client: &Client,
+    store: &BlobStore,
+    owner: AttachmentOwner,
+    owner_id: &str,
+    payload: Multipart,
+) -> HttpResponse {
+    let (filename, data) = match read_upload(payload, attachment_service::max_attachment_size()).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    match attachment_service::add_attachment(client, store, owner, owner_id, filename, data).await {
+        Ok(attachment) => {
+            let attachment = AttachmentResponse::from(attachment);
+            routes::created(attachment.url.clone()).json(attachment)
+        }
+        Err(e) => attachment_service::error_response(e),
+    }
+}
+
+async fn download(client: &Client, store: &BlobStore, attachment_id: &str, thumbnail: bool) -> HttpResponse {
+    match attachment_service::read_attachment(client, store, attachment_id, thumbnail).await {
+        Ok((attachment, content_type, data)) => {
+            let disposition = if content_type.starts_with("image/") {
+                DispositionType::Inline
+            } else {
+                DispositionType::Attachment
+            };
+            // Header values must be ASCII; the original name stays in the metadata
+            let filename = attachment
+                .filename
+                .chars()
+                .map(|c| if c.is_ascii() { c } else { '_' })
+                .collect();
+            HttpResponse::Ok()
+                .content_type(content_type)
+                .insert_header(ContentDisposition {
+                    disposition,
+                    parameters: vec![DispositionParam::Filename(filename)],
+                })
+                .insert_header(("X-Content-Type-Options", "nosniff"))
+                .body(data)
+        }
+        Err(e) => attachment_service::error_response(e),
+    }
+}
+
+#[post("/notes/{id}/attachments")]
+async fn upload_note_attachment(
+    client: web::Data<Client>,
+    store: web::Data<BlobStore>,
+    note_id: web::Path<String>,
+    payload: Multipart,
+) -> impl Responder {
+    upload(&client, &store, AttachmentOwner::Note, &note_id, payload).await
+}
+
+#[post("/todos/{id}/attachments")]
+async fn upload_todo_attachment(
+    client: web::Data<Client>,
+    store: web::Data<BlobStore>,
+    todo_id: web::Path<String>,
+    payload: Multipart,
+) -> impl Responder {
+    upload(&client, &store, AttachmentOwner::Todo, &todo_id, payload).await
+}
+
+#[get("/notes/{id}/attachments")]
+async fn get_note_attachments(client: web::Data<Client>, note_id: web::Path<String>) -> impl Responder {
+    match attachment_service::get_attachments(&client, AttachmentOwner::Note, &note_id).await {
+        Ok(attachments) => HttpResponse::Ok().json(attachments.into_iter().map(AttachmentResponse::from).collect::<Vec<_>>()),
+        Err(e) => attachment_service::error_response(e),
+    }
+}
+
+#[get("/todos/{id}/attachments")]
+async fn get_todo_attachments(client: web::Data<Client>, todo_id: web::Path<String>) -> impl Responder {
+    match attachment_service::get_attachments(&client, AttachmentOwner::Todo, &todo_id).await {
+        Ok(attachments) => HttpResponse::Ok().json(attachments.into_iter().map(AttachmentResponse::from).collect::<Vec<_>>()),
+        Err(e) => attachment_service::error_response(e),
+    }
+}
+
+#[get("/attachments/{id}")]
+async fn get_attachment(
+    client: web::Data<Client>,
+    store: web::Data<BlobStore>,
+    attachment_id: web::Path<String>,
+) -> impl Responder {
+    download(&client, &store, &attachment_id, false).await
+}
+
+#[get("/attachments/{id}/thumbnail")]
+async fn get_attachment_thumbnail(
+    client: web::Data<Client>,
+    store: web::Data<BlobStore>,
+    attachment_id: web::Path<String>,
+) -> impl Responder {
+    download(&client, &store, &attachment_id, true).await
+}
+
+#[delete("/attachments/{id}")]
+async fn delete_attachment(
+    client: web::Data<Client>,
+    store: web::Data<BlobStore>,
+    attachment_id: web::Path<String>,
+) -> impl Responder {
+    match attachment_service::remove_attachment(&client, &store, &attachment_id).await {
+        Ok(_) => HttpResponse::Ok().json("Attachment deleted successfully"),
+        Err(e) => attachment_service::error_response(e),
+    }
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(upload_note_attachment);
+    cfg.service(upload_todo_attachment);
+    cfg.service(get_note_attachments);
+    cfg.service(get_todo_attachments);
+    cfg.service(get_attachment);
+    cfg.service(get_attachment_thumbnail);
+    cfg.service(delete_attachment);
+}
@@ -0,0 +1,198 @@
+//! Requires a known user on every CalDAV request.
+//!
+//! Users and their tokens come from `API_USERS`, as comma-separated
+//! `name:token` pairs. A request authenticates with `Authorization: Bearer
+//! <token>`, or with Basic credentials of a user's name and token, which is
+//! what calendar apps send. Without `API_USERS`, CalDAV refuses every request.
+//!
+//! The API doesn't sign users in yet, so its requests act as the user `local`.
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::future::{ready, Ready};
+
+/// The user API requests act as.
+pub const LOCAL_USER: &str = "local";
+
+/// The users allowed in, with digests of their tokens.
+#[derive(Debug, Clone, Default)]
+pub struct ApiUsers {
+    users: Vec<(String, [u8; 32])>,
+}
+
+impl ApiUsers {
+    /// Reads `API_USERS`; entries without a name or a token are skipped.
+    pub fn from_env() -> Self {
+        std::env::var("API_USERS").map(|users| Self::parse(&users)).unwrap_or_default()
+    }
+
+    pub fn parse(users: &str) -> Self {
+        let users = users
+            .split(',')
+            .filter_map(|entry| entry.trim().split_once(':'))
+            .filter(|(name, token)| !name.is_empty() && !token.is_empty())
+            .map(|(name, token)| (name.to_string(), digest(token)))
+            .collect();
+        ApiUsers { users }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// The user a token belongs to, and when a name is given, only if it is theirs.
+    fn find(&self, name: Option<&str>, token: &str) -> Option<&str> {
+        let token = digest(token);
+        self.users
+            .iter()
+            .find(|(user, digest)| *digest == token && name.is_none_or(|name| name == user))
+            .map(|(user, _)| user.as_str())
+    }
+}
+
+/// The authenticated user of a request, or the local user where requests
+/// aren't authenticated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct User(pub String);
+
+impl FromRequest for User {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let user = req.extensions().get::<User>().cloned();
+        ready(Ok(user.unwrap_or_else(|| User(LOCAL_USER.to_string()))))
+    }
+}
+
+pub async fn authenticate(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let users = request.app_data::<web::Data<ApiUsers>>().cloned().unwrap_or_default();
+    let user = credentials(&request).and_then(|(name, token)| users.find(name.as_deref(), &token).map(str::to_string));
+    let Some(user) = user else {
+        let response = HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"Organise\", charset=\"UTF-8\""))
+            .body("Authentication required");
+        return Ok(request.into_response(response));
+    };
+    request.extensions_mut().insert(User(user));
+    Ok(next.call(request).await?.map_into_boxed_body())
+}
+
+/// The name, for Basic credentials, and token of a request.
+fn credentials(request: &ServiceRequest) -> Option<(Option<String>, String)> {
+    let authorization = request.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, value) = authorization.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("Bearer") {
+        return Some((None, value.trim().to_string()));
+    }
+    if scheme.eq_ignore_ascii_case("Basic") {
+        let decoded = base64::engine::general_purpose::STANDARD.decode(value.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (name, token) = decoded.split_once(':')?;
+        return Some((Some(name.to_string()), token.to_string()));
+    }
+    None
+}
+
+/// Tokens are compared by digest, so how long a comparison takes says
+/// nothing about the token.
+fn digest(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{middleware, App};
+
+    async fn status(users: &str, request: TestRequest) -> (StatusCode, String) {
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(ApiUsers::parse(users))).service(
+                web::scope("/caldav")
+                    .wrap(middleware::from_fn(authenticate))
+                    .default_service(web::to(|user: Option<User>| async move {
+                        HttpResponse::Ok().body(user.map(|User(name)| name).unwrap_or_default())
+                    })),
+            ),
+        )
+        .await;
+        let response = test::call_service(&app, request.to_request()).await;
+        let status = response.status();
+        (status, String::from_utf8(test::read_body(response).await.to_vec()).unwrap())
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))
+    }
+
+    fn remote() -> TestRequest {
+        TestRequest::get().uri("/caldav/").peer_addr("203.0.113.7:50000".parse().unwrap())
+    }
+
+    #[test]
+    fn parse_skips_entries_without_a_name_or_token() {
+        let users = ApiUsers::parse(" alice:secret , :orphan, bob:, carol");
+        assert_eq!(users.users.len(), 1);
+        assert_eq!(users.find(None, "secret"), Some("alice"));
+        assert_eq!(users.find(Some("bob"), "secret"), None);
+    }
+
+    #[actix_web::test]
+    async fn requests_without_credentials_are_challenged() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(ApiUsers::parse("alice:secret")))
+                .wrap(middleware::from_fn(authenticate))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let response = test::call_service(&app, remote().to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let challenge = response.headers().get(header::WWW_AUTHENTICATE).unwrap().to_str().unwrap();
+        assert!(challenge.starts_with("Basic realm="));
+    }
+
+    #[actix_web::test]
+    async fn bearer_and_basic_credentials_identify_the_user() {
+        let users = "alice:secret,bob:hunter2";
+        let bearer = remote().insert_header((header::AUTHORIZATION, "Bearer hunter2"));
+        assert_eq!(status(users, bearer).await, (StatusCode::OK, "bob".to_string()));
+        let basic_auth = remote().insert_header((header::AUTHORIZATION, basic("alice:secret")));
+        assert_eq!(status(users, basic_auth).await, (StatusCode::OK, "alice".to_string()));
+    }
+
+    #[actix_web::test]
+    async fn wrong_tokens_and_names_are_refused() {
+        let users = "alice:secret,bob:hunter2";
+        let wrong_token = remote().insert_header((header::AUTHORIZATION, "Bearer guess"));
+        assert_eq!(status(users, wrong_token).await.0, StatusCode::UNAUTHORIZED);
+        let wrong_name = remote().insert_header((header::AUTHORIZATION, basic("alice:hunter2")));
+        assert_eq!(status(users, wrong_name).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn without_users_every_request_is_refused() {
+        assert_eq!(status("", remote()).await.0, StatusCode::UNAUTHORIZED);
+        // Even from this machine, which may be a proxy for anyone
+        let local = TestRequest::get().uri("/caldav/").peer_addr("127.0.0.1:50000".parse().unwrap());
+        assert_eq!(status("", local).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn unauthenticated_requests_act_as_the_local_user() {
+        let app = test::init_service(App::new().default_service(web::to(|User(name): User| async move { HttpResponse::Ok().body(name) })))
+            .await;
+        let response = test::call_service(&app, TestRequest::get().uri("/api/notes").to_request()).await;
+        assert_eq!(test::read_body(response).await, LOCAL_USER);
+    }
+}
@@ -0,0 +1,272 @@
+//! CalDAV endpoints. They live outside `/api`, where calendar apps find them
+//! through `/.well-known/caldav`, and take the same credentials as the API.
+
+use actix_web::http::header::{self, EntityTag, HttpDate, IfMatch, IfNoneMatch};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{middleware, web, HttpMessage, HttpRequest, HttpResponse, Responder, Route};
+use mongodb::Client;
+use crate::dto::caldav::{DavResource, Multistatus};
+use crate::models::caldav::{
+    event_href, event_name_from_href, CalendarReport, Depth, Precondition, PropRequest, PRINCIPAL_PATH,
+};
+use crate::routes::{self, auth};
+use crate::services::caldav_service::{self, CalDavServiceError};
+use std::time::SystemTime;
+
+/// Point calendar apps looking for the well-known path at the principal
+pub async fn well_known() -> impl Responder {
+    HttpResponse::MovedPermanently().insert_header((header::LOCATION, PRINCIPAL_PATH)).finish()
+}
+
+/// Advertise CalDAV support
+pub async fn options() -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header(("DAV", "1, 3, calendar-access"))
+        .insert_header((header::ALLOW, "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, REPORT"))
+        .finish()
+}
+
+/// Describe the principal, and at depth 1 the calendar home
+pub async fn propfind_principal(req: HttpRequest, body: String) -> impl Responder {
+    let props = match PropRequest::parse_propfind(&body) {
+        Ok(props) => props,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+    let mut multistatus = Multistatus::default();
+    multistatus.add(&DavResource::Principal, &props);
+    if depth(&req) == Depth::One {
+        multistatus.add(&DavResource::CalendarHome, &props);
+    }
+    multistatus_response(multistatus)
+}
+
+/// Describe the calendar home, and at depth 1 the calendars
+pub async fn propfind_home(req: HttpRequest, client: web::Data<Client>, body: String) -> impl Responder {
+    let props = match PropRequest::parse_propfind(&body) {
+        Ok(props) => props,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+    let mut multistatus = Multistatus::default();
+    multistatus.add(&DavResource::CalendarHome, &props);
+    if depth(&req) == Depth::One {
+        let calendar_ids = match caldav_service::get_calendar_ids(&client).await {
+            Ok(calendar_ids) => calendar_ids,
+            Err(e) => return caldav_service::error_response(e),
+        };
+        for id in &calendar_ids {
+            match caldav_service::get_sync_token(&client, id).await {
+                Ok(sync_token) => multistatus.add(&DavResource::Calendar { id, sync_token }, &props),
+                Err(e) => return caldav_service::error_response(e),
+            }
+        }
+    }
+    multistatus_response(multistatus)
+}
+
+/// Describe a calendar, and at depth 1 its events
+pub async fn propfind_calendar(
+    req: HttpRequest,
+    client: web::Data<Client>,
+    calendar_id: web::Path<String>,
+    body: String,
+) -> impl Responder {
+    let props = match PropRequest::parse_propfind(&body) {
+        Ok(props) => props,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+    let sync_token = match caldav_service::get_sync_token(&client, &calendar_id).await {
+        Ok(sync_token) => sync_token,
+        Err(e) => return caldav_service::error_response(e),
+    };
+    let mut multistatus = Multistatus::default();
+    multistatus.add(&DavResource::Calendar { id: &calendar_id, sync_token }, &props);
+    if depth(&req) == Depth::One {
+        match caldav_service::get_resources(&client, &calendar_id).await {
+            Ok(resources) => {
+                for resource in &resources {
+                    multistatus.add(&DavResource::Event { calendar_id: &calendar_id, resource }, &props);
+                }
+            }
+            Err(e) => return caldav_service::error_response(e),
+        }
+    }
+    multistatus_response(multistatus)
+}
+
+/// Answer a `calendar-query`, `calendar-multiget` or `sync-collection` report
+pub async fn report_calendar(
+    client: web::Data<Client>,
+    calendar_id: web::Path<String>,
+    body: String,
+) -> impl Responder {
+    let report = match CalendarReport::parse(&body) {
+        Ok(report) => report,
+        Err(e) => return caldav_service::error_response(e.into()),
+    };
+    let mut multistatus = Multistatus::default();
+    match report {
+        CalendarReport::Query { props, events, start, end } => {
+            let resources = match events {
+                true => caldav_service::query_resources(&client, &calendar_id, start, end).await,
+                false => Ok(Vec::new()),
+            };
+            match resources {
+                Ok(resources) => {
+                    for resource in &resources {
+                        multistatus.add(&DavResource::Event { calendar_id: &calendar_id, resource }, &props);
+                    }
+                }
+                Err(e) => return caldav_service::error_response(e),
+            }
+        }
+        CalendarReport::Multiget { props, hrefs } => {
+            for href in hrefs {
+                let Some(name) = event_name_from_href(&href, &calendar_id) else {
+                    multistatus.add_missing(&href);
+                    continue;
+                };
+                match caldav_service::get_resource(&client, &calendar_id, &name).await {
+                    Ok(resource) => {
+                        multistatus.add(&DavResource::Event { calendar_id: &calendar_id, resource: &resource }, &props)
+                    }
+                    Err(CalDavServiceError::NotFound) => multistatus.add_missing(&href),
+                    Err(e) => return caldav_service::error_response(e),
+                }
+            }
+        }
+        CalendarReport::SyncCollection { props, token } => {
+            let changes = match caldav_service::get_changes(&client, &calendar_id, token.as_deref()).await {
+                Ok(changes) => changes,
+                Err(e) => return caldav_service::error_response(e),
+            };
+            for resource in &changes.changed {
+                multistatus.add(&DavResource::Event { calendar_id: &calendar_id, resource }, &props);
+            }
+            for name in &changes.removed {
+                multistatus.add_missing(&event_href(&calendar_id, name));
+            }
+            multistatus.set_sync_token(changes.token);
+        }
+    }
+    multistatus_response(multistatus)
+}
+
+/// Describe an event resource
+pub async fn propfind_event(
+    client: web::Data<Client>,
+    path: web::Path<(String, String)>,
+    body: String,
+) -> impl Responder {
+    let (calendar_id, name) = path.into_inner();
+    let props = match PropRequest::parse_propfind(&body) {
+        Ok(props) => props,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+    match caldav_service::get_resource(&client, &calendar_id, &name).await {
+        Ok(resource) => {
+            let mut multistatus = Multistatus::default();
+            multistatus.add(&DavResource::Event { calendar_id: &calendar_id, resource: &resource }, &props);
+            multistatus_response(multistatus)
+        }
+        Err(e) => caldav_service::error_response(e),
+    }
+}
+
+/// Download an event resource as iCalendar
+pub async fn get_event(client: web::Data<Client>, path: web::Path<(String, String)>) -> impl Responder {
+    let (calendar_id, name) = path.into_inner();
+    match caldav_service::get_resource(&client, &calendar_id, &name).await {
+        Ok(resource) => HttpResponse::Ok()
+            .insert_header(header::ETag(EntityTag::new_strong(resource.etag)))
+            .insert_header(header::LastModified(HttpDate::from(SystemTime::from(resource.last_modified))))
+            .content_type("text/calendar; charset=utf-8")
+            .body(resource.body),
+        Err(e) => caldav_service::error_response(e),
+    }
+}
+
+/// Create or replace an event resource. The stored event is rewritten rather
+/// than kept byte for byte, so no `ETag` is returned
+pub async fn put_event(
+    req: HttpRequest,
+    client: web::Data<Client>,
+    path: web::Path<(String, String)>,
+    body: String,
+) -> impl Responder {
+    let (calendar_id, name) = path.into_inner();
+    match caldav_service::put_resource(&client, &calendar_id, &name, &body, &precondition(&req)).await {
+        Ok(true) => routes::created(event_href(&calendar_id, &name)).finish(),
+        Ok(false) => HttpResponse::NoContent().finish(),
+        Err(e) => caldav_service::error_response(e),
+    }
+}
+
+/// Move the events of a resource to the trash
+pub async fn delete_event(
+    req: HttpRequest,
+    client: web::Data<Client>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (calendar_id, name) = path.into_inner();
+    match caldav_service::delete_resource(&client, &calendar_id, &name, &precondition(&req)).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => caldav_service::error_response(e),
+    }
+}
+
+fn depth(req: &HttpRequest) -> Depth {
+    Depth::parse(req.headers().get("Depth").and_then(|value| value.to_str().ok()))
+}
+
+/// `If-Match` and `If-None-Match: *`. Weak tags never match, as writes need
+/// strong comparison.
+fn precondition(req: &HttpRequest) -> Precondition {
+    let if_match = req.get_header::<IfMatch>().map(|if_match| match if_match {
+        IfMatch::Any => vec!["*".to_string()],
+        IfMatch::Items(tags) => tags.into_iter().filter(|tag| !tag.weak).map(|tag| tag.tag().to_string()).collect(),
+    });
+    let if_none_match = matches!(req.get_header::<IfNoneMatch>(), Some(IfNoneMatch::Any));
+    Precondition { if_match, if_none_match }
+}
+
+fn multistatus_response(multistatus: Multistatus) -> HttpResponse {
+    HttpResponse::build(StatusCode::MULTI_STATUS)
+        .content_type("application/xml; charset=utf-8")
+        .body(multistatus.into_xml())
+}
+
+fn method(name: &str) -> Route {
+    web::method(Method::from_bytes(name.as_bytes()).expect("WebDAV method names are valid"))
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/.well-known/caldav").to(well_known)).service(
+        web::scope("/caldav")
+            .wrap(middleware::from_fn(auth::authenticate))
+            .service(
+                web::resource(["", "/"])
+                    .route(method("PROPFIND").to(propfind_principal))
+                    .route(web::method(Method::OPTIONS).to(options)),
+            )
+            .service(
+                web::resource(["/calendars", "/calendars/"])
+                    .route(method("PROPFIND").to(propfind_home))
+                    .route(web::method(Method::OPTIONS).to(options)),
+            )
+            .service(
+                web::resource(["/calendars/{calendar_id}", "/calendars/{calendar_id}/"])
+                    .route(method("PROPFIND").to(propfind_calendar))
+                    .route(method("REPORT").to(report_calendar))
+                    .route(web::method(Method::OPTIONS).to(options)),
+            )
+            .service(
+                web::resource("/calendars/{calendar_id}/{name}")
+                    .route(method("PROPFIND").to(propfind_event))
+                    .route(web::get().to(get_event))
+                    .route(web::head().to(get_event))
+                    .route(web::put().to(put_event))
+                    .route(web::delete().to(delete_event))
+                    .route(web::method(Method::OPTIONS).to(options)),
+            ),
+    );
+}
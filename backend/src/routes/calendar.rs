@@ -1,84 +1,387 @@
-use actix_web::{web, HttpResponse, Responder};
-use chrono::{DateTime, Utc};
+use actix_multipart::Multipart;
+use actix_web::http::header::{self, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
 use mongodb::Client;
-use crate::models::calendar::{CalendarEvent, GoogleCalendarCredentials, GoogleCalendarToken};
-use crate::services::calendar_service;
+use crate::dto::calendar::CalendarEventResponse;
+use crate::dto::calendar_connection::CalendarConnectionResponse;
+use crate::dto::calendar_feed::CalendarFeedResponse;
+use crate::models::calendar::{
+    parse_calendar_ids, CalendarEvent, CalendarEventSchema, CalendarExportQuery, CalendarImportOptions, EditScopeQuery, EventRange,
+    EventRangeQuery,
+};
+use crate::models::calendar_connection::OAuthCallbackQuery;
+use crate::models::calendar_feed::{CalendarFeed, CalendarFeedSchema};
+use crate::models::calendar_sync::{CalendarProvider, CalendarSyncRequest};
+use crate::routes;
+use crate::routes::attachments::read_upload;
+use crate::routes::auth::User;
+use crate::services::calendar_service::CalendarServiceError;
+use crate::services::{calendar_feed_service, calendar_oauth_service, calendar_service, calendar_sync_service, ical_service, import_service};
+use std::time::SystemTime;
+use validator::Validate;
 
-/// Get all calendar events
-pub async fn get_all_events(client: web::Data<Client>) -> impl Responder {
-    match calendar_service::get_all_events(&client).await {
-        Ok(events) => HttpResponse::Ok().json(events),
+/// Get calendar events: every event, or with `start` and `end` the
+/// occurrences overlapping that range
+pub async fn get_events(
+    client: web::Data<Client>,
+    query: web::Query<EventRangeQuery>,
+) -> impl Responder {
+    let query = query.into_inner();
+    if !query.is_range() {
+        return match calendar_service::get_all_events(&client).await {
+            Ok(events) => HttpResponse::Ok().json(events.into_iter().map(CalendarEventResponse::from).collect::<Vec<_>>()),
+            Err(e) => calendar_service::error_response(e),
+        };
+    }
+    let range = match EventRange::try_from(query) {
+        Ok(range) => range,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    match calendar_service::get_events_by_date_range(&client, &range).await {
+        Ok(events) => HttpResponse::Ok().json(events.into_iter().map(CalendarEventResponse::from).collect::<Vec<_>>()),
+        Err(e) => calendar_service::error_response(e),
+    }
+}
+
+/// Get a single calendar event
+pub async fn get_event(
+    client: web::Data<Client>,
+    event_id: web::Path<String>,
+) -> impl Responder {
+    match calendar_service::get_event_by_id(&client, &event_id).await {
+        Ok(event) => HttpResponse::Ok().json(CalendarEventResponse::from(event)),
+        Err(e) => calendar_service::error_response(e),
+    }
+}
+
+/// Download the events of some calendars (`?calendar_ids=`), or of every
+/// calendar, as an iCalendar file
+pub async fn export_calendar(
+    client: web::Data<Client>,
+    query: web::Query<CalendarExportQuery>,
+) -> impl Responder {
+    let calendar_ids = parse_calendar_ids(query.calendar_ids.as_deref());
+    match calendar_service::get_events_for_export(&client, &calendar_ids).await {
+        Ok(events) => {
+            let filename = format!("calendar-{}.ics", chrono::Utc::now().format("%Y-%m-%d"));
+            ics_response(&filename, ical_service::write_calendar("Organise", &events))
+        }
+        Err(e) => calendar_service::error_response(e),
+    }
+}
+
+/// Download an event, or the series of an occurrence, as an iCalendar file
+pub async fn export_event(
+    client: web::Data<Client>,
+    event_id: web::Path<String>,
+) -> impl Responder {
+    match calendar_service::get_series(&client, &event_id).await {
+        Ok(events) => {
+            let name = events.first().map(|event| event.title.clone()).unwrap_or_default();
+            ics_response("event.ics", ical_service::write_calendar(&name, &events))
+        }
         Err(e) => calendar_service::error_response(e),
     }
 }
 
-/// Get events by date range
-pub async fn get_events_by_date_range(
+/// Import the events of an iCalendar file, uploaded as the `file` field.
+/// Supports `dry_run` and `calendar_id`.
+pub async fn import_calendar(
     client: web::Data<Client>,
-    start_date: web::Query<DateTime<Utc>>,
-    end_date: web::Query<DateTime<Utc>>,
+    options: web::Query<CalendarImportOptions>,
+    payload: Multipart,
 ) -> impl Responder {
-    match calendar_service::get_events_by_date_range(&client, start_date.into_inner(), end_date.into_inner()).await {
-        Ok(events) => HttpResponse::Ok().json(events),
+    let (_, data) = match read_upload(payload, import_service::max_import_size()).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+
+    // Parsing is CPU bound, so keep it off the async workers
+    let calendar = match web::block(move || ical_service::parse_calendar(&String::from_utf8_lossy(&data))).await {
+        Ok(Ok(calendar)) => calendar,
+        Ok(Err(message)) => return calendar_service::error_response(CalendarServiceError::InvalidCalendar(message)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Import failed: {}", e)),
+    };
+
+    match calendar_service::import_events(&client, calendar, &options).await {
+        Ok(report) => HttpResponse::Ok().json(report),
         Err(e) => calendar_service::error_response(e),
     }
 }
 
-/// Add a new calendar event
+/// List the calendar feeds; their URLs are only shown when issued
+pub async fn get_feeds(client: web::Data<Client>) -> impl Responder {
+    match calendar_feed_service::get_feeds(&client).await {
+        Ok(feeds) => HttpResponse::Ok().json(feeds.into_iter().map(CalendarFeedResponse::from).collect::<Vec<_>>()),
+        Err(e) => calendar_feed_service::error_response(e),
+    }
+}
+
+/// Get a single calendar feed
+pub async fn get_feed(
+    client: web::Data<Client>,
+    feed_id: web::Path<String>,
+) -> impl Responder {
+    match calendar_feed_service::get_feed(&client, &feed_id).await {
+        Ok(feed) => HttpResponse::Ok().json(CalendarFeedResponse::from(feed)),
+        Err(e) => calendar_feed_service::error_response(e),
+    }
+}
+
+/// Create a secret feed link for a calendar, or every calendar, and return it
+/// with its URL
+pub async fn create_feed(
+    req: HttpRequest,
+    client: web::Data<Client>,
+    schema: web::Json<CalendarFeedSchema>,
+) -> impl Responder {
+    match calendar_feed_service::create_feed(&client, schema.into_inner()).await {
+        Ok((feed, token)) => {
+            let feed = issued_feed(&req, feed, &token);
+            routes::created(format!("/api/calendar/feeds/{}", feed.id)).json(feed)
+        }
+        Err(e) => calendar_feed_service::error_response(e),
+    }
+}
+
+/// Issue a new URL for a feed, revoking the old one
+pub async fn regenerate_feed(
+    req: HttpRequest,
+    client: web::Data<Client>,
+    feed_id: web::Path<String>,
+) -> impl Responder {
+    match calendar_feed_service::regenerate_token(&client, &feed_id).await {
+        Ok((feed, token)) => HttpResponse::Ok().json(issued_feed(&req, feed, &token)),
+        Err(e) => calendar_feed_service::error_response(e),
+    }
+}
+
+/// Delete a calendar feed, revoking its URL
+pub async fn delete_feed(
+    client: web::Data<Client>,
+    feed_id: web::Path<String>,
+) -> impl Responder {
+    match calendar_feed_service::delete_feed(&client, &feed_id).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => calendar_feed_service::error_response(e),
+    }
+}
+
+/// Serve a feed as iCalendar. Answers `304 Not Modified` to `If-None-Match`
+/// and `If-Modified-Since` when nothing changed
+pub async fn serve_feed(
+    req: HttpRequest,
+    client: web::Data<Client>,
+    token: web::Path<String>,
+) -> impl Responder {
+    let content = match calendar_feed_service::render_feed(&client, &token).await {
+        Ok(content) => content,
+        Err(e) => return calendar_feed_service::error_response(e),
+    };
+    let etag = EntityTag::new_strong(content.etag);
+    let last_modified = content.last_modified.map(|at| HttpDate::from(SystemTime::from(at)));
+
+    // If-None-Match takes precedence over If-Modified-Since
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => match (req.get_header::<IfModifiedSince>(), last_modified) {
+            (Some(IfModifiedSince(since)), Some(modified)) => modified <= since,
+            _ => false,
+        },
+    };
+    let mut response = match not_modified {
+        true => HttpResponse::NotModified(),
+        false => HttpResponse::Ok(),
+    };
+    response.insert_header(header::ETag(etag)).insert_header((header::CACHE_CONTROL, "no-cache"));
+    if let Some(last_modified) = last_modified {
+        response.insert_header(header::LastModified(last_modified));
+    }
+    match not_modified {
+        true => response.finish(),
+        false => response.content_type("text/calendar; charset=utf-8").body(content.body),
+    }
+}
+
+/// A feed with the absolute URL for its newly issued token.
+fn issued_feed(req: &HttpRequest, feed: CalendarFeed, token: &str) -> CalendarFeedResponse {
+    let info = req.connection_info();
+    let url = format!("{}://{}/api/calendar/feed/{}.ics", info.scheme(), info.host(), token);
+    CalendarFeedResponse { url: Some(url), ..feed.into() }
+}
+
+fn ics_response(filename: &str, body: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/calendar; charset=utf-8")
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", filename)))
+        .body(body)
+}
+
+/// Add a new calendar event and return it, with its URL in `Location`
 pub async fn add_event(
     client: web::Data<Client>,
-    event: web::Json<CalendarEvent>,
+    event: web::Json<CalendarEventSchema>,
 ) -> impl Responder {
-    match calendar_service::add_event(&client, event.into_inner()).await {
-        Ok(_) => HttpResponse::Created().finish(),
+    if let Err(validation_error) = event.validate() {
+        return HttpResponse::BadRequest().json(validation_error);
+    }
+    let event = match CalendarEvent::try_from(event.into_inner()) {
+        Ok(event) => event,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    match calendar_service::add_event(&client, event).await {
+        Ok(event) => {
+            let event = CalendarEventResponse::from(event);
+            routes::created(format!("/api/calendar/events/{}", event.id)).json(event)
+        }
         Err(e) => calendar_service::error_response(e),
     }
 }
 
-/// Update an existing calendar event
+/// Update a calendar event, or part of a series (`?scope=instance|following|all`), and return it
 pub async fn update_event(
     client: web::Data<Client>,
     event_id: web::Path<String>,
-    event: web::Json<CalendarEvent>,
+    query: web::Query<EditScopeQuery>,
+    event: web::Json<CalendarEventSchema>,
 ) -> impl Responder {
-    match calendar_service::update_event(&client, &event_id, event.into_inner()).await {
-        Ok(_) => HttpResponse::Ok().finish(),
+    if let Err(validation_error) = event.validate() {
+        return HttpResponse::BadRequest().json(validation_error);
+    }
+    let event = match CalendarEvent::try_from(event.into_inner()) {
+        Ok(event) => event,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    match calendar_service::update_event(&client, &event_id, event, query.scope).await {
+        Ok(event) => HttpResponse::Ok().json(CalendarEventResponse::from(event)),
         Err(e) => calendar_service::error_response(e),
     }
 }
 
-/// Delete a calendar event
+/// Delete a calendar event, or part of a series (`?scope=instance|following|all`)
 pub async fn delete_event(
     client: web::Data<Client>,
     event_id: web::Path<String>,
+    query: web::Query<EditScopeQuery>,
 ) -> impl Responder {
-    match calendar_service::remove_event(&client, &event_id).await {
+    match calendar_service::remove_event(&client, &event_id, query.scope).await {
         Ok(_) => HttpResponse::NoContent().finish(),
         Err(e) => calendar_service::error_response(e),
     }
 }
 
-/// Sync with Google Calendar
-pub async fn sync_google_calendar(
+/// Start connecting an account of a provider: redirects to its consent screen,
+/// remembering the authorization in a cookie for the callback
+pub async fn connect_calendar(
     client: web::Data<Client>,
-    credentials: web::Json<GoogleCalendarCredentials>,
-    token: web::Json<GoogleCalendarToken>,
+    User(user): User,
+    provider: web::Path<CalendarProvider>,
 ) -> impl Responder {
-    match calendar_service::sync_with_google_calendar(&client, &credentials, &token).await {
-        Ok(_) => HttpResponse::Ok().finish(),
-        Err(e) => calendar_service::error_response(e),
+    let oauth = match calendar_sync_service::oauth_client(provider.into_inner()) {
+        Ok(oauth) => oauth,
+        Err(e) => return calendar_oauth_service::error_response(e),
+    };
+    match calendar_oauth_service::authorization_url(&client, &user, &oauth).await {
+        Ok(request) => HttpResponse::Found()
+            .insert_header((header::LOCATION, request.url))
+            .cookie(calendar_oauth_service::state_cookie(&oauth, &request.state))
+            .finish(),
+        Err(e) => calendar_oauth_service::error_response(e),
+    }
+}
+
+/// Finish connecting an account; the provider redirects here with a code, in
+/// the browser holding the state cookie
+pub async fn calendar_callback(
+    req: HttpRequest,
+    client: web::Data<Client>,
+    provider: web::Path<CalendarProvider>,
+    query: web::Query<OAuthCallbackQuery>,
+) -> impl Responder {
+    let oauth = match calendar_sync_service::oauth_client(provider.into_inner()) {
+        Ok(oauth) => oauth,
+        Err(e) => return calendar_oauth_service::error_response(e),
+    };
+    let browser_state = req.cookie(calendar_oauth_service::STATE_COOKIE);
+    let browser_state = browser_state.as_ref().map(|cookie| cookie.value());
+    let mut response = match calendar_oauth_service::complete_authorization(&client, &oauth, query.into_inner(), browser_state).await {
+        Ok(connection) => HttpResponse::Ok().json(CalendarConnectionResponse::from(connection)),
+        Err(e) => calendar_oauth_service::error_response(e),
+    };
+    // The state is spent either way
+    let _ = response.add_removal_cookie(&calendar_oauth_service::state_cookie(&oauth, ""));
+    response
+}
+
+/// Get the account the user connected to a provider
+pub async fn get_calendar_connection(
+    client: web::Data<Client>,
+    User(user): User,
+    provider: web::Path<CalendarProvider>,
+) -> impl Responder {
+    match calendar_oauth_service::get_connection(&client, &user, provider.name()).await {
+        Ok(connection) => HttpResponse::Ok().json(CalendarConnectionResponse::from(connection)),
+        Err(e) => calendar_oauth_service::error_response(e),
+    }
+}
+
+/// Disconnect the account the user connected to a provider, revoking its
+/// tokens where the provider supports that
+pub async fn disconnect_calendar(
+    client: web::Data<Client>,
+    User(user): User,
+    provider: web::Path<CalendarProvider>,
+) -> impl Responder {
+    let oauth = match calendar_sync_service::oauth_client(provider.into_inner()) {
+        Ok(oauth) => oauth,
+        Err(e) => return calendar_oauth_service::error_response(e),
+    };
+    match calendar_oauth_service::disconnect(&client, &user, &oauth).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => calendar_oauth_service::error_response(e),
+    }
+}
+
+/// Sync a calendar of the user's connected account two ways with its local copy
+pub async fn sync_calendar(
+    client: web::Data<Client>,
+    User(user): User,
+    provider: web::Path<CalendarProvider>,
+    request: web::Json<CalendarSyncRequest>,
+) -> impl Responder {
+    match calendar_sync_service::sync(&client, &user, provider.into_inner(), &request.calendar).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => calendar_sync_service::error_response(e),
     }
 }
 
 /// Configure the calendar routes
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
-        web::scope("/api/calendar")
-            .route("/events", web::get().to(get_all_events))
-            .route("/events/range", web::get().to(get_events_by_date_range))
+        web::scope("/calendar")
+            .route("/events", web::get().to(get_events))
             .route("/events", web::post().to(add_event))
+            .route("/export.ics", web::get().to(export_calendar))
+            .route("/import", web::post().to(import_calendar))
+            .route("/feeds", web::get().to(get_feeds))
+            .route("/feeds", web::post().to(create_feed))
+            .route("/feeds/{id}", web::get().to(get_feed))
+            .route("/feeds/{id}", web::delete().to(delete_feed))
+            .route("/feeds/{id}/regenerate", web::post().to(regenerate_feed))
+            .route("/feed/{token:[^/.]+}.ics", web::get().to(serve_feed))
+            // Registered before the event routes, whose `{id}` would also match `{id}.ics`
+            .route("/events/{id:[^/.]+}.ics", web::get().to(export_event))
+            .route("/events/{id}", web::get().to(get_event))
             .route("/events/{id}", web::put().to(update_event))
             .route("/events/{id}", web::delete().to(delete_event))
-            .route("/sync/google", web::post().to(sync_google_calendar))
+            .route("/{provider:google|microsoft}/connect", web::get().to(connect_calendar))
+            .route("/{provider:google|microsoft}/callback", web::get().to(calendar_callback))
+            .route("/{provider:google|microsoft}/connection", web::get().to(get_calendar_connection))
+            .route("/{provider:google|microsoft}/connection", web::delete().to(disconnect_calendar))
+            .route("/sync/{provider:google|microsoft}", web::post().to(sync_calendar))
     );
-}
\ No newline at end of file
+}
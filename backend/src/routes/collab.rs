@@ -0,0 +1,84 @@
+use actix_web::{get, rt, web, HttpRequest, HttpResponse, Responder};
+use actix_ws::AggregatedMessage;
+use mongodb::Client;
+use crate::services::collab_service::CollabHub;
+use crate::services::notes_service;
+
+/// Largest sync message or presence update accepted from a client.
+const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+/// Opens a collaborative editing session on a note.
+///
+/// Binary frames carry Automerge sync messages; text frames carry JSON
+/// presence updates, which are relayed to the other editors.
+#[get("/notes/{id}/collab")]
+async fn collaborate(
+    req: HttpRequest,
+    body: web::Payload,
+    client: web::Data<Client>,
+    hub: web::Data<CollabHub>,
+    note_id: web::Path<String>,
+) -> impl Responder {
+    let room = match hub.open_room(&client, &note_id).await {
+        Ok(room) => room,
+        Err(e) => return notes_service::error_response(e),
+    };
+    let (response, session, stream) = match actix_ws::handle(&req, body) {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            if let Err(e) = hub.release_room(&client, &room).await {
+                eprintln!("Failed to persist collaborative note {}: {}", note_id, e);
+            }
+            return HttpResponse::from_error(e);
+        }
+    };
+    let mut stream = stream
+        .max_frame_size(MAX_FRAME_SIZE)
+        .aggregate_continuations()
+        .max_continuation_size(MAX_FRAME_SIZE);
+
+    let peer_id = hub.next_peer_id();
+    room.join(peer_id, session.clone()).await;
+
+    rt::spawn(async move {
+        let mut session = session;
+        let mut close_reason = None;
+        while let Some(message) = stream.recv().await {
+            match message {
+                Ok(AggregatedMessage::Binary(data)) => {
+                    if let Err(e) = room.receive_sync(peer_id, &data).await {
+                        eprintln!("Rejected sync message on note {}: {}", note_id, e);
+                        break;
+                    }
+                }
+                Ok(AggregatedMessage::Text(text)) => match serde_json::from_str(&text) {
+                    Ok(presence) => room.receive_presence(peer_id, presence).await,
+                    Err(e) => eprintln!("Ignoring malformed presence on note {}: {}", note_id, e),
+                },
+                Ok(AggregatedMessage::Ping(bytes)) => {
+                    if session.pong(&bytes).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(AggregatedMessage::Pong(_)) => {}
+                Ok(AggregatedMessage::Close(reason)) => {
+                    close_reason = reason;
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+
+        room.leave(peer_id).await;
+        if let Err(e) = hub.release_room(&client, &room).await {
+            eprintln!("Failed to persist collaborative note {}: {}", note_id, e);
+        }
+        let _ = session.close(close_reason).await;
+    });
+
+    response
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(collaborate);
+}
@@ -0,0 +1,241 @@
+//! Honours the `Idempotency-Key` header on POST requests.
+//!
+//! The first response for a key is stored and replayed byte for byte to
+//! retries of the same request. Reusing a key for a different request is
+//! refused as unprocessable, and reusing it while the first one is still
+//! running is a conflict. Server errors are not stored, so the request can be
+//! retried with the same key.
+
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header::{HeaderName, HeaderValue}, Method};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use futures_util::StreamExt;
+use mongodb::Client;
+use crate::models::idempotency::StoredResponse;
+use crate::services::idempotency_service::{self, Claim};
+use crate::services::import_service;
+
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// Set on responses that are replays of a stored response.
+const REPLAYED: &str = "Idempotent-Replayed";
+
+pub async fn idempotency(
+    mut request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    if request.method() != Method::POST {
+        return Ok(next.call(request).await?.map_into_boxed_body());
+    }
+    let key = match request.headers().get(IDEMPOTENCY_KEY).map(|value| value.to_str()) {
+        None => return Ok(next.call(request).await?.map_into_boxed_body()),
+        Some(Ok(key)) if !key.is_empty() && key.len() <= idempotency_service::MAX_KEY_LENGTH => key.to_string(),
+        Some(_) => {
+            let message = format!("{} must be 1 to {} visible ASCII characters", IDEMPOTENCY_KEY, idempotency_service::MAX_KEY_LENGTH);
+            return Ok(request.into_response(HttpResponse::BadRequest().body(message)));
+        }
+    };
+    let Some(client) = request.app_data::<web::Data<Client>>().cloned() else {
+        return Ok(next.call(request).await?.map_into_boxed_body());
+    };
+
+    // The body is part of the fingerprint; buffer it and hand it back to the handler.
+    // Nothing accepts more than an import, so that is the limit here too.
+    let limit = import_service::max_import_size();
+    let mut payload = request.take_payload();
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > limit {
+            return Ok(request.into_response(HttpResponse::PayloadTooLarge().body("Request body too large")));
+        }
+    }
+    let body = body.freeze();
+    let fingerprint = idempotency_service::fingerprint(
+        request.method().as_str(),
+        request.path(),
+        request.query_string(),
+        &body,
+    );
+    request.set_payload(body.into());
+
+    let claim = match idempotency_service::claim(&client, &key, &fingerprint).await {
+        Ok(claim) => claim,
+        Err(e) => {
+            let response = HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+            return Ok(request.into_response(response));
+        }
+    };
+    match claim {
+        Claim::Acquired => {}
+        Claim::Replay(stored) => return Ok(request.into_response(replay(stored))),
+        Claim::Mismatch => {
+            let message = format!("{} was already used for a different request", IDEMPOTENCY_KEY);
+            return Ok(request.into_response(HttpResponse::UnprocessableEntity().body(message)));
+        }
+        Claim::InProgress => {
+            let message = format!("A request with this {} is still being processed", IDEMPOTENCY_KEY);
+            return Ok(request.into_response(HttpResponse::Conflict().body(message)));
+        }
+    }
+
+    let response = match next.call(request).await {
+        Ok(response) => response,
+        Err(e) => {
+            release(&client, &key).await;
+            return Err(e);
+        }
+    };
+    if response.status().is_server_error() {
+        release(&client, &key).await;
+        return Ok(response.map_into_boxed_body());
+    }
+
+    let (request, response) = response.into_parts();
+    let (head, response_body) = response.into_parts();
+    let bytes = match body::to_bytes(response_body).await {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            release(&client, &key).await;
+            let response = HttpResponse::InternalServerError().body("Failed to read the response");
+            return Ok(ServiceResponse::new(request, response));
+        }
+    };
+    let headers = head
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    if let Err(e) = idempotency_service::complete(&client, &key, head.status().as_u16(), headers, bytes.to_vec()).await {
+        eprintln!("Failed to store the response for idempotency key {}: {}", key, e);
+        // Otherwise retries would be answered 409 until the key expires
+        release(&client, &key).await;
+    }
+    Ok(ServiceResponse::new(request, head.set_body(bytes).map_into_boxed_body()))
+}
+
+/// Rebuilds a stored response.
+fn replay(stored: StoredResponse) -> HttpResponse {
+    let status = actix_web::http::StatusCode::from_u16(stored.status).unwrap_or_default();
+    let mut response = HttpResponse::build(status);
+    for (name, value) in stored.headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            response.append_header((name, value));
+        }
+    }
+    response.insert_header((REPLAYED, "true"));
+    response.body(stored.body.bytes)
+}
+
+async fn release(client: &Client, key: &str) {
+    if let Err(e) = idempotency_service::release(client, key).await {
+        eprintln!("Failed to release idempotency key {}: {}", key, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{middleware, App};
+    use mongodb::bson::oid::ObjectId;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    async fn test_client() -> Client {
+        let uri = std::env::var("MONGODB_TEST_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
+        Client::with_uri_str(uri).await.unwrap()
+    }
+
+    /// An app whose only handler counts how often it ran.
+    macro_rules! app {
+        ($client:expr, $calls:expr) => {{
+            let calls = $calls.clone();
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new($client))
+                    .wrap(middleware::from_fn(idempotency))
+                    .default_service(web::to(move |body: web::Bytes| {
+                        let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                        async move {
+                            HttpResponse::Created()
+                                .insert_header(("Location", format!("/api/notes/{}", call)))
+                                .body(body)
+                        }
+                    })),
+            )
+            .await
+        }};
+    }
+
+    fn post(key: &str, body: &'static str) -> TestRequest {
+        TestRequest::post().uri("/api/notes").insert_header((IDEMPOTENCY_KEY, key)).set_payload(body)
+    }
+
+    #[actix_web::test]
+    async fn oversized_bodies_are_refused() {
+        // The client only connects once used, which a refused request never does
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = app!(test_client().await, calls);
+        let body = vec![b'a'; import_service::max_import_size() + 1];
+        let request = TestRequest::post().uri("/api/notes").insert_header((IDEMPOTENCY_KEY, "big")).set_payload(body);
+        assert_eq!(test::call_service(&app, request.to_request()).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn retries_replay_the_first_response() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = app!(test_client().await, calls);
+        let key = ObjectId::new().to_hex();
+
+        let first = test::call_service(&app, post(&key, "groceries").to_request()).await;
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert!(first.headers().get(REPLAYED).is_none());
+        let retry = test::call_service(&app, post(&key, "groceries").to_request()).await;
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert_eq!(retry.headers().get(REPLAYED).unwrap(), "true");
+        assert_eq!(retry.headers().get("Location").unwrap(), "/api/notes/1");
+        assert_eq!(test::read_body(retry).await, "groceries");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn reusing_a_key_for_another_request_is_unprocessable() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = app!(test_client().await, calls);
+        let key = ObjectId::new().to_hex();
+
+        assert_eq!(test::call_service(&app, post(&key, "groceries").to_request()).await.status(), StatusCode::CREATED);
+        let other = test::call_service(&app, post(&key, "chores").to_request()).await;
+        assert_eq!(other.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn only_one_of_concurrent_requests_claims_a_key() {
+        let client = test_client().await;
+        let key = ObjectId::new().to_hex();
+        let fingerprint = idempotency_service::fingerprint("POST", "/api/notes", "", b"groceries");
+
+        let (first, second) = futures_util::future::join(
+            idempotency_service::claim(&client, &key, &fingerprint),
+            idempotency_service::claim(&client, &key, &fingerprint),
+        )
+        .await;
+        let claims = [first.unwrap(), second.unwrap()];
+        assert_eq!(claims.iter().filter(|claim| matches!(claim, Claim::Acquired)).count(), 1);
+        assert_eq!(claims.iter().filter(|claim| matches!(claim, Claim::InProgress)).count(), 1);
+
+        // Released after a failure, the key can be claimed again
+        idempotency_service::release(&client, &key).await.unwrap();
+        assert!(matches!(idempotency_service::claim(&client, &key, &fingerprint).await.unwrap(), Claim::Acquired));
+        idempotency_service::release(&client, &key).await.unwrap();
+    }
+}
@@ -0,0 +1,37 @@
+use actix_multipart::Multipart;
+use actix_web::{post, web, HttpResponse, Responder};
+use mongodb::Client;
+use crate::models::import::ImportOptions;
+use crate::routes::attachments::read_upload;
+use crate::services::import_service;
+
+/// Imports a zip of Markdown files (e.g. an Obsidian vault) or an Evernote
+/// ENEX export, uploaded as the `file` field. Supports `dry_run`,
+/// `duplicates` (skip, overwrite or keep_both) and `notebook_id`.
+#[post("/import")]
+async fn import_notes(
+    client: web::Data<Client>,
+    options: web::Query<ImportOptions>,
+    payload: Multipart,
+) -> impl Responder {
+    let (filename, data) = match read_upload(payload, import_service::max_import_size()).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+
+    // Unpacking and parsing is CPU bound, so keep it off the async workers
+    let archive = match web::block(move || import_service::parse_archive(&filename, &data)).await {
+        Ok(Ok(archive)) => archive,
+        Ok(Err(e)) => return import_service::error_response(e),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Import failed: {}", e)),
+    };
+
+    match import_service::import_archive(&client, archive, &options).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => import_service::error_response(e),
+    }
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(import_notes);
+}
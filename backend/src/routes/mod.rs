@@ -1,14 +1,39 @@
 pub mod todo;
 pub mod notes;
 pub mod calendar;
+pub mod notebooks;
+pub mod attachments;
+pub mod templates;
+pub mod collab;
+pub mod import;
+pub mod trash;
+pub mod idempotency;
+pub mod auth;
+pub mod caldav;
 
-use actix_web::web;
+use actix_web::{http::header, middleware, web, HttpResponse, HttpResponseBuilder};
+
+/// 201 response with a `Location` header pointing at the created resource.
+pub(crate) fn created(location: String) -> HttpResponseBuilder {
+    let mut response = HttpResponse::Created();
+    response.insert_header((header::LOCATION, location));
+    response
+}
 
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/api")
+            .wrap(middleware::from_fn(idempotency::idempotency))
             .configure(todo::init_routes)
             .configure(notes::init_routes)
             .configure(calendar::init_routes)
+            .configure(notebooks::init_routes)
+            .configure(attachments::init_routes)
+            .configure(templates::init_routes)
+            .configure(collab::init_routes)
+            .configure(import::init_routes)
+            .configure(trash::init_routes)
     );
+    // Calendar apps don't send idempotency keys, and look for CalDAV at the root
+    cfg.configure(caldav::init_routes);
 }
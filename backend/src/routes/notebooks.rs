@@ -0,0 +1,153 @@
+use actix_web::{get, post, put, delete, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{bson::oid::ObjectId, Client};
+use serde::{Deserialize, Serialize};
+use crate::dto::note::NoteResponse;
+use crate::dto::notebook::NotebookResponse;
+use crate::models::notebook::{Notebook, NotebookDeleteMode, NotebookSummary};
+use crate::dto::preconditions;
+use crate::routes;
+use crate::services::notebook_service;
+use validator::Validate;
+
+#[derive(Serialize, Deserialize)]
+struct NotebookData {
+    name: String,
+    parent_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RenameData {
+    name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MoveNotebookData {
+    parent_id: Option<String>,
+    position: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MoveNoteData {
+    notebook_id: Option<String>,
+    position: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct DeleteQuery {
+    #[serde(default)]
+    mode: NotebookDeleteMode,
+    #[serde(default)]
+    confirm: bool,
+}
+
+#[get("/notebooks")]
+async fn get_notebooks(client: web::Data<Client>) -> impl Responder {
+    match notebook_service::get_all_notebooks(&client).await {
+        Ok(notebooks) => HttpResponse::Ok().json(notebooks.into_iter().map(NotebookResponse::from).collect::<Vec<_>>()),
+        Err(e) => notebook_service::error_response(e),
+    }
+}
+
+#[get("/notebooks/{id}")]
+async fn get_notebook(client: web::Data<Client>, notebook_id: web::Path<String>) -> impl Responder {
+    match notebook_service::get_notebook_summary(&client, &notebook_id).await {
+        Ok(notebook) => HttpResponse::Ok().json(NotebookResponse::from(notebook)),
+        Err(e) => notebook_service::error_response(e),
+    }
+}
+
+#[get("/notebooks/{id}/notes")]
+async fn get_notebook_notes(client: web::Data<Client>, notebook_id: web::Path<String>) -> impl Responder {
+    match notebook_service::get_notebook_notes(&client, &notebook_id).await {
+        Ok(notes) => HttpResponse::Ok().json(notes.into_iter().map(NoteResponse::from).collect::<Vec<_>>()),
+        Err(e) => notebook_service::error_response(e),
+    }
+}
+
+/// Creates a notebook and returns it, with its URL in `Location`.
+#[post("/notebooks")]
+async fn create_notebook(client: web::Data<Client>, notebook_data: web::Json<NotebookData>) -> impl Responder {
+    let parent_id = match notebook_data.parent_id.as_deref().map(ObjectId::parse_str).transpose() {
+        Ok(parent_id) => parent_id,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid ObjectId: {}", e)),
+    };
+    let new_notebook = Notebook::new(notebook_data.name.clone(), parent_id);
+
+    if let Err(validation_error) = new_notebook.validate() {
+        return HttpResponse::BadRequest().json(validation_error);
+    }
+
+    match notebook_service::add_notebook(&client, new_notebook).await {
+        Ok(notebook) => {
+            // A new notebook is empty
+            let notebook = NotebookResponse::from(NotebookSummary { notebook, note_count: 0, notebook_count: 0 });
+            routes::created(format!("/api/notebooks/{}", notebook.id)).json(notebook)
+        }
+        Err(e) => notebook_service::error_response(e),
+    }
+}
+
+#[put("/notebooks/{id}")]
+async fn rename_notebook(
+    client: web::Data<Client>,
+    notebook_id: web::Path<String>,
+    notebook_data: web::Json<RenameData>,
+) -> impl Responder {
+    match notebook_service::rename_notebook(&client, &notebook_id, notebook_data.into_inner().name).await {
+        Ok(notebook) => HttpResponse::Ok().json(NotebookResponse::from(notebook)),
+        Err(e) => notebook_service::error_response(e),
+    }
+}
+
+#[post("/notebooks/{id}/move")]
+async fn move_notebook(
+    client: web::Data<Client>,
+    notebook_id: web::Path<String>,
+    move_data: web::Json<MoveNotebookData>,
+) -> impl Responder {
+    match notebook_service::move_notebook(&client, &notebook_id, move_data.parent_id.as_deref(), move_data.position).await {
+        Ok(notebook) => HttpResponse::Ok().json(NotebookResponse::from(notebook)),
+        Err(e) => notebook_service::error_response(e),
+    }
+}
+
+#[delete("/notebooks/{id}")]
+async fn delete_notebook(
+    client: web::Data<Client>,
+    notebook_id: web::Path<String>,
+    query: web::Query<DeleteQuery>,
+) -> impl Responder {
+    let DeleteQuery { mode, confirm } = query.into_inner();
+    match notebook_service::remove_notebook(&client, &notebook_id, mode, confirm).await {
+        Ok(_) => HttpResponse::Ok().json("Notebook deleted successfully"),
+        Err(e) => notebook_service::error_response(e),
+    }
+}
+
+/// Moves a note. With `If-Match`, fails with 412 and the current note unless
+/// it is still at the given version.
+#[post("/notes/{id}/move")]
+async fn move_note(
+    request: HttpRequest,
+    client: web::Data<Client>,
+    note_id: web::Path<String>,
+    move_data: web::Json<MoveNoteData>,
+) -> impl Responder {
+    let expected_versions = preconditions::if_match(&request);
+    let MoveNoteData { notebook_id, position } = move_data.into_inner();
+    match notebook_service::move_note(&client, &note_id, notebook_id.as_deref(), position, expected_versions.as_deref()).await {
+        Ok(note) => preconditions::ok_with_etag(note.version).json(NoteResponse::from(note)),
+        Err(e) => notebook_service::error_response(e),
+    }
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_notebooks);
+    cfg.service(get_notebook);
+    cfg.service(get_notebook_notes);
+    cfg.service(create_notebook);
+    cfg.service(rename_notebook);
+    cfg.service(move_notebook);
+    cfg.service(delete_notebook);
+    cfg.service(move_note);
+}
@@ -1,8 +1,16 @@
-use actix_web::{get, post, put, delete, web, HttpResponse, Responder};
-use mongodb::Client;
-use serde::{Deserialize, Serialize};
-use crate::models::note::Note;
-use crate::services::notes_service;
+use actix_web::{get, post, put, delete, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{bson::oid::ObjectId, Client};
+use serde::{Deserialize, Deserializer, Serialize};
+use crate::models::bulk::{BulkRequest, NoteBulkAction};
+use crate::models::note::{KeyRotation, Note, NoteEncryption, NoteListQuery};
+use crate::db::blob_store::BlobStore;
+use crate::dto::note::{NoteResponse, RenderedNoteResponse};
+use crate::dto::preconditions;
+use crate::routes;
+use crate::services::{bulk_service, markdown_service, notes_service};
+use crate::services::notes_service::NotesServiceError;
+use crate::services::collab_service::CollabHub;
+use crate::services::export_service;
 use validator::Validate;
 
 #[derive(Serialize, Deserialize)]
@@ -10,68 +18,293 @@ struct NoteData {
     title: String,
     content: String,
     tags: Option<Vec<String>>,
+    extract_todos: Option<bool>,
+    notebook_id: Option<String>,
+    /// Present when `title` and `content` are client-side encrypted. On
+    /// updates, `null` decrypts the note and leaving it out keeps it as is.
+    #[serde(default, deserialize_with = "present")]
+    encryption: Option<Option<NoteEncryption>>,
 }
 
+/// Tells a field sent as `null` (`Some(None)`) from one left out (`None`).
+fn present<'de, D: Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> Result<Option<Option<T>>, D::Error> {
+    Option::deserialize(deserializer).map(Some)
+}
+
+/// Per-request switch for turning task-list items into linked todos.
+#[derive(Deserialize)]
+struct ExtractTodosQuery {
+    #[serde(default)]
+    extract_todos: bool,
+}
+
+/// Representation requested for note content.
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum NoteFormat {
+    #[default]
+    Markdown,
+    Html,
+}
+
+#[derive(Deserialize)]
+struct FormatQuery {
+    #[serde(default)]
+    format: NoteFormat,
+}
+
+/// Lists notes. Supports `sort`, `order`, `limit`, `cursor`, `pinned`,
+/// `favourite` and `key_id`; the cursor for the next page is sent in
+/// `X-Next-Cursor`.
 #[get("/notes")]
-async fn get_notes(client: web::Data<Client>) -> impl Responder {
-    match notes_service::get_all_notes(&client).await {
-        Ok(notes) => HttpResponse::Ok().json(notes),
+async fn get_notes(
+    client: web::Data<Client>,
+    query: web::Query<FormatQuery>,
+    list_query: web::Query<NoteListQuery>,
+) -> impl Responder {
+    match notes_service::get_all_notes(&client, &list_query).await {
+        Ok(page) => {
+            let mut response = HttpResponse::Ok();
+            if let Some(cursor) = page.next_cursor {
+                response.insert_header(("X-Next-Cursor", cursor));
+            }
+            if query.format == NoteFormat::Html {
+                let rendered: Vec<_> = page
+                    .notes
+                    .into_iter()
+                    .map(|note| RenderedNoteResponse::from(markdown_service::render_note(note)))
+                    .collect();
+                response.json(rendered)
+            } else {
+                response.json(page.notes.into_iter().map(NoteResponse::from).collect::<Vec<_>>())
+            }
+        }
+        Err(e) => notes_service::error_response(e),
+    }
+}
+
+/// Downloads every note as a zip of Markdown files with front matter, laid
+/// out by notebook, with attachments in an `assets` folder.
+#[get("/notes/export")]
+async fn export_notes(client: web::Data<Client>, store: web::Data<BlobStore>) -> impl Responder {
+    match export_service::export_notes(&client, &store).await {
+        Ok(stream) => {
+            let filename = format!("notes-{}.zip", chrono::Utc::now().format("%Y-%m-%d"));
+            HttpResponse::Ok()
+                .content_type("application/zip")
+                .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", filename)))
+                .streaming(stream)
+        }
+        Err(e) => export_service::error_response(e),
+    }
+}
+
+/// Fetches a note. The `ETag` header carries its version, to be sent back in
+/// `If-Match` when updating or deleting it.
+#[get("/notes/{id}")]
+async fn get_note(
+    client: web::Data<Client>,
+    note_id: web::Path<String>,
+    query: web::Query<FormatQuery>,
+) -> impl Responder {
+    match notes_service::get_note_by_id(&client, &note_id).await {
+        Ok(note) if query.format == NoteFormat::Html && note.is_encrypted() => {
+            notes_service::error_response(NotesServiceError::EncryptedNote("Markdown rendering"))
+        }
+        Ok(note) if query.format == NoteFormat::Html => {
+            preconditions::ok_with_etag(note.version).json(RenderedNoteResponse::from(markdown_service::render_note(note)))
+        }
+        Ok(note) => note_response(note),
         Err(e) => notes_service::error_response(e),
     }
 }
 
+/// Creates a note and returns it, with its URL in `Location`.
 #[post("/notes")]
-async fn create_note(client: web::Data<Client>, note_data: web::Json<NoteData>) -> impl Responder {
-    let new_note = Note::new(note_data.title.clone(), note_data.content.clone());
+async fn create_note(
+    client: web::Data<Client>,
+    note_data: web::Json<NoteData>,
+    query: web::Query<ExtractTodosQuery>,
+) -> impl Responder {
+    let mut new_note = Note::new(note_data.title.clone(), note_data.content.clone());
+    new_note.extract_todos = note_data.extract_todos;
+    new_note.encryption = note_data.encryption.clone().flatten();
+    new_note.notebook_id = match note_data.notebook_id.as_deref().map(ObjectId::parse_str).transpose() {
+        Ok(notebook_id) => notebook_id,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid ObjectId: {}", e)),
+    };
     
     if let Err(validation_error) = new_note.validate() {
         return HttpResponse::BadRequest().json(validation_error);
     }
 
-    match notes_service::add_note(&client, new_note).await {
-        Ok(_) => HttpResponse::Created().json("Note created successfully"),
+    match notes_service::add_note(&client, new_note, query.extract_todos).await {
+        Ok(note) => {
+            let note = NoteResponse::from(note);
+            routes::created(format!("/api/notes/{}", note.id))
+                .insert_header(preconditions::etag(note.version))
+                .json(note)
+        }
         Err(e) => notes_service::error_response(e),
     }
 }
 
+/// Replaces a note. With `If-Match`, fails with 412 and the current note
+/// unless it is still at the given version.
 #[put("/notes/{id}")]
 async fn update_note(
+    request: HttpRequest,
     client: web::Data<Client>,
+    hub: web::Data<CollabHub>,
     note_id: web::Path<String>,
     note_data: web::Json<NoteData>,
+    query: web::Query<ExtractTodosQuery>,
 ) -> impl Responder {
-    let updated_note = Note::new(note_data.title.clone(), note_data.content.clone());
-    
-    if let Err(validation_error) = updated_note.validate() {
-        return HttpResponse::BadRequest().json(validation_error);
-    }
+    let mut updated_note = Note::new(note_data.title.clone(), note_data.content.clone());
+    updated_note.extract_todos = note_data.extract_todos;
+    updated_note.encryption = note_data.encryption.clone().flatten();
 
-    match notes_service::update_note(&client, &note_id, updated_note).await {
-        Ok(_) => HttpResponse::Ok().json("Note updated successfully"),
+    // Merge into the live document instead of overwriting concurrent edits
+    let expected_versions = preconditions::if_match(&request);
+    let result = match hub.active_room(&note_id).await.filter(|_| !updated_note.is_encrypted()) {
+        Some(room) => room.update_note(&client, updated_note, query.extract_todos, expected_versions.as_deref()).await,
+        None => {
+            let keep_encryption = note_data.encryption.is_none();
+            notes_service::update_note(&client, &note_id, updated_note, query.extract_todos, expected_versions.as_deref(), keep_encryption)
+                .await
+        }
+    };
+    match result {
+        // Drop the collaborative document, which still holds the plain text
+        Ok(note) if note.is_encrypted() => match hub.close_room(&client, &note_id).await {
+            Ok(()) => note_response(note),
+            Err(e) => notes_service::error_response(e),
+        },
+        Ok(note) => note_response(note),
         Err(e) => notes_service::error_response(e),
     }
 }
 
 #[delete("/notes/{id}")]
-async fn delete_note(client: web::Data<Client>, note_id: web::Path<String>) -> impl Responder {
-    match notes_service::remove_note(&client, &note_id).await {
+async fn delete_note(
+    request: HttpRequest,
+    client: web::Data<Client>,
+    note_id: web::Path<String>,
+) -> impl Responder {
+    let expected_versions = preconditions::if_match(&request);
+    match notes_service::remove_note(&client, &note_id, expected_versions.as_deref()).await {
         Ok(_) => HttpResponse::Ok().json("Note deleted successfully"),
         Err(e) => notes_service::error_response(e),
     }
 }
 
 #[post("/notes/{id}/archive")]
-async fn archive_note(client: web::Data<Client>, note_id: web::Path<String>) -> impl Responder {
-    match notes_service::toggle_archive(&client, &note_id).await {
-        Ok(_) => HttpResponse::Ok().json("Note archived successfully"),
+async fn archive_note(request: HttpRequest, client: web::Data<Client>, note_id: web::Path<String>) -> impl Responder {
+    let expected_versions = preconditions::if_match(&request);
+    match notes_service::toggle_archive(&client, &note_id, expected_versions.as_deref()).await {
+        Ok(note) => note_response(note),
         Err(e) => notes_service::error_response(e),
     }
 }
 
+#[post("/notes/{id}/pin")]
+async fn pin_note(request: HttpRequest, client: web::Data<Client>, note_id: web::Path<String>) -> impl Responder {
+    let expected_versions = preconditions::if_match(&request);
+    match notes_service::set_pinned(&client, &note_id, true, expected_versions.as_deref()).await {
+        Ok(note) => note_response(note),
+        Err(e) => notes_service::error_response(e),
+    }
+}
+
+#[delete("/notes/{id}/pin")]
+async fn unpin_note(request: HttpRequest, client: web::Data<Client>, note_id: web::Path<String>) -> impl Responder {
+    let expected_versions = preconditions::if_match(&request);
+    match notes_service::set_pinned(&client, &note_id, false, expected_versions.as_deref()).await {
+        Ok(note) => note_response(note),
+        Err(e) => notes_service::error_response(e),
+    }
+}
+
+#[post("/notes/{id}/favourite")]
+async fn favourite_note(request: HttpRequest, client: web::Data<Client>, note_id: web::Path<String>) -> impl Responder {
+    let expected_versions = preconditions::if_match(&request);
+    match notes_service::set_favourite(&client, &note_id, true, expected_versions.as_deref()).await {
+        Ok(note) => note_response(note),
+        Err(e) => notes_service::error_response(e),
+    }
+}
+
+#[delete("/notes/{id}/favourite")]
+async fn unfavourite_note(request: HttpRequest, client: web::Data<Client>, note_id: web::Path<String>) -> impl Responder {
+    let expected_versions = preconditions::if_match(&request);
+    match notes_service::set_favourite(&client, &note_id, false, expected_versions.as_deref()).await {
+        Ok(note) => note_response(note),
+        Err(e) => notes_service::error_response(e),
+    }
+}
+
+/// Rewraps an encrypted note's data key after the wrapping key was rotated.
+#[post("/notes/{id}/encryption/rotate")]
+async fn rotate_note_key(
+    request: HttpRequest,
+    client: web::Data<Client>,
+    note_id: web::Path<String>,
+    rotation: web::Json<KeyRotation>,
+) -> impl Responder {
+    let expected_versions = preconditions::if_match(&request);
+    match notes_service::rotate_note_key(&client, &note_id, rotation.into_inner(), expected_versions.as_deref()).await {
+        Ok(note) => note_response(note),
+        Err(e) => notes_service::error_response(e),
+    }
+}
+
+/// Applies a list of `archive`, `move`, `add_tag`, `remove_tag` and `delete`
+/// operations. Answers 207 with per-item results when some of them failed.
+#[post("/notes/bulk")]
+async fn bulk_notes(client: web::Data<Client>, request: web::Json<BulkRequest<NoteBulkAction>>) -> impl Responder {
+    match bulk_service::bulk_notes(&client, request.into_inner().operations).await {
+        Ok(report) if report.failed > 0 => HttpResponse::MultiStatus().json(report),
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => bulk_service::error_response(e),
+    }
+}
+
+/// 200 response with the note and its `ETag`.
+fn note_response(note: Note) -> HttpResponse {
+    preconditions::ok_with_etag(note.version).json(NoteResponse::from(note))
+}
+
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(get_notes);
+    // Registered before get_note, which would otherwise match "export" as an id
+    cfg.service(export_notes);
+    cfg.service(get_note);
     cfg.service(create_note);
     cfg.service(update_note);
     cfg.service(delete_note);
     cfg.service(archive_note);
+    cfg.service(pin_note);
+    cfg.service(unpin_note);
+    cfg.service(favourite_note);
+    cfg.service(unfavourite_note);
+    cfg.service(rotate_note_key);
+    cfg.service(bulk_notes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encryption(body: &str) -> Option<Option<NoteEncryption>> {
+        serde_json::from_str::<NoteData>(body).unwrap().encryption
+    }
+
+    #[test]
+    fn encryption_tells_null_from_missing() {
+        assert!(encryption(r#"{"title": "t", "content": "c"}"#).is_none());
+        assert!(matches!(encryption(r#"{"title": "t", "content": "c", "encryption": null}"#), Some(None)));
+        let sent = r#"{"title": "t", "content": "c", "encryption": {"cipher": "aes256-gcm", "nonce": "AAAAAAAAAAAAAAAA",
+            "title_nonce": "AAAAAAAAAAAAAAAA", "key_id": "key-1", "wrapped_key": "a2V5"}}"#;
+        assert!(matches!(encryption(sent), Some(Some(encryption)) if encryption.key_id == "key-1"));
+    }
 }
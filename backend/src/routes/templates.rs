@@ -0,0 +1,117 @@
+use actix_web::{get, post, put, delete, web, HttpResponse, Responder};
+use chrono::NaiveDate;
+use mongodb::Client;
+use serde::Deserialize;
+use crate::dto::note::NoteResponse;
+use crate::dto::template::TemplateResponse;
+use crate::models::note::Note;
+use crate::models::template::{NoteTemplateSchema, TemplateValues};
+use crate::dto::preconditions;
+use crate::routes;
+use crate::services::template_service;
+use validator::Validate;
+
+#[derive(Deserialize)]
+struct DailyNoteQuery {
+    /// Date in YYYY-MM-DD format; defaults to today.
+    date: Option<NaiveDate>,
+    /// IANA time zone used to determine "today".
+    tz: Option<String>,
+    template_id: Option<String>,
+}
+
+#[get("/templates")]
+async fn get_templates(client: web::Data<Client>) -> impl Responder {
+    match template_service::get_all_templates(&client).await {
+        Ok(templates) => HttpResponse::Ok().json(templates.into_iter().map(TemplateResponse::from).collect::<Vec<_>>()),
+        Err(e) => template_service::error_response(e),
+    }
+}
+
+#[get("/templates/{id}")]
+async fn get_template(client: web::Data<Client>, template_id: web::Path<String>) -> impl Responder {
+    match template_service::get_template(&client, &template_id).await {
+        Ok(template) => HttpResponse::Ok().json(TemplateResponse::from(template)),
+        Err(e) => template_service::error_response(e),
+    }
+}
+
+/// Creates a template and returns it, with its URL in `Location`.
+#[post("/templates")]
+async fn create_template(client: web::Data<Client>, new_template: web::Json<NoteTemplateSchema>) -> impl Responder {
+    if let Err(validation_error) = new_template.validate() {
+        return HttpResponse::BadRequest().json(validation_error);
+    }
+
+    match template_service::add_template(&client, new_template.into_inner().into()).await {
+        Ok(template) => {
+            let template = TemplateResponse::from(template);
+            routes::created(format!("/api/templates/{}", template.id)).json(template)
+        }
+        Err(e) => template_service::error_response(e),
+    }
+}
+
+#[put("/templates/{id}")]
+async fn update_template(
+    client: web::Data<Client>,
+    template_id: web::Path<String>,
+    updated_template: web::Json<NoteTemplateSchema>,
+) -> impl Responder {
+    if let Err(validation_error) = updated_template.validate() {
+        return HttpResponse::BadRequest().json(validation_error);
+    }
+
+    match template_service::update_template(&client, &template_id, updated_template.into_inner().into()).await {
+        Ok(template) => HttpResponse::Ok().json(TemplateResponse::from(template)),
+        Err(e) => template_service::error_response(e),
+    }
+}
+
+#[delete("/templates/{id}")]
+async fn delete_template(client: web::Data<Client>, template_id: web::Path<String>) -> impl Responder {
+    match template_service::remove_template(&client, &template_id).await {
+        Ok(_) => HttpResponse::Ok().json("Template deleted successfully"),
+        Err(e) => template_service::error_response(e),
+    }
+}
+
+#[post("/notes/from-template/{id}")]
+async fn create_note_from_template(
+    client: web::Data<Client>,
+    template_id: web::Path<String>,
+    values: web::Json<TemplateValues>,
+) -> impl Responder {
+    match template_service::create_note_from_template(&client, &template_id, values.into_inner()).await {
+        Ok(note) => created_note(note),
+        Err(e) => template_service::error_response(e),
+    }
+}
+
+/// Returns today's daily note, creating it on first request.
+#[post("/notes/daily")]
+async fn daily_note(client: web::Data<Client>, query: web::Query<DailyNoteQuery>) -> impl Responder {
+    let query = query.into_inner();
+    match template_service::get_or_create_daily_note(&client, query.date, query.tz.as_deref(), query.template_id.as_deref()).await {
+        Ok((note, true)) => created_note(note),
+        Ok((note, false)) => preconditions::ok_with_etag(note.version).json(NoteResponse::from(note)),
+        Err(e) => template_service::error_response(e),
+    }
+}
+
+fn created_note(note: Note) -> HttpResponse {
+    let note = NoteResponse::from(note);
+    routes::created(format!("/api/notes/{}", note.id))
+        .insert_header(preconditions::etag(note.version))
+        .json(note)
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_templates);
+    cfg.service(get_template);
+    cfg.service(create_template);
+    cfg.service(update_template);
+    cfg.service(delete_template);
+    cfg.service(create_note_from_template);
+    cfg.service(daily_note);
+}
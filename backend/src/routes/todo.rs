@@ -1,17 +1,32 @@
-use actix_web::{get, post, put, patch, delete, web, HttpResponse, Responder};
+use actix_web::{get, post, put, patch, delete, web, HttpRequest, HttpResponse, Responder};
 use mongodb::Client;
-use crate::services::todo_service;
+use crate::dto::todo::TodoResponse;
+use crate::dto::preconditions;
+use crate::routes;
+use crate::services::{bulk_service, todo_service};
+use crate::models::bulk::{BulkRequest, TodoBulkAction};
 use crate::models::todo::TodoSchema;
 use validator::Validate;
 
 #[get("/todos")]
 async fn get_todos(db: web::Data<Client>) -> impl Responder {
     match todo_service::get_all_todos(&db).await {
-        Ok(todos) => HttpResponse::Ok().json(todos),
+        Ok(todos) => HttpResponse::Ok().json(todos.into_iter().map(TodoResponse::from).collect::<Vec<_>>()),
         Err(e) => todo_service::error_response(e),
     }
 }
 
+/// Fetches a todo. The `ETag` header carries its version, to be sent back in
+/// `If-Match` when updating, toggling or deleting it.
+#[get("/todos/{id}")]
+async fn get_todo(db: web::Data<Client>, todo_id: web::Path<String>) -> impl Responder {
+    match todo_service::get_todo_by_id(&db, &todo_id).await {
+        Ok(todo) => preconditions::ok_with_etag(todo.version).json(TodoResponse::from(todo)),
+        Err(e) => todo_service::error_response(e),
+    }
+}
+
+/// Creates a todo and returns it, with its URL in `Location`.
 #[post("/todos")]
 async fn create_todo(db: web::Data<Client>, new_todo: web::Json<TodoSchema>) -> impl Responder {
     if let Err(validation_error) = new_todo.validate() {
@@ -19,13 +34,19 @@ async fn create_todo(db: web::Data<Client>, new_todo: web::Json<TodoSchema>) ->
     }
 
     match todo_service::add_todo(&db, new_todo.into_inner().into()).await {
-        Ok(_) => HttpResponse::Created().json("Todo created successfully"),
+        Ok(todo) => {
+            let todo = TodoResponse::from(todo);
+            routes::created(format!("/api/todos/{}", todo.id))
+                .insert_header(preconditions::etag(todo.version))
+                .json(todo)
+        }
         Err(e) => todo_service::error_response(e),
     }
 }
 
 #[put("/todos/{id}")]
 async fn edit_todo(
+    request: HttpRequest,
     db: web::Data<Client>,
     todo_id: web::Path<String>,
     updated_todo: web::Json<TodoSchema>,
@@ -34,32 +55,57 @@ async fn edit_todo(
         return HttpResponse::BadRequest().json(validation_error);
     }
 
-    match todo_service::update_todo(&db, &todo_id, updated_todo.into_inner().into()).await {
-        Ok(_) => HttpResponse::Ok().json("Todo updated successfully"),
+    let expected_versions = preconditions::if_match(&request);
+    match todo_service::update_todo(&db, &todo_id, updated_todo.into_inner().into(), expected_versions.as_deref()).await {
+        Ok(todo) => preconditions::ok_with_etag(todo.version).json(TodoResponse::from(todo)),
         Err(e) => todo_service::error_response(e),
     }
 }
 
 #[delete("/todos/{id}")]
-async fn delete_todo(db: web::Data<Client>, todo_id: web::Path<String>) -> impl Responder {
-    match todo_service::remove_todo(&db, &todo_id).await {
+async fn delete_todo(
+    request: HttpRequest,
+    db: web::Data<Client>,
+    todo_id: web::Path<String>,
+) -> impl Responder {
+    let expected_versions = preconditions::if_match(&request);
+    match todo_service::remove_todo(&db, &todo_id, expected_versions.as_deref()).await {
         Ok(_) => HttpResponse::Ok().json("Todo deleted successfully"),
         Err(e) => todo_service::error_response(e),
     }
 }
 
 #[patch("/todos/{id}/toggle")]
-async fn toggle_todo_completion(db: web::Data<Client>, todo_id: web::Path<String>) -> impl Responder {
-    match todo_service::set_todo_completion(&db, &todo_id).await {
-        Ok(_) => HttpResponse::Ok().json("Todo completion status updated successfully"),
+async fn toggle_todo_completion(
+    request: HttpRequest,
+    db: web::Data<Client>,
+    todo_id: web::Path<String>,
+) -> impl Responder {
+    let expected_versions = preconditions::if_match(&request);
+    match todo_service::set_todo_completion(&db, &todo_id, expected_versions.as_deref()).await {
+        Ok(todo) => preconditions::ok_with_etag(todo.version).json(TodoResponse::from(todo)),
         Err(e) => todo_service::error_response(e),
     }
 }
 
+/// Applies a list of `complete`, `archive`, `move`, `add_tag`, `remove_tag`
+/// and `delete` operations. Answers 207 with per-item results when some of
+/// them failed.
+#[post("/todos/bulk")]
+async fn bulk_todos(db: web::Data<Client>, request: web::Json<BulkRequest<TodoBulkAction>>) -> impl Responder {
+    match bulk_service::bulk_todos(&db, request.into_inner().operations).await {
+        Ok(report) if report.failed > 0 => HttpResponse::MultiStatus().json(report),
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => bulk_service::error_response(e),
+    }
+}
+
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(get_todos);
+    cfg.service(get_todo);
     cfg.service(create_todo);
     cfg.service(edit_todo);
     cfg.service(delete_todo);
     cfg.service(toggle_todo_completion);
+    cfg.service(bulk_todos);
 }
\ No newline at end of file
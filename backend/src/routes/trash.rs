@@ -0,0 +1,60 @@
+use actix_web::{get, post, delete, web, HttpResponse, Responder};
+use mongodb::Client;
+use crate::db::blob_store::BlobStore;
+use crate::models::trash::{TrashKind, TrashQuery};
+use crate::services::{attachment_service, trash_service};
+
+#[get("/trash")]
+async fn get_trash(client: web::Data<Client>, query: web::Query<TrashQuery>) -> impl Responder {
+    match trash_service::get_trash(&client, query.kind).await {
+        Ok(items) => HttpResponse::Ok().json(items),
+        Err(e) => trash_service::error_response(e),
+    }
+}
+
+#[post("/trash/{type}/{id}/restore")]
+async fn restore_item(client: web::Data<Client>, path: web::Path<(TrashKind, String)>) -> impl Responder {
+    let (kind, id) = path.into_inner();
+    match trash_service::restore(&client, kind, &id).await {
+        Ok(_) => HttpResponse::Ok().json("Item restored successfully"),
+        Err(e) => trash_service::error_response(e),
+    }
+}
+
+#[delete("/trash/{type}/{id}")]
+async fn purge_item(
+    client: web::Data<Client>,
+    store: web::Data<BlobStore>,
+    path: web::Path<(TrashKind, String)>,
+) -> impl Responder {
+    let (kind, id) = path.into_inner();
+    match trash_service::purge(&client, kind, &id).await {
+        Ok(_) => {
+            if let Err(e) = attachment_service::collect_garbage(&client, &store).await {
+                eprintln!("Attachment garbage collection failed: {}", e);
+            }
+            HttpResponse::Ok().json("Item deleted permanently")
+        }
+        Err(e) => trash_service::error_response(e),
+    }
+}
+
+#[delete("/trash")]
+async fn empty_trash(client: web::Data<Client>, store: web::Data<BlobStore>) -> impl Responder {
+    match trash_service::empty_trash(&client).await {
+        Ok(summary) => {
+            if let Err(e) = attachment_service::collect_garbage(&client, &store).await {
+                eprintln!("Attachment garbage collection failed: {}", e);
+            }
+            HttpResponse::Ok().json(summary)
+        }
+        Err(e) => trash_service::error_response(e),
+    }
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_trash);
+    cfg.service(restore_item);
+    cfg.service(purge_item);
+    cfg.service(empty_trash);
+}
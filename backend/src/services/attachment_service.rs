@@ -0,0 +1,517 @@
+use mongodb::{Client, bson::{doc, oid::ObjectId, Document}};
+use mongodb::error::Error;
+use mongodb::options::ReturnDocument;
+use futures_util::TryStreamExt;
+use crate::db::blob_store::BlobStore;
+use crate::models::attachment::{Attachment, AttachmentOwner, Blob};
+use thiserror::Error;
+use actix_web::{web, HttpResponse};
+use bytes::Bytes;
+use chrono::Utc;
+use image::{ImageFormat, ImageReader, Limits};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Cursor;
+
+/// Default upper bound for a single upload, overridable via MAX_ATTACHMENT_BYTES.
+const DEFAULT_MAX_ATTACHMENT_BYTES: usize = 10 * 1024 * 1024;
+
+/// Longest edge of generated thumbnails, in pixels.
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// Largest image dimension we are willing to decode for a thumbnail.
+const MAX_IMAGE_DIMENSION: u32 = 8_192;
+
+/// Most memory decoding an image for a thumbnail may take; larger images are
+/// stored without one.
+const MAX_DECODE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Content types accepted for upload, as sniffed from the file contents.
+const ALLOWED_CONTENT_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "application/pdf",
+    "text/plain",
+];
+
+#[derive(Error, Debug)]
+pub enum AttachmentServiceError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] Error),
+    #[error("Invalid ObjectId: {0}")]
+    InvalidObjectId(#[from] mongodb::bson::oid::Error),
+    #[error("Storage error: {0}")]
+    StorageError(#[from] object_store::Error),
+    #[error("Attachment not found")]
+    AttachmentNotFound,
+    #[error("Owner not found")]
+    OwnerNotFound,
+    #[error("Attachment exceeds the maximum size of {0} bytes")]
+    TooLarge(usize),
+    #[error("Unsupported content type: {0}")]
+    UnsupportedType(String),
+}
+
+/// Returns the maximum accepted upload size in bytes.
+pub fn max_attachment_size() -> usize {
+    std::env::var("MAX_ATTACHMENT_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_ATTACHMENT_BYTES)
+}
+
+/// Stores an uploaded file and attaches it to a note or todo.
+///
+/// The content type is sniffed from the bytes rather than trusted from the
+/// client. Identical content is stored once and reference counted.
+pub async fn add_attachment(
+    client: &Client,
+    store: &BlobStore,
+    owner: AttachmentOwner,
+    owner_id: &str,
+    filename: String,
+    data: Bytes,
+) -> Result<Attachment, AttachmentServiceError> {
+    if data.len() > max_attachment_size() {
+        return Err(AttachmentServiceError::TooLarge(max_attachment_size()));
+    }
+    let owner_id = ObjectId::parse_str(owner_id)?;
+    if get_owner_collection(client, owner).count_documents(doc! { "_id": owner_id, "deleted_at": null }).await? == 0 {
+        return Err(AttachmentServiceError::OwnerNotFound);
+    }
+
+    let content_type = sniff_content_type(&data);
+    if !ALLOWED_CONTENT_TYPES.contains(&content_type.as_str()) {
+        return Err(AttachmentServiceError::UnsupportedType(content_type));
+    }
+
+    let sha256 = hex::encode(Sha256::digest(&data));
+    let blob = acquire_blob(client, store, &sha256, &content_type, data).await?;
+
+    let attachment = Attachment {
+        id: Some(ObjectId::new()),
+        owner_type: owner,
+        owner_id,
+        filename: sanitize_filename(&filename),
+        content_type,
+        size: blob.size,
+        sha256,
+        has_thumbnail: blob.thumbnail_key.is_some(),
+        created_at: Utc::now().to_rfc3339(),
+    };
+    get_attachments_collection(client).insert_one(&attachment).await?;
+    Ok(attachment)
+}
+
+/// Retrieves the attachments of a note or todo.
+pub async fn get_attachments(
+    client: &Client,
+    owner: AttachmentOwner,
+    owner_id: &str,
+) -> Result<Vec<Attachment>, AttachmentServiceError> {
+    let owner_id = ObjectId::parse_str(owner_id)?;
+    let cursor = get_attachments_collection(client)
+        .find(doc! { "owner_type": owner_name(owner), "owner_id": owner_id })
+        .sort(doc! { "created_at": 1 })
+        .await?;
+    Ok(cursor.try_collect().await?)
+}
+
+/// Reads an attachment's content, or its thumbnail when `thumbnail` is set.
+///
+/// Returns the attachment metadata, the content type of the returned bytes and
+/// the bytes themselves.
+pub async fn read_attachment(
+    client: &Client,
+    store: &BlobStore,
+    attachment_id: &str,
+    thumbnail: bool,
+) -> Result<(Attachment, String, Bytes), AttachmentServiceError> {
+    let object_id = ObjectId::parse_str(attachment_id)?;
+    let attachment = get_attachments_collection(client)
+        .find_one(doc! { "_id": object_id })
+        .await?
+        .ok_or(AttachmentServiceError::AttachmentNotFound)?;
+    let blob = get_blobs_collection(client)
+        .find_one(doc! { "_id": &attachment.sha256 })
+        .await?
+        .ok_or(AttachmentServiceError::AttachmentNotFound)?;
+
+    let (key, content_type) = if thumbnail {
+        let key = blob.thumbnail_key.ok_or(AttachmentServiceError::AttachmentNotFound)?;
+        (key, "image/png".to_string())
+    } else {
+        (blob.key, attachment.content_type.clone())
+    };
+    let data = store.get(&key).await?;
+    Ok((attachment, content_type, data))
+}
+
+/// Removes a single attachment and releases its blob.
+pub async fn remove_attachment(
+    client: &Client,
+    store: &BlobStore,
+    attachment_id: &str,
+) -> Result<(), AttachmentServiceError> {
+    let object_id = ObjectId::parse_str(attachment_id)?;
+    let attachment = get_attachments_collection(client)
+        .find_one_and_delete(doc! { "_id": object_id })
+        .await?
+        .ok_or(AttachmentServiceError::AttachmentNotFound)?;
+    release_blobs(client, HashMap::from([(attachment.sha256, 1)])).await?;
+    collect_garbage(client, store).await?;
+    Ok(())
+}
+
+/// Removes every attachment of a note or todo and releases their blobs.
+///
+/// Blob contents are only deleted by the next `collect_garbage` run.
+pub(crate) async fn detach_owner(client: &Client, owner: AttachmentOwner, owner_id: ObjectId) -> Result<(), Error> {
+    let collection = get_attachments_collection(client);
+    let filter = doc! { "owner_type": owner_name(owner), "owner_id": owner_id };
+    let attachments: Vec<Attachment> = collection.find(filter.clone()).await?.try_collect().await?;
+    if attachments.is_empty() {
+        return Ok(());
+    }
+
+    collection.delete_many(filter).await?;
+    let mut released: HashMap<String, i64> = HashMap::new();
+    for attachment in attachments {
+        *released.entry(attachment.sha256).or_default() += 1;
+    }
+    release_blobs(client, released).await
+}
+
+/// Deletes blobs that are no longer referenced by any attachment.
+///
+/// Returns the number of blobs removed from the store.
+pub async fn collect_garbage(client: &Client, store: &BlobStore) -> Result<u64, AttachmentServiceError> {
+    let blobs = get_blobs_collection(client);
+    let unreferenced: Vec<Blob> = blobs.find(doc! { "ref_count": { "$lte": 0 } }).await?.try_collect().await?;
+
+    let mut removed = 0;
+    for blob in unreferenced {
+        // Re-check the count atomically in case an upload revived the blob
+        let deleted = blobs
+            .find_one_and_delete(doc! { "_id": &blob.id, "ref_count": { "$lte": 0 } })
+            .await?;
+        let Some(blob) = deleted else {
+            continue;
+        };
+        store.delete(&blob.key).await?;
+        if let Some(thumbnail_key) = &blob.thumbnail_key {
+            store.delete(thumbnail_key).await?;
+        }
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Takes a reference on the blob with the given hash, uploading it first if
+/// it isn't stored yet.
+async fn acquire_blob(
+    client: &Client,
+    store: &BlobStore,
+    sha256: &str,
+    content_type: &str,
+    data: Bytes,
+) -> Result<Blob, AttachmentServiceError> {
+    let blobs = get_blobs_collection(client);
+    let existing = blobs
+        .find_one_and_update(doc! { "_id": sha256 }, doc! { "$inc": { "ref_count": 1 } })
+        .return_document(ReturnDocument::After)
+        .await?;
+    if let Some(blob) = existing {
+        return Ok(blob);
+    }
+
+    // Every upload gets its own keys so a concurrent garbage collection of an
+    // older copy can never delete the bytes written here
+    let generation = ObjectId::new().to_hex();
+    let key = format!("blobs/{}/{}", sha256, generation);
+    let size = data.len() as i64;
+    let thumbnail = if content_type.starts_with("image/") {
+        let image_data = data.clone();
+        web::block(move || make_thumbnail(&image_data)).await.ok().flatten()
+    } else {
+        None
+    };
+
+    store.put(&key, data).await?;
+    let mut thumbnail_key = None;
+    if let Some(thumbnail) = thumbnail {
+        let thumb_key = format!("thumbnails/{}/{}.png", sha256, generation);
+        store.put(&thumb_key, Bytes::from(thumbnail)).await?;
+        thumbnail_key = Some(thumb_key);
+    }
+
+    let update = doc! {
+        "$inc": { "ref_count": 1 },
+        "$setOnInsert": {
+            "key": &key,
+            "thumbnail_key": &thumbnail_key,
+            "content_type": content_type,
+            "size": size,
+            "created_at": Utc::now().to_rfc3339()
+        }
+    };
+    let blob = blobs
+        .find_one_and_update(doc! { "_id": sha256 }, update)
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .await?
+        .ok_or(AttachmentServiceError::AttachmentNotFound)?;
+
+    // Another upload of the same content won the race; drop our copy
+    if blob.key != key {
+        store.delete(&key).await?;
+        if let Some(thumbnail_key) = &thumbnail_key {
+            store.delete(thumbnail_key).await?;
+        }
+    }
+    Ok(blob)
+}
+
+/// Decrements blob reference counts by the given amounts.
+async fn release_blobs(client: &Client, released: HashMap<String, i64>) -> Result<(), Error> {
+    let blobs = get_blobs_collection(client);
+    for (sha256, count) in released {
+        blobs
+            .update_one(doc! { "_id": sha256 }, doc! { "$inc": { "ref_count": -count } })
+            .await?;
+    }
+    Ok(())
+}
+
+/// Determines the content type from the file's magic bytes.
+fn sniff_content_type(data: &[u8]) -> String {
+    match infer::get(data) {
+        Some(kind) => kind.mime_type().to_string(),
+        None if std::str::from_utf8(data).is_ok() => "text/plain".to_string(),
+        None => "application/octet-stream".to_string(),
+    }
+}
+
+/// Renders a PNG thumbnail, or `None` if the image can't be decoded.
+fn make_thumbnail(data: &[u8]) -> Option<Vec<u8>> {
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format().ok()?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    reader.limits(limits);
+
+    let thumbnail = reader.decode().ok()?.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    let mut out = Cursor::new(Vec::new());
+    thumbnail.write_to(&mut out, ImageFormat::Png).ok()?;
+    Some(out.into_inner())
+}
+
+/// Strips path components and control characters from a client-supplied filename.
+fn sanitize_filename(filename: &str) -> String {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control() && *c != '"').take(255).collect();
+    if name.trim().is_empty() {
+        "attachment".to_string()
+    } else {
+        name
+    }
+}
+
+fn owner_name(owner: AttachmentOwner) -> &'static str {
+    match owner {
+        AttachmentOwner::Note => "note",
+        AttachmentOwner::Todo => "todo",
+    }
+}
+
+/// Helper function to get the collection holding the owner documents.
+fn get_owner_collection(client: &Client, owner: AttachmentOwner) -> mongodb::Collection<Document> {
+    let db = client.database("organise");
+    match owner {
+        AttachmentOwner::Note => db.collection::<Document>("notes"),
+        AttachmentOwner::Todo => db.collection::<Document>("todos"),
+    }
+}
+
+/// Helper function to get the "attachments" collection.
+pub(crate) fn get_attachments_collection(client: &Client) -> mongodb::Collection<Attachment> {
+    let db = client.database("organise");
+    db.collection::<Attachment>("attachments")
+}
+
+/// Helper function to get the "blobs" collection.
+fn get_blobs_collection(client: &Client) -> mongodb::Collection<Blob> {
+    let db = client.database("organise");
+    db.collection::<Blob>("blobs")
+}
+
+// Custom function to convert AttachmentServiceError to HttpResponse
+pub fn error_response(error: AttachmentServiceError) -> HttpResponse {
+    match error {
+        AttachmentServiceError::DatabaseError(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+        AttachmentServiceError::InvalidObjectId(e) => HttpResponse::BadRequest().body(format!("Invalid ObjectId: {}", e)),
+        AttachmentServiceError::StorageError(e) => HttpResponse::InternalServerError().body(format!("Storage error: {}", e)),
+        AttachmentServiceError::AttachmentNotFound => HttpResponse::NotFound().body("Attachment not found"),
+        AttachmentServiceError::OwnerNotFound => HttpResponse::NotFound().body("Owner not found"),
+        e @ AttachmentServiceError::TooLarge(_) => HttpResponse::PayloadTooLarge().body(e.to_string()),
+        e @ AttachmentServiceError::UnsupportedType(_) => HttpResponse::UnsupportedMediaType().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::note::Note;
+    use crate::services::notes_service;
+    use image::{ImageBuffer, Rgb};
+    use object_store::memory::InMemory;
+    use std::sync::Arc;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = ImageBuffer::from_pixel(width, height, Rgb([200u8, 80, 40]));
+        let mut out = Cursor::new(Vec::new());
+        image.write_to(&mut out, ImageFormat::Png).unwrap();
+        out.into_inner()
+    }
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for byte in bytes {
+            crc ^= u32::from(*byte);
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            }
+        }
+        !crc
+    }
+
+    /// The start of an RGBA PNG claiming the given size, without pixel data.
+    fn png_header(width: u32, height: u32) -> Vec<u8> {
+        let mut chunk = b"IHDR".to_vec();
+        chunk.extend(width.to_be_bytes());
+        chunk.extend(height.to_be_bytes());
+        chunk.extend([8, 6, 0, 0, 0]);
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend(13u32.to_be_bytes());
+        data.extend(&chunk);
+        data.extend(crc32(&chunk).to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn content_types_come_from_the_bytes() {
+        assert_eq!(sniff_content_type(&png(2, 2)), "image/png");
+        assert_eq!(sniff_content_type(b"%PDF-1.7\n"), "application/pdf");
+        assert_eq!(sniff_content_type("plain text, ünïcode".as_bytes()), "text/plain");
+        assert_eq!(sniff_content_type(&[0xff, 0xfe, 0x00, 0x81]), "application/octet-stream");
+        // HTML is recognised, whatever the file is called, and refused
+        let html = sniff_content_type(b"<html><script>alert(1)</script></html>");
+        assert_eq!(html, "text/html");
+        assert!(!ALLOWED_CONTENT_TYPES.contains(&html.as_str()));
+    }
+
+    #[test]
+    fn thumbnails_fit_the_thumbnail_size() {
+        let thumbnail = make_thumbnail(&png(600, 300)).unwrap();
+        let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (THUMBNAIL_SIZE, THUMBNAIL_SIZE / 2));
+    }
+
+    #[test]
+    fn oversized_images_get_no_thumbnail() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        // Too wide, and within the dimensions but too much memory
+        assert!(make_thumbnail(&png_header(MAX_IMAGE_DIMENSION + 1, 1)).is_none());
+        assert!(make_thumbnail(&png_header(7_000, 7_000)).is_none());
+    }
+
+    #[test]
+    fn filenames_lose_paths_and_control_characters() {
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("C:\\Users\\me\\report\".pdf"), "report.pdf");
+        assert_eq!(sanitize_filename("a\nb\u{0}c.txt"), "abc.txt");
+        assert_eq!(sanitize_filename("/"), "attachment");
+    }
+
+    async fn test_client() -> Client {
+        let uri = std::env::var("MONGODB_TEST_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
+        Client::with_uri_str(uri).await.unwrap()
+    }
+
+    async fn blob(client: &Client, sha256: &str) -> Option<Blob> {
+        get_blobs_collection(client).find_one(doc! { "_id": sha256 }).await.unwrap()
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn identical_uploads_share_a_blob_until_the_last_is_gone() {
+        let client = test_client().await;
+        let store = BlobStore::new(Arc::new(InMemory::new()));
+        let note = notes_service::add_note(&client, Note::new("Attachments".to_string(), "x".to_string()), false)
+            .await
+            .unwrap();
+        let note_id = note.id.unwrap();
+        // Content of its own, so other runs don't share the blob
+        let data = Bytes::from(format!("notes {}", ObjectId::new().to_hex()));
+
+        let first = add_attachment(&client, &store, AttachmentOwner::Note, &note_id.to_hex(), "a.txt".to_string(), data.clone())
+            .await
+            .unwrap();
+        let second = add_attachment(&client, &store, AttachmentOwner::Note, &note_id.to_hex(), "b.txt".to_string(), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(first.content_type, "text/plain");
+        assert_eq!(first.sha256, second.sha256);
+        let stored = blob(&client, &first.sha256).await.unwrap();
+        assert_eq!(stored.ref_count, 2);
+        assert_eq!(store.get(&stored.key).await.unwrap(), data);
+
+        remove_attachment(&client, &store, &first.id.unwrap().to_hex()).await.unwrap();
+        assert_eq!(blob(&client, &first.sha256).await.unwrap().ref_count, 1);
+        assert_eq!(store.get(&stored.key).await.unwrap(), data);
+
+        // Detaching only releases the blob; garbage collection deletes it
+        detach_owner(&client, AttachmentOwner::Note, note_id).await.unwrap();
+        assert!(get_attachments(&client, AttachmentOwner::Note, &note_id.to_hex()).await.unwrap().is_empty());
+        assert_eq!(blob(&client, &first.sha256).await.unwrap().ref_count, 0);
+        assert!(store.get(&stored.key).await.is_ok());
+        assert!(collect_garbage(&client, &store).await.unwrap() >= 1);
+        assert!(blob(&client, &first.sha256).await.is_none());
+        assert!(matches!(store.get(&stored.key).await, Err(object_store::Error::NotFound { .. })));
+
+        notes_service::purge_note(&client, note_id).await.unwrap();
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn images_are_stored_with_a_thumbnail_and_other_types_refused() {
+        let client = test_client().await;
+        let store = BlobStore::new(Arc::new(InMemory::new()));
+        let note = notes_service::add_note(&client, Note::new("Images".to_string(), "x".to_string()), false)
+            .await
+            .unwrap();
+        let note_id = note.id.unwrap().to_hex();
+        // A size of its own, so other runs don't share the blob
+        let width = 300 + (ObjectId::new().timestamp().timestamp_millis() % 1000) as u32;
+
+        let image = add_attachment(&client, &store, AttachmentOwner::Note, &note_id, "p.png".to_string(), Bytes::from(png(width, 10)))
+            .await
+            .unwrap();
+        assert_eq!(image.content_type, "image/png");
+        assert!(image.has_thumbnail);
+        let (_, content_type, thumbnail) = read_attachment(&client, &store, &image.id.unwrap().to_hex(), true).await.unwrap();
+        assert_eq!(content_type, "image/png");
+        assert_eq!(image::load_from_memory(&thumbnail).unwrap().width(), THUMBNAIL_SIZE);
+
+        let binary = Bytes::from_static(&[0xff, 0xfe, 0x00, 0x81]);
+        let refused = add_attachment(&client, &store, AttachmentOwner::Note, &note_id, "x.bin".to_string(), binary).await;
+        assert!(matches!(refused, Err(AttachmentServiceError::UnsupportedType(_))));
+
+        detach_owner(&client, AttachmentOwner::Note, note.id.unwrap()).await.unwrap();
+        collect_garbage(&client, &store).await.unwrap();
+        notes_service::purge_note(&client, note.id.unwrap()).await.unwrap();
+    }
+}
@@ -0,0 +1,427 @@
+//! Bulk operations on todos and notes.
+//!
+//! Operations run in order on one session, inside a transaction when the
+//! deployment supports them (replica sets and sharded clusters). Failures of
+//! individual operations, such as a missing document or a version conflict,
+//! are reported per item and don't stop the others. A database error rolls
+//! back the whole transaction; without a transaction it is reported for its
+//! item like any other failure.
+
+use mongodb::{Client, ClientSession, Collection};
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+use mongodb::error::Error;
+use mongodb::options::{ReturnDocument, UpdateModifications};
+use crate::models::bulk::{BulkItemResult, BulkOperation, BulkReport, NoteBulkAction, TodoBulkAction};
+use crate::models::datetime;
+use crate::services::{notebook_service, notes_service, todo_service};
+use thiserror::Error;
+use actix_web::HttpResponse;
+use chrono::Utc;
+
+/// Largest number of operations accepted in one request.
+pub const MAX_OPERATIONS: usize = 500;
+
+/// Longest tag accepted by `add_tag`.
+const MAX_TAG_LENGTH: usize = 100;
+
+/// Longest project name accepted by `move` on todos.
+const MAX_PROJECT_LENGTH: usize = 100;
+
+#[derive(Error, Debug)]
+pub enum BulkServiceError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] Error),
+    #[error("Expected between 1 and {0} operations")]
+    InvalidOperationCount(usize),
+}
+
+/// Why a single operation failed.
+enum ItemError {
+    InvalidObjectId(String),
+    NotFound(&'static str),
+    VersionMismatch(i64),
+    Invalid(String),
+    Database(Error),
+}
+
+impl From<Error> for ItemError {
+    fn from(error: Error) -> Self {
+        ItemError::Database(error)
+    }
+}
+
+/// An operation on one document, applied within the bulk request's session.
+trait BulkAction {
+    /// Applies the operation and returns the document's new version.
+    async fn apply(
+        &self,
+        client: &Client,
+        session: &mut ClientSession,
+        id: ObjectId,
+        version: Option<i64>,
+    ) -> Result<i64, ItemError>;
+}
+
+impl BulkAction for TodoBulkAction {
+    async fn apply(
+        &self,
+        client: &Client,
+        session: &mut ClientSession,
+        id: ObjectId,
+        version: Option<i64>,
+    ) -> Result<i64, ItemError> {
+        let todos = get_documents(client, "todos");
+        // Todos keep their timestamps as RFC 3339 strings
+        let now = Utc::now().to_rfc3339();
+        let update: UpdateModifications = match self {
+            TodoBulkAction::Complete { completed } => doc! {
+                "$set": { "completed": completed, "updated_at": now },
+                "$inc": { "version": 1 }
+            }
+            .into(),
+            TodoBulkAction::Archive { archived } => doc! {
+                "$set": { "is_archived": archived, "updated_at": now },
+                "$inc": { "version": 1 }
+            }
+            .into(),
+            TodoBulkAction::Move { project } => {
+                let project = project.as_deref().map(str::trim).filter(|project| !project.is_empty());
+                if project.is_some_and(|project| project.chars().count() > MAX_PROJECT_LENGTH) {
+                    return Err(ItemError::Invalid(format!("Project must be at most {} characters", MAX_PROJECT_LENGTH)));
+                }
+                doc! {
+                    "$set": { "project": project, "updated_at": now },
+                    "$inc": { "version": 1 }
+                }
+                .into()
+            }
+            TodoBulkAction::AddTag { tag } => add_tag(tag, now.into())?,
+            TodoBulkAction::RemoveTag { tag } => doc! {
+                "$pull": { "tags": tag.trim() },
+                "$set": { "updated_at": now },
+                "$inc": { "version": 1 }
+            }
+            .into(),
+            TodoBulkAction::Delete => doc! {
+                "$set": { "deleted_at": datetime::to_bson(Utc::now()) },
+                "$inc": { "version": 1 }
+            }
+            .into(),
+        };
+        update_live(&todos, session, id, version, update, "Todo not found").await
+    }
+}
+
+impl BulkAction for NoteBulkAction {
+    async fn apply(
+        &self,
+        client: &Client,
+        session: &mut ClientSession,
+        id: ObjectId,
+        version: Option<i64>,
+    ) -> Result<i64, ItemError> {
+        let notes = get_documents(client, "notes");
+        let now = datetime::to_bson(Utc::now());
+        let update: UpdateModifications = match self {
+            NoteBulkAction::Archive { archived } => doc! {
+                "$set": { "is_archived": archived, "updated_at": now },
+                "$inc": { "version": 1 }
+            }
+            .into(),
+            NoteBulkAction::Move { notebook_id } => {
+                let notebook_id = match notebook_id.as_deref().map(ObjectId::parse_str).transpose() {
+                    Ok(notebook_id) => notebook_id,
+                    Err(e) => return Err(ItemError::InvalidObjectId(e.to_string())),
+                };
+                if let Some(notebook_id) = notebook_id {
+                    let exists = notebook_service::get_notebooks_collection(client)
+                        .count_documents(doc! { "_id": notebook_id })
+                        .session(&mut *session)
+                        .await?;
+                    if exists == 0 {
+                        return Err(ItemError::NotFound("Notebook not found"));
+                    }
+                }
+                // Check the note before shifting its siblings around
+                let note = notes_service::get_notes_collection(client)
+                    .find_one(doc! { "_id": id, "deleted_at": null })
+                    .session(&mut *session)
+                    .await?
+                    .ok_or(ItemError::NotFound("Note not found"))?;
+                if version.is_some_and(|version| version != note.version) {
+                    return Err(ItemError::VersionMismatch(note.version));
+                }
+                let fields = notebook_service::make_room_for_note(client, session, &note, notebook_id, None).await?;
+                doc! { "$set": fields, "$inc": { "version": 1 } }.into()
+            }
+            NoteBulkAction::AddTag { tag } => add_tag(tag, now.into())?,
+            NoteBulkAction::RemoveTag { tag } => doc! {
+                "$pull": { "tags": tag.trim() },
+                "$set": { "updated_at": now },
+                "$inc": { "version": 1 }
+            }
+            .into(),
+            NoteBulkAction::Delete => doc! {
+                "$set": { "deleted_at": now },
+                "$rename": { "daily_date": "trashed_daily_date" },
+                "$inc": { "version": 1 }
+            }
+            .into(),
+        };
+        update_live(&notes, session, id, version, update, "Note not found").await
+    }
+}
+
+/// Update adding a tag to a note or todo, unless it already has it.
+fn add_tag(tag: &str, updated_at: Bson) -> Result<UpdateModifications, ItemError> {
+    let tag = tag.trim();
+    if tag.is_empty() || tag.chars().count() > MAX_TAG_LENGTH {
+        return Err(ItemError::Invalid(format!("Tag must be between 1 and {} characters", MAX_TAG_LENGTH)));
+    }
+    // A pipeline, because `$addToSet` fails on documents whose tags are null
+    let tags = doc! { "$ifNull": ["$tags", []] };
+    Ok(vec![doc! {
+        "$set": {
+            "tags": {
+                "$cond": [{ "$in": [tag, &tags] }, &tags, { "$concatArrays": [&tags, [tag]] }]
+            },
+            "updated_at": updated_at,
+            "version": { "$add": [{ "$ifNull": ["$version", 0] }, 1] }
+        }
+    }]
+    .into())
+}
+
+/// Applies bulk operations to todos.
+///
+/// Completing a todo also ticks its checkbox in the source note, once the
+/// operations have been committed.
+pub async fn bulk_todos(
+    client: &Client,
+    operations: Vec<BulkOperation<TodoBulkAction>>,
+) -> Result<BulkReport, BulkServiceError> {
+    let report = run_bulk(client, &operations).await?;
+
+    let completed = report.results.iter().filter(|result| result.error.is_none()).filter_map(|result| {
+        match operations[result.index].action {
+            TodoBulkAction::Complete { completed } => Some((ObjectId::parse_str(&result.id).ok()?, completed)),
+            _ => None,
+        }
+    });
+    for (todo_id, checked) in completed {
+        // The operations are already committed, so only log failures from here on
+        let note_id = match todo_service::get_todo_collection(client).find_one(doc! { "_id": todo_id }).await {
+            Ok(todo) => todo.and_then(|todo| todo.note_id),
+            Err(e) => {
+                eprintln!("Failed to load todo {}: {}", todo_id, e);
+                None
+            }
+        };
+        if let Some(note_id) = note_id {
+            if let Err(e) = notes_service::set_linked_task_state(client, note_id, todo_id, checked).await {
+                eprintln!("Failed to update the task list of note {}: {}", note_id, e);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Applies bulk operations to notes.
+pub async fn bulk_notes(
+    client: &Client,
+    operations: Vec<BulkOperation<NoteBulkAction>>,
+) -> Result<BulkReport, BulkServiceError> {
+    run_bulk(client, &operations).await
+}
+
+async fn run_bulk<A: BulkAction>(client: &Client, operations: &[BulkOperation<A>]) -> Result<BulkReport, BulkServiceError> {
+    if operations.is_empty() || operations.len() > MAX_OPERATIONS {
+        return Err(BulkServiceError::InvalidOperationCount(MAX_OPERATIONS));
+    }
+
+    let mut session = client.start_session().await?;
+    // Standalone servers don't support transactions; run the operations one by one there
+    let transactional = session.start_transaction().await.is_ok();
+
+    let mut results = Vec::with_capacity(operations.len());
+    for (index, operation) in operations.iter().enumerate() {
+        let outcome = match ObjectId::parse_str(&operation.id) {
+            Ok(id) => operation.action.apply(client, &mut session, id, operation.version).await,
+            Err(e) => Err(ItemError::InvalidObjectId(e.to_string())),
+        };
+        let result = match outcome {
+            Ok(version) => BulkItemResult { index, id: operation.id.clone(), status: 200, error: None, version: Some(version) },
+            Err(ItemError::Database(e)) if transactional => {
+                let _ = session.abort_transaction().await;
+                return Err(e.into());
+            }
+            Err(e) => item_failure(index, operation.id.clone(), e),
+        };
+        results.push(result);
+    }
+    if transactional {
+        session.commit_transaction().await?;
+    }
+
+    let failed = results.iter().filter(|result| result.error.is_some()).count();
+    Ok(BulkReport { transactional, succeeded: results.len() - failed, failed, results })
+}
+
+/// Updates a live document, optionally only at the expected version, and
+/// returns its new version.
+async fn update_live(
+    collection: &Collection<Document>,
+    session: &mut ClientSession,
+    id: ObjectId,
+    version: Option<i64>,
+    update: impl Into<UpdateModifications>,
+    not_found: &'static str,
+) -> Result<i64, ItemError> {
+    let mut filter = doc! { "_id": id, "deleted_at": null };
+    if let Some(version) = version {
+        filter.insert("version", version);
+    }
+    let updated = collection
+        .find_one_and_update(filter, update)
+        .return_document(ReturnDocument::After)
+        .projection(doc! { "version": 1 })
+        .session(&mut *session)
+        .await?;
+    if let Some(updated) = updated {
+        return Ok(document_version(&updated));
+    }
+
+    // Nothing matched: either the document is gone or it is at another version
+    let current = collection
+        .find_one(doc! { "_id": id, "deleted_at": null })
+        .projection(doc! { "version": 1 })
+        .session(&mut *session)
+        .await?;
+    match current {
+        Some(current) => Err(ItemError::VersionMismatch(document_version(&current))),
+        None => Err(ItemError::NotFound(not_found)),
+    }
+}
+
+/// Reads `version`, which is an `Int32` on documents only ever updated by
+/// `$inc` from a 32-bit value.
+fn document_version(document: &Document) -> i64 {
+    match document.get("version") {
+        Some(Bson::Int64(version)) => *version,
+        Some(Bson::Int32(version)) => i64::from(*version),
+        _ => 0,
+    }
+}
+
+fn item_failure(index: usize, id: String, error: ItemError) -> BulkItemResult {
+    let (status, error, version) = match error {
+        ItemError::InvalidObjectId(e) => (400, format!("Invalid ObjectId: {}", e), None),
+        ItemError::NotFound(message) => (404, message.to_string(), None),
+        ItemError::VersionMismatch(current) => (412, "Document has been modified".to_string(), Some(current)),
+        ItemError::Invalid(message) => (400, message, None),
+        ItemError::Database(e) => (500, format!("Database error: {}", e), None),
+    };
+    BulkItemResult { index, id, status, error: Some(error), version }
+}
+
+/// Helper function to get a collection as raw documents.
+fn get_documents(client: &Client, name: &str) -> Collection<Document> {
+    client.database("organise").collection::<Document>(name)
+}
+
+// Custom function to convert BulkServiceError to HttpResponse
+pub fn error_response(error: BulkServiceError) -> HttpResponse {
+    match error {
+        BulkServiceError::DatabaseError(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+        e @ BulkServiceError::InvalidOperationCount(_) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::bulk::BulkRequest;
+
+    #[test]
+    fn todo_operations_deserialize() {
+        let request: BulkRequest<TodoBulkAction> = serde_json::from_str(r#"{"operations": [
+            {"id": "a", "op": "move", "project": "Home"},
+            {"id": "b", "op": "move", "project": null},
+            {"id": "c", "op": "add_tag", "tag": "urgent"},
+            {"id": "d", "op": "archive"}
+        ]}"#).unwrap();
+        let actions: Vec<_> = request.operations.into_iter().map(|operation| operation.action).collect();
+        assert!(matches!(&actions[0], TodoBulkAction::Move { project: Some(project) } if project == "Home"));
+        assert!(matches!(&actions[1], TodoBulkAction::Move { project: None }));
+        assert!(matches!(&actions[2], TodoBulkAction::AddTag { tag } if tag == "urgent"));
+        assert!(matches!(&actions[3], TodoBulkAction::Archive { archived: true }));
+    }
+
+    #[test]
+    fn add_tag_rejects_blank_and_long_tags() {
+        assert!(matches!(add_tag("  ", Bson::Null), Err(ItemError::Invalid(_))));
+        assert!(matches!(add_tag(&"x".repeat(MAX_TAG_LENGTH + 1), Bson::Null), Err(ItemError::Invalid(_))));
+        assert!(add_tag(" work ", Bson::Null).is_ok());
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn moving_notes_keeps_both_notebooks_in_order() {
+        use crate::models::note::Note;
+        use crate::models::notebook::Notebook;
+        use futures_util::TryStreamExt;
+
+        let uri = std::env::var("MONGODB_TEST_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let mut notebook_ids = Vec::new();
+        for name in ["Source", "Target"] {
+            let notebook = Notebook::new(format!("test-{}-{}", name, ObjectId::new().to_hex()), None);
+            notebook_ids.push(notebook_service::add_notebook(&client, notebook).await.unwrap().id.unwrap());
+        }
+        let (source, target) = (notebook_ids[0], notebook_ids[1]);
+        let mut notes = Vec::new();
+        for (title, notebook_id) in [("A", source), ("B", source), ("C", source), ("T", target)] {
+            let mut note = Note::new(title.to_string(), String::new());
+            note.notebook_id = Some(notebook_id);
+            notes.push(notes_service::add_note(&client, note, false).await.unwrap());
+        }
+        let moves = |version| {
+            vec![BulkOperation {
+                id: notes[1].id.unwrap().to_hex(),
+                version: Some(version),
+                action: NoteBulkAction::Move { notebook_id: Some(target.to_hex()) },
+            }]
+        };
+        let order = |notebook_id: ObjectId| {
+            let client = client.clone();
+            async move {
+                let cursor = notes_service::get_notes_collection(&client)
+                    .find(doc! { "notebook_id": notebook_id })
+                    .sort(doc! { "position": 1 })
+                    .await
+                    .unwrap();
+                let notes: Vec<Note> = cursor.try_collect().await.unwrap();
+                notes.into_iter().map(|note| (note.title, note.position.unwrap())).collect::<Vec<_>>()
+            }
+        };
+
+        // A stale version fails without shifting any siblings
+        let report = bulk_notes(&client, moves(notes[1].version + 1)).await.unwrap();
+        assert_eq!(report.results[0].status, 412);
+        assert_eq!(order(source).await, [("A".to_string(), 0), ("B".to_string(), 1), ("C".to_string(), 2)]);
+
+        let report = bulk_notes(&client, moves(notes[1].version)).await.unwrap();
+        assert_eq!(report.succeeded, 1);
+        assert_eq!(order(source).await, [("A".to_string(), 0), ("C".to_string(), 1)]);
+        assert_eq!(order(target).await, [("T".to_string(), 0), ("B".to_string(), 1)]);
+
+        notes_service::get_notes_collection(&client)
+            .delete_many(doc! { "notebook_id": { "$in": &notebook_ids } })
+            .await
+            .unwrap();
+        notebook_service::get_notebooks_collection(&client)
+            .delete_many(doc! { "_id": { "$in": &notebook_ids } })
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+//! CalDAV (RFC 4791) access to calendar events, for desktop and mobile
+//! calendar apps.
+//!
+//! Each calendar is a collection of resources, one per series or single event
+//! with the exceptions to it. A sync token is the number of a calendar's last
+//! change, as recorded by `calendar_change_service`, so the series changed or
+//! trashed since a token are those stamped with a higher number. Tokens from
+//! before a purged series' last change are refused, since it can no longer be
+//! reported as removed.
+
+use mongodb::{Client, bson::{doc, oid::ObjectId, Bson, Document}};
+use mongodb::error::Error;
+use futures_util::TryStreamExt;
+use crate::dto::caldav::error_body;
+use crate::models::caldav::{event_href, EventResource, Precondition, ReportError, SyncToken, CALDAV_NS, DAV_NS};
+use crate::models::calendar::{CalendarEvent, EditScope, EventRange, LOCAL_CALENDAR_ID};
+use crate::services::calendar_service::{self, CalendarServiceError};
+use crate::services::calendar_change_service::{self, CHANGE_FIELD};
+use crate::services::ical_service;
+use thiserror::Error;
+use actix_web::HttpResponse;
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// How far a `calendar-query` time range open at one end reaches.
+const OPEN_RANGE_DAYS: i64 = 100 * 366;
+
+#[derive(Error, Debug)]
+pub enum CalDavServiceError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] Error),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Resource not found")]
+    NotFound,
+    #[error("Precondition failed")]
+    PreconditionFailed,
+    #[error("Unsupported report: {0}")]
+    UnsupportedReport(String),
+    #[error("Unsupported filter: {0}")]
+    UnsupportedFilter(String),
+    #[error("Invalid calendar data: {0}")]
+    InvalidCalendarData(String),
+    #[error("Invalid calendar object: {0}")]
+    InvalidCalendarObject(String),
+    #[error("Another resource has the same UID: {0}")]
+    UidConflict(String),
+    #[error("Invalid sync token")]
+    InvalidSyncToken,
+    #[error(transparent)]
+    CalendarError(#[from] CalendarServiceError),
+}
+
+impl From<ReportError> for CalDavServiceError {
+    fn from(error: ReportError) -> Self {
+        match error {
+            ReportError::Invalid(e) => CalDavServiceError::InvalidRequest(e),
+            ReportError::UnsupportedReport(report) => CalDavServiceError::UnsupportedReport(report),
+            ReportError::UnsupportedFilter(filter) => CalDavServiceError::UnsupportedFilter(filter),
+        }
+    }
+}
+
+/// What changed in a calendar since a sync token.
+pub struct SyncChanges {
+    pub token: SyncToken,
+    pub changed: Vec<EventResource>,
+    /// Names of the removed resources.
+    pub removed: Vec<String>,
+}
+
+/// Lists the calendars: the local one, and every other calendar with events.
+pub async fn get_calendar_ids(client: &Client) -> Result<Vec<String>, CalDavServiceError> {
+    let ids = calendar_service::get_calendar_collection(client)
+        .distinct("calendar_id", doc! { "deleted_at": null })
+        .await?;
+    let mut calendar_ids = vec![LOCAL_CALENDAR_ID.to_string()];
+    calendar_ids.extend(
+        ids.into_iter()
+            .filter_map(|id| match id {
+                Bson::String(id) => Some(id),
+                _ => None,
+            })
+            .filter(|id| id != LOCAL_CALENDAR_ID),
+    );
+    Ok(calendar_ids)
+}
+
+/// The sync token for the current state of a calendar.
+pub async fn get_sync_token(client: &Client, calendar_id: &str) -> Result<SyncToken, CalDavServiceError> {
+    let (_, latest) = calendar_change_service::latest_change(client, calendar_id).await?;
+    Ok(SyncToken(latest))
+}
+
+/// Lists the resources of a calendar.
+pub async fn get_resources(client: &Client, calendar_id: &str) -> Result<Vec<EventResource>, CalDavServiceError> {
+    let filter = doc! {
+        "calendar_id": calendar_service::calendar_filter(&[calendar_id.to_string()]),
+        "recurring_event_id": null,
+        "deleted_at": null
+    };
+    resources_of(client, filter).await
+}
+
+/// Lists the resources of a calendar with an occurrence in a time range, or
+/// every resource without one.
+pub async fn query_resources(
+    client: &Client,
+    calendar_id: &str,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+) -> Result<Vec<EventResource>, CalDavServiceError> {
+    let (start, end) = match (start, end) {
+        (None, None) => return get_resources(client, calendar_id).await,
+        (Some(start), None) => (start, start + Duration::days(OPEN_RANGE_DAYS)),
+        (None, Some(end)) => (end - Duration::days(OPEN_RANGE_DAYS), end),
+        (Some(start), Some(end)) => (start, end),
+    };
+    let range = EventRange { start, end, calendar_ids: vec![calendar_id.to_string()] };
+    let events = calendar_service::get_series_in_range(client, &range).await?;
+    Ok(group(events))
+}
+
+/// Retrieves a resource by its name.
+pub async fn get_resource(client: &Client, calendar_id: &str, name: &str) -> Result<EventResource, CalDavServiceError> {
+    find_resource(client, calendar_id, name).await?.ok_or(CalDavServiceError::NotFound)
+}
+
+/// Stores the `VEVENT`s of an iCalendar object as a resource, replacing the
+/// events it held before. Returns whether the resource was created.
+pub async fn put_resource(
+    client: &Client,
+    calendar_id: &str,
+    name: &str,
+    body: &str,
+    precondition: &Precondition,
+) -> Result<bool, CalDavServiceError> {
+    let parsed = ical_service::parse_calendar(body).map_err(CalDavServiceError::InvalidCalendarData)?;
+    if let Some(skipped) = parsed.skipped.first() {
+        return Err(CalDavServiceError::InvalidCalendarObject(skipped.reason.clone()));
+    }
+    let Some(uid) = parsed.events.first().map(|parsed| parsed.uid.clone()) else {
+        return Err(CalDavServiceError::InvalidCalendarObject("No VEVENT found".to_string()));
+    };
+    if parsed.events.iter().any(|parsed| parsed.uid != uid) {
+        return Err(CalDavServiceError::InvalidCalendarObject("Every VEVENT must have the same UID".to_string()));
+    }
+    let (mut series, exceptions): (Vec<_>, Vec<_>) =
+        parsed.events.into_iter().partition(|parsed| parsed.recurrence_id.is_none());
+    let (Some(series), true) = (series.pop(), series.is_empty()) else {
+        return Err(CalDavServiceError::InvalidCalendarObject(
+            "Exactly one VEVENT must be without a RECURRENCE-ID".to_string(),
+        ));
+    };
+    let mut event = series.event;
+    if !exceptions.is_empty() && !event.is_recurring() {
+        return Err(CalDavServiceError::InvalidCalendarObject("Only recurring events can have exceptions".to_string()));
+    }
+
+    let existing = find_resource(client, calendar_id, name).await?;
+    if !precondition.allows(existing.as_ref().map(|resource| resource.etag.as_str())) {
+        return Err(CalDavServiceError::PreconditionFailed);
+    }
+    let existing = existing.and_then(|resource| resource.events.into_iter().next());
+    if existing.as_ref().is_some_and(|existing| ical_service::uid(existing) != uid) {
+        return Err(CalDavServiceError::InvalidCalendarObject("The UID of a resource can't change".to_string()));
+    }
+    if let Some(other) = calendar_service::find_by_uid(client, calendar_id, &uid).await? {
+        if existing.as_ref().is_none_or(|existing| existing.id != other.id) {
+            return Err(CalDavServiceError::UidConflict(event_href(calendar_id, &resource_name(&other))));
+        }
+    }
+
+    event.calendar_id = Some(calendar_id.to_string()).filter(|id| id != LOCAL_CALENDAR_ID);
+    event.dav_name = Some(name.to_string());
+    calendar_service::save_series(client, existing.as_ref(), event, exceptions).await?;
+    Ok(existing.is_none())
+}
+
+/// Moves the events of a resource to the trash.
+pub async fn delete_resource(
+    client: &Client,
+    calendar_id: &str,
+    name: &str,
+    precondition: &Precondition,
+) -> Result<(), CalDavServiceError> {
+    let resource = get_resource(client, calendar_id, name).await?;
+    if !precondition.allows(Some(&resource.etag)) {
+        return Err(CalDavServiceError::PreconditionFailed);
+    }
+    let series_id = resource.events.first().and_then(|event| event.id).unwrap_or_default();
+    calendar_service::remove_event(client, &series_id.to_hex(), EditScope::All).await?;
+    Ok(())
+}
+
+/// Lists what changed in a calendar since a sync token, or every resource
+/// for the initial sync without one.
+pub async fn get_changes(client: &Client, calendar_id: &str, token: Option<&str>) -> Result<SyncChanges, CalDavServiceError> {
+    // Taken first, so that changes made while this runs are reported again next time
+    let (counter, latest) = calendar_change_service::latest_change(client, calendar_id).await?;
+    let current = SyncToken(latest);
+    let Some(token) = token else {
+        return Ok(SyncChanges { token: current, changed: get_resources(client, calendar_id).await?, removed: Vec::new() });
+    };
+    let since: SyncToken = token.parse().map_err(|_| CalDavServiceError::InvalidSyncToken)?;
+    // Series purged since the token can no longer be reported as removed
+    if since.0 > counter.last || since.0 < counter.purged {
+        return Err(CalDavServiceError::InvalidSyncToken);
+    }
+
+    let filter = doc! {
+        "calendar_id": calendar_service::calendar_filter(&[calendar_id.to_string()]),
+        "recurring_event_id": null,
+        CHANGE_FIELD: { "$gt": since.0 }
+    };
+    let changed_series: Vec<CalendarEvent> =
+        calendar_service::get_calendar_collection(client).find(filter).await?.try_collect().await?;
+    let (removed, live): (Vec<_>, Vec<_>) = changed_series.into_iter().partition(|series| series.deleted_at.is_some());
+    let series_ids: Vec<ObjectId> = live.iter().filter_map(|series| series.id).collect();
+    let changed = resources_of(client, doc! { "_id": { "$in": series_ids }, "deleted_at": null }).await?;
+    let removed = removed.iter().map(resource_name).collect();
+    Ok(SyncChanges { token: current, changed, removed })
+}
+
+/// The resources of the series and single events matching `filter`.
+async fn resources_of(client: &Client, filter: Document) -> Result<Vec<EventResource>, CalDavServiceError> {
+    let collection = calendar_service::get_calendar_collection(client);
+    let mut events: Vec<CalendarEvent> = collection.find(filter).await?.try_collect().await?;
+    let series_ids: Vec<ObjectId> = events.iter().filter(|event| event.is_recurring()).filter_map(|event| event.id).collect();
+    if !series_ids.is_empty() {
+        let exceptions: Vec<CalendarEvent> = collection
+            .find(doc! { "recurring_event_id": { "$in": series_ids }, "deleted_at": null })
+            .await?
+            .try_collect()
+            .await?;
+        events.extend(exceptions);
+    }
+    Ok(group(events))
+}
+
+async fn find_resource(client: &Client, calendar_id: &str, name: &str) -> Result<Option<EventResource>, CalDavServiceError> {
+    // Events created in the app are named after their id
+    let mut names = vec![doc! { "dav_name": name }];
+    if let Some(event_id) = name.strip_suffix(".ics").and_then(|id| ObjectId::parse_str(id).ok()) {
+        names.push(doc! { "_id": event_id, "dav_name": null });
+    }
+    let filter = doc! {
+        "calendar_id": calendar_service::calendar_filter(&[calendar_id.to_string()]),
+        "recurring_event_id": null,
+        "deleted_at": null,
+        "$or": names
+    };
+    Ok(resources_of(client, filter).await?.pop())
+}
+
+/// Groups series and single events with the exceptions to them into resources.
+fn group(events: Vec<CalendarEvent>) -> Vec<EventResource> {
+    let (exceptions, series): (Vec<_>, Vec<_>) = events.into_iter().partition(|event| event.is_exception());
+    let mut resources: BTreeMap<ObjectId, Vec<CalendarEvent>> =
+        series.into_iter().filter_map(|event| Some((event.id?, vec![event]))).collect();
+    for exception in exceptions {
+        if let Some(events) = exception.recurring_event_id.and_then(|id| resources.get_mut(&id)) {
+            events.push(exception);
+        }
+    }
+    resources.into_values().map(to_resource).collect()
+}
+
+fn to_resource(events: Vec<CalendarEvent>) -> EventResource {
+    let body = ical_service::write_object(&events);
+    EventResource {
+        name: events.first().map(resource_name).unwrap_or_default(),
+        etag: hex::encode(&Sha256::digest(body.as_bytes())[..16]),
+        last_modified: events.iter().map(|event| event.updated_at).max().unwrap_or_default(),
+        events,
+        body,
+    }
+}
+
+fn resource_name(event: &CalendarEvent) -> String {
+    match &event.dav_name {
+        Some(name) => name.clone(),
+        None => format!("{}.ics", event.id.unwrap_or_default().to_hex()),
+    }
+}
+
+// Custom function to convert CalDavServiceError to HttpResponse
+pub fn error_response(error: CalDavServiceError) -> HttpResponse {
+    let forbidden = |namespace: &str, condition: &str, conflict: Option<&str>| {
+        HttpResponse::Forbidden()
+            .content_type("application/xml; charset=utf-8")
+            .body(error_body(namespace, condition, conflict))
+    };
+    match error {
+        CalDavServiceError::DatabaseError(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+        e @ CalDavServiceError::InvalidRequest(_) => HttpResponse::BadRequest().body(e.to_string()),
+        CalDavServiceError::NotFound => HttpResponse::NotFound().body("Resource not found"),
+        CalDavServiceError::PreconditionFailed => HttpResponse::PreconditionFailed().body("Precondition failed"),
+        CalDavServiceError::UnsupportedReport(_) => forbidden(DAV_NS, "supported-report", None),
+        CalDavServiceError::UnsupportedFilter(_) => forbidden(CALDAV_NS, "supported-filter", None),
+        CalDavServiceError::InvalidCalendarData(_) => forbidden(CALDAV_NS, "valid-calendar-data", None),
+        CalDavServiceError::InvalidCalendarObject(_) => forbidden(CALDAV_NS, "valid-calendar-object-resource", None),
+        CalDavServiceError::UidConflict(href) => forbidden(CALDAV_NS, "no-uid-conflict", Some(&href)),
+        CalDavServiceError::InvalidSyncToken => forbidden(DAV_NS, "valid-sync-token", None),
+        CalDavServiceError::CalendarError(e) => calendar_service::error_response(e),
+    }
+}
@@ -0,0 +1,105 @@
+//! Numbers the changes to calendar events, for CalDAV sync tokens.
+//!
+//! Every write to a series or single event, or to the exceptions to it, ends
+//! with `record_changes`, which stamps the series with the next number of a
+//! counter shared by all calendars. A calendar's sync token is the highest
+//! number among its series, so the changes since a token are the series
+//! stamped with a higher one, whether live or trashed.
+//!
+//! Numbers are handed out and read under one lock, so a token never covers a
+//! number whose stamp isn't written yet. The lock is held in process, which
+//! assumes a single server writes to the database, as collaborative editing
+//! already does.
+
+use futures_util::lock::Mutex as AsyncMutex;
+use mongodb::{Client, Collection, bson::{doc, oid::ObjectId, Document}};
+use mongodb::error::Error;
+use mongodb::options::ReturnDocument;
+use crate::services::calendar_service;
+
+/// Field of a series holding the number of its last change.
+pub const CHANGE_FIELD: &str = "change";
+
+/// `_id` of the counter document in the "counters" collection.
+const COUNTER_ID: &str = "calendar_changes";
+
+/// Serialises handing out change numbers with reading the latest one.
+static CHANGES: AsyncMutex<()> = AsyncMutex::new(());
+
+/// Where the change numbering stands.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChangeCounter {
+    /// Number of the latest change in any calendar.
+    pub last: i64,
+    /// Highest number among the purged series. Changes up to it may no longer
+    /// be found, so tokens below it can't be served.
+    pub purged: i64,
+}
+
+/// Records that series or single events changed, or that exceptions to them
+/// did, by stamping them with the next change number.
+pub async fn record_changes(client: &Client, series_ids: &[ObjectId]) -> Result<(), Error> {
+    if series_ids.is_empty() {
+        return Ok(());
+    }
+    let _changes = CHANGES.lock().await;
+    let counter = get_counters_collection(client)
+        .find_one_and_update(doc! { "_id": COUNTER_ID }, doc! { "$inc": { "last": 1_i64 } })
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .await?
+        .unwrap_or_default();
+    let number = counter.get_i64("last").unwrap_or_default();
+    calendar_service::get_calendar_collection(client)
+        .update_many(doc! { "_id": { "$in": series_ids } }, doc! { "$set": { CHANGE_FIELD: number } })
+        .await?;
+    Ok(())
+}
+
+/// Notes that series are about to be purged, so that tokens from before
+/// their last change are refused from now on.
+pub async fn record_purge(client: &Client, series_ids: &[ObjectId]) -> Result<(), Error> {
+    let last = calendar_service::get_calendar_collection(client)
+        .clone_with_type::<Document>()
+        .find_one(doc! { "_id": { "$in": series_ids }, CHANGE_FIELD: { "$exists": true } })
+        .sort(doc! { CHANGE_FIELD: -1 })
+        .await?
+        .and_then(|series| series.get_i64(CHANGE_FIELD).ok());
+    if let Some(last) = last {
+        let _changes = CHANGES.lock().await;
+        get_counters_collection(client)
+            .update_one(doc! { "_id": COUNTER_ID }, doc! { "$max": { "purged": last } })
+            .upsert(true)
+            .await?;
+    }
+    Ok(())
+}
+
+/// The counter, and the number of the latest change to a calendar; 0 when
+/// it has none.
+pub async fn latest_change(client: &Client, calendar_id: &str) -> Result<(ChangeCounter, i64), Error> {
+    let _changes = CHANGES.lock().await;
+    let counter = get_counters_collection(client).find_one(doc! { "_id": COUNTER_ID }).await?.unwrap_or_default();
+    let counter = ChangeCounter {
+        last: counter.get_i64("last").unwrap_or_default(),
+        purged: counter.get_i64("purged").unwrap_or_default(),
+    };
+    let latest = calendar_service::get_calendar_collection(client)
+        .clone_with_type::<Document>()
+        .find_one(doc! {
+            "calendar_id": calendar_service::calendar_filter(&[calendar_id.to_string()]),
+            "recurring_event_id": null,
+            CHANGE_FIELD: { "$exists": true }
+        })
+        .sort(doc! { CHANGE_FIELD: -1 })
+        .projection(doc! { CHANGE_FIELD: 1 })
+        .await?
+        .and_then(|series| series.get_i64(CHANGE_FIELD).ok());
+    Ok((counter, latest.unwrap_or_default()))
+}
+
+/// Helper function to get the "counters" collection.
+fn get_counters_collection(client: &Client) -> Collection<Document> {
+    let db = client.database("organise");
+    db.collection::<Document>("counters")
+}
@@ -0,0 +1,164 @@
+//! Secret iCalendar feed links, for subscribing to a calendar from phone and
+//! desktop calendar apps.
+//!
+//! Each feed has a random token that is part of its URL; only a hash of the
+//! token is stored. Regenerating the token or deleting the feed revokes every
+//! copy of the old link.
+
+use mongodb::{Client, Collection, bson::{doc, oid::ObjectId}};
+use mongodb::error::Error;
+use mongodb::options::ReturnDocument;
+use futures_util::TryStreamExt;
+use crate::models::calendar::{EventRange, LOCAL_CALENDAR_ID};
+use crate::models::calendar_feed::{CalendarFeed, CalendarFeedSchema};
+use crate::models::datetime;
+use crate::services::calendar_service::{self, CalendarServiceError};
+use crate::services::ical_service;
+use thiserror::Error;
+use actix_web::HttpResponse;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// How far back feeds reach; older events drop out of subscribed calendars.
+const PAST_DAYS: i64 = 30;
+
+/// How far ahead feeds reach.
+const FUTURE_DAYS: i64 = 365;
+
+/// Random bytes in a feed token.
+const TOKEN_BYTES: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum CalendarFeedServiceError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] Error),
+    #[error("Invalid ObjectId: {0}")]
+    InvalidObjectId(#[from] mongodb::bson::oid::Error),
+    #[error("Feed not found")]
+    FeedNotFound,
+    #[error(transparent)]
+    CalendarError(#[from] CalendarServiceError),
+}
+
+/// A feed's iCalendar body, and what clients need to poll it cheaply.
+pub struct FeedContent {
+    pub body: String,
+    /// Hash of the body.
+    pub etag: String,
+    /// When events in the feed's calendars last changed.
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+/// Lists the feeds, oldest first. Their tokens can't be shown again.
+pub async fn get_feeds(client: &Client) -> Result<Vec<CalendarFeed>, CalendarFeedServiceError> {
+    let feeds = get_feeds_collection(client)
+        .find(doc! {})
+        .sort(doc! { "created_at": 1 })
+        .await?
+        .try_collect()
+        .await?;
+    Ok(feeds)
+}
+
+/// Retrieves a feed by its id.
+pub async fn get_feed(client: &Client, feed_id: &str) -> Result<CalendarFeed, CalendarFeedServiceError> {
+    let object_id = ObjectId::parse_str(feed_id)?;
+    get_feeds_collection(client)
+        .find_one(doc! { "_id": object_id })
+        .await?
+        .ok_or(CalendarFeedServiceError::FeedNotFound)
+}
+
+/// Creates a feed and returns it with its token.
+pub async fn create_feed(client: &Client, schema: CalendarFeedSchema) -> Result<(CalendarFeed, String), CalendarFeedServiceError> {
+    let token = new_token();
+    let now = Utc::now();
+    let feed = CalendarFeed {
+        id: Some(ObjectId::new()),
+        calendar_id: schema.calendar_id.filter(|id| !id.trim().is_empty()),
+        token_hash: hash_token(&token),
+        created_at: now,
+        token_issued_at: now,
+    };
+    get_feeds_collection(client).insert_one(&feed).await?;
+    Ok((feed, token))
+}
+
+/// Replaces a feed's token, so that links with the old one stop working.
+pub async fn regenerate_token(client: &Client, feed_id: &str) -> Result<(CalendarFeed, String), CalendarFeedServiceError> {
+    let object_id = ObjectId::parse_str(feed_id)?;
+    let token = new_token();
+    let update = doc! {
+        "$set": { "token_hash": hash_token(&token), "token_issued_at": datetime::to_bson(Utc::now()) }
+    };
+    let feed = get_feeds_collection(client)
+        .find_one_and_update(doc! { "_id": object_id }, update)
+        .return_document(ReturnDocument::After)
+        .await?
+        .ok_or(CalendarFeedServiceError::FeedNotFound)?;
+    Ok((feed, token))
+}
+
+/// Deletes a feed, revoking its link.
+pub async fn delete_feed(client: &Client, feed_id: &str) -> Result<(), CalendarFeedServiceError> {
+    let object_id = ObjectId::parse_str(feed_id)?;
+    let result = get_feeds_collection(client).delete_one(doc! { "_id": object_id }).await?;
+    if result.deleted_count == 0 {
+        return Err(CalendarFeedServiceError::FeedNotFound);
+    }
+    Ok(())
+}
+
+/// Renders the feed with the given token: the events with an occurrence in
+/// the last `PAST_DAYS` or the next `FUTURE_DAYS`, whole series included.
+pub async fn render_feed(client: &Client, token: &str) -> Result<FeedContent, CalendarFeedServiceError> {
+    let feed = get_feeds_collection(client)
+        .find_one(doc! { "token_hash": hash_token(token) })
+        .await?
+        .ok_or(CalendarFeedServiceError::FeedNotFound)?;
+
+    let now = Utc::now();
+    let range = EventRange {
+        start: now - Duration::days(PAST_DAYS),
+        end: now + Duration::days(FUTURE_DAYS),
+        calendar_ids: feed.calendar_id.iter().cloned().collect(),
+    };
+    let events = calendar_service::get_series_in_range(client, &range).await?;
+    let last_modified = calendar_service::last_changed(client, &range.calendar_ids).await?;
+    let name = match feed.calendar_id.as_deref() {
+        None | Some(LOCAL_CALENDAR_ID) => "Organise".to_string(),
+        Some(calendar_id) => format!("Organise ({})", calendar_id),
+    };
+    let body = ical_service::write_calendar(&name, &events);
+    let etag = hex::encode(&Sha256::digest(body.as_bytes())[..16]);
+    Ok(FeedContent { body, etag, last_modified })
+}
+
+/// A URL-safe random token.
+fn new_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Helper function to get the "calendar_feeds" collection.
+fn get_feeds_collection(client: &Client) -> Collection<CalendarFeed> {
+    client.database("organise").collection::<CalendarFeed>("calendar_feeds")
+}
+
+// Custom function to convert CalendarFeedServiceError to HttpResponse
+pub fn error_response(error: CalendarFeedServiceError) -> HttpResponse {
+    match error {
+        CalendarFeedServiceError::DatabaseError(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+        CalendarFeedServiceError::InvalidObjectId(e) => HttpResponse::BadRequest().body(format!("Invalid ObjectId: {}", e)),
+        CalendarFeedServiceError::FeedNotFound => HttpResponse::NotFound().body("Feed not found"),
+        CalendarFeedServiceError::CalendarError(e) => calendar_service::error_response(e),
+    }
+}
@@ -0,0 +1,581 @@
+//! OAuth 2.0 connections to external calendar accounts.
+//!
+//! Accounts are connected with the authorization code flow. The user is sent
+//! to the provider with a random `state` and a PKCE challenge, and the
+//! callback trades the code for tokens. The state is also set in a cookie,
+//! so a callback is only accepted in the browser that started it. Each user
+//! connects their own accounts. Tokens are stored encrypted with AES-256-GCM
+//! under `TOKEN_ENCRYPTION_KEY`, and access tokens are refreshed shortly
+//! before they expire.
+
+use actix_web::cookie::{self, Cookie, SameSite};
+use actix_web::HttpResponse;
+use aes_gcm::aead::Aead;
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::doc;
+use mongodb::error::Error;
+use mongodb::{Client, Collection};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use std::env;
+use thiserror::Error;
+use crate::models::calendar_connection::{
+    CalendarConnection, OAuthCallbackQuery, OAuthErrorResponse, OAuthState, OAuthTokenResponse,
+};
+use crate::models::datetime;
+use crate::services::calendar_sync_service::http_client;
+
+/// Cookie holding the `state` of the authorization in progress.
+pub const STATE_COOKIE: &str = "oauth_state";
+
+/// Random bytes in a `state`.
+const STATE_BYTES: usize = 32;
+
+/// Random bytes in a PKCE verifier, which makes a 43 character verifier.
+const VERIFIER_BYTES: usize = 32;
+
+/// How long the user has to grant access.
+const STATE_TTL_MINUTES: i64 = 10;
+
+/// Access tokens are refreshed once they expire within this many seconds.
+const REFRESH_MARGIN_SECONDS: i64 = 60;
+
+/// Lifetime assumed for access tokens issued without `expires_in`.
+const DEFAULT_EXPIRES_IN_SECONDS: i64 = 3600;
+
+/// Bytes in an AES-GCM nonce.
+const NONCE_BYTES: usize = 12;
+
+#[derive(Error, Debug)]
+pub enum CalendarOAuthServiceError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] Error),
+    #[error("Calendar connections aren't configured: {0}")]
+    NotConfigured(String),
+    #[error("Invalid callback: {0}")]
+    InvalidCallback(String),
+    #[error("Unknown or expired authorization state")]
+    InvalidState,
+    #[error("Access was denied: {0}")]
+    AccessDenied(String),
+    #[error("Token request failed: {0}")]
+    TokenError(String),
+    /// The provider no longer accepts the grant, so the account must be
+    /// connected again.
+    #[error("Authorization expired or was revoked; connect the account again: {0}")]
+    Reauthorize(String),
+    #[error("No {0} account is connected")]
+    NotConnected(String),
+    #[error("Token encryption error: {0}")]
+    EncryptionError(String),
+}
+
+/// An OAuth client registered with a calendar provider.
+#[derive(Debug, Clone)]
+pub struct OAuthClient {
+    /// Provider the connection is stored under, e.g. `google`.
+    pub provider: &'static str,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Callback URL registered with the provider.
+    pub redirect_uri: String,
+    pub authorization_url: String,
+    pub token_url: String,
+    /// Endpoint tokens are revoked at on disconnect, if the provider has one.
+    pub revocation_url: Option<String>,
+    pub scope: &'static str,
+    /// Extra parameters of the authorization request.
+    pub authorization_params: &'static [(&'static str, &'static str)],
+}
+
+/// A required setting of an OAuth client.
+pub fn required_var(name: &str) -> Result<String, CalendarOAuthServiceError> {
+    env::var(name)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .ok_or_else(|| CalendarOAuthServiceError::NotConfigured(format!("{} must be set", name)))
+}
+
+/// An endpoint URL that may be overridden, e.g. with a local stub.
+pub fn url_var(name: &str, default: &str) -> String {
+    env::var(name)
+        .ok()
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| default.to_string())
+}
+
+/// Where to send the user to grant access, and the `state` to remember in
+/// their browser.
+#[derive(Debug)]
+pub struct AuthorizationRequest {
+    pub url: String,
+    pub state: String,
+}
+
+/// Starts connecting an account of a user: remembers a new `state` with its
+/// PKCE verifier and returns the provider URL to send the user to.
+pub async fn authorization_url(
+    client: &Client,
+    user: &str,
+    oauth: &OAuthClient,
+) -> Result<AuthorizationRequest, CalendarOAuthServiceError> {
+    let state = random_token(STATE_BYTES);
+    let verifier = random_token(VERIFIER_BYTES);
+    let url = provider_url(oauth, &state, &pkce_challenge(&verifier))?;
+    let pending = OAuthState {
+        id: None,
+        state_hash: hash_token(&state),
+        user: user.to_string(),
+        provider: oauth.provider.to_string(),
+        code_verifier: verifier,
+        expires_at: Utc::now() + Duration::minutes(STATE_TTL_MINUTES),
+    };
+    get_states_collection(client).insert_one(&pending).await?;
+    Ok(AuthorizationRequest { url, state })
+}
+
+/// The cookie binding an authorization to the browser that started it. It is
+/// only sent to the callback, and `SameSite=Lax` so that it survives the
+/// redirect back from the provider.
+pub fn state_cookie(oauth: &OAuthClient, state: &str) -> Cookie<'static> {
+    let secure = oauth.redirect_uri.starts_with("https://");
+    Cookie::build(STATE_COOKIE, state.to_string())
+        .path(callback_path(oauth))
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Lax)
+        .max_age(cookie::time::Duration::minutes(STATE_TTL_MINUTES))
+        .finish()
+}
+
+fn callback_path(oauth: &OAuthClient) -> String {
+    Url::parse(&oauth.redirect_uri).map(|url| url.path().to_string()).unwrap_or_else(|_| "/".to_string())
+}
+
+/// The provider URL of an authorization request.
+fn provider_url(oauth: &OAuthClient, state: &str, challenge: &str) -> Result<String, CalendarOAuthServiceError> {
+    let mut params = vec![
+        ("response_type", "code"),
+        ("client_id", oauth.client_id.as_str()),
+        ("redirect_uri", oauth.redirect_uri.as_str()),
+        ("scope", oauth.scope),
+        ("state", state),
+        ("code_challenge", challenge),
+        ("code_challenge_method", "S256"),
+    ];
+    params.extend_from_slice(oauth.authorization_params);
+    Url::parse_with_params(&oauth.authorization_url, &params)
+        .map(String::from)
+        .map_err(|e| CalendarOAuthServiceError::NotConfigured(format!("Invalid authorization URL: {}", e)))
+}
+
+/// Finishes connecting an account with the code the provider redirected back
+/// with, replacing any earlier connection of the user to the provider.
+/// `browser_state` is the state cookie of the browser the callback came from.
+pub async fn complete_authorization(
+    client: &Client,
+    oauth: &OAuthClient,
+    query: OAuthCallbackQuery,
+    browser_state: Option<&str>,
+) -> Result<CalendarConnection, CalendarOAuthServiceError> {
+    if let Some(error) = query.error {
+        return Err(CalendarOAuthServiceError::AccessDenied(query.error_description.unwrap_or(error)));
+    }
+    let (Some(code), Some(state)) = (query.code, query.state) else {
+        return Err(CalendarOAuthServiceError::InvalidCallback("code and state are required".to_string()));
+    };
+    // A callback carrying a state started in another browser is refused
+    if browser_state.map(hash_token) != Some(hash_token(&state)) {
+        return Err(CalendarOAuthServiceError::InvalidState);
+    }
+    // Each state is only good for one callback
+    let pending = get_states_collection(client)
+        .find_one_and_delete(doc! { "state_hash": hash_token(&state), "provider": oauth.provider })
+        .await?;
+    let Some(pending) = pending.filter(|pending| pending.expires_at > Utc::now()) else {
+        return Err(CalendarOAuthServiceError::InvalidState);
+    };
+
+    let tokens = request_token(
+        oauth,
+        &[
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &oauth.redirect_uri),
+            ("code_verifier", &pending.code_verifier),
+        ],
+    )
+    .await?;
+    let Some(refresh_token) = tokens.refresh_token.as_deref() else {
+        return Err(CalendarOAuthServiceError::TokenError("No refresh token was issued".to_string()));
+    };
+    let connection = CalendarConnection {
+        id: None,
+        user: pending.user,
+        provider: oauth.provider.to_string(),
+        access_token: encrypt(&tokens.access_token)?,
+        refresh_token: encrypt(refresh_token)?,
+        expires_at: expires_at(&tokens),
+        scope: tokens.scope.clone(),
+        connected_at: Utc::now(),
+    };
+    get_connections_collection(client)
+        .replace_one(doc! { "user": &connection.user, "provider": oauth.provider }, &connection)
+        .upsert(true)
+        .await?;
+    Ok(connection)
+}
+
+/// Retrieves the connection of a user to a provider.
+pub async fn get_connection(client: &Client, user: &str, provider: &str) -> Result<CalendarConnection, CalendarOAuthServiceError> {
+    get_connections_collection(client)
+        .find_one(doc! { "user": user, "provider": provider })
+        .await?
+        .ok_or_else(|| CalendarOAuthServiceError::NotConnected(provider.to_string()))
+}
+
+/// A current access token for the user's connected account, refreshed first
+/// when it is about to expire. A refresh token the provider no longer accepts
+/// disconnects the account.
+pub async fn access_token(client: &Client, user: &str, oauth: &OAuthClient) -> Result<String, CalendarOAuthServiceError> {
+    let connection = get_connection(client, user, oauth.provider).await?;
+    let filter = doc! { "user": user, "provider": oauth.provider };
+    if connection.expires_at - Duration::seconds(REFRESH_MARGIN_SECONDS) > Utc::now() {
+        return decrypt(&connection.access_token);
+    }
+
+    let refresh_token = decrypt(&connection.refresh_token)?;
+    let tokens = match request_token(oauth, &[("grant_type", "refresh_token"), ("refresh_token", &refresh_token)]).await {
+        Ok(tokens) => tokens,
+        Err(e @ CalendarOAuthServiceError::Reauthorize(_)) => {
+            get_connections_collection(client).delete_one(filter).await?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    let mut fields = doc! {
+        "access_token": encrypt(&tokens.access_token)?,
+        "expires_at": datetime::to_bson(expires_at(&tokens))
+    };
+    // Some providers issue a new refresh token with each refresh
+    if let Some(refresh_token) = &tokens.refresh_token {
+        fields.insert("refresh_token", encrypt(refresh_token)?);
+    }
+    if let Some(scope) = &tokens.scope {
+        fields.insert("scope", scope);
+    }
+    get_connections_collection(client)
+        .update_one(filter, doc! { "$set": fields })
+        .await?;
+    Ok(tokens.access_token)
+}
+
+/// Forgets the tokens of the user's connected account, revoking them with the
+/// provider first when it supports that.
+pub async fn disconnect(client: &Client, user: &str, oauth: &OAuthClient) -> Result<(), CalendarOAuthServiceError> {
+    let connection = get_connection(client, user, oauth.provider).await?;
+    if let (Some(revocation_url), Ok(refresh_token)) = (&oauth.revocation_url, decrypt(&connection.refresh_token)) {
+        // Best effort: the tokens are forgotten here either way
+        let _ = http_client().post(revocation_url).form(&[("token", refresh_token)]).send().await;
+    }
+    get_connections_collection(client).delete_one(doc! { "user": user, "provider": oauth.provider }).await?;
+    Ok(())
+}
+
+/// Posts a grant to the token endpoint.
+async fn request_token(oauth: &OAuthClient, grant: &[(&str, &str)]) -> Result<OAuthTokenResponse, CalendarOAuthServiceError> {
+    let mut form = vec![("client_id", oauth.client_id.as_str()), ("client_secret", oauth.client_secret.as_str())];
+    form.extend_from_slice(grant);
+    let response = http_client()
+        .post(&oauth.token_url)
+        .form(&form)
+        .send()
+        .await
+        .map_err(|e| CalendarOAuthServiceError::TokenError(e.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        return response.json().await.map_err(|e| CalendarOAuthServiceError::TokenError(e.to_string()));
+    }
+    let body = response.text().await.unwrap_or_default();
+    match serde_json::from_str::<OAuthErrorResponse>(&body) {
+        Ok(error) if error.error == "invalid_grant" => {
+            Err(CalendarOAuthServiceError::Reauthorize(error.error_description.unwrap_or(error.error)))
+        }
+        _ => Err(CalendarOAuthServiceError::TokenError(format!("{} {}", status, body.trim()))),
+    }
+}
+
+fn expires_at(tokens: &OAuthTokenResponse) -> DateTime<Utc> {
+    Utc::now() + Duration::seconds(tokens.expires_in.unwrap_or(DEFAULT_EXPIRES_IN_SECONDS))
+}
+
+/// The cipher tokens are stored with. `TOKEN_ENCRYPTION_KEY` holds its 32 byte
+/// key, base64-encoded.
+fn cipher() -> Result<Aes256Gcm, CalendarOAuthServiceError> {
+    let key = required_var("TOKEN_ENCRYPTION_KEY")?;
+    let key = STANDARD
+        .decode(key)
+        .ok()
+        .filter(|key| key.len() == 32)
+        .ok_or_else(|| CalendarOAuthServiceError::NotConfigured("TOKEN_ENCRYPTION_KEY must be 32 base64-encoded bytes".to_string()))?;
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+}
+
+fn encrypt(token: &str) -> Result<String, CalendarOAuthServiceError> {
+    encrypt_with(&cipher()?, token)
+}
+
+fn decrypt(stored: &str) -> Result<String, CalendarOAuthServiceError> {
+    decrypt_with(&cipher()?, stored)
+}
+
+/// Encrypts a token as base64 of a random nonce followed by the ciphertext.
+fn encrypt_with(cipher: &Aes256Gcm, token: &str) -> Result<String, CalendarOAuthServiceError> {
+    let mut nonce = [0u8; NONCE_BYTES];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), token.as_bytes())
+        .map_err(|_| CalendarOAuthServiceError::EncryptionError("Couldn't encrypt the token".to_string()))?;
+    Ok(STANDARD.encode([nonce.as_slice(), &ciphertext].concat()))
+}
+
+fn decrypt_with(cipher: &Aes256Gcm, stored: &str) -> Result<String, CalendarOAuthServiceError> {
+    let error = || CalendarOAuthServiceError::EncryptionError("Couldn't decrypt a stored token; was TOKEN_ENCRYPTION_KEY changed?".to_string());
+    let bytes = STANDARD.decode(stored).map_err(|_| error())?;
+    if bytes.len() < NONCE_BYTES {
+        return Err(error());
+    }
+    let (nonce, ciphertext) = bytes.split_at(NONCE_BYTES);
+    let token = cipher.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| error())?;
+    String::from_utf8(token).map_err(|_| error())
+}
+
+fn random_token(bytes: usize) -> String {
+    let mut token = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut token);
+    URL_SAFE_NO_PAD.encode(token)
+}
+
+/// The S256 PKCE challenge of a verifier.
+fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Helper function to get the "calendar_connections" collection.
+fn get_connections_collection(client: &Client) -> Collection<CalendarConnection> {
+    client.database("organise").collection::<CalendarConnection>("calendar_connections")
+}
+
+/// Helper function to get the "oauth_states" collection.
+fn get_states_collection(client: &Client) -> Collection<OAuthState> {
+    client.database("organise").collection::<OAuthState>("oauth_states")
+}
+
+// Custom function to convert CalendarOAuthServiceError to HttpResponse
+pub fn error_response(error: CalendarOAuthServiceError) -> HttpResponse {
+    match error {
+        CalendarOAuthServiceError::DatabaseError(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+        e @ (CalendarOAuthServiceError::NotConfigured(_) | CalendarOAuthServiceError::EncryptionError(_)) => {
+            HttpResponse::InternalServerError().body(e.to_string())
+        }
+        e @ (CalendarOAuthServiceError::InvalidCallback(_)
+        | CalendarOAuthServiceError::InvalidState
+        | CalendarOAuthServiceError::AccessDenied(_)) => HttpResponse::BadRequest().body(e.to_string()),
+        e @ CalendarOAuthServiceError::TokenError(_) => HttpResponse::BadGateway().body(e.to_string()),
+        e @ CalendarOAuthServiceError::Reauthorize(_) => HttpResponse::Unauthorized().body(e.to_string()),
+        e @ CalendarOAuthServiceError::NotConnected(_) => HttpResponse::NotFound().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpServer};
+    use mongodb::bson::oid::ObjectId;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn oauth(redirect_uri: &str, token_url: &str) -> OAuthClient {
+        OAuthClient {
+            provider: "google",
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            redirect_uri: redirect_uri.to_string(),
+            authorization_url: "https://accounts.example.com/auth".to_string(),
+            token_url: token_url.to_string(),
+            revocation_url: None,
+            scope: "calendar",
+            authorization_params: &[("access_type", "offline")],
+        }
+    }
+
+    fn callback(code: Option<&str>, state: Option<&str>) -> OAuthCallbackQuery {
+        OAuthCallbackQuery {
+            code: code.map(str::to_string),
+            state: state.map(str::to_string),
+            error: None,
+            error_description: None,
+        }
+    }
+
+    fn test_cipher(key: u8) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&[key; 32]))
+    }
+
+    /// A client that never connects; the callbacks refused here are refused
+    /// before the database is asked.
+    async fn offline_client() -> Client {
+        Client::with_uri_str("mongodb://localhost:27017").await.unwrap()
+    }
+
+    #[test]
+    fn pkce_challenge_is_the_s256_of_the_verifier() {
+        // Example from RFC 7636, appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+        let verifier = random_token(VERIFIER_BYTES);
+        assert_eq!(verifier.len(), 43);
+        assert!(verifier.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_ne!(verifier, random_token(VERIFIER_BYTES));
+    }
+
+    #[test]
+    fn provider_url_carries_the_state_and_challenge() {
+        let oauth = oauth("https://organise.example.com/api/calendar/google/callback", "");
+        let url = Url::parse(&provider_url(&oauth, "the-state", "the-challenge").unwrap()).unwrap();
+        let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["response_type"], "code");
+        assert_eq!(params["client_id"], "client");
+        assert_eq!(params["redirect_uri"], oauth.redirect_uri);
+        assert_eq!(params["state"], "the-state");
+        assert_eq!(params["code_challenge"], "the-challenge");
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert_eq!(params["access_type"], "offline");
+    }
+
+    #[test]
+    fn state_cookie_is_only_sent_to_the_callback() {
+        let cookie = state_cookie(&oauth("https://organise.example.com/api/calendar/google/callback", ""), "the-state");
+        assert_eq!(cookie.name(), STATE_COOKIE);
+        assert_eq!(cookie.value(), "the-state");
+        assert_eq!(cookie.path(), Some("/api/calendar/google/callback"));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        let local = state_cookie(&oauth("http://localhost:8080/api/calendar/google/callback", ""), "the-state");
+        assert_eq!(local.secure(), Some(false));
+    }
+
+    #[actix_web::test]
+    async fn callbacks_without_the_browsers_state_are_refused() {
+        let client = offline_client().await;
+        let oauth = oauth("http://localhost:8080/api/calendar/google/callback", "");
+        let missing = complete_authorization(&client, &oauth, callback(Some("code"), Some("state")), None).await;
+        assert!(matches!(missing, Err(CalendarOAuthServiceError::InvalidState)));
+        let other = complete_authorization(&client, &oauth, callback(Some("code"), Some("state")), Some("other")).await;
+        assert!(matches!(other, Err(CalendarOAuthServiceError::InvalidState)));
+    }
+
+    #[actix_web::test]
+    async fn denied_and_incomplete_callbacks_are_refused() {
+        let client = offline_client().await;
+        let oauth = oauth("http://localhost:8080/api/calendar/google/callback", "");
+        let mut denied = callback(None, Some("state"));
+        denied.error = Some("access_denied".to_string());
+        let result = complete_authorization(&client, &oauth, denied, Some("state")).await;
+        assert!(matches!(result, Err(CalendarOAuthServiceError::AccessDenied(e)) if e == "access_denied"));
+        let result = complete_authorization(&client, &oauth, callback(None, Some("state")), Some("state")).await;
+        assert!(matches!(result, Err(CalendarOAuthServiceError::InvalidCallback(_))));
+    }
+
+    #[test]
+    fn tokens_round_trip_through_encryption() {
+        let cipher = test_cipher(7);
+        let first = encrypt_with(&cipher, "refresh-token").unwrap();
+        let second = encrypt_with(&cipher, "refresh-token").unwrap();
+        // A fresh nonce each time
+        assert_ne!(first, second);
+        assert_eq!(decrypt_with(&cipher, &first).unwrap(), "refresh-token");
+        assert_eq!(decrypt_with(&cipher, &second).unwrap(), "refresh-token");
+    }
+
+    #[test]
+    fn tampered_or_foreign_tokens_dont_decrypt() {
+        let cipher = test_cipher(7);
+        let mut bytes = STANDARD.decode(encrypt_with(&cipher, "refresh-token").unwrap()).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        assert!(decrypt_with(&cipher, &STANDARD.encode(&bytes)).is_err());
+        let stored = encrypt_with(&cipher, "refresh-token").unwrap();
+        assert!(decrypt_with(&test_cipher(8), &stored).is_err());
+        assert!(decrypt_with(&cipher, &STANDARD.encode([0u8; NONCE_BYTES - 1])).is_err());
+        assert!(decrypt_with(&cipher, "not base64!").is_err());
+    }
+
+    async fn token(form: web::Form<HashMap<String, String>>) -> HttpResponse {
+        let code = form.get("code").cloned().unwrap_or_default();
+        HttpResponse::Ok().json(json!({
+            "access_token": format!("access-{}", code),
+            "refresh_token": format!("refresh-{}", code),
+            "expires_in": 3600
+        }))
+    }
+
+    /// Starts an authorization for a user and returns its state.
+    async fn start(client: &Client, user: &str, oauth: &OAuthClient) -> String {
+        let request = authorization_url(client, user, oauth).await.unwrap();
+        let url = Url::parse(&request.url).unwrap();
+        let state = url.query_pairs().find(|(name, _)| name == "state").unwrap().1.into_owned();
+        assert_eq!(state, request.state);
+        state
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn each_user_connects_their_own_account() {
+        std::env::set_var("TOKEN_ENCRYPTION_KEY", STANDARD.encode([7u8; 32]));
+        let uri = std::env::var("MONGODB_TEST_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
+        let client = Client::with_uri_str(uri).await.unwrap();
+        crate::db::indexes::ensure_indexes(&client).await.unwrap();
+        let server = HttpServer::new(|| App::new().route("/token", web::post().to(token)))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let oauth = oauth("http://localhost:8080/api/calendar/google/callback", &format!("http://{}/token", server.addrs()[0]));
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let alice = format!("alice-{}", ObjectId::new().to_hex());
+        let bob = format!("bob-{}", ObjectId::new().to_hex());
+        for (user, code) in [(&alice, "a"), (&bob, "b")] {
+            let state = start(&client, user, &oauth).await;
+            let connection = complete_authorization(&client, &oauth, callback(Some(code), Some(&state)), Some(&state))
+                .await
+                .unwrap();
+            assert_eq!(&connection.user, user);
+            // Each state is only good for one callback
+            let reused = complete_authorization(&client, &oauth, callback(Some(code), Some(&state)), Some(&state)).await;
+            assert!(matches!(reused, Err(CalendarOAuthServiceError::InvalidState)));
+        }
+        assert_eq!(access_token(&client, &alice, &oauth).await.unwrap(), "access-a");
+        assert_eq!(access_token(&client, &bob, &oauth).await.unwrap(), "access-b");
+
+        disconnect(&client, &alice, &oauth).await.unwrap();
+        assert!(matches!(get_connection(&client, &alice, "google").await, Err(CalendarOAuthServiceError::NotConnected(_))));
+        assert_eq!(access_token(&client, &bob, &oauth).await.unwrap(), "access-b");
+        disconnect(&client, &bob, &oauth).await.unwrap();
+        handle.stop(false).await;
+    }
+}
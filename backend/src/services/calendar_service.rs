@@ -1,11 +1,19 @@
-use mongodb::{Client, bson::{doc, oid::ObjectId, DateTime as BsonDateTime}};
+use mongodb::{Client, bson::{doc, oid::ObjectId, Bson, DateTime as BsonDateTime, Document}};
 use mongodb::error::Error;
+use mongodb::options::ReturnDocument;
 use futures_util::TryStreamExt;
-use crate::models::calendar::{CalendarEvent, GoogleCalendarCredentials, GoogleCalendarToken};
+use crate::models::calendar::{
+    parse_instance_id, CalendarEvent, CalendarImportAction, CalendarImportItem, CalendarImportOptions,
+    CalendarImportReport, EditScope, EventOccurrence, EventRange, LOCAL_CALENDAR_ID,
+};
+use crate::models::datetime;
+use crate::models::recurrence::Until;
+use crate::services::ical_service::{self, ParsedCalendar, ParsedEvent};
+use crate::services::{calendar_change_service, recurrence_service};
 use thiserror::Error;
 use actix_web::HttpResponse;
-use chrono::{DateTime, Utc};
-use reqwest;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, HashSet};
 use std::time::SystemTime;
 
 #[derive(Error, Debug)]
@@ -18,10 +26,12 @@ pub enum CalendarServiceError {
     EventNotFound,
     #[error("Validation error: {0}")]
     ValidationError(#[from] validator::ValidationErrors),
-    #[error("Google Calendar API error: {0}")]
-    GoogleApiError(String),
-    #[error("Authentication error: {0}")]
-    AuthError(String),
+    #[error("Invalid event: {0}")]
+    InvalidEvent(String),
+    #[error("Invalid scope: {0}")]
+    InvalidScope(String),
+    #[error("Invalid calendar file: {0}")]
+    InvalidCalendar(String),
 }
 
 /// Converts a chrono DateTime to MongoDB's BsonDateTime
@@ -34,141 +44,671 @@ fn to_bson_datetime(date: DateTime<Utc>) -> BsonDateTime {
     BsonDateTime::from_system_time(system_time)
 }
 
-/// Retrieves all calendar events from the MongoDB "calendar_events" collection.
+/// Retrieves all calendar events from the MongoDB "calendar_events" collection, except trashed ones.
 pub async fn get_all_events(client: &Client) -> Result<Vec<CalendarEvent>, CalendarServiceError> {
     let collection = get_calendar_collection(client);
-    let cursor = collection.find(doc! {}).await?;
+    let cursor = collection.find(doc! { "deleted_at": null }).await?;
     collect_events(cursor).await.map_err(CalendarServiceError::from)
 }
 
-/// Retrieves calendar events for a specific time range.
+/// Retrieves the events of some calendars, or of every calendar when
+/// `calendar_ids` is empty, together with the exceptions to their series.
+pub async fn get_events_for_export(client: &Client, calendar_ids: &[String]) -> Result<Vec<CalendarEvent>, CalendarServiceError> {
+    let mut filter = doc! { "deleted_at": null };
+    if !calendar_ids.is_empty() {
+        filter.insert("calendar_id", calendar_filter(calendar_ids));
+    }
+    let cursor = get_calendar_collection(client).find(filter).await?;
+    Ok(collect_events(cursor).await?)
+}
+
+/// Retrieves an event with the exceptions to its series. Occurrences and
+/// exceptions are addressed as part of their series.
+pub async fn get_series(client: &Client, event_id: &str) -> Result<Vec<CalendarEvent>, CalendarServiceError> {
+    let target = resolve(client, event_id).await?;
+    let cursor = get_calendar_collection(client)
+        .find(doc! { "recurring_event_id": target.series.id, "deleted_at": null })
+        .await?;
+    let mut events = vec![target.series];
+    events.extend(collect_events(cursor).await?);
+    Ok(events)
+}
+
+/// Retrieves a single calendar event, or one occurrence of a series by its instance id.
+pub async fn get_event_by_id(client: &Client, event_id: &str) -> Result<EventOccurrence, CalendarServiceError> {
+    let target = resolve(client, event_id).await?;
+    let Some(start) = target.instance else {
+        return Ok(recurrence_service::as_occurrence(&target.series));
+    };
+    if let Some(exception) = find_exception(client, &target.series, start).await? {
+        return Ok(recurrence_service::as_occurrence(&exception));
+    }
+    recurrence_service::occurrence_at(&target.series, start).ok_or(CalendarServiceError::EventNotFound)
+}
+
+/// Retrieves the event occurrences within a specific time range.
+///
+/// Recurring events are expanded into one occurrence per instance of the
+/// series that falls inside the range.
 pub async fn get_events_by_date_range(
-    client: &Client, 
-    start_date: DateTime<Utc>, 
-    end_date: DateTime<Utc>
-) -> Result<Vec<CalendarEvent>, CalendarServiceError> {
+    client: &Client,
+    range: &EventRange,
+) -> Result<Vec<EventOccurrence>, CalendarServiceError> {
     let collection = get_calendar_collection(client);
-    let filter = doc! {
-        "$or": [
-            {
-                "start_time": { "$gte": to_bson_datetime(start_date), "$lte": to_bson_datetime(end_date) }
-            },
-            {
-                "end_time": { "$gte": to_bson_datetime(start_date), "$lte": to_bson_datetime(end_date) }
-            },
-            {
-                "$and": [
-                    { "start_time": { "$lte": to_bson_datetime(start_date) } },
-                    { "end_time": { "$gte": to_bson_datetime(end_date) } }
-                ]
-            }
-        ]
-    };
+    let cursor = collection.find(range_filter(range)).await?;
+    let events = collect_events(cursor).await?;
+
+    // Occurrences replaced by exceptions are left out, wherever the exception was moved to
+    let series_ids: Vec<ObjectId> = events.iter().filter(|event| event.is_recurring()).filter_map(|event| event.id).collect();
+    let mut replaced = HashSet::new();
+    if !series_ids.is_empty() {
+        let mut cursor = collection
+            .find(doc! { "recurring_event_id": { "$in": &series_ids }, "deleted_at": null })
+            .await?;
+        while let Some(exception) = cursor.try_next().await? {
+            replaced.insert(recurrence_service::as_occurrence(&exception).instance_id);
+        }
+    }
+
+    let mut occurrences: Vec<EventOccurrence> = events
+        .iter()
+        .flat_map(|event| recurrence_service::occurrences(event, range.start, range.end))
+        .filter(|occurrence| occurrence.event.is_exception() || !replaced.contains(&occurrence.instance_id))
+        .collect();
+    occurrences.sort_by_key(|occurrence| occurrence.event.start_time);
+    Ok(occurrences)
+}
+
+/// Retrieves the events and whole series with an occurrence in a range,
+/// together with the exceptions to those series.
+pub async fn get_series_in_range(client: &Client, range: &EventRange) -> Result<Vec<CalendarEvent>, CalendarServiceError> {
+    let collection = get_calendar_collection(client);
+    let mut filter = range_filter(range);
+    filter.insert("recurring_event_id", Bson::Null);
     let cursor = collection.find(filter).await?;
-    collect_events(cursor).await.map_err(CalendarServiceError::from)
+    let mut events: Vec<CalendarEvent> = collect_events(cursor)
+        .await?
+        .into_iter()
+        .filter(|event| !recurrence_service::occurrences(event, range.start, range.end).is_empty())
+        .collect();
+
+    let series_ids: Vec<ObjectId> = events.iter().filter(|event| event.is_recurring()).filter_map(|event| event.id).collect();
+    if !series_ids.is_empty() {
+        let cursor = collection
+            .find(doc! { "recurring_event_id": { "$in": &series_ids }, "deleted_at": null })
+            .await?;
+        events.extend(collect_events(cursor).await?);
+    }
+    Ok(events)
 }
 
-/// Inserts a new calendar event into the MongoDB "calendar_events" collection.
-pub async fn add_event(client: &Client, mut event: CalendarEvent) -> Result<(), CalendarServiceError> {
-    if let Err(_e) = event.validate() {
-        return Err(CalendarServiceError::ValidationError(validator::ValidationErrors::new()));
+/// When events in some calendars, or in every calendar when `calendar_ids`
+/// is empty, were last changed or moved to the trash.
+pub async fn last_changed(client: &Client, calendar_ids: &[String]) -> Result<Option<DateTime<Utc>>, CalendarServiceError> {
+    let collection = get_calendar_collection(client);
+    let mut filter = doc! {};
+    if !calendar_ids.is_empty() {
+        filter.insert("calendar_id", calendar_filter(calendar_ids));
+    }
+    let updated = collection.find_one(filter.clone()).sort(doc! { "updated_at": -1 }).await?;
+    filter.insert("deleted_at", doc! { "$ne": Bson::Null });
+    let deleted = collection.find_one(filter).sort(doc! { "deleted_at": -1 }).await?;
+    Ok([updated.map(|event| event.updated_at), deleted.and_then(|event| event.deleted_at)].into_iter().flatten().max())
+}
+
+/// Inserts a new calendar event into the MongoDB "calendar_events" collection and returns it.
+pub async fn add_event(client: &Client, mut event: CalendarEvent) -> Result<CalendarEvent, CalendarServiceError> {
+    if let Err(e) = event.validate() {
+        return Err(CalendarServiceError::InvalidEvent(e));
     }
 
     let collection = get_calendar_collection(client);
     event.id = Some(ObjectId::new());
     event.created_at = Utc::now();
     event.updated_at = Utc::now();
-    collection.insert_one(event).await?;
-    Ok(())
+    collection.insert_one(&event).await?;
+    calendar_change_service::record_changes(client, event.id.as_slice()).await?;
+    Ok(event)
 }
 
-/// Updates an existing calendar event in the MongoDB "calendar_events" collection.
-pub async fn update_event(client: &Client, event_id: &str, mut updated_event: CalendarEvent) -> Result<(), CalendarServiceError> {
-    if let Err(_e) = updated_event.validate() {
-        return Err(CalendarServiceError::ValidationError(validator::ValidationErrors::new()));
+/// Updates a calendar event and returns it.
+///
+/// For an occurrence of a series, `scope` picks what changes: `Instance`
+/// stores an exception replacing that occurrence, `Following` ends the series
+/// before it and starts a new series from the update, and `All` updates the
+/// whole series, moving it by as much as the occurrence was moved. Single
+/// events are always updated as a whole.
+pub async fn update_event(
+    client: &Client,
+    event_id: &str,
+    mut updated_event: CalendarEvent,
+    scope: EditScope,
+) -> Result<CalendarEvent, CalendarServiceError> {
+    if let Err(e) = updated_event.validate() {
+        return Err(CalendarServiceError::InvalidEvent(e));
     }
 
     let collection = get_calendar_collection(client);
-    let object_id = ObjectId::parse_str(event_id)?;
-    let filter = doc! { "_id": object_id };
-    
-    // Check if event exists
-    let existing_event = collection.find_one(filter.clone()).await?;
-    if existing_event.is_none() {
-        return Err(CalendarServiceError::EventNotFound);
+    let target = resolve(client, event_id).await?;
+    let series_id = target.series.id.unwrap_or_default();
+    updated_event.updated_at = Utc::now();
+    match (effective_scope(&target, scope)?, target.instance) {
+        (EditScope::Instance, Some(start)) => {
+            ensure_occurrence(client, &target.series, start).await?;
+            let exception = save_exception(client, &target.series, start, updated_event).await?;
+            calendar_change_service::record_changes(client, &[series_id]).await?;
+            Ok(exception)
+        }
+        (EditScope::Following, Some(start)) if start > target.series.start_time => {
+            ensure_occurrence(client, &target.series, start).await?;
+            end_series_before(client, &target.series, start).await?;
+            updated_event.id = Some(ObjectId::new());
+            updated_event.calendar_id = target.series.calendar_id.clone();
+            updated_event.created_at = updated_event.updated_at;
+            collection.insert_one(&updated_event).await?;
+            calendar_change_service::record_changes(client, &[series_id, updated_event.id.unwrap_or_default()]).await?;
+            Ok(updated_event)
+        }
+        _ => {
+            if let Some(start) = target.instance {
+                // The update carries the occurrence's times; the series moves by as much
+                let shown = find_exception(client, &target.series, start).await?.map_or(start, |exception| exception.start_time);
+                let shift = updated_event.start_time - shown;
+                onto_series(&mut updated_event, &target.series, shift);
+                if shift != Duration::zero() {
+                    shift_exceptions(client, &target.series, shift).await?;
+                }
+            }
+            let filter = doc! { "_id": series_id, "deleted_at": null };
+            let updated = collection
+                .find_one_and_update(filter, doc! { "$set": event_fields(&updated_event) })
+                .return_document(ReturnDocument::After)
+                .await?
+                .ok_or(CalendarServiceError::EventNotFound)?;
+            calendar_change_service::record_changes(client, &[series_id]).await?;
+            Ok(updated)
+        }
     }
+}
 
-    updated_event.updated_at = Utc::now();
-    let update = doc! { 
-        "$set": { 
-            "title": updated_event.title, 
-            "description": updated_event.description, 
-            "start_time": to_bson_datetime(updated_event.start_time), 
-            "end_time": to_bson_datetime(updated_event.end_time),
-            "location": updated_event.location,
-            "color": updated_event.color,
-            "is_all_day": updated_event.is_all_day,
-            "recurrence_rule": updated_event.recurrence_rule,
-            "attendees": updated_event.attendees,
-            "updated_at": to_bson_datetime(updated_event.updated_at)
-        } 
-    };
-    collection.update_one(filter, update).await?;
-    Ok(())
+/// Turns an update made to one occurrence into an update of its series: the
+/// series starts `shift` later, takes the occurrence's new duration, and its
+/// extra and excluded dates move along with it.
+fn onto_series(event: &mut CalendarEvent, series: &CalendarEvent, shift: Duration) {
+    let duration = event.end_time - event.start_time;
+    event.start_time = series.start_time + shift;
+    event.end_time = event.start_time + duration;
+    for date in event.recurrence_dates.iter_mut().chain(event.excluded_dates.iter_mut()) {
+        *date += shift;
+    }
 }
 
-/// Removes an existing calendar event from the MongoDB "calendar_events" collection.
-pub async fn remove_event(client: &Client, event_id: &str) -> Result<(), CalendarServiceError> {
+/// Moves the occurrences the live exceptions of a series replace by `shift`,
+/// so they keep replacing the same occurrences once the series has moved.
+async fn shift_exceptions(client: &Client, series: &CalendarEvent, shift: Duration) -> Result<(), Error> {
     let collection = get_calendar_collection(client);
-    let object_id = ObjectId::parse_str(event_id)?;
-    let filter = doc! { "_id": object_id };
-    
-    // Check if event exists
-    let existing_event = collection.find_one(filter.clone()).await?;
-    if existing_event.is_none() {
-        return Err(CalendarServiceError::EventNotFound);
+    let filter = doc! { "recurring_event_id": series.id, "deleted_at": null };
+    let mut exceptions = collect_events(collection.find(filter).await?).await?;
+    // Furthest first, so that no exception takes the place of one not yet moved
+    exceptions.sort_by_key(|exception| exception.recurrence_id);
+    if shift > Duration::zero() {
+        exceptions.reverse();
+    }
+    for exception in exceptions {
+        let Some(recurrence_id) = exception.recurrence_id else {
+            continue;
+        };
+        let update = doc! { "$set": { "recurrence_id": to_bson_datetime(recurrence_id + shift) } };
+        collection.update_one(doc! { "_id": exception.id }, update).await?;
     }
+    Ok(())
+}
 
-    collection.delete_one(filter).await?;
+/// Removes a calendar event.
+///
+/// For an occurrence of a series, `scope` picks what goes: `Instance` cancels
+/// that occurrence, `Following` ends the series before it, and `All` moves the
+/// series to the trash. Single events are moved to the trash.
+pub async fn remove_event(client: &Client, event_id: &str, scope: EditScope) -> Result<(), CalendarServiceError> {
+    let collection = get_calendar_collection(client);
+    let target = resolve(client, event_id).await?;
+    let series_id = target.series.id.unwrap_or_default();
+    let now = to_bson_datetime(Utc::now());
+    match (effective_scope(&target, scope)?, target.instance) {
+        (EditScope::Instance, Some(start)) => {
+            ensure_occurrence(client, &target.series, start).await?;
+            let update = doc! {
+                "$addToSet": { "excluded_dates": to_bson_datetime(start) },
+                "$set": { "updated_at": now }
+            };
+            collection.update_one(doc! { "_id": series_id }, update).await?;
+            trash_exceptions(client, doc! { "recurring_event_id": series_id, "recurrence_id": to_bson_datetime(start) }).await?;
+        }
+        (EditScope::Following, Some(start)) if start > target.series.start_time => {
+            ensure_occurrence(client, &target.series, start).await?;
+            end_series_before(client, &target.series, start).await?;
+        }
+        _ => {
+            // Exceptions go to the trash with their series, and come back with it
+            let filter = doc! {
+                "$or": [{ "_id": series_id }, { "recurring_event_id": series_id }],
+                "deleted_at": null
+            };
+            collection.update_many(filter, doc! { "$set": { "deleted_at": now } }).await?;
+        }
+    }
+    calendar_change_service::record_changes(client, &[series_id]).await?;
     Ok(())
 }
 
-/// Syncs events with Google Calendar
-pub async fn sync_with_google_calendar(
-    _client: &Client, 
-    _credentials: &GoogleCalendarCredentials,
-    token: &GoogleCalendarToken
-) -> Result<(), CalendarServiceError> {
-    // This is a placeholder for the actual Google Calendar API integration
-    // In a real implementation, you would:
-    // 1. Use the Google Calendar API client to fetch events
-    // 2. Compare with local events and update accordingly
-    // 3. Handle pagination and rate limiting
-    
-    // Example of how you might make an API call:
-    let client = reqwest::Client::new();
-    let url = "https://www.googleapis.com/calendar/v3/calendars/primary/events";
-    
-    let response = client.get(url)
-        .header("Authorization", format!("Bearer {}", token.access_token))
-        .send()
+/// An event addressed by id: a whole event, or one occurrence of a series.
+struct Target {
+    /// The event, or the series the occurrence belongs to.
+    series: CalendarEvent,
+    /// Original start of the addressed occurrence.
+    instance: Option<DateTime<Utc>>,
+}
+
+/// Looks up an event by its id or, for occurrences of a series, by the
+/// instance id; exceptions are addressed as the occurrence they replace.
+async fn resolve(client: &Client, event_id: &str) -> Result<Target, CalendarServiceError> {
+    let (object_id, instance) = match parse_instance_id(event_id) {
+        Some((series_id, start)) => (series_id, Some(start)),
+        None => (ObjectId::parse_str(event_id)?, None),
+    };
+    let event = find_live_event(client, object_id).await?;
+    match (event.recurring_event_id, event.recurrence_id) {
+        (Some(series_id), Some(start)) => Ok(Target {
+            series: find_live_event(client, series_id).await?,
+            instance: Some(start),
+        }),
+        _ => Ok(Target { series: event, instance }),
+    }
+}
+
+/// The scope an edit actually has: single events are always edited whole,
+/// and narrower scopes need an occurrence to start from.
+fn effective_scope(target: &Target, scope: EditScope) -> Result<EditScope, CalendarServiceError> {
+    if !target.series.is_recurring() {
+        return Ok(EditScope::All);
+    }
+    if scope != EditScope::All && target.instance.is_none() {
+        return Err(CalendarServiceError::InvalidScope(
+            "Address an occurrence by its instance id to edit only part of a series".to_string(),
+        ));
+    }
+    Ok(scope)
+}
+
+async fn find_live_event(client: &Client, event_id: ObjectId) -> Result<CalendarEvent, CalendarServiceError> {
+    get_calendar_collection(client)
+        .find_one(doc! { "_id": event_id, "deleted_at": null })
+        .await?
+        .ok_or(CalendarServiceError::EventNotFound)
+}
+
+/// The exception replacing the occurrence of a series that starts at `start`, if any.
+pub(crate) async fn find_exception(client: &Client, series: &CalendarEvent, start: DateTime<Utc>) -> Result<Option<CalendarEvent>, Error> {
+    get_calendar_collection(client)
+        .find_one(doc! { "recurring_event_id": series.id, "recurrence_id": to_bson_datetime(start), "deleted_at": null })
         .await
-        .map_err(|e| CalendarServiceError::GoogleApiError(e.to_string()))?;
-    
-    if !response.status().is_success() {
-        return Err(CalendarServiceError::GoogleApiError(format!(
-            "Google API returned error: {}", 
-            response.status()
-        )));
-    }
-    
-    // Process the response and update local events
-    // This is just a placeholder
+}
+
+/// Fails with `EventNotFound` unless the series has an occurrence starting at `start`.
+async fn ensure_occurrence(client: &Client, series: &CalendarEvent, start: DateTime<Utc>) -> Result<(), CalendarServiceError> {
+    if recurrence_service::occurrence_at(series, start).is_some() || find_exception(client, series, start).await?.is_some() {
+        return Ok(());
+    }
+    Err(CalendarServiceError::EventNotFound)
+}
+
+/// Stores `event` as the exception replacing the occurrence of a series that
+/// starts at `start`, creating it or replacing an earlier one.
+pub(crate) async fn save_exception(
+    client: &Client,
+    series: &CalendarEvent,
+    start: DateTime<Utc>,
+    mut event: CalendarEvent,
+) -> Result<CalendarEvent, CalendarServiceError> {
+    // An exception is one occurrence; it doesn't recur itself
+    event.recurrence_rule = None;
+    event.recurrence_dates.clear();
+    event.excluded_dates.clear();
+    let filter = doc! { "recurring_event_id": series.id, "recurrence_id": to_bson_datetime(start), "deleted_at": null };
+    let mut fields = event_fields(&event);
+    if let Some(calendar_id) = &series.calendar_id {
+        fields.insert("calendar_id", calendar_id);
+    }
+    if let Some(ical_uid) = &series.ical_uid {
+        fields.insert("ical_uid", ical_uid);
+    }
+    let update = doc! {
+        "$set": fields,
+        "$setOnInsert": { "created_at": to_bson_datetime(event.updated_at) }
+    };
+    get_calendar_collection(client)
+        .find_one_and_update(filter, update)
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .await?
+        .ok_or(CalendarServiceError::EventNotFound)
+}
+
+/// Ends a series just before its occurrence at `start`, dropping the extra
+/// dates and exclusions from there on and moving the exceptions to the trash.
+async fn end_series_before(client: &Client, series: &CalendarEvent, start: DateTime<Utc>) -> Result<(), CalendarServiceError> {
+    let collection = get_calendar_collection(client);
+    let earlier = |dates: &[DateTime<Utc>]| -> Vec<BsonDateTime> {
+        dates.iter().filter(|date| **date < start).map(|date| to_bson_datetime(*date)).collect()
+    };
+    let mut fields = doc! {
+        "recurrence_dates": earlier(&series.recurrence_dates),
+        "excluded_dates": earlier(&series.excluded_dates),
+        "updated_at": to_bson_datetime(Utc::now())
+    };
+    if let Some(mut rule) = series.rule().map_err(CalendarServiceError::InvalidEvent)? {
+        rule.count = None;
+        rule.until = Some(Until::Utc(start - Duration::seconds(1)));
+        fields.insert("recurrence_rule", rule.to_string());
+    }
+    collection.update_one(doc! { "_id": series.id }, doc! { "$set": fields }).await?;
+    trash_exceptions(client, doc! { "recurring_event_id": series.id, "recurrence_id": { "$gte": to_bson_datetime(start) } }).await?;
+    Ok(())
+}
+
+/// Moves the live exceptions matching `filter` to the trash, where they are
+/// listed on their own as long as their series isn't trashed.
+pub(crate) async fn trash_exceptions(client: &Client, mut filter: Document) -> Result<(), Error> {
+    filter.insert("deleted_at", Bson::Null);
+    let update = doc! { "$set": { "deleted_at": to_bson_datetime(Utc::now()) } };
+    get_calendar_collection(client).update_many(filter, update).await?;
     Ok(())
 }
 
+/// Events that may overlap a range. An event overlaps it when it starts
+/// before its end and ends after its start; series still need expanding, so
+/// only their start is bounded here.
+fn range_filter(range: &EventRange) -> Document {
+    let mut filter = doc! {
+        "deleted_at": null,
+        "start_time": { "$lt": to_bson_datetime(range.end) },
+        "$or": [
+            { "end_time": { "$gt": to_bson_datetime(range.start) } },
+            { "recurrence_rule": { "$type": "string" } },
+            { "recurrence_dates.0": { "$exists": true } }
+        ]
+    };
+    if !range.calendar_ids.is_empty() {
+        filter.insert("calendar_id", calendar_filter(&range.calendar_ids));
+    }
+    filter
+}
+
+/// Matches events in any of the calendars; events of the local calendar
+/// don't store its id.
+pub(crate) fn calendar_filter(calendar_ids: &[String]) -> Document {
+    let calendar_ids: Vec<Bson> = calendar_ids
+        .iter()
+        .map(|id| match id.as_str() {
+            LOCAL_CALENDAR_ID => Bson::Null,
+            id => Bson::String(id.to_string()),
+        })
+        .collect();
+    doc! { "$in": calendar_ids }
+}
+
+/// Whether writing `event` over `existing` would change anything.
+pub(crate) fn unchanged(existing: &CalendarEvent, event: &CalendarEvent) -> bool {
+    let mut fields = event_fields(event);
+    fields.insert("updated_at", to_bson_datetime(existing.updated_at));
+    event_fields(existing) == fields
+}
+
+/// Fields written when an event is updated.
+pub(crate) fn event_fields(event: &CalendarEvent) -> Document {
+    doc! {
+        "title": &event.title,
+        "description": &event.description,
+        "start_time": to_bson_datetime(event.start_time),
+        "end_time": to_bson_datetime(event.end_time),
+        "location": &event.location,
+        "color": &event.color,
+        "is_all_day": event.is_all_day,
+        "time_zone": &event.time_zone,
+        "recurrence_rule": &event.recurrence_rule,
+        "recurrence_dates": event.recurrence_dates.iter().map(|date| datetime::to_bson(*date)).collect::<Vec<_>>(),
+        "excluded_dates": event.excluded_dates.iter().map(|date| datetime::to_bson(*date)).collect::<Vec<_>>(),
+        "attendees": &event.attendees,
+        "updated_at": to_bson_datetime(event.updated_at)
+    }
+}
+
+/// Imports the events of an iCalendar file, matching existing events by `UID`.
+///
+/// Events imported before are updated when they changed and skipped
+/// otherwise. Exceptions replace the matching occurrence of their series, and
+/// cancelled exceptions remove it. Dry runs report the same without writing.
+pub async fn import_events(
+    client: &Client,
+    calendar: ParsedCalendar,
+    options: &CalendarImportOptions,
+) -> Result<CalendarImportReport, CalendarServiceError> {
+    let collection = get_calendar_collection(client);
+    let mut report = CalendarImportReport { dry_run: options.dry_run, warnings: calendar.warnings, ..Default::default() };
+    for skipped in calendar.skipped {
+        report.record(CalendarImportItem {
+            uid: skipped.uid,
+            recurrence_id: None,
+            title: skipped.title,
+            action: CalendarImportAction::Skip,
+            event_id: None,
+            reason: Some(skipped.reason),
+        });
+    }
+    // Events are only matched within the calendar they are imported into
+    let target_calendar = options.calendar_id.as_deref().unwrap_or(LOCAL_CALENDAR_ID);
+    let calendar_id = Some(target_calendar.to_string()).filter(|id| id != LOCAL_CALENDAR_ID);
+
+    // Series first, so that their exceptions can find them
+    let (exceptions, series): (Vec<_>, Vec<_>) =
+        calendar.events.into_iter().partition(|parsed| parsed.recurrence_id.is_some());
+    let mut imported: HashMap<String, CalendarEvent> = HashMap::new();
+    for parsed in series {
+        let mut item = CalendarImportItem {
+            uid: parsed.uid.clone(),
+            recurrence_id: None,
+            title: parsed.event.title.clone(),
+            action: CalendarImportAction::Skip,
+            event_id: None,
+            reason: None,
+        };
+        if imported.contains_key(&parsed.uid) {
+            item.reason = Some("Duplicate UID".to_string());
+            report.record(item);
+            continue;
+        }
+        if parsed.cancelled {
+            item.reason = Some("Cancelled".to_string());
+            report.record(item);
+            continue;
+        }
+
+        let mut event = parsed.event;
+        let stored = match find_by_uid(client, target_calendar, &parsed.uid).await? {
+            Some(existing) if unchanged(&existing, &event) => {
+                item.reason = Some("Unchanged".to_string());
+                existing
+            }
+            Some(existing) => {
+                item.action = CalendarImportAction::Update;
+                let updated = match options.dry_run {
+                    true => None,
+                    false => {
+                        collection
+                            .find_one_and_update(doc! { "_id": existing.id }, doc! { "$set": event_fields(&event) })
+                            .return_document(ReturnDocument::After)
+                            .await?
+                    }
+                };
+                updated.unwrap_or(CalendarEvent { id: existing.id, ical_uid: existing.ical_uid, ..event })
+            }
+            None => {
+                item.action = CalendarImportAction::Create;
+                event.id = Some(ObjectId::new());
+                event.calendar_id = calendar_id.clone();
+                if !options.dry_run {
+                    collection.insert_one(&event).await?;
+                }
+                event
+            }
+        };
+        if !options.dry_run && item.action != CalendarImportAction::Skip {
+            calendar_change_service::record_changes(client, stored.id.as_slice()).await?;
+        }
+        if !(options.dry_run && item.action == CalendarImportAction::Create) {
+            item.event_id = stored.id.map(|id| id.to_hex());
+        }
+        report.record(item);
+        imported.insert(parsed.uid, stored);
+    }
+
+    for parsed in exceptions {
+        let start = parsed.recurrence_id.unwrap_or(parsed.event.start_time);
+        let mut item = CalendarImportItem {
+            uid: parsed.uid.clone(),
+            recurrence_id: Some(start),
+            title: parsed.event.title.clone(),
+            action: CalendarImportAction::Skip,
+            event_id: None,
+            reason: None,
+        };
+        let series = match imported.get(&parsed.uid) {
+            Some(series) => Some(series.clone()),
+            None => find_by_uid(client, target_calendar, &parsed.uid).await?,
+        };
+        let Some(mut series) = series.filter(|series| series.is_recurring()) else {
+            item.reason = Some("Series not found".to_string());
+            report.record(item);
+            continue;
+        };
+        let series_id = series.id.unwrap_or_default();
+        let existing = find_exception(client, &series, start).await?;
+
+        if parsed.cancelled {
+            if series.excluded_dates.contains(&start) && existing.is_none() {
+                item.reason = Some("Unchanged".to_string());
+            } else {
+                item.action = CalendarImportAction::Update;
+                if !options.dry_run {
+                    let update = doc! {
+                        "$addToSet": { "excluded_dates": to_bson_datetime(start) },
+                        "$set": { "updated_at": to_bson_datetime(Utc::now()) }
+                    };
+                    collection.update_one(doc! { "_id": series_id }, update).await?;
+                    trash_exceptions(client, doc! { "recurring_event_id": series_id, "recurrence_id": to_bson_datetime(start) })
+                        .await?;
+                    calendar_change_service::record_changes(client, &[series_id]).await?;
+                }
+                series.excluded_dates.push(start);
+                imported.insert(parsed.uid.clone(), series.clone());
+            }
+            item.event_id = Some(series_id.to_hex());
+            report.record(item);
+            continue;
+        }
+
+        let mut event = parsed.event;
+        event.recurrence_rule = None;
+        event.recurrence_dates.clear();
+        event.excluded_dates.clear();
+        match &existing {
+            Some(existing) if unchanged(existing, &event) => {
+                item.reason = Some("Unchanged".to_string());
+                item.event_id = existing.id.map(|id| id.to_hex());
+            }
+            _ => {
+                item.action = match existing {
+                    Some(_) => CalendarImportAction::Update,
+                    None => CalendarImportAction::Create,
+                };
+                if !options.dry_run {
+                    let exception = save_exception(client, &series, start, event).await?;
+                    calendar_change_service::record_changes(client, &[series_id]).await?;
+                    item.event_id = exception.id.map(|id| id.to_hex());
+                }
+            }
+        }
+        report.record(item);
+    }
+    Ok(report)
+}
+
+/// Replaces a series, or a single event, and the exceptions to it with the
+/// `VEVENT`s of one calendar object, or stores them as a new event when there
+/// is no `existing` one.
+///
+/// Cancelled exceptions become excluded dates, and stored exceptions missing
+/// from `exceptions` are moved to the trash.
+pub async fn save_series(
+    client: &Client,
+    existing: Option<&CalendarEvent>,
+    mut series: CalendarEvent,
+    exceptions: Vec<ParsedEvent>,
+) -> Result<CalendarEvent, CalendarServiceError> {
+    let collection = get_calendar_collection(client);
+    let (cancelled, exceptions): (Vec<_>, Vec<_>) = exceptions.into_iter().partition(|parsed| parsed.cancelled);
+    series.excluded_dates.extend(cancelled.iter().filter_map(|parsed| parsed.recurrence_id));
+    series.excluded_dates.sort();
+    series.excluded_dates.dedup();
+    series.updated_at = Utc::now();
+    let series = match existing {
+        Some(existing) => collection
+            .find_one_and_update(doc! { "_id": existing.id, "deleted_at": null }, doc! { "$set": event_fields(&series) })
+            .return_document(ReturnDocument::After)
+            .await?
+            .ok_or(CalendarServiceError::EventNotFound)?,
+        None => {
+            series.id = Some(ObjectId::new());
+            collection.insert_one(&series).await?;
+            series
+        }
+    };
+
+    let mut kept = Vec::new();
+    for parsed in exceptions {
+        let Some(start) = parsed.recurrence_id else {
+            continue;
+        };
+        let mut event = parsed.event;
+        event.updated_at = series.updated_at;
+        save_exception(client, &series, start, event).await?;
+        kept.push(to_bson_datetime(start));
+    }
+    trash_exceptions(client, doc! { "recurring_event_id": series.id, "recurrence_id": { "$nin": kept } }).await?;
+    calendar_change_service::record_changes(client, series.id.as_slice()).await?;
+    Ok(series)
+}
+
+/// Finds the live series or single event of a calendar with an iCalendar
+/// `UID`, including the `UID`s written by the export for events that weren't
+/// imported.
+pub(crate) async fn find_by_uid(client: &Client, calendar_id: &str, uid: &str) -> Result<Option<CalendarEvent>, Error> {
+    let mut matches = vec![doc! { "ical_uid": uid }];
+    if let Some(event_id) = ical_service::event_id_from_uid(uid) {
+        matches.push(doc! { "_id": event_id });
+    }
+    get_calendar_collection(client)
+        .find_one(doc! {
+            "calendar_id": calendar_filter(&[calendar_id.to_string()]),
+            "recurring_event_id": null,
+            "deleted_at": null,
+            "$or": matches
+        })
+        .await
+}
+
 /// Helper function to get the "calendar_events" collection.
-fn get_calendar_collection(client: &Client) -> mongodb::Collection<CalendarEvent> {
+pub(crate) fn get_calendar_collection(client: &Client) -> mongodb::Collection<CalendarEvent> {
     let db = client.database("organise");
     db.collection::<CalendarEvent>("calendar_events")
 }
@@ -189,7 +729,161 @@ pub fn error_response(error: CalendarServiceError) -> HttpResponse {
         CalendarServiceError::InvalidObjectId(e) => HttpResponse::BadRequest().body(format!("Invalid ObjectId: {}", e)),
         CalendarServiceError::EventNotFound => HttpResponse::NotFound().body("Event not found"),
         CalendarServiceError::ValidationError(e) => HttpResponse::BadRequest().json(e),
-        CalendarServiceError::GoogleApiError(e) => HttpResponse::InternalServerError().body(format!("Google Calendar API error: {}", e)),
-        CalendarServiceError::AuthError(e) => HttpResponse::Unauthorized().body(format!("Authentication error: {}", e)),
+        e @ CalendarServiceError::InvalidEvent(_) => HttpResponse::BadRequest().body(e.to_string()),
+        e @ CalendarServiceError::InvalidScope(_) => HttpResponse::BadRequest().body(e.to_string()),
+        e @ CalendarServiceError::InvalidCalendar(_) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::calendar::instance_id;
+    use chrono::TimeZone;
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn event(title: &str, start: DateTime<Utc>, rule: Option<&str>) -> CalendarEvent {
+        CalendarEvent {
+            id: None,
+            title: title.to_string(),
+            description: None,
+            start_time: start,
+            end_time: start + Duration::minutes(30),
+            location: None,
+            is_all_day: false,
+            time_zone: None,
+            recurrence_rule: rule.map(str::to_string),
+            recurrence_dates: Vec::new(),
+            excluded_dates: Vec::new(),
+            recurring_event_id: None,
+            recurrence_id: None,
+            attendees: Vec::new(),
+            color: None,
+            calendar_id: None,
+            ical_uid: None,
+            dav_name: None,
+            external_id: None,
+            synced_at: None,
+            created_at: start,
+            updated_at: start,
+            deleted_at: None,
+        }
+    }
+
+    /// The occurrence of `series` starting at `start`, edited by `edit`.
+    fn edited(series: &CalendarEvent, start: DateTime<Utc>, edit: impl FnOnce(&mut CalendarEvent)) -> CalendarEvent {
+        let mut occurrence = recurrence_service::occurrence_at(series, start).unwrap().event;
+        edit(&mut occurrence);
+        occurrence
+    }
+
+    async fn test_client() -> Client {
+        let uri = std::env::var("MONGODB_TEST_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
+        Client::with_uri_str(uri).await.unwrap()
+    }
+
+    async fn purge_series(client: &Client, series_id: Option<ObjectId>) {
+        let filter = doc! { "$or": [{ "_id": series_id }, { "recurring_event_id": series_id }] };
+        get_calendar_collection(client).delete_many(filter).await.unwrap();
     }
-}
\ No newline at end of file
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn uids_are_matched_within_one_calendar() {
+        let client = test_client().await;
+        crate::db::indexes::ensure_indexes(&client).await.unwrap();
+        let uid = format!("{}@example.com", ObjectId::new().to_hex());
+        let data = format!(
+            "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:{}\r\nSUMMARY:Launch\r\n\
+             DTSTART:20240601T090000Z\r\nDTEND:20240601T100000Z\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+            uid
+        );
+        let import = |calendar_id: &str| CalendarImportOptions { dry_run: false, calendar_id: Some(calendar_id.to_string()) };
+
+        let local = import_events(&client, ical_service::parse_calendar(&data).unwrap(), &import(LOCAL_CALENDAR_ID)).await.unwrap();
+        let work = import_events(&client, ical_service::parse_calendar(&data).unwrap(), &import("work")).await.unwrap();
+        assert_eq!(local.items[0].action, CalendarImportAction::Create);
+        assert_eq!(work.items[0].action, CalendarImportAction::Create);
+        let found = find_by_uid(&client, "work", &uid).await.unwrap().unwrap();
+        assert_eq!(found.calendar_id.as_deref(), Some("work"));
+
+        // A second live copy in the same calendar is refused
+        let mut copy = found.clone();
+        copy.id = Some(ObjectId::new());
+        assert!(get_calendar_collection(&client).insert_one(&copy).await.is_err());
+        get_calendar_collection(&client).delete_many(doc! { "ical_uid": &uid }).await.unwrap();
+    }
+
+    #[test]
+    fn series_moves_by_as_much_as_the_occurrence() {
+        let mut series = event("Stand-up", utc(2024, 1, 1, 9, 0), Some("FREQ=DAILY"));
+        series.excluded_dates = vec![utc(2024, 1, 3, 9, 0)];
+        // The third occurrence moved an hour later and made an hour long
+        let mut update = edited(&series, utc(2024, 1, 4, 9, 0), |occurrence| {
+            occurrence.start_time = utc(2024, 1, 4, 10, 0);
+            occurrence.end_time = utc(2024, 1, 4, 11, 0);
+        });
+
+        onto_series(&mut update, &series, Duration::hours(1));
+        assert_eq!((update.start_time, update.end_time), (utc(2024, 1, 1, 10, 0), utc(2024, 1, 1, 11, 0)));
+        assert_eq!(update.excluded_dates, vec![utc(2024, 1, 3, 10, 0)]);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn editing_one_occurrence_stores_an_exception() {
+        let client = test_client().await;
+        let series = add_event(&client, event("Stand-up", utc(2024, 1, 1, 9, 0), Some("FREQ=DAILY"))).await.unwrap();
+        let start = utc(2024, 1, 3, 9, 0);
+        let update = edited(&series, start, |occurrence| occurrence.title = "Retro".to_string());
+
+        update_event(&client, &instance_id(series.id.unwrap(), start), update, EditScope::Instance).await.unwrap();
+        let stored = find_live_event(&client, series.id.unwrap()).await.unwrap();
+        assert_eq!(stored.title, "Stand-up");
+        let exception = find_exception(&client, &stored, start).await.unwrap().unwrap();
+        assert_eq!((exception.title.as_str(), exception.start_time), ("Retro", start));
+        purge_series(&client, series.id).await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn editing_following_occurrences_splits_the_series() {
+        let client = test_client().await;
+        let series = add_event(&client, event("Stand-up", utc(2024, 1, 1, 9, 0), Some("FREQ=DAILY"))).await.unwrap();
+        let start = utc(2024, 1, 3, 9, 0);
+        let update = edited(&series, start, |occurrence| occurrence.start_time += Duration::hours(1));
+
+        let following = update_event(&client, &instance_id(series.id.unwrap(), start), update, EditScope::Following).await.unwrap();
+        let earlier = find_live_event(&client, series.id.unwrap()).await.unwrap();
+        let range = |event: &CalendarEvent| recurrence_service::occurrences(event, utc(2024, 1, 1, 0, 0), utc(2024, 1, 5, 0, 0));
+        assert_eq!(range(&earlier).len(), 2);
+        assert_eq!(range(&following)[0].event.start_time, utc(2024, 1, 3, 10, 0));
+        purge_series(&client, series.id).await;
+        purge_series(&client, following.id).await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn editing_all_occurrences_through_one_moves_the_whole_series() {
+        let client = test_client().await;
+        let series = add_event(&client, event("Stand-up", utc(2024, 1, 1, 9, 0), Some("FREQ=DAILY"))).await.unwrap();
+        let moved = utc(2024, 1, 5, 9, 0);
+        let exception = edited(&series, moved, |occurrence| occurrence.title = "Demo".to_string());
+        update_event(&client, &instance_id(series.id.unwrap(), moved), exception, EditScope::Instance).await.unwrap();
+
+        let start = utc(2024, 1, 3, 9, 0);
+        let update = edited(&series, start, |occurrence| {
+            occurrence.title = "Sync".to_string();
+            occurrence.start_time += Duration::minutes(30);
+            occurrence.end_time += Duration::minutes(30);
+        });
+        let updated = update_event(&client, &instance_id(series.id.unwrap(), start), update, EditScope::All).await.unwrap();
+        assert_eq!(updated.title, "Sync");
+        assert_eq!(updated.start_time, utc(2024, 1, 1, 9, 30));
+        // The exception still replaces the same, now later, occurrence
+        assert!(find_exception(&client, &updated, moved + Duration::minutes(30)).await.unwrap().is_some());
+        purge_series(&client, series.id).await;
+    }
+}
@@ -0,0 +1,912 @@
+//! Two-way sync between calendars of connected accounts and their local
+//! copies.
+//!
+//! Each provider lists the changes made since the last sync and applies local
+//! changes through `RemoteCalendar`; this module decides what goes which way.
+//! Synced events keep their provider id in `external_id` and the time they
+//! last matched the provider in `synced_at`, so an event updated or trashed
+//! after `synced_at` has changes to push. When an event changed on both sides,
+//! the later change wins. A full sync, the first one or after the provider
+//! expired its sync token, lists every event, so synced events it leaves out
+//! were deleted remotely.
+
+use actix_web::HttpResponse;
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId};
+use mongodb::error::Error;
+use mongodb::Client;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use std::collections::HashSet;
+use std::sync::OnceLock;
+use std::time::Duration as StdDuration;
+use thiserror::Error;
+use crate::db::errors::is_duplicate_key;
+use crate::models::calendar::CalendarEvent;
+use crate::models::calendar_sync::{CalendarProvider, CalendarSyncReport, CalendarSyncState};
+use crate::models::datetime;
+use crate::services::calendar_oauth_service::{self, CalendarOAuthServiceError, OAuthClient};
+use crate::services::calendar_change_service;
+use crate::services::calendar_service::{self, CalendarServiceError};
+use crate::services::google_calendar_service::{self, GoogleCalendar};
+use crate::services::microsoft_calendar_service::{self, MicrosoftCalendar};
+use crate::services::recurrence_service;
+
+#[derive(Error, Debug)]
+pub enum CalendarSyncServiceError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] Error),
+    #[error("Calendar API error: {0}")]
+    ApiError(String),
+    #[error("The calendar API rejected the access token: {0}")]
+    Unauthorized(String),
+    /// The provider expired the sync token, so the calendar needs a full sync.
+    #[error("Sync token expired")]
+    SyncTokenExpired,
+    /// The event can't be represented by the provider.
+    #[error("{0}")]
+    Unsupported(String),
+    #[error(transparent)]
+    CalendarError(#[from] CalendarServiceError),
+    #[error(transparent)]
+    OAuthError(#[from] CalendarOAuthServiceError),
+}
+
+/// How long connecting to a provider may take.
+const CONNECT_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+/// How long a request to a provider may take, response included.
+const REQUEST_TIMEOUT: StdDuration = StdDuration::from_secs(30);
+
+/// The HTTP client for requests to providers, shared so that connections are
+/// reused across calendars and syncs.
+pub(crate) fn http_client() -> reqwest::Client {
+    static HTTP: OnceLock<reqwest::Client> = OnceLock::new();
+    HTTP.get_or_init(|| {
+        reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("The HTTP client has a valid configuration")
+    })
+    .clone()
+}
+
+impl From<reqwest::Error> for CalendarSyncServiceError {
+    fn from(error: reqwest::Error) -> Self {
+        CalendarSyncServiceError::ApiError(error.to_string())
+    }
+}
+
+/// A page of the events of a remote calendar, or of the changes since a sync.
+pub(crate) struct RemotePage {
+    pub events: Vec<RemoteEvent>,
+    /// Token for the next page; `None` on the last page.
+    pub next_page: Option<String>,
+    /// Token for the changes after this sync, sent with the last page.
+    pub sync_token: Option<String>,
+}
+
+/// An event of a remote calendar, as the provider listed it.
+pub(crate) struct RemoteEvent {
+    pub id: String,
+    /// Id of the series and original start of an occurrence that was changed
+    /// or cancelled on its own.
+    pub occurrence_of: Option<(String, DateTime<Utc>)>,
+    pub title: String,
+    /// When the event last changed in the provider.
+    pub updated: Option<DateTime<Utc>>,
+    /// The event as it is stored locally; `None` when it was deleted or
+    /// cancelled, an error when it can't be represented.
+    pub event: Option<Result<CalendarEvent, String>>,
+}
+
+impl RemoteEvent {
+    /// An event the provider reports as deleted.
+    pub fn removed(id: String, title: &str) -> Self {
+        RemoteEvent { id, occurrence_of: None, title: title.to_string(), updated: None, event: None }
+    }
+}
+
+/// A calendar of a connected account.
+pub(crate) trait RemoteCalendar {
+    /// A page of the events, or of the changes since `sync_token`, including
+    /// deleted ones. Fails with `SyncTokenExpired` when the provider no longer
+    /// accepts the token.
+    async fn changes(&self, sync_token: Option<&str>, page_token: Option<&str>) -> Result<RemotePage, CalendarSyncServiceError>;
+
+    /// The series with an id as it is now, for providers that may report the
+    /// deletion of a series, or the cancellation of one of its occurrences,
+    /// only by occurrence ids no local event has; `None` for other providers.
+    async fn series(&self, series_id: &str) -> Result<Option<RemoteEvent>, CalendarSyncServiceError>;
+
+    /// Creates an event and returns its id.
+    async fn insert(&self, event: &CalendarEvent) -> Result<String, CalendarSyncServiceError>;
+
+    /// Updates an event or an occurrence; `false` when it doesn't exist.
+    async fn update(&self, id: &str, event: &CalendarEvent) -> Result<bool, CalendarSyncServiceError>;
+
+    /// The dates a listing without a sync token covers; `None` when it lists
+    /// every event.
+    fn listed_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)>;
+
+    /// Deletes an event; events that are already gone count as deleted.
+    async fn delete(&self, id: &str) -> Result<(), CalendarSyncServiceError>;
+
+    /// Id of the occurrence of a series that originally starts at `start`.
+    async fn occurrence_id(
+        &self,
+        series_id: &str,
+        series: &CalendarEvent,
+        start: DateTime<Utc>,
+    ) -> Result<Option<String>, CalendarSyncServiceError>;
+}
+
+/// The app's OAuth client for a provider.
+pub fn oauth_client(provider: CalendarProvider) -> Result<OAuthClient, CalendarOAuthServiceError> {
+    match provider {
+        CalendarProvider::Google => google_calendar_service::oauth_client(),
+        CalendarProvider::Microsoft => microsoft_calendar_service::oauth_client(),
+    }
+}
+
+/// Syncs a calendar of the account a user connected to a provider with its
+/// local copy and reports what changed.
+pub async fn sync(
+    client: &Client,
+    user: &str,
+    provider: CalendarProvider,
+    remote_calendar_id: &str,
+) -> Result<CalendarSyncReport, CalendarSyncServiceError> {
+    let access_token = calendar_oauth_service::access_token(client, user, &oauth_client(provider)?).await?;
+    let mut state = get_state(client, user, provider, remote_calendar_id).await?;
+    let mut report = CalendarSyncReport {
+        calendar_id: state.calendar_id.clone(),
+        full_sync: state.sync_token.is_none(),
+        ..Default::default()
+    };
+    match provider {
+        CalendarProvider::Google => {
+            let remote = GoogleCalendar::new(&access_token, remote_calendar_id);
+            pull(client, &remote, &mut state, &mut report).await?;
+            push(client, &remote, &state, &mut report).await?;
+        }
+        CalendarProvider::Microsoft => {
+            let remote = MicrosoftCalendar::new(&access_token, remote_calendar_id);
+            pull(client, &remote, &mut state, &mut report).await?;
+            push(client, &remote, &state, &mut report).await?;
+        }
+    }
+    state.last_synced_at = Some(Utc::now());
+    save_state(client, &state).await?;
+    Ok(report)
+}
+
+/// Applies the changes made remotely since the last sync, or every event on
+/// the first sync. Series are applied before occurrences, which need them.
+async fn pull(
+    client: &Client,
+    remote_calendar: &impl RemoteCalendar,
+    state: &mut CalendarSyncState,
+    report: &mut CalendarSyncReport,
+) -> Result<(), CalendarSyncServiceError> {
+    let mut page_token = None;
+    let mut occurrences = Vec::new();
+    let mut unknown_removals = false;
+    // Ids of the events a full sync lists, which leaves out those deleted since
+    let mut listed = HashSet::new();
+    loop {
+        let page = match remote_calendar.changes(state.sync_token.as_deref(), page_token.as_deref()).await {
+            Err(CalendarSyncServiceError::SyncTokenExpired) if state.sync_token.is_some() => {
+                state.sync_token = None;
+                report.full_sync = true;
+                page_token = None;
+                occurrences.clear();
+                listed.clear();
+                continue;
+            }
+            page => page?,
+        };
+        for remote in page.events {
+            if report.full_sync {
+                listed.insert(remote.id.clone());
+            }
+            if remote.occurrence_of.is_some() {
+                occurrences.push(remote);
+            } else if remote.event.is_none() && find_synced(client, state, &remote.id).await?.is_none() {
+                unknown_removals = true;
+            } else {
+                pull_event(client, state, remote, report).await?;
+            }
+        }
+        match page.next_page {
+            Some(next) => page_token = Some(next),
+            None => {
+                state.sync_token = page.sync_token;
+                break;
+            }
+        }
+    }
+    for remote in occurrences {
+        pull_occurrence(client, state, remote, report).await?;
+    }
+    if unknown_removals {
+        refresh_series(client, remote_calendar, state, report).await?;
+    }
+    if report.full_sync {
+        remove_unlisted(client, remote_calendar, state, &listed, report).await?;
+    }
+    Ok(())
+}
+
+/// Applies the deletion of the synced series and single events a full sync
+/// didn't list, as the provider no longer has them. Providers that only list
+/// a range of dates keep the events without an occurrence in it.
+async fn remove_unlisted(
+    client: &Client,
+    remote_calendar: &impl RemoteCalendar,
+    state: &CalendarSyncState,
+    listed: &HashSet<String>,
+    report: &mut CalendarSyncReport,
+) -> Result<(), CalendarSyncServiceError> {
+    let filter = doc! {
+        "calendar_id": &state.calendar_id,
+        "recurring_event_id": null,
+        "external_id": { "$type": "string" },
+        "deleted_at": null
+    };
+    let synced: Vec<CalendarEvent> = calendar_service::get_calendar_collection(client).find(filter).await?.try_collect().await?;
+    let range = remote_calendar.listed_range();
+    for local in synced {
+        let external_id = local.external_id.clone().unwrap_or_default();
+        if listed.contains(&external_id) {
+            continue;
+        }
+        if range.is_some_and(|(start, end)| recurrence_service::occurrences(&local, start, end).is_empty()) {
+            continue;
+        }
+        pull_event(client, state, RemoteEvent::removed(external_id, &local.title), report).await?;
+    }
+    Ok(())
+}
+
+/// Applies a series or single event.
+async fn pull_event(
+    client: &Client,
+    state: &CalendarSyncState,
+    remote: RemoteEvent,
+    report: &mut CalendarSyncReport,
+) -> Result<(), CalendarSyncServiceError> {
+    let collection = calendar_service::get_calendar_collection(client);
+    let existing = find_synced(client, state, &remote.id).await?;
+    let now = Utc::now();
+
+    let Some(event) = &remote.event else {
+        let Some(local) = existing.filter(|local| local.deleted_at.is_none()) else {
+            return Ok(());
+        };
+        // Some providers report a cancelled occurrence by the id of its exception
+        if let (Some(series_id), Some(start)) = (local.recurring_event_id, local.recurrence_id) {
+            if let Some(series) = collection.find_one(doc! { "_id": series_id, "deleted_at": null }).await? {
+                cancel_occurrence(client, &series, start, Some(local), &remote, report).await?;
+            }
+            return Ok(());
+        }
+        if local_wins(&local, &remote) {
+            report.conflicts += 1;
+            return Ok(());
+        }
+        // Exceptions go to the trash with their series
+        let filter = doc! { "$or": [{ "_id": local.id }, { "recurring_event_id": local.id }], "deleted_at": null };
+        let update = doc! { "$set": { "deleted_at": datetime::to_bson(now), "synced_at": datetime::to_bson(now) } };
+        collection.update_many(filter, update).await?;
+        calendar_change_service::record_changes(client, local.id.as_slice()).await?;
+        report.pulled.deleted += 1;
+        return Ok(());
+    };
+
+    let mut event = match event {
+        Ok(event) => event.clone(),
+        Err(reason) => {
+            report.warnings.push(format!("Skipped \"{}\": {}", remote.title, reason));
+            return Ok(());
+        }
+    };
+    event.external_id = Some(remote.id.clone());
+    event.updated_at = now;
+    event.synced_at = Some(now);
+    let local = match existing {
+        Some(local) => local,
+        None => {
+            event.id = Some(ObjectId::new());
+            event.calendar_id = Some(state.calendar_id.clone());
+            event.created_at = now;
+            match collection.insert_one(&event).await {
+                Ok(_) => {
+                    calendar_change_service::record_changes(client, event.id.as_slice()).await?;
+                    report.pulled.created += 1;
+                    return Ok(());
+                }
+                // A sync running alongside this one stored it first, so it is updated instead
+                Err(e) if is_duplicate_key(&e) => match find_synced(client, state, &remote.id).await? {
+                    Some(local) => local,
+                    None => return Err(e.into()),
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+    };
+    if local.deleted_at.is_none() && calendar_service::unchanged(&local, &event) {
+        return Ok(());
+    }
+    if local_wins(&local, &remote) {
+        report.conflicts += 1;
+        return Ok(());
+    }
+    let mut fields = calendar_service::event_fields(&event);
+    fields.insert("synced_at", datetime::to_bson(now));
+    let mut update = doc! { "$set": fields };
+    if let Some(deleted_at) = local.deleted_at {
+        // Changed remotely after it was trashed here, so it comes back with its exceptions
+        update.insert("$unset", doc! { "deleted_at": "" });
+        collection
+            .update_many(
+                doc! { "recurring_event_id": local.id, "deleted_at": datetime::to_bson(deleted_at) },
+                doc! { "$unset": { "deleted_at": "" } },
+            )
+            .await?;
+    }
+    collection.update_one(doc! { "_id": local.id }, update).await?;
+    calendar_change_service::record_changes(client, local.id.as_slice()).await?;
+    report.pulled.updated += 1;
+    Ok(())
+}
+
+/// Applies a changed or cancelled occurrence of a series.
+async fn pull_occurrence(
+    client: &Client,
+    state: &CalendarSyncState,
+    remote: RemoteEvent,
+    report: &mut CalendarSyncReport,
+) -> Result<(), CalendarSyncServiceError> {
+    let Some((series_id, start)) = remote.occurrence_of.clone() else {
+        return Ok(());
+    };
+    // Occurrences of series trashed here are left to the deletion of the series
+    let Some(series) = find_synced(client, state, &series_id).await?.filter(|series| series.deleted_at.is_none()) else {
+        return Ok(());
+    };
+    let existing = calendar_service::find_exception(client, &series, start).await?;
+
+    let mut event = match &remote.event {
+        None => return cancel_occurrence(client, &series, start, existing, &remote, report).await,
+        Some(Ok(event)) => event.clone(),
+        Some(Err(reason)) => {
+            report.warnings.push(format!("Skipped \"{}\": {}", remote.title, reason));
+            return Ok(());
+        }
+    };
+    if let Some(local) = &existing {
+        if calendar_service::unchanged(local, &event) {
+            return Ok(());
+        }
+        if local_wins(local, &remote) {
+            report.conflicts += 1;
+            return Ok(());
+        }
+    }
+    let now = Utc::now();
+    event.updated_at = now;
+    let exception = calendar_service::save_exception(client, &series, start, event).await?;
+    let update = doc! { "$set": { "external_id": &remote.id, "synced_at": datetime::to_bson(now) } };
+    calendar_service::get_calendar_collection(client)
+        .update_one(doc! { "_id": exception.id }, update)
+        .await?;
+    calendar_change_service::record_changes(client, series.id.as_slice()).await?;
+    match existing {
+        Some(_) => report.pulled.updated += 1,
+        None => report.pulled.created += 1,
+    }
+    Ok(())
+}
+
+/// Excludes a cancelled occurrence from its series, dropping its exception.
+async fn cancel_occurrence(
+    client: &Client,
+    series: &CalendarEvent,
+    start: DateTime<Utc>,
+    existing: Option<CalendarEvent>,
+    remote: &RemoteEvent,
+    report: &mut CalendarSyncReport,
+) -> Result<(), CalendarSyncServiceError> {
+    let collection = calendar_service::get_calendar_collection(client);
+    if series.excluded_dates.contains(&start) && existing.is_none() {
+        return Ok(());
+    }
+    if local_wins(existing.as_ref().unwrap_or(series), remote) {
+        report.conflicts += 1;
+        return Ok(());
+    }
+    // The series only counts as synced if it had nothing else to push
+    let now = Utc::now();
+    let mut fields = doc! { "updated_at": datetime::to_bson(now) };
+    if pending_change(series).is_none() {
+        fields.insert("synced_at", datetime::to_bson(now));
+    }
+    let update = doc! { "$addToSet": { "excluded_dates": datetime::to_bson(start) }, "$set": fields };
+    collection.update_one(doc! { "_id": series.id }, update).await?;
+    calendar_service::trash_exceptions(client, doc! { "recurring_event_id": series.id, "recurrence_id": datetime::to_bson(start) })
+        .await?;
+    calendar_change_service::record_changes(client, series.id.as_slice()).await?;
+    report.pulled.deleted += 1;
+    Ok(())
+}
+
+/// Fetches the synced series again when the provider removed events by ids
+/// no local event has, which may have been occurrences of those series.
+async fn refresh_series(
+    client: &Client,
+    remote_calendar: &impl RemoteCalendar,
+    state: &CalendarSyncState,
+    report: &mut CalendarSyncReport,
+) -> Result<(), CalendarSyncServiceError> {
+    let filter = doc! {
+        "calendar_id": &state.calendar_id,
+        "recurring_event_id": null,
+        "recurrence_rule": { "$ne": null },
+        "external_id": { "$type": "string" },
+        "deleted_at": null
+    };
+    let series: Vec<CalendarEvent> = calendar_service::get_calendar_collection(client).find(filter).await?.try_collect().await?;
+    for series in series {
+        let external_id = series.external_id.as_deref().unwrap_or_default();
+        match remote_calendar.series(external_id).await? {
+            Some(remote) => pull_event(client, state, remote, report).await?,
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+/// Sends the local changes made since the last sync. Series are pushed before
+/// their exceptions, which are addressed through them.
+async fn push(
+    client: &Client,
+    remote_calendar: &impl RemoteCalendar,
+    state: &CalendarSyncState,
+    report: &mut CalendarSyncReport,
+) -> Result<(), CalendarSyncServiceError> {
+    let collection = calendar_service::get_calendar_collection(client);
+
+    // Exceptions are deleted with their series
+    let filter = doc! {
+        "calendar_id": &state.calendar_id,
+        "recurring_event_id": null,
+        "external_id": { "$type": "string" },
+        "deleted_at": { "$ne": null },
+        "$expr": { "$gt": ["$deleted_at", "$synced_at"] }
+    };
+    let trashed: Vec<CalendarEvent> = collection.find(filter).await?.try_collect().await?;
+    for event in trashed {
+        let external_id = event.external_id.as_deref().unwrap_or_default();
+        match remote_calendar.delete(external_id).await {
+            Ok(()) => report.pushed.deleted += 1,
+            Err(e) => {
+                report.warnings.push(format!("Couldn't delete \"{}\": {}", event.title, warning(e)?));
+                continue;
+            }
+        }
+        let filter = doc! { "$or": [{ "_id": event.id }, { "recurring_event_id": event.id }] };
+        collection
+            .update_many(filter, doc! { "$set": { "synced_at": datetime::to_bson(Utc::now()) } })
+            .await?;
+    }
+
+    let filter = doc! {
+        "calendar_id": &state.calendar_id,
+        "deleted_at": null,
+        "$expr": { "$gt": ["$updated_at", "$synced_at"] }
+    };
+    let changed: Vec<CalendarEvent> = collection.find(filter).await?.try_collect().await?;
+    let (exceptions, events): (Vec<_>, Vec<_>) = changed.into_iter().partition(CalendarEvent::is_exception);
+    for event in events {
+        let pushed = match &event.external_id {
+            Some(external_id) => match remote_calendar.update(external_id, &event).await {
+                Ok(true) => Ok((external_id.clone(), false)),
+                // Deleted remotely since the last sync; local changes win, so it is created again
+                Ok(false) => remote_calendar.insert(&event).await.map(|id| (id, true)),
+                Err(e) => Err(e),
+            },
+            None => remote_calendar.insert(&event).await.map(|id| (id, true)),
+        };
+        let (external_id, created) = match pushed {
+            Ok(pushed) => pushed,
+            Err(e) => {
+                report.warnings.push(format!("Couldn't push \"{}\": {}", event.title, warning(e)?));
+                continue;
+            }
+        };
+        match created {
+            true => report.pushed.created += 1,
+            false => report.pushed.updated += 1,
+        }
+        let update = doc! { "$set": { "external_id": external_id, "synced_at": datetime::to_bson(event.updated_at) } };
+        collection.update_one(doc! { "_id": event.id }, update).await?;
+    }
+
+    for event in exceptions {
+        let series = collection.find_one(doc! { "_id": event.recurring_event_id, "deleted_at": null }).await?;
+        let Some((series, series_external_id)) =
+            series.and_then(|series| series.external_id.clone().map(|external_id| (series, external_id)))
+        else {
+            report.warnings.push(format!("Couldn't push \"{}\": its series isn't synced", event.title));
+            continue;
+        };
+        let start = event.recurrence_id.unwrap_or(event.start_time);
+        let occurrence_id = match &event.external_id {
+            Some(external_id) => Ok(Some(external_id.clone())),
+            None => remote_calendar.occurrence_id(&series_external_id, &series, start).await,
+        };
+        let pushed = match occurrence_id {
+            Ok(Some(occurrence_id)) => remote_calendar.update(&occurrence_id, &event).await.map(|found| found.then_some(occurrence_id)),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        let occurrence_id = match pushed {
+            Ok(Some(occurrence_id)) => occurrence_id,
+            Ok(None) => {
+                report.warnings.push(format!("Couldn't push \"{}\": the occurrence wasn't found", event.title));
+                continue;
+            }
+            Err(e) => {
+                report.warnings.push(format!("Couldn't push \"{}\": {}", event.title, warning(e)?));
+                continue;
+            }
+        };
+        report.pushed.updated += 1;
+        let update = doc! { "$set": { "external_id": occurrence_id, "synced_at": datetime::to_bson(event.updated_at) } };
+        collection.update_one(doc! { "_id": event.id }, update).await?;
+    }
+    Ok(())
+}
+
+/// The message of an error that only fails pushing one event, which the sync
+/// reports and moves past; other errors end the sync.
+fn warning(error: CalendarSyncServiceError) -> Result<String, CalendarSyncServiceError> {
+    match error {
+        CalendarSyncServiceError::ApiError(message) | CalendarSyncServiceError::Unsupported(message) => Ok(message),
+        e => Err(e),
+    }
+}
+
+/// When the event last changed here, if that was after it last matched the
+/// provider.
+fn pending_change(event: &CalendarEvent) -> Option<DateTime<Utc>> {
+    let changed = event.deleted_at.map_or(event.updated_at, |deleted_at| deleted_at.max(event.updated_at));
+    match event.synced_at {
+        Some(synced_at) if changed <= synced_at => None,
+        _ => Some(changed),
+    }
+}
+
+/// Whether a local change is kept over a conflicting remote change.
+fn local_wins(local: &CalendarEvent, remote: &RemoteEvent) -> bool {
+    pending_change(local).is_some_and(|changed| remote.updated.is_none_or(|updated| changed > updated))
+}
+
+/// The synced event with a provider id, live ones first.
+async fn find_synced(client: &Client, state: &CalendarSyncState, external_id: &str) -> Result<Option<CalendarEvent>, Error> {
+    calendar_service::get_calendar_collection(client)
+        .find_one(doc! { "calendar_id": &state.calendar_id, "external_id": external_id })
+        .sort(doc! { "deleted_at": 1 })
+        .await
+}
+
+/// First and last day of an all-day event in its time zone, the last one
+/// exclusive as providers expect.
+pub(crate) fn all_day_dates(event: &CalendarEvent) -> (NaiveDate, NaiveDate) {
+    let tz = event.tz();
+    let start = event.start_time.with_timezone(&tz).date_naive();
+    let end = event.end_time.with_timezone(&tz);
+    // An end at midnight already closes the previous day
+    match end.time() == NaiveTime::MIN && end.date_naive() > start {
+        true => (start, end.date_naive()),
+        false => (start, end.date_naive() + Duration::days(1)),
+    }
+}
+
+/// Fails unless the response is a success, with the error the provider sent.
+pub(crate) async fn check(response: reqwest::Response) -> Result<reqwest::Response, CalendarSyncServiceError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let message = format!("{} {}", status, response.text().await.unwrap_or_default().trim());
+    match status {
+        StatusCode::UNAUTHORIZED => Err(CalendarSyncServiceError::Unauthorized(message)),
+        _ => Err(CalendarSyncServiceError::ApiError(message)),
+    }
+}
+
+pub(crate) async fn json<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, CalendarSyncServiceError> {
+    Ok(check(response).await?.json().await?)
+}
+
+async fn get_state(
+    client: &Client,
+    user: &str,
+    provider: CalendarProvider,
+    remote_calendar_id: &str,
+) -> Result<CalendarSyncState, Error> {
+    let state = get_sync_collection(client)
+        .find_one(doc! { "user": user, "provider": provider.name(), "remote_calendar_id": remote_calendar_id })
+        .await?;
+    Ok(state.unwrap_or_else(|| CalendarSyncState::new(user, provider.name(), remote_calendar_id)))
+}
+
+async fn save_state(client: &Client, state: &CalendarSyncState) -> Result<(), Error> {
+    let update = doc! {
+        "$set": {
+            "calendar_id": &state.calendar_id,
+            "sync_token": &state.sync_token,
+            "last_synced_at": state.last_synced_at.map(datetime::to_bson)
+        }
+    };
+    get_sync_collection(client)
+        .update_one(
+            doc! { "user": &state.user, "provider": &state.provider, "remote_calendar_id": &state.remote_calendar_id },
+            update,
+        )
+        .upsert(true)
+        .await?;
+    Ok(())
+}
+
+/// Helper function to get the "calendar_sync_states" collection.
+fn get_sync_collection(client: &Client) -> mongodb::Collection<CalendarSyncState> {
+    client.database("organise").collection::<CalendarSyncState>("calendar_sync_states")
+}
+
+// Custom function to convert CalendarSyncServiceError to HttpResponse
+pub fn error_response(error: CalendarSyncServiceError) -> HttpResponse {
+    match error {
+        CalendarSyncServiceError::DatabaseError(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+        e @ CalendarSyncServiceError::Unauthorized(_) => HttpResponse::Unauthorized().body(e.to_string()),
+        e @ (CalendarSyncServiceError::ApiError(_)
+        | CalendarSyncServiceError::SyncTokenExpired
+        | CalendarSyncServiceError::Unsupported(_)) => HttpResponse::BadGateway().body(e.to_string()),
+        CalendarSyncServiceError::CalendarError(e) => calendar_service::error_response(e),
+        CalendarSyncServiceError::OAuthError(e) => calendar_oauth_service::error_response(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::indexes;
+    use crate::routes::auth::LOCAL_USER;
+    use crate::services::google_calendar_service::tests::{cancelled, google_event, MockGoogle, MockState};
+
+    fn event(updated_at: DateTime<Utc>, synced_at: Option<DateTime<Utc>>) -> CalendarEvent {
+        let start = "2024-07-01T10:00:00Z".parse().unwrap();
+        let mut event = CalendarEvent::new("Lunch".to_string(), None, start, start + Duration::hours(1), None, false, None, Vec::new(), None);
+        event.updated_at = updated_at;
+        event.synced_at = synced_at;
+        event
+    }
+
+    fn remote(updated: Option<DateTime<Utc>>) -> RemoteEvent {
+        RemoteEvent { updated, ..RemoteEvent::removed("a".to_string(), "Lunch") }
+    }
+
+    #[test]
+    fn only_changes_after_the_last_sync_are_pending() {
+        let now = Utc::now();
+        assert_eq!(pending_change(&event(now, None)), Some(now));
+        assert_eq!(pending_change(&event(now, Some(now))), None);
+        let mut trashed = event(now - Duration::hours(1), Some(now - Duration::minutes(30)));
+        trashed.deleted_at = Some(now);
+        assert_eq!(pending_change(&trashed), Some(now));
+    }
+
+    #[test]
+    fn the_later_change_wins_a_conflict() {
+        let now = Utc::now();
+        let changed = event(now, Some(now - Duration::hours(1)));
+        assert!(local_wins(&changed, &remote(Some(now - Duration::minutes(1)))));
+        assert!(!local_wins(&changed, &remote(Some(now + Duration::minutes(1)))));
+        // Deletions carry no time, so a pending local change is kept
+        assert!(local_wins(&changed, &remote(None)));
+        assert!(!local_wins(&event(now, Some(now)), &remote(None)));
+    }
+
+    async fn test_client() -> Client {
+        let uri = std::env::var("MONGODB_TEST_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
+        let client = Client::with_uri_str(uri).await.unwrap();
+        indexes::ensure_indexes(&client).await.unwrap();
+        client
+    }
+
+    /// Sync state of a calendar of its own, for one test.
+    fn test_state() -> CalendarSyncState {
+        CalendarSyncState::new(LOCAL_USER, CalendarProvider::Google.name(), &format!("test-{}", ObjectId::new().to_hex()))
+    }
+
+    fn mock(events: Vec<serde_json::Value>) -> MockGoogle {
+        MockGoogle::start(MockState { events, sync_token: "token-1".to_string(), ..Default::default() })
+    }
+
+    async fn purge(client: &Client, state: &CalendarSyncState) {
+        calendar_service::get_calendar_collection(client)
+            .delete_many(doc! { "calendar_id": &state.calendar_id })
+            .await
+            .unwrap();
+    }
+
+    async fn pull_once(client: &Client, google: &MockGoogle, state: &mut CalendarSyncState) -> CalendarSyncReport {
+        let mut report = CalendarSyncReport { full_sync: state.sync_token.is_none(), ..Default::default() };
+        pull(client, &google.calendar(), state, &mut report).await.unwrap();
+        report
+    }
+
+    async fn push_once(client: &Client, google: &MockGoogle, state: &CalendarSyncState) -> CalendarSyncReport {
+        let mut report = CalendarSyncReport::default();
+        push(client, &google.calendar(), state, &mut report).await.unwrap();
+        report
+    }
+
+    async fn set(client: &Client, id: Option<ObjectId>, fields: mongodb::bson::Document) {
+        calendar_service::get_calendar_collection(client)
+            .update_one(doc! { "_id": id }, doc! { "$set": fields })
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn pull_applies_remote_changes() {
+        let client = test_client().await;
+        let mut state = test_state();
+        let google = mock(["a", "b", "c"].map(|id| google_event(id, id, "2024-06-01T00:00:00Z")).to_vec());
+
+        let report = pull_once(&client, &google, &mut state).await;
+        assert_eq!(report.pulled.created, 3);
+        assert_eq!(state.sync_token.as_deref(), Some("token-1"));
+        let a = find_synced(&client, &state, "a").await.unwrap().unwrap();
+        assert_eq!(a.calendar_id.as_deref(), Some(state.calendar_id.as_str()));
+
+        google.state().changes = vec![google_event("a", "a, moved", &Utc::now().to_rfc3339()), cancelled("b")];
+        let report = pull_once(&client, &google, &mut state).await;
+        assert_eq!((report.pulled.updated, report.pulled.deleted), (1, 1));
+        assert!(!report.full_sync);
+        assert_eq!(find_synced(&client, &state, "a").await.unwrap().unwrap().title, "a, moved");
+        assert!(find_synced(&client, &state, "b").await.unwrap().unwrap().deleted_at.is_some());
+        assert!(find_synced(&client, &state, "c").await.unwrap().unwrap().deleted_at.is_none());
+
+        purge(&client, &state).await;
+        google.stop().await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn push_sends_local_changes() {
+        let client = test_client().await;
+        let state = test_state();
+        let google = mock(Vec::new());
+        let mut local = event(Utc::now(), None);
+        local.id = Some(ObjectId::new());
+        local.calendar_id = Some(state.calendar_id.clone());
+        calendar_service::get_calendar_collection(&client).insert_one(&local).await.unwrap();
+
+        assert_eq!(push_once(&client, &google, &state).await.pushed.created, 1);
+        assert_eq!(google.state().events[0]["summary"], "Lunch");
+        let stored = find_synced(&client, &state, "created1").await.unwrap().unwrap();
+        assert_eq!(stored.id, local.id);
+        assert_eq!(pending_change(&stored), None);
+        let report = push_once(&client, &google, &state).await;
+        assert_eq!((report.pushed.created, report.pushed.updated), (0, 0));
+
+        set(&client, local.id, doc! { "title": "Brunch", "updated_at": datetime::to_bson(Utc::now()) }).await;
+        assert_eq!(push_once(&client, &google, &state).await.pushed.updated, 1);
+        assert_eq!(google.state().events[0]["summary"], "Brunch");
+
+        set(&client, local.id, doc! { "deleted_at": datetime::to_bson(Utc::now()) }).await;
+        assert_eq!(push_once(&client, &google, &state).await.pushed.deleted, 1);
+        assert!(google.state().events.is_empty());
+
+        purge(&client, &state).await;
+        google.stop().await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn conflicting_changes_keep_the_later_one() {
+        let client = test_client().await;
+        let mut state = test_state();
+        let google = mock(vec![google_event("a", "Lunch", "2024-06-01T00:00:00Z")]);
+        pull_once(&client, &google, &mut state).await;
+        let local = find_synced(&client, &state, "a").await.unwrap().unwrap();
+
+        // Changed here after the remote change, so the local change wins and is pushed
+        set(&client, local.id, doc! { "title": "Local", "updated_at": datetime::to_bson(Utc::now()) }).await;
+        google.state().changes = vec![google_event("a", "Remote", &(Utc::now() - Duration::minutes(5)).to_rfc3339())];
+        let report = pull_once(&client, &google, &mut state).await;
+        assert_eq!((report.conflicts, report.pulled.updated), (1, 0));
+        assert_eq!(find_synced(&client, &state, "a").await.unwrap().unwrap().title, "Local");
+        assert_eq!(push_once(&client, &google, &state).await.pushed.updated, 1);
+        assert_eq!(google.state().events[0]["summary"], "Local");
+
+        // Changed remotely after the local change, so the remote change wins
+        set(&client, local.id, doc! { "title": "Local again", "updated_at": datetime::to_bson(Utc::now()) }).await;
+        google.state().changes = vec![google_event("a", "Remote", &(Utc::now() + Duration::minutes(5)).to_rfc3339())];
+        let report = pull_once(&client, &google, &mut state).await;
+        assert_eq!((report.conflicts, report.pulled.updated), (0, 1));
+        assert_eq!(find_synced(&client, &state, "a").await.unwrap().unwrap().title, "Remote");
+
+        purge(&client, &state).await;
+        google.stop().await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn expired_sync_tokens_resync_and_remove_what_is_gone() {
+        let client = test_client().await;
+        let mut state = test_state();
+        let google = mock(["a", "b", "c"].map(|id| google_event(id, id, "2024-06-01T00:00:00Z")).to_vec());
+        pull_once(&client, &google, &mut state).await;
+        let mut unsynced = event(Utc::now(), None);
+        unsynced.id = Some(ObjectId::new());
+        unsynced.calendar_id = Some(state.calendar_id.clone());
+        calendar_service::get_calendar_collection(&client).insert_one(&unsynced).await.unwrap();
+
+        // Deleted while the token expired, so only a full listing shows it is gone
+        {
+            let mut mock_state = google.state();
+            mock_state.events.retain(|event| event["id"] != "b");
+            mock_state.sync_token = "token-2".to_string();
+        }
+        let report = pull_once(&client, &google, &mut state).await;
+        assert!(report.full_sync);
+        assert_eq!(report.pulled.deleted, 1);
+        assert_eq!(state.sync_token.as_deref(), Some("token-2"));
+        assert!(find_synced(&client, &state, "b").await.unwrap().unwrap().deleted_at.is_some());
+        assert!(find_synced(&client, &state, "a").await.unwrap().unwrap().deleted_at.is_none());
+        let unsynced = calendar_service::get_calendar_collection(&client).find_one(doc! { "_id": unsynced.id }).await.unwrap();
+        assert!(unsynced.unwrap().deleted_at.is_none());
+        let listings = google.state().requests.iter().filter(|request| request.starts_with("GET")).count();
+        // Two pages, the refused token, then one page of the two events left
+        assert_eq!(listings, 2 + 1 + 1);
+
+        purge(&client, &state).await;
+        google.stop().await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn provider_ids_are_unique_among_live_events_of_a_calendar() {
+        let client = test_client().await;
+        let mut state = test_state();
+        let google = mock(vec![google_event("a", "Lunch", "2024-06-01T00:00:00Z")]);
+        pull_once(&client, &google, &mut state).await;
+
+        let mut copy = event(Utc::now(), Some(Utc::now()));
+        copy.id = Some(ObjectId::new());
+        copy.calendar_id = Some(state.calendar_id.clone());
+        copy.external_id = Some("a".to_string());
+        let collection = calendar_service::get_calendar_collection(&client);
+        let error = collection.insert_one(&copy).await.unwrap_err();
+        assert!(is_duplicate_key(&error));
+        copy.deleted_at = Some(Utc::now());
+        collection.insert_one(&copy).await.unwrap();
+
+        // The trashed copy doesn't stand in the way of the live event
+        google.state().changes = vec![google_event("a", "Lunch, moved", &Utc::now().to_rfc3339())];
+        let report = pull_once(&client, &google, &mut state).await;
+        assert_eq!(report.pulled.updated, 1);
+        assert_eq!(find_synced(&client, &state, "a").await.unwrap().unwrap().title, "Lunch, moved");
+
+        purge(&client, &state).await;
+        google.stop().await;
+    }
+}
@@ -0,0 +1,699 @@
+//! Real-time collaborative editing of note content.
+//!
+//! Each note being edited gets a room holding an Automerge document whose
+//! `content` text mirrors `Note.content`. Clients speak the Automerge sync
+//! protocol over binary WebSocket frames and exchange presence (name, cursor,
+//! selection) as JSON text frames. Rooms are persisted periodically and when
+//! the last editor leaves.
+//!
+//! The room remembers which document state each stored note version holds, so
+//! an edit made outside the room is applied as a patch against the version it
+//! was based on rather than overwriting what collaborators typed since.
+
+use actix_ws::Session;
+use automerge::sync::{self, SyncDoc};
+use automerge::transaction::Transactable;
+use automerge::{AutoCommit, ChangeHash, ObjId, ObjType, ReadDoc, ROOT};
+use chrono::Utc;
+use futures_util::lock::Mutex as AsyncMutex;
+use mongodb::{Client, bson::{doc, oid::ObjectId, spec::BinarySubtype, Binary, Document}};
+use mongodb::options::ReturnDocument;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::models::datetime;
+use crate::models::note::Note;
+use crate::services::notes_service::{self, NotesServiceError};
+
+/// Key of the text object holding the note content in the shared document.
+const CONTENT_KEY: &str = "content";
+
+/// Number of stored note versions a room remembers the document state of.
+const MAX_VERSIONS: usize = 64;
+
+/// Tracks the collaborative editing rooms of all notes.
+///
+/// The map is only locked to look rooms up, add and remove them; loading and
+/// persisting happen outside it so one slow note doesn't hold up the others.
+#[derive(Default)]
+pub struct CollabHub {
+    rooms: AsyncMutex<HashMap<ObjectId, Arc<Room>>>,
+    next_peer_id: AtomicU64,
+    /// Number of rooms closed so far. A room loaded while another one was
+    /// closed is loaded again, as its note may have been encrypted meanwhile.
+    closures: AtomicU64,
+}
+
+/// The shared document of one note and the editors connected to it.
+pub struct Room {
+    note_id: ObjectId,
+    state: Mutex<RoomState>,
+    /// Serialises writes of the note, so a version is always recorded with
+    /// the document state it was written from.
+    writes: AsyncMutex<()>,
+}
+
+struct RoomState {
+    doc: AutoCommit,
+    text: ObjId,
+    peers: HashMap<u64, Peer>,
+    /// Connections that opened the room and haven't released it yet.
+    members: usize,
+    /// Set once the note was encrypted; the room is never persisted again.
+    closed: bool,
+    /// Document heads whose text each stored note version holds.
+    versions: BTreeMap<i64, Vec<ChangeHash>>,
+    /// Whether the document changed since it was last persisted.
+    dirty: bool,
+}
+
+/// A merged document waiting for the note to be written.
+pub struct PendingEdit {
+    doc: AutoCommit,
+    content: String,
+}
+
+struct Peer {
+    session: Session,
+    sync_state: sync::State,
+    presence: Option<Value>,
+}
+
+/// Outgoing frames collected under the room lock and sent after releasing it.
+enum Outgoing {
+    Binary(Session, Vec<u8>),
+    Text(Session, String),
+}
+
+impl CollabHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the room of an active note, if anyone is editing it.
+    pub async fn active_room(&self, note_id: &str) -> Option<Arc<Room>> {
+        let note_id = ObjectId::parse_str(note_id).ok()?;
+        self.rooms.lock().await.get(&note_id).cloned()
+    }
+
+    /// Returns the room of a note, loading its document on first use. Every
+    /// room opened must be released with `release_room`.
+    pub async fn open_room(&self, client: &Client, note_id: &str) -> Result<Arc<Room>, NotesServiceError> {
+        let object_id = ObjectId::parse_str(note_id)?;
+        loop {
+            if let Some(room) = self.join_open_room(&object_id).await {
+                return Ok(room);
+            }
+
+            let closures = self.closures.load(Ordering::SeqCst);
+            let room = Room::load(client, object_id).await?;
+            let mut rooms = self.rooms.lock().await;
+            if self.closures.load(Ordering::SeqCst) != closures {
+                continue;
+            }
+            // Another connection may have loaded the room in the meantime
+            if let Some(open) = rooms.get(&object_id) {
+                open.state.lock().unwrap().members += 1;
+                return Ok(open.clone());
+            }
+            rooms.insert(object_id, room.clone());
+            return Ok(room);
+        }
+    }
+
+    /// Joins the room of a note if it is already open.
+    async fn join_open_room(&self, note_id: &ObjectId) -> Option<Arc<Room>> {
+        let rooms = self.rooms.lock().await;
+        let room = rooms.get(note_id)?;
+        room.state.lock().unwrap().members += 1;
+        Some(room.clone())
+    }
+
+    /// Allocates an id for a new connection.
+    pub fn next_peer_id(&self) -> u64 {
+        self.next_peer_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Gives up a connection's hold on a room, persisting and closing it once
+    /// nobody holds it. A room that fails to persist stays open and is
+    /// retried by `persist_all`.
+    pub async fn release_room(&self, client: &Client, room: &Arc<Room>) -> Result<(), NotesServiceError> {
+        let members = {
+            let mut state = room.state.lock().unwrap();
+            state.members = state.members.saturating_sub(1);
+            state.members
+        };
+        if members == 0 {
+            room.persist(client).await?;
+            forget_room(&mut *self.rooms.lock().await, room);
+        }
+        Ok(())
+    }
+
+    /// Closes the room of a note that was encrypted, disconnecting its editors,
+    /// and deletes the saved document so no plain text of the note is left.
+    pub async fn close_room(&self, client: &Client, note_id: &str) -> Result<(), NotesServiceError> {
+        let object_id = ObjectId::parse_str(note_id)?;
+        let room = {
+            let mut rooms = self.rooms.lock().await;
+            self.closures.fetch_add(1, Ordering::SeqCst);
+            rooms.remove(&object_id)
+        };
+        let sessions = match &room {
+            Some(room) => {
+                // Wait for a write in progress, which may still save the document
+                let _writing = room.writes.lock().await;
+                let mut state = room.state.lock().unwrap();
+                state.closed = true;
+                state.dirty = false;
+                state.peers.drain().map(|(_, peer)| peer.session).collect()
+            }
+            None => Vec::new(),
+        };
+        get_documents_collection(client).delete_one(doc! { "_id": object_id }).await?;
+
+        for session in sessions {
+            let _ = session.close(None).await;
+        }
+        Ok(())
+    }
+
+    /// Persists every room with unsaved changes and closes those nobody
+    /// holds any more.
+    pub async fn persist_all(&self, client: &Client) {
+        let pending: Vec<Arc<Room>> = self
+            .rooms
+            .lock()
+            .await
+            .values()
+            .filter(|room| {
+                let state = room.state.lock().unwrap();
+                state.dirty || state.members == 0
+            })
+            .cloned()
+            .collect();
+
+        let mut persisted = Vec::new();
+        for room in pending {
+            match room.persist(client).await {
+                Ok(()) => persisted.push(room),
+                Err(e) => eprintln!("Failed to persist collaborative note {}: {}", room.note_id, e),
+            }
+        }
+
+        let mut rooms = self.rooms.lock().await;
+        for room in &persisted {
+            forget_room(&mut rooms, room);
+        }
+    }
+}
+
+/// Removes a room nobody holds from the open rooms. A connection may have
+/// joined it again while it was persisted, and it may have been closed and
+/// replaced in the meantime.
+fn forget_room(rooms: &mut HashMap<ObjectId, Arc<Room>>, room: &Arc<Room>) {
+    let unused = room.state.lock().unwrap().members == 0;
+    if unused && rooms.get(&room.note_id).is_some_and(|open| Arc::ptr_eq(open, room)) {
+        rooms.remove(&room.note_id);
+    }
+}
+
+impl Room {
+    /// Loads the document of a note into a new room held by one connection.
+    async fn load(client: &Client, note_id: ObjectId) -> Result<Arc<Room>, NotesServiceError> {
+        let note = notes_service::get_note_by_id(client, &note_id.to_hex()).await?;
+        if note.is_encrypted() {
+            return Err(NotesServiceError::EncryptedNote("collaborative editing"));
+        }
+        let saved = get_documents_collection(client)
+            .find_one(doc! { "_id": note_id })
+            .await?
+            .and_then(|saved| saved.get_binary_generic("state").ok().cloned());
+        let (mut doc, text) = load_document(saved.as_deref(), &note.content);
+        let versions = BTreeMap::from([(note.version, doc.get_heads())]);
+        Ok(Arc::new(Room {
+            note_id,
+            state: Mutex::new(RoomState { doc, text, peers: HashMap::new(), members: 1, closed: false, versions, dirty: false }),
+            writes: AsyncMutex::new(()),
+        }))
+    }
+
+    /// Registers a connection and sends it the initial sync message and the
+    /// presence of everyone already in the room.
+    pub async fn join(&self, peer_id: u64, session: Session) {
+        let outgoing = {
+            let mut state = self.state.lock().unwrap();
+            let peers: Vec<Value> = state
+                .peers
+                .iter()
+                .filter_map(|(id, peer)| peer.presence.as_ref().map(|p| json!({ "peer_id": id, "state": p })))
+                .collect();
+            let welcome = json!({ "type": "welcome", "peer_id": peer_id, "peers": peers }).to_string();
+
+            state.peers.insert(peer_id, Peer { session: session.clone(), sync_state: sync::State::new(), presence: None });
+            let mut outgoing = vec![Outgoing::Text(session, welcome)];
+            outgoing.extend(state.sync_messages());
+            outgoing
+        };
+        send(outgoing).await;
+    }
+
+    /// Applies a sync message from a peer and forwards new changes to everyone.
+    pub async fn receive_sync(&self, peer_id: u64, data: &[u8]) -> Result<(), String> {
+        let message = sync::Message::decode(data).map_err(|e| e.to_string())?;
+        let outgoing = {
+            let mut guard = self.state.lock().unwrap();
+            let state = &mut *guard;
+            let heads = state.doc.get_heads();
+            let peer = state.peers.get_mut(&peer_id).ok_or("Unknown peer")?;
+            state
+                .doc
+                .sync()
+                .receive_sync_message(&mut peer.sync_state, message)
+                .map_err(|e| e.to_string())?;
+            if state.doc.get_heads() != heads {
+                state.dirty = true;
+            }
+            state.sync_messages()
+        };
+        send(outgoing).await;
+        Ok(())
+    }
+
+    /// Stores a peer's presence (name, cursor, selection, ...) and relays it
+    /// to the other peers.
+    pub async fn receive_presence(&self, peer_id: u64, presence: Value) {
+        let message = json!({ "type": "presence", "peer_id": peer_id, "state": presence }).to_string();
+        let outgoing = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(peer) = state.peers.get_mut(&peer_id) {
+                peer.presence = Some(presence);
+            }
+            state.broadcast_except(peer_id, &message)
+        };
+        send(outgoing).await;
+    }
+
+    /// Removes a connection and tells the others it left.
+    pub async fn leave(&self, peer_id: u64) {
+        let message = json!({ "type": "leave", "peer_id": peer_id }).to_string();
+        let outgoing = {
+            let mut state = self.state.lock().unwrap();
+            state.peers.remove(&peer_id);
+            state.broadcast_except(peer_id, &message)
+        };
+        send(outgoing).await;
+    }
+
+    /// Updates a note edited outside the room (e.g. a plain PUT).
+    ///
+    /// The new content is applied as a patch against the stored version it
+    /// replaces, merged with whatever collaborators typed since, and only
+    /// shown to them once the note has been written.
+    pub async fn update_note(
+        &self,
+        client: &Client,
+        mut note: Note,
+        extract_todos: bool,
+        expected_versions: Option<&[i64]>,
+    ) -> Result<Note, NotesServiceError> {
+        let _writing = self.writes.lock().await;
+        let note_id = self.note_id.to_hex();
+        let current = notes_service::get_note_by_id(client, &note_id).await?;
+        let Some(edit) = self.state.lock().unwrap().prepare_edit(current.version, &note.content) else {
+            return Err(NotesServiceError::VersionMismatch(Box::new(current)));
+        };
+
+        note.content = edit.content.clone();
+        // Rooms only exist for notes that aren't encrypted
+        let updated = notes_service::update_note(client, &note_id, note, extract_todos, expected_versions, true).await?;
+        let outgoing = self.state.lock().unwrap().commit_edit(edit, &updated);
+        send(outgoing).await;
+        Ok(updated)
+    }
+
+    /// Writes the merged text back to the note and saves the document.
+    async fn persist(&self, client: &Client) -> Result<(), NotesServiceError> {
+        let _writing = self.writes.lock().await;
+        let (content, saved, heads) = {
+            let mut state = self.state.lock().unwrap();
+            if !state.dirty || state.closed {
+                return Ok(());
+            }
+            state.dirty = false;
+            let text = state.text.clone();
+            (state.doc.text(&text).unwrap_or_default(), state.doc.save(), state.doc.get_heads())
+        };
+
+        match save_document(client, self.note_id, &content, saved).await {
+            Ok(written) => {
+                if let Some((version, written)) = written {
+                    let outgoing = self.state.lock().unwrap().commit_write(version, heads, &content, &written);
+                    send(outgoing).await;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // Try again on the next round
+                self.state.lock().unwrap().dirty = true;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl RoomState {
+    /// Applies `content` as a change to what the note held at `base_version`
+    /// and merges it with the current document, leaving the room untouched.
+    ///
+    /// Returns `None` if the room doesn't know the document at that version.
+    fn prepare_edit(&mut self, base_version: i64, content: &str) -> Option<PendingEdit> {
+        // Versions the room didn't write only changed fields other than the content
+        let heads = self.versions.range(..=base_version).next_back()?.1.clone();
+        let mut patch = self.doc.fork_at(&heads).ok()?;
+        patch.update_text(&self.text, content).ok()?;
+        let mut doc = self.doc.fork();
+        doc.merge(&mut patch).ok()?;
+        let content = doc.text(&self.text).ok()?;
+        Some(PendingEdit { doc, content })
+    }
+
+    /// Merges an edit whose note has been written as `saved` into the room
+    /// and returns the sync messages announcing it.
+    fn commit_edit(&mut self, edit: PendingEdit, saved: &Note) -> Vec<Outgoing> {
+        let mut doc = edit.doc;
+        // Writing the note may have added todo link markers to the content
+        if saved.content != edit.content {
+            let _ = doc.update_text(&self.text, &saved.content);
+        }
+        let heads = doc.get_heads();
+        if self.doc.merge(&mut doc).is_ok() {
+            self.record_version(saved.version, heads);
+        }
+        if self.doc.text(&self.text).map(|text| text != saved.content).unwrap_or(true) {
+            self.dirty = true;
+        }
+        self.sync_messages()
+    }
+
+    /// Records the note version written from the document at `heads`. Where
+    /// writing the note changed `sent`, e.g. adding todo link markers, the
+    /// change is merged in as a patch, so typing since isn't lost, and
+    /// announced to the peers.
+    fn commit_write(&mut self, version: i64, heads: Vec<ChangeHash>, sent: &str, written: &str) -> Vec<Outgoing> {
+        if sent == written {
+            self.record_version(version, heads);
+            return Vec::new();
+        }
+        let Ok(mut patch) = self.doc.fork_at(&heads) else {
+            return Vec::new();
+        };
+        if patch.update_text(&self.text, written).is_err() || self.doc.merge(&mut patch).is_err() {
+            return Vec::new();
+        }
+        self.record_version(version, patch.get_heads());
+        if self.doc.text(&self.text).map(|text| text != written).unwrap_or(true) {
+            self.dirty = true;
+        }
+        self.sync_messages()
+    }
+
+    /// Remembers the document heads a stored note version was written from.
+    fn record_version(&mut self, version: i64, heads: Vec<ChangeHash>) {
+        self.versions.insert(version, heads);
+        while self.versions.len() > MAX_VERSIONS {
+            self.versions.pop_first();
+        }
+    }
+
+    /// Generates pending sync messages for every peer.
+    fn sync_messages(&mut self) -> Vec<Outgoing> {
+        let doc = &mut self.doc;
+        self.peers
+            .values_mut()
+            .filter_map(|peer| {
+                doc.sync()
+                    .generate_sync_message(&mut peer.sync_state)
+                    .map(|message| Outgoing::Binary(peer.session.clone(), message.encode()))
+            })
+            .collect()
+    }
+
+    fn broadcast_except(&self, peer_id: u64, message: &str) -> Vec<Outgoing> {
+        self.peers
+            .iter()
+            .filter(|(id, _)| **id != peer_id)
+            .map(|(_, peer)| Outgoing::Text(peer.session.clone(), message.to_string()))
+            .collect()
+    }
+}
+
+/// Sends frames; peers that have disconnected are cleaned up by their own
+/// connection task.
+async fn send(outgoing: Vec<Outgoing>) {
+    for frame in outgoing {
+        let _ = match frame {
+            Outgoing::Binary(mut session, data) => session.binary(data).await,
+            Outgoing::Text(mut session, text) => session.text(text).await,
+        };
+    }
+}
+
+/// Loads the saved document, bringing it up to date with the note content if
+/// the note was edited without the room, or starts a new one.
+fn load_document(saved: Option<&[u8]>, content: &str) -> (AutoCommit, ObjId) {
+    if let Some(mut doc) = saved.and_then(|bytes| AutoCommit::load(bytes).ok()) {
+        if let Ok(Some((_, text))) = doc.get(ROOT, CONTENT_KEY) {
+            if doc.text(&text).map(|current| current != content).unwrap_or(true) {
+                let _ = doc.update_text(&text, content);
+            }
+            return (doc, text);
+        }
+    }
+
+    let mut doc = AutoCommit::new();
+    let text = doc
+        .put_object(ROOT, CONTENT_KEY, ObjType::Text)
+        .expect("putting an object on the root of a new document cannot fail");
+    let _ = doc.splice_text(&text, 0, 0, content);
+    (doc, text)
+}
+
+/// Saves the document state and copies the merged text into the note,
+/// syncing its linked todos when the note extracts them.
+///
+/// Returns the note version and the content written if the note was written.
+/// Notes that have been encrypted or trashed are left alone.
+async fn save_document(
+    client: &Client,
+    note_id: ObjectId,
+    content: &str,
+    saved: Vec<u8>,
+) -> Result<Option<(i64, String)>, NotesServiceError> {
+    let notes = notes_service::get_notes_collection(client);
+    let Some(note) = notes.find_one(doc! { "_id": note_id, "encryption": null, "deleted_at": null }).await? else {
+        return Ok(None);
+    };
+
+    let state = Binary { subtype: BinarySubtype::Generic, bytes: saved };
+    get_documents_collection(client)
+        .update_one(
+            doc! { "_id": note_id },
+            doc! { "$set": { "state": state, "updated_at": datetime::to_bson(Utc::now()) } },
+        )
+        .upsert(true)
+        .await?;
+
+    // Notes must not be empty; keep the last non-empty content until someone types again.
+    // Never write plain text over a note that was encrypted while the room was open.
+    if content.is_empty() {
+        return Ok(None);
+    }
+    // Ticked items update their todos; new items get todos and link markers
+    let content = match note.extract_todos == Some(true) {
+        true => notes_service::sync_linked_todos(client, note_id, &note.title, content).await?,
+        false => content.to_string(),
+    };
+    let note = notes
+        .find_one_and_update(
+            doc! { "_id": note_id, "encryption": null, "deleted_at": null, "content": { "$ne": &content } },
+            doc! {
+                "$set": { "content": &content, "updated_at": datetime::to_bson(Utc::now()) },
+                "$inc": { "version": 1 }
+            },
+        )
+        .return_document(ReturnDocument::After)
+        .await?;
+    Ok(note.map(|note| (note.version, content)))
+}
+
+/// Helper function to get the "note_documents" collection holding saved CRDT state.
+fn get_documents_collection(client: &Client) -> mongodb::Collection<Document> {
+    let db = client.database("organise");
+    db.collection::<Document>("note_documents")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room_state(content: &str, version: i64) -> RoomState {
+        let (mut doc, text) = load_document(None, content);
+        let versions = BTreeMap::from([(version, doc.get_heads())]);
+        RoomState { doc, text, peers: HashMap::new(), members: 1, closed: false, versions, dirty: false }
+    }
+
+    fn text(state: &RoomState) -> String {
+        state.doc.text(&state.text).unwrap()
+    }
+
+    fn stored(content: &str, version: i64) -> Note {
+        let mut note = Note::new("Title".to_string(), content.to_string());
+        note.version = version;
+        note
+    }
+
+    #[test]
+    fn external_edit_keeps_unsaved_collaborator_edits() {
+        let mut state = room_state("hello world", 1);
+        let text_id = state.text.clone();
+        state.doc.update_text(&text_id, "hello brave world").unwrap();
+
+        let edit = state.prepare_edit(1, "hello world!").unwrap();
+        assert_eq!(edit.content, "hello brave world!");
+    }
+
+    #[test]
+    fn prepared_edit_leaves_the_room_untouched_until_committed() {
+        let mut state = room_state("draft", 1);
+        let edit = state.prepare_edit(1, "final draft").unwrap();
+        assert_eq!(text(&state), "draft");
+
+        state.commit_edit(edit, &stored("final draft", 2));
+        assert_eq!(text(&state), "final draft");
+        assert!(!state.dirty);
+        assert!(state.versions.contains_key(&2));
+    }
+
+    #[test]
+    fn committed_edit_takes_content_added_when_writing_the_note() {
+        let mut state = room_state("- [ ] task", 1);
+        let edit = state.prepare_edit(1, "- [ ] task\n- [ ] other").unwrap();
+
+        state.commit_edit(edit, &stored("- [ ] task\n- [ ] other <!-- todo -->", 2));
+        assert_eq!(text(&state), "- [ ] task\n- [ ] other <!-- todo -->");
+    }
+
+    #[test]
+    fn markers_added_when_writing_merge_with_typing_since() {
+        let mut state = room_state("- [ ] task", 1);
+        let text_id = state.text.clone();
+        state.doc.update_text(&text_id, "- [ ] task\n- [ ] new").unwrap();
+        let heads = state.doc.get_heads();
+        // Someone types while the note is written
+        state.doc.update_text(&text_id, "# Tasks\n- [ ] task\n- [ ] new").unwrap();
+
+        state.commit_write(2, heads, "- [ ] task\n- [ ] new", "- [ ] task\n- [ ] new <!-- todo:abc -->");
+        assert_eq!(text(&state), "# Tasks\n- [ ] task\n- [ ] new <!-- todo:abc -->");
+        assert!(state.versions.contains_key(&2));
+        assert!(state.dirty);
+    }
+
+    #[test]
+    fn unchanged_writes_only_record_the_version() {
+        let mut state = room_state("one", 1);
+        let heads = state.doc.get_heads();
+        state.commit_write(2, heads.clone(), "one", "one");
+        assert_eq!(state.versions.get(&2), Some(&heads));
+        assert!(!state.dirty);
+    }
+
+    #[test]
+    fn edit_against_a_version_written_elsewhere_uses_the_last_known_content() {
+        let mut state = room_state("one", 3);
+        // Version 4 only pinned the note
+        let edit = state.prepare_edit(4, "one two").unwrap();
+        assert_eq!(edit.content, "one two");
+    }
+
+    #[test]
+    fn edit_against_an_unknown_version_is_refused() {
+        let mut state = room_state("one", 3);
+        assert!(state.prepare_edit(2, "two").is_none());
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn encrypting_a_note_leaves_no_plain_text() {
+        use crate::models::note::{NoteCipher, NoteEncryption};
+        use crate::services::todo_service;
+
+        let uri = std::env::var("MONGODB_TEST_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let note = Note::new("Passwords".to_string(), "- [ ] rotate the bank password".to_string());
+        let note = notes_service::add_note(&client, note, true).await.unwrap();
+        let note_id = note.id.unwrap();
+        let hub = CollabHub::new();
+        let room = hub.open_room(&client, &note_id.to_hex()).await.unwrap();
+        room.state.lock().unwrap().dirty = true;
+        hub.persist_all(&client).await;
+        assert!(get_documents_collection(&client).find_one(doc! { "_id": note_id }).await.unwrap().is_some());
+
+        let mut encrypted = Note::new("c2VjcmV0".to_string(), "Y2lwaGVydGV4dA==".to_string());
+        encrypted.encryption = Some(NoteEncryption {
+            cipher: NoteCipher::Aes256Gcm,
+            nonce: "AAAAAAAAAAAAAAAA".to_string(),
+            title_nonce: "AAAAAAAAAAAAAAAA".to_string(),
+            key_id: "key-1".to_string(),
+            wrapped_key: "a2V5".to_string(),
+        });
+        notes_service::update_note(&client, &note_id.to_hex(), encrypted, false, None, false).await.unwrap();
+        hub.close_room(&client, &note_id.to_hex()).await.unwrap();
+        // A connection still holding the room must not bring the document back
+        room.state.lock().unwrap().dirty = true;
+        hub.release_room(&client, &room).await.unwrap();
+
+        assert!(get_documents_collection(&client).find_one(doc! { "_id": note_id }).await.unwrap().is_none());
+        let todos = todo_service::get_todo_collection(&client).count_documents(doc! { "note_id": note_id }).await.unwrap();
+        assert_eq!(todos, 0);
+        let stored = notes_service::get_note_by_id(&client, &note_id.to_hex()).await.unwrap();
+        assert!(!stored.content.contains("bank"));
+        notes_service::purge_note(&client, note_id).await.unwrap();
+    }
+
+    #[test]
+    fn rooms_are_forgotten_only_when_nobody_holds_them() {
+        let room = |members| {
+            let mut state = room_state("one", 1);
+            state.members = members;
+            Arc::new(Room { note_id: ObjectId::new(), state: Mutex::new(state), writes: AsyncMutex::new(()) })
+        };
+        let held = room(1);
+        let unused = room(0);
+        let replaced = room(0);
+        let mut rooms = HashMap::from([
+            (held.note_id, held.clone()),
+            (unused.note_id, unused.clone()),
+            (replaced.note_id, room(1)),
+        ]);
+
+        for room in [&held, &unused, &replaced] {
+            forget_room(&mut rooms, room);
+        }
+        assert!(rooms.contains_key(&held.note_id));
+        assert!(!rooms.contains_key(&unused.note_id));
+        assert!(rooms.contains_key(&replaced.note_id));
+    }
+
+    #[test]
+    fn remembers_a_bounded_number_of_versions() {
+        let mut state = room_state("one", 0);
+        let heads = state.doc.get_heads();
+        for version in 1..=(MAX_VERSIONS as i64 + 5) {
+            state.record_version(version, heads.clone());
+        }
+        assert_eq!(state.versions.len(), MAX_VERSIONS);
+        assert!(!state.versions.contains_key(&0));
+    }
+}
@@ -0,0 +1,462 @@
+//! Exports all notes as a zip of Markdown files.
+//!
+//! Each note becomes `<notebook path>/<title>.md` with YAML front matter, and
+//! note attachments are stored under `assets/<attachment id>/<file name>`.
+//! Links to attachments and to other notes in note content are rewritten to
+//! relative paths, so the archive works as a plain folder of Markdown (and can
+//! be imported again).
+
+use mongodb::{Client, bson::{doc, oid::ObjectId, Document}};
+use mongodb::error::Error;
+use futures_util::{Stream, TryStreamExt};
+use crate::db::blob_store::BlobStore;
+use crate::models::attachment::Attachment;
+use crate::models::note::{Note, NoteEncryption};
+use crate::models::notebook::Notebook;
+use crate::services::attachment_service::{self, AttachmentServiceError};
+use crate::services::import_service::NOTE_LINK_PREFIX;
+use crate::services::{notebook_service, notes_service};
+use thiserror::Error;
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::rc::Rc;
+use zip::write::{SimpleFileOptions, StreamWriter};
+use zip::{CompressionMethod, ZipWriter};
+
+/// Folder attachments are exported to.
+const ASSETS_DIR: &str = "assets";
+
+#[derive(Error, Debug)]
+pub enum ExportServiceError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] Error),
+    #[error("Failed to write archive: {0}")]
+    ArchiveError(#[from] zip::result::ZipError),
+    #[error("Failed to write archive: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    AttachmentError(#[from] AttachmentServiceError),
+}
+
+/// Front matter written at the top of every exported note.
+#[derive(Serialize)]
+struct FrontMatter<'a> {
+    id: String,
+    title: &'a str,
+    tags: &'a [String],
+    created_at: Option<DateTime<Utc>>,
+    updated_at: Option<DateTime<Utc>>,
+    is_archived: bool,
+    is_pinned: bool,
+    is_favourite: bool,
+    /// Key metadata needed to decrypt encrypted notes; their content stays ciphertext.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encryption: Option<&'a NoteEncryption>,
+}
+
+/// Starts an export and returns the zip as a stream of chunks.
+///
+/// Notebooks and attachment metadata are loaded up front; notes and
+/// attachment contents are read while the archive is streamed.
+pub async fn export_notes(
+    client: &Client,
+    store: &BlobStore,
+) -> Result<impl Stream<Item = Result<Bytes, ExportServiceError>>, ExportServiceError> {
+    let notebooks: Vec<Notebook> = notebook_service::get_notebooks_collection(client)
+        .find(doc! {})
+        .await?
+        .try_collect()
+        .await?;
+    let folders = notebook_folders(&notebooks);
+
+    let mut attachments: HashMap<ObjectId, Vec<Attachment>> = HashMap::new();
+    let mut assets = HashMap::new();
+    let mut cursor = attachment_service::get_attachments_collection(client)
+        .find(doc! { "owner_type": "note" })
+        .sort(doc! { "created_at": 1 })
+        .await?;
+    while let Some(attachment) = cursor.try_next().await? {
+        let Some(id) = attachment.id else {
+            continue;
+        };
+        assets.insert(id.to_hex(), format!("{}/{}/{}", ASSETS_DIR, id.to_hex(), sanitize_name(&attachment.filename)));
+        attachments.entry(attachment.owner_id).or_default().push(attachment);
+    }
+
+    // Every note's path is needed up front to rewrite links between notes
+    let collection = notes_service::get_notes_collection(client);
+    let sort = doc! { "notebook_id": 1, "position": 1, "created_at": 1 };
+    let mut used_paths = HashSet::new();
+    let mut note_paths = HashMap::new();
+    let mut listing = collection
+        .clone_with_type::<Document>()
+        .find(doc! { "deleted_at": null })
+        .projection(doc! { "title": 1, "notebook_id": 1, "encryption": 1 })
+        .sort(sort.clone())
+        .await?;
+    while let Some(note) = listing.try_next().await? {
+        let Ok(note_id) = note.get_object_id("_id") else {
+            continue;
+        };
+        let title = match note.get("encryption") {
+            Some(encryption) if encryption.as_null().is_none() => None,
+            _ => note.get_str("title").ok(),
+        };
+        let folder = note.get_object_id("notebook_id").ok().and_then(|id| folders.get(&id));
+        let path = unique_path(&mut used_paths, folder.map(String::as_str), &note_name(note_id, title));
+        note_paths.insert(note_id.to_hex(), path);
+    }
+
+    let notes = collection.find(doc! { "deleted_at": null }).sort(sort).await?;
+
+    let buffer = SharedBuffer::default();
+    let state = ExportState {
+        client: client.clone(),
+        store: store.clone(),
+        notes,
+        folders,
+        attachments,
+        assets,
+        note_paths,
+        used_paths,
+        zip: Some(ZipWriter::new_stream(buffer.clone())),
+        buffer,
+    };
+    Ok(futures_util::stream::try_unfold(state, |mut state| async move {
+        loop {
+            if state.zip.is_none() {
+                return Ok(None);
+            }
+            match state.notes.try_next().await? {
+                Some(note) => state.write_note(note).await?,
+                None => {
+                    if let Some(zip) = state.zip.take() {
+                        zip.finish()?;
+                    }
+                }
+            }
+            let chunk = state.buffer.take();
+            if !chunk.is_empty() {
+                return Ok(Some((chunk, state)));
+            }
+        }
+    }))
+}
+
+/// Everything the export stream needs between chunks.
+struct ExportState {
+    client: Client,
+    store: BlobStore,
+    notes: mongodb::Cursor<Note>,
+    /// Folder path of every notebook.
+    folders: HashMap<ObjectId, String>,
+    attachments: HashMap<ObjectId, Vec<Attachment>>,
+    /// Archive path of every note attachment, by attachment id.
+    assets: HashMap<String, String>,
+    /// Archive path of every note, by note id.
+    note_paths: HashMap<String, String>,
+    /// Lowercased note paths already taken, to keep file names unique.
+    used_paths: HashSet<String>,
+    zip: Option<ZipWriter<StreamWriter<SharedBuffer>>>,
+    buffer: SharedBuffer,
+}
+
+impl ExportState {
+    async fn write_note(&mut self, note: Note) -> Result<(), ExportServiceError> {
+        let Some(note_id) = note.id else {
+            return Ok(());
+        };
+        let folder = note.notebook_id.and_then(|id| self.folders.get(&id)).cloned();
+        let path = match self.note_paths.get(&note_id.to_hex()) {
+            Some(path) => path.clone(),
+            // Created after the export started
+            None => {
+                let title = (!note.is_encrypted()).then_some(note.title.as_str());
+                unique_path(&mut self.used_paths, folder.as_deref(), &note_name(note_id, title))
+            }
+        };
+
+        let depth = folder.as_deref().map_or(0, |folder| folder.split('/').count());
+        let content = if note.is_encrypted() {
+            note.content.clone()
+        } else {
+            let prefix = "../".repeat(depth);
+            let content = rewrite_links(&note.content, "/api/attachments/", Some("/thumbnail"), &self.assets, &prefix);
+            rewrite_links(&content, NOTE_LINK_PREFIX, None, &self.note_paths, &prefix)
+        };
+        let front_matter = FrontMatter {
+            id: note_id.to_hex(),
+            title: &note.title,
+            tags: note.tags.as_deref().unwrap_or_default(),
+            created_at: note.created_at,
+            updated_at: note.updated_at,
+            is_archived: note.is_archived.unwrap_or(false),
+            is_pinned: note.is_pinned.unwrap_or(false),
+            is_favourite: note.is_favourite.unwrap_or(false),
+            encryption: note.encryption.as_ref(),
+        };
+        let yaml = serde_yaml::to_string(&front_matter).map_err(std::io::Error::other)?;
+
+        let zip = self.zip.as_mut().expect("notes are only written before the archive is finished");
+        zip.start_file(path, file_options(&note.updated_at))?;
+        write!(zip, "---\n{}---\n\n{}", yaml, content)?;
+        if !content.ends_with('\n') {
+            zip.write_all(b"\n")?;
+        }
+
+        for attachment in self.attachments.remove(&note_id).unwrap_or_default() {
+            let Some(id) = attachment.id.map(|id| id.to_hex()) else {
+                continue;
+            };
+            let data = match attachment_service::read_attachment(&self.client, &self.store, &id, false).await {
+                Ok((_, _, data)) => data,
+                // A missing blob shouldn't abort the whole export
+                Err(AttachmentServiceError::AttachmentNotFound) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let zip = self.zip.as_mut().expect("notes are only written before the archive is finished");
+            // Most attachments are already compressed images and PDFs
+            let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+            zip.start_file(&self.assets[&id], options)?;
+            zip.write_all(&data)?;
+        }
+        Ok(())
+    }
+}
+
+/// File name of a note, without extension. Encrypted titles (`None`) are
+/// ciphertext, which makes a poor file name, so those use the note id.
+fn note_name(note_id: ObjectId, title: Option<&str>) -> String {
+    title.map(sanitize_name).unwrap_or_else(|| note_id.to_hex())
+}
+
+/// Returns `<folder>/<name>.md`, numbering the name if it is already taken.
+fn unique_path(used_paths: &mut HashSet<String>, folder: Option<&str>, name: &str) -> String {
+    let prefix = folder.map(|folder| format!("{}/", folder)).unwrap_or_default();
+    let mut path = format!("{}{}.md", prefix, name);
+    let mut counter = 2;
+    while !used_paths.insert(path.to_lowercase()) {
+        path = format!("{}{} ({}).md", prefix, name, counter);
+        counter += 1;
+    }
+    path
+}
+
+/// Zip entry options, using the note's modification time when it fits the
+/// zip date range.
+fn file_options(updated_at: &Option<DateTime<Utc>>) -> SimpleFileOptions {
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    let modified = updated_at.and_then(|date| {
+        use chrono::{Datelike, Timelike};
+        zip::DateTime::from_date_and_time(
+            u16::try_from(date.year()).ok()?,
+            date.month() as u8,
+            date.day() as u8,
+            date.hour() as u8,
+            date.minute() as u8,
+            date.second() as u8,
+        )
+        .ok()
+    });
+    match modified {
+        Some(modified) => options.last_modified_time(modified),
+        None => options,
+    }
+}
+
+/// Builds the folder path of every notebook from its ancestors' names,
+/// numbering siblings whose names clash.
+fn notebook_folders(notebooks: &[Notebook]) -> HashMap<ObjectId, String> {
+    let mut names: HashMap<ObjectId, String> = HashMap::new();
+    let mut taken: HashSet<(Option<ObjectId>, String)> = HashSet::new();
+    for notebook in notebooks {
+        let Some(id) = notebook.id else {
+            continue;
+        };
+        let base = sanitize_name(&notebook.name);
+        let mut name = base.clone();
+        let mut counter = 2;
+        while !taken.insert((notebook.parent_id, name.to_lowercase())) {
+            name = format!("{} ({})", base, counter);
+            counter += 1;
+        }
+        names.insert(id, name);
+    }
+
+    let parents: HashMap<ObjectId, Option<ObjectId>> =
+        notebooks.iter().filter_map(|n| Some((n.id?, n.parent_id))).collect();
+    names
+        .keys()
+        .map(|&id| {
+            let mut parts = Vec::new();
+            let mut current = Some(id);
+            // Bounded by the number of notebooks in case the tree is corrupt
+            while let Some(notebook_id) = current.filter(|_| parts.len() <= notebooks.len()) {
+                if let Some(name) = names.get(&notebook_id) {
+                    parts.push(name.as_str());
+                }
+                current = parents.get(&notebook_id).copied().flatten();
+            }
+            parts.reverse();
+            (id, parts.join("/"))
+        })
+        .collect()
+}
+
+/// Replaces links made of `marker` and an id (`/api/attachments/<id>` or
+/// `/notes/<id>`, optionally with an origin in front or `suffix` after) by the
+/// relative path of the exported file, as found in `targets` by id.
+fn rewrite_links(content: &str, marker: &str, suffix: Option<&str>, targets: &HashMap<String, String>, prefix: &str) -> String {
+    let mut rewritten = String::with_capacity(content.len());
+    let mut rest_start = 0;
+    let mut search_from = 0;
+
+    while let Some(offset) = content[search_from..].find(marker) {
+        let marker_start = search_from + offset;
+        let id_start = marker_start + marker.len();
+        let id_end = id_start + content[id_start..].chars().take_while(char::is_ascii_hexdigit).count();
+        search_from = id_end.max(id_start + 1).min(content.len());
+        let Some(target) = targets.get(&content[id_start..id_end]) else {
+            continue;
+        };
+
+        // Include an origin such as `https://example.com` in front of the path
+        let start = content[rest_start..marker_start]
+            .rfind(|c: char| c.is_whitespace() || matches!(c, '(' | '<' | '"' | '\'' | '['))
+            .map_or(rest_start, |i| rest_start + i + 1);
+        let origin = &content[start..marker_start];
+        if !(origin.is_empty() || origin.starts_with("http://") || origin.starts_with("https://")) {
+            continue;
+        }
+        let end = match suffix {
+            Some(suffix) if content[id_end..].starts_with(suffix) => id_end + suffix.len(),
+            _ => id_end,
+        };
+
+        rewritten.push_str(&content[rest_start..start]);
+        rewritten.push_str(prefix);
+        rewritten.push_str(&encode_link_path(target));
+        rest_start = end;
+        search_from = end;
+    }
+    rewritten.push_str(&content[rest_start..]);
+    rewritten
+}
+
+/// Percent-encodes the characters that would end or break a Markdown link.
+fn encode_link_path(path: &str) -> String {
+    path.chars()
+        .map(|c| match c {
+            ' ' => "%20".to_string(),
+            '(' => "%28".to_string(),
+            ')' => "%29".to_string(),
+            '<' => "%3C".to_string(),
+            '>' => "%3E".to_string(),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+/// Makes a title or file name safe to use as a path component on any platform.
+fn sanitize_name(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| if c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') { '_' } else { c })
+        .take(100)
+        .collect();
+    let trimmed = cleaned.trim().trim_matches('.').trim();
+    if trimmed.is_empty() {
+        "Untitled".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// A writer whose contents are drained into the response between notes.
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Bytes {
+        Bytes::from(std::mem::take(&mut *self.0.borrow_mut()))
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Custom function to convert ExportServiceError to HttpResponse
+pub fn error_response(error: ExportServiceError) -> HttpResponse {
+    match error {
+        ExportServiceError::DatabaseError(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+        e @ (ExportServiceError::ArchiveError(_) | ExportServiceError::IoError(_)) => {
+            HttpResponse::InternalServerError().body(e.to_string())
+        }
+        ExportServiceError::AttachmentError(e) => attachment_service::error_response(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTE_ID: &str = "65f0c0ffee0000000000abcd";
+
+    fn targets(path: &str) -> HashMap<String, String> {
+        HashMap::from([(NOTE_ID.to_string(), path.to_string())])
+    }
+
+    #[test]
+    fn links_between_notes_point_to_the_exported_files() {
+        let content = format!("See [plan](/notes/{}) and [[gone]](/notes/{}).", NOTE_ID, "65f0c0ffee000000000000ff");
+        let rewritten = rewrite_links(&content, NOTE_LINK_PREFIX, None, &targets("Work/Q3 plan.md"), "../");
+        assert_eq!(
+            rewritten,
+            "See [plan](../Work/Q3%20plan.md) and [[gone]](/notes/65f0c0ffee000000000000ff)."
+        );
+    }
+
+    #[test]
+    fn links_with_an_origin_are_rewritten_but_other_paths_are_not() {
+        let content = format!("https://example.com/notes/{id} /api/notes/{id}", id = NOTE_ID);
+        let rewritten = rewrite_links(&content, NOTE_LINK_PREFIX, None, &targets("Plan.md"), "");
+        assert_eq!(rewritten, format!("Plan.md /api/notes/{}", NOTE_ID));
+    }
+
+    #[test]
+    fn attachment_thumbnails_point_to_the_asset() {
+        let content = format!("![](/api/attachments/{}/thumbnail)", NOTE_ID);
+        let assets = targets(&format!("assets/{}/photo (1).png", NOTE_ID));
+        let rewritten = rewrite_links(&content, "/api/attachments/", Some("/thumbnail"), &assets, "");
+        assert_eq!(rewritten, format!("![](assets/{}/photo%20%281%29.png)", NOTE_ID));
+    }
+
+    #[test]
+    fn clashing_names_are_numbered() {
+        let mut used = HashSet::new();
+        assert_eq!(unique_path(&mut used, Some("Work"), "Plan"), "Work/Plan.md");
+        assert_eq!(unique_path(&mut used, Some("Work"), "plan"), "Work/plan (2).md");
+        assert_eq!(unique_path(&mut used, None, "Plan"), "Plan.md");
+    }
+
+    #[test]
+    fn names_are_safe_file_names() {
+        assert_eq!(sanitize_name("a/b: c?"), "a_b_ c_");
+        assert_eq!(sanitize_name(" .. "), "Untitled");
+        let id = ObjectId::parse_str(NOTE_ID).unwrap();
+        assert_eq!(note_name(id, None), NOTE_ID);
+    }
+}
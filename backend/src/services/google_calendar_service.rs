@@ -0,0 +1,473 @@
+//! Google Calendar as a calendar provider.
+//!
+//! Changes are listed with the incremental sync tokens of `events.list`, which
+//! reports deleted events and cancelled occurrences as cancelled events.
+//! Series carry their recurrence as `RRULE`, `RDATE` and `EXDATE` lines, so
+//! they map to local series as they are.
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::StatusCode;
+use std::env;
+use crate::models::calendar::CalendarEvent;
+use crate::models::calendar_sync::CalendarProvider;
+use crate::models::google_calendar::{GoogleAttendee, GoogleEvent, GoogleEventList, GoogleEventTime};
+use crate::models::recurrence::Recurrence;
+use crate::services::calendar_oauth_service::{self, CalendarOAuthServiceError, OAuthClient};
+use crate::services::calendar_sync_service::{
+    all_day_dates, check, http_client, json, CalendarSyncServiceError, RemoteCalendar, RemoteEvent, RemotePage,
+};
+use crate::services::ical_service;
+
+const DEFAULT_API_URL: &str = "https://www.googleapis.com/calendar/v3";
+
+const DEFAULT_AUTHORIZATION_URL: &str = "https://accounts.google.com/o/oauth2/v2/auth";
+
+const DEFAULT_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+
+const DEFAULT_REVOCATION_URL: &str = "https://oauth2.googleapis.com/revoke";
+
+/// Read and write access to the user's calendars.
+const SCOPE: &str = "https://www.googleapis.com/auth/calendar";
+
+/// Events per page of `events.list`, which allows up to 2500.
+const PAGE_SIZE: &str = "250";
+
+const UNTITLED: &str = "Untitled event";
+
+/// Base URL of the Calendar API, from `GOOGLE_CALENDAR_API_URL`.
+pub fn api_url() -> String {
+    env::var("GOOGLE_CALENDAR_API_URL")
+        .ok()
+        .map(|url| url.trim().trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| DEFAULT_API_URL.to_string())
+}
+
+/// The app's Google OAuth client, from `GOOGLE_CLIENT_ID`,
+/// `GOOGLE_CLIENT_SECRET` and `GOOGLE_REDIRECT_URI`. `GOOGLE_AUTHORIZATION_URL`,
+/// `GOOGLE_TOKEN_URL` and `GOOGLE_REVOCATION_URL` override Google's endpoints.
+pub fn oauth_client() -> Result<OAuthClient, CalendarOAuthServiceError> {
+    Ok(OAuthClient {
+        provider: CalendarProvider::Google.name(),
+        client_id: calendar_oauth_service::required_var("GOOGLE_CLIENT_ID")?,
+        client_secret: calendar_oauth_service::required_var("GOOGLE_CLIENT_SECRET")?,
+        redirect_uri: calendar_oauth_service::required_var("GOOGLE_REDIRECT_URI")?,
+        authorization_url: calendar_oauth_service::url_var("GOOGLE_AUTHORIZATION_URL", DEFAULT_AUTHORIZATION_URL),
+        token_url: calendar_oauth_service::url_var("GOOGLE_TOKEN_URL", DEFAULT_TOKEN_URL),
+        revocation_url: Some(calendar_oauth_service::url_var("GOOGLE_REVOCATION_URL", DEFAULT_REVOCATION_URL)),
+        scope: SCOPE,
+        // Google only issues a refresh token for offline access, and again only on consent
+        authorization_params: &[("access_type", "offline"), ("prompt", "consent")],
+    })
+}
+
+/// Id Google gives the occurrence of a series that originally starts at `start`.
+fn instance_id(series_id: &str, start: DateTime<Utc>, all_day: bool) -> String {
+    match all_day {
+        true => format!("{}_{}", series_id, start.format("%Y%m%d")),
+        false => format!("{}_{}", series_id, start.format("%Y%m%dT%H%M%SZ")),
+    }
+}
+
+fn title(remote: &GoogleEvent) -> &str {
+    remote.summary.as_deref().filter(|summary| !summary.trim().is_empty()).unwrap_or(UNTITLED)
+}
+
+/// A listed Google event as the sync sees it.
+fn remote_event(remote: GoogleEvent) -> Option<RemoteEvent> {
+    let id = remote.id.clone()?;
+    let original_start = remote.original_start_time.as_ref().and_then(|time| time.instant().ok()).map(|(at, _)| at);
+    let (occurrence_of, event) = match (remote.recurring_event_id.clone(), original_start) {
+        (Some(_), None) => (None, Some(Err("Missing original start time".to_string()))),
+        (series_id, _) => (series_id.zip(original_start), (!remote.is_cancelled()).then(|| to_local(&remote))),
+    };
+    Some(RemoteEvent { id, occurrence_of, title: title(&remote).to_string(), updated: remote.updated, event })
+}
+
+/// Maps a Google event to a local one, failing when it can't be represented.
+fn to_local(remote: &GoogleEvent) -> Result<CalendarEvent, String> {
+    let start = remote.start.as_ref().ok_or("Missing start")?;
+    let (start_time, is_all_day) = start.instant()?;
+    let (end_time, _) = remote.end.as_ref().ok_or("Missing end")?.instant()?;
+    let zone: Tz = match start.time_zone.as_deref() {
+        Some(name) if !is_all_day => name.parse().map_err(|_| format!("Unknown time zone: {}", name))?,
+        _ => Tz::UTC,
+    };
+    let recurrence = Recurrence::parse(&remote.recurrence, start_time, zone)?;
+    let text = |value: &Option<String>| value.clone().filter(|value| !value.trim().is_empty());
+    let now = Utc::now();
+    let event = CalendarEvent {
+        id: None,
+        title: title(remote).to_string(),
+        description: text(&remote.description),
+        start_time,
+        end_time,
+        location: text(&remote.location),
+        is_all_day,
+        time_zone: (zone != Tz::UTC).then(|| zone.name().to_string()),
+        recurrence_rule: recurrence.rule.map(|rule| rule.to_string()),
+        recurrence_dates: recurrence.dates,
+        excluded_dates: recurrence.excluded_dates,
+        recurring_event_id: None,
+        recurrence_id: None,
+        attendees: remote
+            .attendees
+            .iter()
+            .map(|attendee| attendee.email.clone())
+            .filter(|email| !email.is_empty())
+            .collect(),
+        color: remote.color_id.clone(),
+        calendar_id: None,
+        ical_uid: None,
+        dav_name: None,
+        external_id: None,
+        synced_at: None,
+        created_at: now,
+        updated_at: now,
+        deleted_at: None,
+    };
+    event.validate()?;
+    Ok(event)
+}
+
+/// Maps a local event to the Google event it is pushed as.
+fn to_google(event: &CalendarEvent) -> GoogleEvent {
+    let tz = event.tz();
+    let (start, end) = match event.is_all_day {
+        true => {
+            let (start, end) = all_day_dates(event);
+            (
+                GoogleEventTime { date: Some(start), ..Default::default() },
+                GoogleEventTime { date: Some(end), ..Default::default() },
+            )
+        }
+        // Google needs a time zone to expand a series in
+        false => {
+            let time = |at| GoogleEventTime { date_time: Some(at), time_zone: Some(tz.name().to_string()), ..Default::default() };
+            (time(event.start_time), time(event.end_time))
+        }
+    };
+    GoogleEvent {
+        summary: Some(event.title.clone()),
+        description: event.description.clone(),
+        location: event.location.clone(),
+        // Google only knows its own palette, numbered from 1 to 11
+        color_id: event.color.clone().filter(|color| color.parse::<u8>().is_ok_and(|color| (1..=11).contains(&color))),
+        start: Some(start),
+        end: Some(end),
+        recurrence: ical_service::recurrence_lines(event),
+        attendees: event
+            .attendees
+            .iter()
+            .filter(|attendee| attendee.contains('@'))
+            .map(|attendee| GoogleAttendee { email: attendee.trim().to_string() })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// Requests to the events of one Google calendar.
+pub struct GoogleCalendar<'a> {
+    http: reqwest::Client,
+    url: String,
+    access_token: &'a str,
+}
+
+impl<'a> GoogleCalendar<'a> {
+    pub fn new(access_token: &'a str, calendar_id: &str) -> Self {
+        Self::at(&api_url(), access_token, calendar_id)
+    }
+
+    /// A calendar of the Calendar API at another base URL.
+    pub(crate) fn at(api_url: &str, access_token: &'a str, calendar_id: &str) -> Self {
+        GoogleCalendar {
+            http: http_client(),
+            url: format!("{}/calendars/{}/events", api_url, utf8_percent_encode(calendar_id, NON_ALPHANUMERIC)),
+            access_token,
+        }
+    }
+
+    fn event_url(&self, event_id: &str) -> String {
+        format!("{}/{}", self.url, utf8_percent_encode(event_id, NON_ALPHANUMERIC))
+    }
+}
+
+impl RemoteCalendar for GoogleCalendar<'_> {
+    async fn changes(&self, sync_token: Option<&str>, page_token: Option<&str>) -> Result<RemotePage, CalendarSyncServiceError> {
+        let mut query = vec![("showDeleted", "true"), ("maxResults", PAGE_SIZE)];
+        if let Some(sync_token) = sync_token {
+            query.push(("syncToken", sync_token));
+        }
+        if let Some(page_token) = page_token {
+            query.push(("pageToken", page_token));
+        }
+        let response = self.http.get(&self.url).bearer_auth(self.access_token).query(&query).send().await?;
+        let page: GoogleEventList = match response.status() {
+            StatusCode::GONE => return Err(CalendarSyncServiceError::SyncTokenExpired),
+            _ => json(response).await?,
+        };
+        Ok(RemotePage {
+            events: page.items.into_iter().filter_map(remote_event).collect(),
+            next_page: page.next_page_token,
+            sync_token: page.next_sync_token,
+        })
+    }
+
+    /// Google lists deleted series and cancelled occurrences by their own ids.
+    async fn series(&self, _series_id: &str) -> Result<Option<RemoteEvent>, CalendarSyncServiceError> {
+        Ok(None)
+    }
+
+    fn listed_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        None
+    }
+
+    async fn insert(&self, event: &CalendarEvent) -> Result<String, CalendarSyncServiceError> {
+        let response = self.http.post(&self.url).bearer_auth(self.access_token).json(&to_google(event)).send().await?;
+        let remote: GoogleEvent = json(response).await?;
+        remote.id.ok_or_else(|| CalendarSyncServiceError::ApiError("Google returned an event without an id".to_string()))
+    }
+
+    async fn update(&self, id: &str, event: &CalendarEvent) -> Result<bool, CalendarSyncServiceError> {
+        let response = self.http.put(self.event_url(id)).bearer_auth(self.access_token).json(&to_google(event)).send().await?;
+        match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::GONE => Ok(false),
+            _ => check(response).await.map(|_| true),
+        }
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), CalendarSyncServiceError> {
+        let response = self.http.delete(self.event_url(id)).bearer_auth(self.access_token).send().await?;
+        match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::GONE => Ok(()),
+            _ => check(response).await.map(|_| ()),
+        }
+    }
+
+    async fn occurrence_id(
+        &self,
+        series_id: &str,
+        series: &CalendarEvent,
+        start: DateTime<Utc>,
+    ) -> Result<Option<String>, CalendarSyncServiceError> {
+        Ok(Some(instance_id(series_id, start, series.is_all_day)))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use actix_web::dev::ServerHandle;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Access token the mock accepts.
+    pub(crate) const TOKEN: &str = "test-token";
+
+    /// What the mock Calendar API holds.
+    #[derive(Default)]
+    pub(crate) struct MockState {
+        /// Events of the calendar, in the order a full listing pages through them.
+        pub events: Vec<Value>,
+        /// Events listed with the current sync token.
+        pub changes: Vec<Value>,
+        pub sync_token: String,
+        /// Requests received, as "METHOD path".
+        pub requests: Vec<String>,
+    }
+
+    /// A stand-in for the Calendar API on a local port, serving the events of
+    /// the calendar `primary` two to a page.
+    pub(crate) struct MockGoogle {
+        pub url: String,
+        pub state: Arc<Mutex<MockState>>,
+        handle: ServerHandle,
+    }
+
+    impl MockGoogle {
+        pub(crate) fn start(state: MockState) -> Self {
+            let state = Arc::new(Mutex::new(state));
+            let data = web::Data::from(state.clone());
+            let server = HttpServer::new(move || App::new().app_data(data.clone()).default_service(web::to(respond)))
+                .workers(1)
+                .bind(("127.0.0.1", 0))
+                .expect("a local port");
+            let url = format!("http://{}", server.addrs()[0]);
+            let server = server.run();
+            let handle = server.handle();
+            actix_web::rt::spawn(server);
+            MockGoogle { url, state, handle }
+        }
+
+        pub(crate) fn calendar(&self) -> GoogleCalendar<'static> {
+            GoogleCalendar::at(&self.url, TOKEN, "primary")
+        }
+
+        pub(crate) fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+            self.state.lock().unwrap()
+        }
+
+        pub(crate) async fn stop(self) {
+            self.handle.stop(false).await;
+        }
+    }
+
+    /// A timed event as `events.list` returns it.
+    pub(crate) fn google_event(id: &str, summary: &str, updated: &str) -> Value {
+        json!({
+            "id": id,
+            "status": "confirmed",
+            "summary": summary,
+            "start": { "dateTime": "2024-07-01T10:00:00Z", "timeZone": "UTC" },
+            "end": { "dateTime": "2024-07-01T11:00:00Z", "timeZone": "UTC" },
+            "updated": updated
+        })
+    }
+
+    pub(crate) fn cancelled(id: &str) -> Value {
+        json!({ "id": id, "status": "cancelled", "updated": Utc::now() })
+    }
+
+    async fn respond(req: HttpRequest, body: web::Bytes, state: web::Data<Mutex<MockState>>) -> HttpResponse {
+        let mut state = state.lock().unwrap();
+        state.requests.push(format!("{} {}", req.method(), req.path()));
+        let authorization = req.headers().get("Authorization").and_then(|value| value.to_str().ok());
+        if authorization != Some(&format!("Bearer {}", TOKEN)) {
+            return HttpResponse::Unauthorized().json(json!({ "error": { "message": "Invalid Credentials" } }));
+        }
+        let Some(path) = req.path().strip_prefix("/calendars/primary/events") else {
+            return HttpResponse::NotFound().finish();
+        };
+        let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).unwrap().into_inner();
+        let id = path.trim_start_matches('/').to_string();
+        let position = state.events.iter().position(|event| event["id"] == id.as_str());
+        let stored = |mut event: Value, id: &str| {
+            event["id"] = json!(id);
+            event["updated"] = json!(Utc::now());
+            event
+        };
+        match (req.method().as_str(), path) {
+            ("GET", "") => {
+                if let Some(token) = query.get("syncToken") {
+                    return match *token == state.sync_token {
+                        true => HttpResponse::Ok().json(json!({ "items": state.changes, "nextSyncToken": state.sync_token })),
+                        false => HttpResponse::Gone().json(json!({ "error": { "message": "Sync token is no longer valid" } })),
+                    };
+                }
+                let page: usize = query.get("pageToken").and_then(|page| page.parse().ok()).unwrap_or(0);
+                let items: Vec<&Value> = state.events.iter().skip(page * 2).take(2).collect();
+                let mut list = json!({ "items": items });
+                match (page + 1) * 2 < state.events.len() {
+                    true => list["nextPageToken"] = json!((page + 1).to_string()),
+                    false => list["nextSyncToken"] = json!(state.sync_token),
+                }
+                HttpResponse::Ok().json(list)
+            }
+            ("POST", "") => {
+                let event = stored(serde_json::from_slice(&body).unwrap(), &format!("created{}", state.events.len() + 1));
+                state.events.push(event.clone());
+                HttpResponse::Ok().json(event)
+            }
+            ("PUT", _) => match position {
+                Some(position) => {
+                    state.events[position] = stored(serde_json::from_slice(&body).unwrap(), &id);
+                    HttpResponse::Ok().json(&state.events[position])
+                }
+                None => HttpResponse::NotFound().finish(),
+            },
+            ("DELETE", _) => match position {
+                Some(position) => {
+                    state.events.remove(position);
+                    HttpResponse::NoContent().finish()
+                }
+                None => HttpResponse::Gone().finish(),
+            },
+            _ => HttpResponse::NotFound().finish(),
+        }
+    }
+
+    fn mock(events: Vec<Value>) -> MockGoogle {
+        MockGoogle::start(MockState { events, sync_token: "token-1".to_string(), ..Default::default() })
+    }
+
+    #[actix_web::test]
+    async fn full_listings_page_through_every_event() {
+        let events = ["a", "b", "c"].map(|id| google_event(id, id, "2024-06-01T00:00:00Z"));
+        let google = mock(events.to_vec());
+        let calendar = google.calendar();
+
+        let first = calendar.changes(None, None).await.unwrap();
+        assert_eq!(first.events.iter().map(|event| event.id.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(first.sync_token, None);
+        let second = calendar.changes(None, first.next_page.as_deref()).await.unwrap();
+        assert_eq!(second.events.iter().map(|event| event.id.as_str()).collect::<Vec<_>>(), ["c"]);
+        assert_eq!(second.next_page, None);
+        assert_eq!(second.sync_token.as_deref(), Some("token-1"));
+        let event = second.events[0].event.as_ref().unwrap().as_ref().unwrap();
+        assert_eq!(event.start_time, "2024-07-01T10:00:00Z".parse::<DateTime<Utc>>().unwrap());
+        google.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn incremental_listings_report_removals_and_occurrences() {
+        let google = mock(Vec::new());
+        let mut occurrence = google_event("series_20240708T100000Z", "Moved", "2024-06-02T00:00:00Z");
+        occurrence["recurringEventId"] = json!("series");
+        occurrence["originalStartTime"] = json!({ "dateTime": "2024-07-08T10:00:00Z" });
+        google.state().changes = vec![cancelled("gone"), occurrence];
+
+        let page = google.calendar().changes(Some("token-1"), None).await.unwrap();
+        assert_eq!(page.events[0].id, "gone");
+        assert!(page.events[0].event.is_none());
+        let (series_id, start) = page.events[1].occurrence_of.clone().unwrap();
+        assert_eq!(series_id, "series");
+        assert_eq!(start, "2024-07-08T10:00:00Z".parse::<DateTime<Utc>>().unwrap());
+        google.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn expired_sync_tokens_are_reported() {
+        let google = mock(Vec::new());
+        let result = google.calendar().changes(Some("token-0"), None).await;
+        assert!(matches!(result, Err(CalendarSyncServiceError::SyncTokenExpired)));
+        google.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn writes_create_update_and_delete_events() {
+        let google = mock(vec![google_event("a", "Lunch", "2024-06-01T00:00:00Z")]);
+        let calendar = google.calendar();
+        let mut event = to_local(&serde_json::from_value(google_event("x", "Dinner", "2024-06-01T00:00:00Z")).unwrap()).unwrap();
+
+        let id = calendar.insert(&event).await.unwrap();
+        assert_eq!(id, "created2");
+        assert_eq!(google.state().events[1]["summary"], "Dinner");
+        event.title = "Late dinner".to_string();
+        assert!(calendar.update(&id, &event).await.unwrap());
+        assert_eq!(google.state().events[1]["summary"], "Late dinner");
+        assert!(!calendar.update("missing", &event).await.unwrap());
+        calendar.delete("a").await.unwrap();
+        calendar.delete("a").await.unwrap();
+        assert_eq!(google.state().events.len(), 1);
+        google.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn rejected_access_tokens_are_reported() {
+        let google = mock(Vec::new());
+        let calendar = GoogleCalendar::at(&google.url, "expired", "primary");
+        assert!(matches!(calendar.changes(None, None).await, Err(CalendarSyncServiceError::Unauthorized(_))));
+        google.stop().await;
+    }
+
+    #[test]
+    fn instance_ids_follow_googles_format() {
+        let start = "2024-07-08T10:00:00Z".parse().unwrap();
+        assert_eq!(instance_id("series", start, false), "series_20240708T100000Z");
+        assert_eq!(instance_id("series", start, true), "series_20240708");
+    }
+}
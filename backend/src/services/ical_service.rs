@@ -0,0 +1,1064 @@
+//! Reads and writes calendar events as iCalendar (RFC 5545).
+//!
+//! Events with a time zone are written in local time with a `TZID`, and the
+//! calendar carries a `VTIMEZONE` for each zone so that recurrences keep their
+//! local time across daylight saving changes. The zone's current daylight
+//! saving rules are used for every year. All-day events are written as `DATE`
+//! values, and exceptions to a series as extra `VEVENT`s sharing the series'
+//! `UID`, with a `RECURRENCE-ID`.
+//!
+//! When reading, `TZID`s that aren't IANA names are matched to a zone by name
+//! (including Windows names) or, failing that, by the offsets of their
+//! `VTIMEZONE`.
+
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Offset, TimeZone, Utc, Weekday,
+};
+use chrono_tz::{OffsetName, Tz};
+use mongodb::bson::oid::ObjectId;
+use crate::models::calendar::CalendarEvent;
+use crate::models::recurrence::{local_to_utc, RecurrenceRule, Until};
+use std::collections::{BTreeMap, HashMap};
+
+/// Identifies the app as the producer of exported calendars.
+const PRODUCT_ID: &str = "-//Organise//Organise App//EN";
+
+/// Ends the `UID`s of events that weren't imported.
+const UID_SUFFIX: &str = "@organise";
+
+/// Title of imported events without a `SUMMARY`.
+const UNTITLED: &str = "Untitled event";
+
+/// Longest content line, in octets, before it is folded.
+const MAX_LINE_OCTETS: usize = 75;
+
+/// Serialises events, and the exceptions to their series, as one `VCALENDAR`.
+pub fn write_calendar(name: &str, events: &[CalendarEvent]) -> String {
+    write(Some(name), events)
+}
+
+/// Serialises one series, or a single event, with the exceptions to it as a
+/// CalDAV calendar object resource, which carries no `METHOD`.
+pub fn write_object(events: &[CalendarEvent]) -> String {
+    write(None, events)
+}
+
+fn write(name: Option<&str>, events: &[CalendarEvent]) -> String {
+    let mut writer = Writer::default();
+    writer.line("BEGIN", &[], "VCALENDAR");
+    writer.line("VERSION", &[], "2.0");
+    writer.line("PRODID", &[], PRODUCT_ID);
+    writer.line("CALSCALE", &[], "GREGORIAN");
+    if let Some(name) = name {
+        writer.line("METHOD", &[], "PUBLISH");
+        writer.line("X-WR-CALNAME", &[], &escape_text(name));
+    }
+
+    let time_zones: BTreeMap<&str, Tz> = events
+        .iter()
+        .filter(|event| !event.is_all_day)
+        .filter_map(|event| Some((event.time_zone.as_deref()?, event.tz())))
+        .filter(|(_, tz)| *tz != Tz::UTC)
+        .collect();
+    let year = Utc::now().year();
+    for (name, tz) in time_zones {
+        write_time_zone(&mut writer, name, tz, year);
+    }
+
+    // Exceptions come after the series they belong to
+    let (exceptions, series): (Vec<&CalendarEvent>, Vec<&CalendarEvent>) =
+        events.iter().partition(|event| event.is_exception());
+    for event in series.into_iter().chain(exceptions) {
+        write_event(&mut writer, event);
+    }
+
+    writer.line("END", &[], "VCALENDAR");
+    writer.output
+}
+
+/// iCalendar `UID` of an event; exceptions share the `UID` of their series.
+pub fn uid(event: &CalendarEvent) -> String {
+    if let Some(uid) = &event.ical_uid {
+        return uid.clone();
+    }
+    let id = event.recurring_event_id.or(event.id).unwrap_or_default();
+    format!("{}{}", id.to_hex(), UID_SUFFIX)
+}
+
+/// Id of the event a `UID` written by [`uid`] stands for.
+pub fn event_id_from_uid(uid: &str) -> Option<ObjectId> {
+    ObjectId::parse_str(uid.strip_suffix(UID_SUFFIX)?).ok()
+}
+
+fn write_event(writer: &mut Writer, event: &CalendarEvent) {
+    writer.line("BEGIN", &[], "VEVENT");
+    writer.line("UID", &[], &uid(event));
+    writer.line("DTSTAMP", &[], &utc_value(event.updated_at));
+    writer.line("CREATED", &[], &utc_value(event.created_at));
+    writer.line("LAST-MODIFIED", &[], &utc_value(event.updated_at));
+    if let Some(recurrence_id) = event.recurrence_id {
+        write_dates(writer, "RECURRENCE-ID", event, &[recurrence_id]);
+    }
+
+    write_dates(writer, "DTSTART", event, &[event.start_time]);
+    if event.is_all_day {
+        let (start, end) = (local_date(event, event.start_time), local_date(event, event.end_time));
+        // DTEND is exclusive; an end at midnight already closes the previous day
+        let ends_at_midnight = event.end_time.with_timezone(&event.tz()).time() == NaiveTime::MIN;
+        let end = if ends_at_midnight && end > start { end } else { end + Duration::days(1) };
+        writer.line("DTEND", &[("VALUE", "DATE")], &end.format("%Y%m%d").to_string());
+    } else {
+        write_dates(writer, "DTEND", event, &[event.end_time]);
+    }
+
+    writer.line("SUMMARY", &[], &escape_text(&event.title));
+    if let Some(description) = event.description.as_deref().filter(|description| !description.is_empty()) {
+        writer.line("DESCRIPTION", &[], &escape_text(description));
+    }
+    if let Some(location) = event.location.as_deref().filter(|location| !location.is_empty()) {
+        writer.line("LOCATION", &[], &escape_text(location));
+    }
+    if let Some(color) = event.color.as_deref().filter(|color| !color.is_empty()) {
+        // COLOR only takes CSS colour names; anything else keeps an extension property
+        match color.chars().all(|c| c.is_ascii_alphabetic()) {
+            true => writer.line("COLOR", &[], &color.to_ascii_lowercase()),
+            false => writer.line("X-ORGANISE-COLOR", &[], &escape_text(color)),
+        }
+    }
+    for attendee in event.attendees.iter().filter(|attendee| !attendee.trim().is_empty()) {
+        let attendee = attendee.trim();
+        let address = attendee.trim_start_matches("mailto:");
+        match validator::validate_email(address) {
+            true => writer.line("ATTENDEE", &[], &format!("mailto:{}", address)),
+            // Attendees without an address still need a calendar user address
+            false => writer.line("ATTENDEE", &[("CN", attendee)], "invalid:nomail"),
+        }
+    }
+
+    write_recurrence(writer, event);
+    writer.line("END", &[], "VEVENT");
+}
+
+/// The recurrence of an event as unfolded `RRULE`, `RDATE` and `EXDATE`
+/// lines, for calendar APIs that take them as a list.
+pub fn recurrence_lines(event: &CalendarEvent) -> Vec<String> {
+    let mut writer = Writer::default();
+    write_recurrence(&mut writer, event);
+    writer.output.replace("\r\n ", "").lines().map(str::to_string).collect()
+}
+
+fn write_recurrence(writer: &mut Writer, event: &CalendarEvent) {
+    if let Ok(Some(rule)) = event.rule() {
+        writer.line("RRULE", &[], &rule_value(event, rule));
+    }
+    if !event.recurrence_dates.is_empty() {
+        write_dates(writer, "RDATE", event, &event.recurrence_dates);
+    }
+    if !event.excluded_dates.is_empty() {
+        write_dates(writer, "EXDATE", event, &event.excluded_dates);
+    }
+}
+
+/// Writes a date property in the event's form: dates for all-day events,
+/// local times with a `TZID` for events with a time zone, and UTC otherwise.
+fn write_dates(writer: &mut Writer, name: &str, event: &CalendarEvent, dates: &[DateTime<Utc>]) {
+    let time_zone = event.time_zone.as_deref().filter(|_| event.tz() != Tz::UTC);
+    let (params, values): (Vec<(&str, &str)>, Vec<String>) = match (event.is_all_day, time_zone) {
+        (true, _) => (
+            vec![("VALUE", "DATE")],
+            dates.iter().map(|date| local_date(event, *date).format("%Y%m%d").to_string()).collect(),
+        ),
+        (false, Some(time_zone)) => (
+            vec![("TZID", time_zone)],
+            dates
+                .iter()
+                .map(|date| date.with_timezone(&event.tz()).format("%Y%m%dT%H%M%S").to_string())
+                .collect(),
+        ),
+        (false, None) => (Vec::new(), dates.iter().map(|date| utc_value(*date)).collect()),
+    };
+    writer.line(name, &params, &values.join(","));
+}
+
+/// The rule with `UNTIL` in the form RFC 5545 requires: a date for all-day
+/// events and a UTC time otherwise.
+fn rule_value(event: &CalendarEvent, mut rule: RecurrenceRule) -> String {
+    let tz = event.tz();
+    rule.until = rule.until.map(|until| match (event.is_all_day, until) {
+        (true, Until::Utc(until)) => Until::Date(until.with_timezone(&tz).date_naive()),
+        (true, Until::Floating(until)) => Until::Date(until.date()),
+        (false, Until::Floating(until)) => Until::Utc(local_to_utc(until, tz)),
+        (false, Until::Date(until)) => {
+            Until::Utc(local_to_utc(until.and_time(NaiveTime::MIN) + Duration::days(1), tz) - Duration::seconds(1))
+        }
+        (_, until) => until,
+    });
+    rule.to_string()
+}
+
+/// Writes a `VTIMEZONE` describing the zone's offsets in `year`, repeated yearly.
+fn write_time_zone(writer: &mut Writer, name: &str, tz: Tz, year: i32) {
+    writer.line("BEGIN", &[], "VTIMEZONE");
+    writer.line("TZID", &[], name);
+    let transitions = transitions(tz, year);
+    if transitions.is_empty() {
+        let start = Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).single().unwrap_or_default();
+        let offset = tz.offset_from_utc_datetime(&start.naive_utc());
+        writer.line("BEGIN", &[], "STANDARD");
+        writer.line("DTSTART", &[], "19700101T000000");
+        writer.line("TZOFFSETFROM", &[], &offset_value(offset.fix()));
+        writer.line("TZOFFSETTO", &[], &offset_value(offset.fix()));
+        if let Some(abbreviation) = offset.abbreviation() {
+            writer.line("TZNAME", &[], &escape_text(abbreviation));
+        }
+        writer.line("END", &[], "STANDARD");
+    }
+
+    let largest = transitions.iter().map(|(_, _, to)| to.fix().local_minus_utc()).max().unwrap_or_default();
+    for (at, from, to) in &transitions {
+        let component = match to.fix().local_minus_utc() == largest && transitions.len() > 1 {
+            true => "DAYLIGHT",
+            false => "STANDARD",
+        };
+        // Observances start at the local time in force before the change
+        let onset = at.naive_utc() + Duration::seconds(i64::from(from.local_minus_utc()));
+        let (month, ordinal, weekday) = (onset.month(), week_of_month(onset.date()), onset.weekday());
+        let first = nth_weekday(1970, month, ordinal, weekday).unwrap_or(onset.date()).and_time(onset.time());
+        writer.line("BEGIN", &[], component);
+        writer.line("DTSTART", &[], &first.format("%Y%m%dT%H%M%S").to_string());
+        writer.line("TZOFFSETFROM", &[], &offset_value(*from));
+        writer.line("TZOFFSETTO", &[], &offset_value(to.fix()));
+        writer.line("RRULE", &[], &format!("FREQ=YEARLY;BYMONTH={};BYDAY={}{}", month, ordinal, weekday_code(weekday)));
+        if let Some(abbreviation) = to.abbreviation() {
+            writer.line("TZNAME", &[], &escape_text(abbreviation));
+        }
+        writer.line("END", &[], component);
+    }
+    writer.line("END", &[], "VTIMEZONE");
+}
+
+/// Offset changes of a zone during `year`: when, the offset before, and the offset after.
+fn transitions(tz: Tz, year: i32) -> Vec<(DateTime<Utc>, FixedOffset, chrono_tz::TzOffset)> {
+    let (Some(start), Some(end)) = (
+        Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).single(),
+        Utc.with_ymd_and_hms(year + 1, 1, 1, 0, 0, 0).single(),
+    ) else {
+        return Vec::new();
+    };
+    // Zones change on the quarter hour at the finest
+    let step = Duration::minutes(15);
+    let mut transitions = Vec::new();
+    let mut offset = tz.offset_from_utc_datetime(&start.naive_utc()).fix();
+    let mut at = start;
+    while at < end {
+        at += step;
+        let next = tz.offset_from_utc_datetime(&at.naive_utc());
+        if next.fix() != offset {
+            transitions.push((at, offset, next));
+            offset = next.fix();
+        }
+    }
+    transitions
+}
+
+/// Which `BYDAY` ordinal a date is in its month: 1 to 4, or -1 for the last week.
+fn week_of_month(date: NaiveDate) -> i32 {
+    let next_month = date + Duration::days(7);
+    if next_month.month() != date.month() {
+        return -1;
+    }
+    (date.day0() / 7 + 1) as i32
+}
+
+fn nth_weekday(year: i32, month: u32, ordinal: i32, weekday: Weekday) -> Option<NaiveDate> {
+    if ordinal > 0 {
+        return NaiveDate::from_weekday_of_month_opt(year, month, weekday, ordinal as u8);
+    }
+    let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    let last = NaiveDate::from_ymd_opt(next_year, next_month, 1)?.pred_opt()?;
+    Some(last - Duration::days(i64::from(last.weekday().days_since(weekday))))
+}
+
+fn weekday_code(weekday: Weekday) -> &'static str {
+    match weekday {
+        Weekday::Mon => "MO",
+        Weekday::Tue => "TU",
+        Weekday::Wed => "WE",
+        Weekday::Thu => "TH",
+        Weekday::Fri => "FR",
+        Weekday::Sat => "SA",
+        Weekday::Sun => "SU",
+    }
+}
+
+/// `UTC-OFFSET` value, e.g. `+0100` or `-0930`.
+fn offset_value(offset: FixedOffset) -> String {
+    let seconds = offset.local_minus_utc();
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let seconds = seconds.unsigned_abs();
+    match seconds % 60 {
+        0 => format!("{}{:02}{:02}", sign, seconds / 3600, seconds / 60 % 60),
+        rest => format!("{}{:02}{:02}{:02}", sign, seconds / 3600, seconds / 60 % 60, rest),
+    }
+}
+
+fn utc_value(date: DateTime<Utc>) -> String {
+    date.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// The calendar date of a time in the event's time zone.
+fn local_date(event: &CalendarEvent, date: DateTime<Utc>) -> NaiveDate {
+    date.with_timezone(&event.tz()).date_naive()
+}
+
+/// Escapes a `TEXT` value.
+fn escape_text(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c if c.is_control() && c != '\t' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Quotes a parameter value when it contains separators; quotes themselves
+/// can't be represented and are dropped.
+fn quote_param(value: &str) -> String {
+    let value: String = value.chars().filter(|c| *c != '"' && (!c.is_control() || *c == '\t')).collect();
+    match value.contains([':', ';', ',']) {
+        true => format!("\"{}\"", value),
+        false => value,
+    }
+}
+
+/// Windows time zone names, as written by Outlook and Exchange, and the
+/// IANA zones they stand for.
+const WINDOWS_ZONES: &[(&str, &str)] = &[
+    ("Dateline Standard Time", "Etc/GMT+12"),
+    ("Hawaiian Standard Time", "Pacific/Honolulu"),
+    ("Alaskan Standard Time", "America/Anchorage"),
+    ("Pacific Standard Time", "America/Los_Angeles"),
+    ("US Mountain Standard Time", "America/Phoenix"),
+    ("Mountain Standard Time", "America/Denver"),
+    ("Central Standard Time", "America/Chicago"),
+    ("Central America Standard Time", "America/Guatemala"),
+    ("Central Standard Time (Mexico)", "America/Mexico_City"),
+    ("Canada Central Standard Time", "America/Regina"),
+    ("Eastern Standard Time", "America/New_York"),
+    ("SA Pacific Standard Time", "America/Bogota"),
+    ("Atlantic Standard Time", "America/Halifax"),
+    ("Newfoundland Standard Time", "America/St_Johns"),
+    ("E. South America Standard Time", "America/Sao_Paulo"),
+    ("Argentina Standard Time", "America/Buenos_Aires"),
+    ("UTC", "Etc/UTC"),
+    ("GMT Standard Time", "Europe/London"),
+    ("Greenwich Standard Time", "Atlantic/Reykjavik"),
+    ("W. Europe Standard Time", "Europe/Berlin"),
+    ("Central Europe Standard Time", "Europe/Budapest"),
+    ("Romance Standard Time", "Europe/Paris"),
+    ("Central European Standard Time", "Europe/Warsaw"),
+    ("W. Central Africa Standard Time", "Africa/Lagos"),
+    ("GTB Standard Time", "Europe/Bucharest"),
+    ("FLE Standard Time", "Europe/Helsinki"),
+    ("E. Europe Standard Time", "Europe/Chisinau"),
+    ("Egypt Standard Time", "Africa/Cairo"),
+    ("South Africa Standard Time", "Africa/Johannesburg"),
+    ("Israel Standard Time", "Asia/Jerusalem"),
+    ("Turkey Standard Time", "Europe/Istanbul"),
+    ("Russian Standard Time", "Europe/Moscow"),
+    ("Arabian Standard Time", "Asia/Dubai"),
+    ("Iran Standard Time", "Asia/Tehran"),
+    ("Pakistan Standard Time", "Asia/Karachi"),
+    ("India Standard Time", "Asia/Kolkata"),
+    ("Nepal Standard Time", "Asia/Kathmandu"),
+    ("Bangladesh Standard Time", "Asia/Dhaka"),
+    ("SE Asia Standard Time", "Asia/Bangkok"),
+    ("China Standard Time", "Asia/Shanghai"),
+    ("Singapore Standard Time", "Asia/Singapore"),
+    ("Taipei Standard Time", "Asia/Taipei"),
+    ("W. Australia Standard Time", "Australia/Perth"),
+    ("Tokyo Standard Time", "Asia/Tokyo"),
+    ("Korea Standard Time", "Asia/Seoul"),
+    ("Cen. Australia Standard Time", "Australia/Adelaide"),
+    ("E. Australia Standard Time", "Australia/Brisbane"),
+    ("AUS Eastern Standard Time", "Australia/Sydney"),
+    ("New Zealand Standard Time", "Pacific/Auckland"),
+];
+
+/// A content line: `NAME;PARAM=value:value`.
+#[derive(Debug, Clone)]
+struct Property {
+    name: String,
+    params: Vec<(String, String)>,
+    value: String,
+}
+
+impl Property {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(param, _)| param == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// A component such as `VEVENT`, with its properties and nested components.
+#[derive(Debug, Default)]
+struct Component {
+    name: String,
+    properties: Vec<Property>,
+    components: Vec<Component>,
+}
+
+impl Component {
+    fn property(&self, name: &str) -> Option<&Property> {
+        self.properties.iter().find(|property| property.name == name)
+    }
+
+    fn properties<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Property> {
+        self.properties.iter().filter(move |property| property.name == name)
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Component> {
+        self.components.iter().filter(move |component| component.name == name)
+    }
+
+    /// Unescaped value of a `TEXT` property, if it isn't blank.
+    fn text(&self, name: &str) -> Option<String> {
+        self.property(name).map(|property| unescape_text(&property.value)).filter(|text| !text.trim().is_empty())
+    }
+}
+
+/// A `VEVENT` mapped onto a calendar event.
+#[derive(Debug)]
+pub struct ParsedEvent {
+    pub uid: String,
+    /// Original start of the occurrence an exception replaces.
+    pub recurrence_id: Option<DateTime<Utc>>,
+    /// `STATUS:CANCELLED`; for an exception, the occurrence is cancelled.
+    pub cancelled: bool,
+    pub event: CalendarEvent,
+}
+
+/// A `VEVENT` that can't be imported.
+#[derive(Debug)]
+pub struct SkippedEvent {
+    pub uid: String,
+    pub title: String,
+    pub reason: String,
+}
+
+/// The events of an iCalendar file.
+#[derive(Debug, Default)]
+pub struct ParsedCalendar {
+    pub events: Vec<ParsedEvent>,
+    pub skipped: Vec<SkippedEvent>,
+    pub warnings: Vec<String>,
+}
+
+/// Parses the `VEVENT`s of an iCalendar file. Events that can't be mapped
+/// onto a calendar event are listed as skipped, with the reason.
+pub fn parse_calendar(data: &str) -> Result<ParsedCalendar, String> {
+    let root = parse_components(data)?;
+    let calendars: Vec<&Component> = root.iter().filter(|component| component.name == "VCALENDAR").collect();
+    if calendars.is_empty() {
+        return Err("Not an iCalendar file: no VCALENDAR found".to_string());
+    }
+
+    let mut parsed = ParsedCalendar::default();
+    for calendar in calendars {
+        let mut zones = Zones {
+            // Floating times are in the calendar's zone, when it names one
+            default: calendar.property("X-WR-TIMEZONE").and_then(|property| resolve_time_zone(&property.value, None)),
+            by_id: HashMap::new(),
+        };
+        for definition in calendar.children("VTIMEZONE") {
+            let Some(tzid) = definition.property("TZID").map(|property| property.value.clone()) else {
+                continue;
+            };
+            match resolve_time_zone(&tzid, Some(definition)) {
+                Some(tz) => {
+                    zones.by_id.insert(tzid, tz);
+                }
+                None => parsed.warnings.push(format!("No matching time zone for {}", tzid)),
+            }
+        }
+
+        for component in calendar.children("VEVENT") {
+            match parse_event(component, &zones) {
+                Ok(event) => parsed.events.push(event),
+                Err(reason) => parsed.skipped.push(SkippedEvent {
+                    uid: component.text("UID").unwrap_or_default(),
+                    title: component.text("SUMMARY").unwrap_or_else(|| UNTITLED.to_string()),
+                    reason,
+                }),
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+fn parse_event(component: &Component, zones: &Zones) -> Result<ParsedEvent, String> {
+    let uid = component.text("UID").ok_or("Missing UID")?;
+    let start = component.property("DTSTART").ok_or("Missing DTSTART")?;
+    let start = zones.instant(start, &start.value, None)?;
+    let end = match (component.property("DTEND"), component.property("DURATION")) {
+        (Some(end), _) => zones.instant(end, &end.value, None)?.at,
+        (None, Some(duration)) => {
+            start.at + parse_duration(&duration.value).ok_or_else(|| format!("Invalid DURATION: {}", duration.value))?
+        }
+        // Without an end, all-day events last the day and others take no time
+        (None, None) if start.all_day => start.at + Duration::days(1),
+        (None, None) => start.at,
+    };
+    let zone = start.zone.unwrap_or(Tz::UTC);
+    // Dates in RDATE and EXDATE of timed events take the time of day of the start
+    let time_of_day = (!start.all_day).then(|| (start.at.with_timezone(&zone).time(), zone));
+
+    let mut rules = component.properties("RRULE");
+    let rule = rules.next().map(|rule| rule.value.parse::<RecurrenceRule>()).transpose()?;
+    if rules.next().is_some() {
+        return Err("Only one RRULE is supported".to_string());
+    }
+    let dates = |name: &str| -> Result<Vec<DateTime<Utc>>, String> {
+        let mut dates = Vec::new();
+        for property in component.properties(name) {
+            for value in property.value.split(',').map(str::trim).filter(|value| !value.is_empty()) {
+                // Periods occur at their start
+                let value = value.split('/').next().unwrap_or(value);
+                dates.push(zones.instant(property, value, time_of_day)?.at);
+            }
+        }
+        dates.sort();
+        dates.dedup();
+        Ok(dates)
+    };
+    let recurrence_id = component
+        .property("RECURRENCE-ID")
+        .map(|property| zones.instant(property, &property.value, time_of_day))
+        .transpose()?
+        .map(|instant| instant.at);
+
+    let attendees = component
+        .properties("ATTENDEE")
+        .filter_map(|attendee| {
+            let address = attendee.value.trim();
+            let address = address.strip_prefix("mailto:").or_else(|| address.strip_prefix("MAILTO:")).unwrap_or(address);
+            match validator::validate_email(address) {
+                true => Some(address.to_string()),
+                false => attendee.param("CN").map(str::to_string),
+            }
+        })
+        .filter(|attendee| !attendee.trim().is_empty())
+        .collect();
+    let created_at = component
+        .property("CREATED")
+        .and_then(|created| zones.instant(created, &created.value, None).ok())
+        .map_or_else(Utc::now, |instant| instant.at);
+
+    let event = CalendarEvent {
+        id: None,
+        title: component.text("SUMMARY").unwrap_or_else(|| UNTITLED.to_string()),
+        description: component.text("DESCRIPTION"),
+        start_time: start.at,
+        end_time: end,
+        location: component.text("LOCATION"),
+        is_all_day: start.all_day,
+        time_zone: start.zone.filter(|zone| *zone != Tz::UTC).map(|zone| zone.name().to_string()),
+        recurrence_rule: rule.map(|rule| rule.to_string()),
+        recurrence_dates: dates("RDATE")?,
+        excluded_dates: dates("EXDATE")?,
+        recurring_event_id: None,
+        recurrence_id: None,
+        attendees,
+        color: component.text("COLOR").or_else(|| component.text("X-ORGANISE-COLOR")),
+        calendar_id: None,
+        ical_uid: Some(uid.clone()),
+        dav_name: None,
+        external_id: None,
+        synced_at: None,
+        created_at,
+        updated_at: Utc::now(),
+        deleted_at: None,
+    };
+    event.validate()?;
+    Ok(ParsedEvent {
+        uid,
+        recurrence_id,
+        cancelled: component.property("STATUS").is_some_and(|status| status.value.eq_ignore_ascii_case("CANCELLED")),
+        event,
+    })
+}
+
+/// A parsed date or date-time value.
+struct Instant {
+    at: DateTime<Utc>,
+    /// A `DATE` value; it stands for midnight UTC.
+    all_day: bool,
+    /// Zone of a local time; `None` for UTC times and dates.
+    zone: Option<Tz>,
+}
+
+/// The time zones of a calendar, by `TZID`.
+struct Zones {
+    default: Option<Tz>,
+    by_id: HashMap<String, Tz>,
+}
+
+impl Zones {
+    /// Parses one value of a date property. Dates stand for midnight UTC
+    /// unless `time_of_day` gives them a time in a zone.
+    fn instant(&self, property: &Property, value: &str, time_of_day: Option<(NaiveTime, Tz)>) -> Result<Instant, String> {
+        let value = value.trim();
+        let invalid = || format!("Invalid {}: {}", property.name, value);
+        if property.param("VALUE") == Some("DATE") || value.len() == 8 {
+            let date = NaiveDate::parse_from_str(value, "%Y%m%d").map_err(|_| invalid())?;
+            return Ok(match time_of_day {
+                Some((time, zone)) => Instant { at: local_to_utc(date.and_time(time), zone), all_day: false, zone: Some(zone) },
+                None => Instant { at: date.and_time(NaiveTime::MIN).and_utc(), all_day: true, zone: None },
+            });
+        }
+        if let Some(utc) = value.strip_suffix('Z') {
+            let at = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").map_err(|_| invalid())?.and_utc();
+            return Ok(Instant { at, all_day: false, zone: None });
+        }
+        let local = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").map_err(|_| invalid())?;
+        let zone = match property.param("TZID") {
+            Some(tzid) => self.zone(tzid)?,
+            None => self.default.unwrap_or(Tz::UTC),
+        };
+        Ok(Instant { at: local_to_utc(local, zone), all_day: false, zone: Some(zone) })
+    }
+
+    fn zone(&self, tzid: &str) -> Result<Tz, String> {
+        self.by_id
+            .get(tzid)
+            .copied()
+            .or_else(|| resolve_time_zone(tzid, None))
+            .ok_or_else(|| format!("Unknown time zone: {}", tzid))
+    }
+}
+
+/// The IANA zone for a time zone name, which may also be a Windows name or a
+/// prefixed `TZID`.
+pub fn find_time_zone(name: &str) -> Option<Tz> {
+    resolve_time_zone(name, None)
+}
+
+/// Finds the IANA zone a `TZID` stands for: by name, by the IANA name at the
+/// end of prefixed ids such as `/mozilla.org/20050126_1/Europe/Berlin`, by
+/// Windows name, or by the offsets of its `VTIMEZONE`.
+fn resolve_time_zone(tzid: &str, definition: Option<&Component>) -> Option<Tz> {
+    let tzid = tzid.trim();
+    if let Ok(tz) = tzid.parse() {
+        return Some(tz);
+    }
+    let segments: Vec<&str> = tzid.split('/').collect();
+    for first in 1..segments.len() {
+        if let Ok(tz) = segments[first..].join("/").parse() {
+            return Some(tz);
+        }
+    }
+    if let Some((_, name)) = WINDOWS_ZONES.iter().find(|(windows, _)| windows.eq_ignore_ascii_case(tzid)) {
+        return name.parse().ok();
+    }
+    definition.and_then(match_definition)
+}
+
+/// The zone whose offsets this year match a `VTIMEZONE`'s standard and
+/// daylight offsets, preferring one whose abbreviations match its `TZNAME`s.
+fn match_definition(definition: &Component) -> Option<Tz> {
+    let offset = |name: &str| {
+        definition
+            .children(name)
+            .filter_map(|observance| parse_utc_offset(&observance.property("TZOFFSETTO")?.value))
+            .last()
+    };
+    let standard = offset("STANDARD").or_else(|| offset("DAYLIGHT"))?;
+    let daylight = offset("DAYLIGHT").unwrap_or(standard);
+    let mut expected = [standard, daylight];
+    expected.sort();
+    let names: Vec<&str> = definition
+        .components
+        .iter()
+        .filter_map(|observance| Some(observance.property("TZNAME")?.value.as_str()))
+        .collect();
+
+    let year = Utc::now().year();
+    let samples = [
+        Utc.with_ymd_and_hms(year, 1, 15, 12, 0, 0).single()?,
+        Utc.with_ymd_and_hms(year, 7, 15, 12, 0, 0).single()?,
+    ];
+    let candidates: Vec<Tz> = chrono_tz::TZ_VARIANTS
+        .iter()
+        .copied()
+        .filter(|tz| {
+            let mut offsets = samples.map(|at| tz.offset_from_utc_datetime(&at.naive_utc()).fix().local_minus_utc());
+            offsets.sort();
+            offsets == expected
+        })
+        .collect();
+    let abbreviations_match = |tz: &Tz| {
+        samples.iter().all(|at| {
+            let abbreviation = tz.offset_from_utc_datetime(&at.naive_utc()).abbreviation().unwrap_or_default().to_string();
+            names.contains(&abbreviation.as_str())
+        })
+    };
+    // Region names are preferred over legacy and `Etc/` aliases
+    let regional = |tz: &Tz| tz.name().contains('/') && !tz.name().starts_with("Etc/");
+    candidates
+        .iter()
+        .find(|tz| regional(tz) && abbreviations_match(tz))
+        .or_else(|| candidates.iter().find(|tz| regional(tz)))
+        .or_else(|| candidates.first())
+        .copied()
+}
+
+/// Parses a `UTC-OFFSET` such as `+0100` or `-053000` into seconds.
+fn parse_utc_offset(value: &str) -> Option<i32> {
+    let value = value.trim();
+    let (sign, digits) = match value.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    if !(digits.len() == 4 || digits.len() == 6) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let part = |range: std::ops::Range<usize>| digits.get(range).and_then(|part| part.parse::<i32>().ok());
+    let seconds = part(0..2)? * 3600 + part(2..4)? * 60 + part(4..6).unwrap_or(0);
+    Some(sign * seconds)
+}
+
+/// Parses a `DURATION` such as `PT1H30M`, `P1D` or `-P1W`.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(value) => (true, value),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let mut total = Duration::zero();
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in value.strip_prefix('P')?.chars() {
+        match (c, in_time) {
+            ('0'..='9', _) => number.push(c),
+            ('T', false) => in_time = true,
+            (unit, _) => {
+                let count: i64 = number.parse().ok()?;
+                number.clear();
+                total += match (unit, in_time) {
+                    ('W', false) => Duration::try_weeks(count)?,
+                    ('D', false) => Duration::try_days(count)?,
+                    ('H', true) => Duration::try_hours(count)?,
+                    ('M', true) => Duration::try_minutes(count)?,
+                    ('S', true) => Duration::try_seconds(count)?,
+                    _ => return None,
+                };
+            }
+        }
+    }
+    number.is_empty().then_some(if negative { -total } else { total })
+}
+
+/// Unfolds the content lines of a file and groups them into components.
+fn parse_components(data: &str) -> Result<Vec<Component>, String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in data.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line)) {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ if line.trim().is_empty() => {}
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    let mut root = Vec::new();
+    let mut stack: Vec<Component> = Vec::new();
+    for line in lines {
+        let Some(property) = parse_property(&line) else {
+            continue;
+        };
+        match property.name.as_str() {
+            "BEGIN" => stack.push(Component { name: property.value.trim().to_ascii_uppercase(), ..Component::default() }),
+            "END" => {
+                let component = stack.pop().ok_or_else(|| format!("Unexpected END:{}", property.value))?;
+                if !component.name.eq_ignore_ascii_case(property.value.trim()) {
+                    return Err(format!("Expected END:{}, found END:{}", component.name, property.value));
+                }
+                match stack.last_mut() {
+                    Some(parent) => parent.components.push(component),
+                    None => root.push(component),
+                }
+            }
+            _ => {
+                if let Some(component) = stack.last_mut() {
+                    component.properties.push(property);
+                }
+            }
+        }
+    }
+    if let Some(component) = stack.last() {
+        return Err(format!("Missing END:{}", component.name));
+    }
+    Ok(root)
+}
+
+/// Parses a content line; separators inside quoted parameter values don't count.
+fn parse_property(line: &str) -> Option<Property> {
+    let mut in_quotes = false;
+    let mut separators = Vec::new();
+    let mut colon = None;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => separators.push(index),
+            ':' if !in_quotes => {
+                colon = Some(index);
+                break;
+            }
+            _ => {}
+        }
+    }
+    let colon = colon?;
+    let mut bounds = separators;
+    bounds.push(colon);
+    let name = line[..bounds[0]].trim().to_ascii_uppercase();
+    let params = bounds
+        .windows(2)
+        .filter_map(|pair| line[pair[0] + 1..pair[1]].split_once('='))
+        .map(|(param, value)| (param.trim().to_ascii_uppercase(), value.trim().trim_matches('"').to_string()))
+        .collect();
+    Some(Property { name, params, value: line[colon + 1..].to_string() })
+}
+
+/// Unescapes a `TEXT` value.
+fn unescape_text(value: &str) -> String {
+    let mut text = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => text.push('\n'),
+            Some(escaped) => text.push(escaped),
+            None => text.push('\\'),
+        }
+    }
+    text
+}
+
+/// Accumulates content lines, folded and terminated with CRLF. Control
+/// characters other than tabs are dropped, so no value can start a line.
+#[derive(Default)]
+struct Writer {
+    output: String,
+}
+
+impl Writer {
+    fn line(&mut self, name: &str, params: &[(&str, &str)], value: &str) {
+        let mut line = name.to_string();
+        for (param, value) in params {
+            line.push(';');
+            line.push_str(param);
+            line.push('=');
+            line.push_str(&quote_param(value));
+        }
+        line.push(':');
+        line.push_str(value);
+        line.retain(|c| !c.is_control() || c == '\t');
+        self.fold(&line);
+    }
+
+    /// Splits a line into chunks of at most 75 octets, never inside a UTF-8
+    /// character; continuation lines start with a space.
+    fn fold(&mut self, line: &str) {
+        let mut octets = 0;
+        for c in line.chars() {
+            if octets + c.len_utf8() > MAX_LINE_OCTETS {
+                self.output.push_str("\r\n ");
+                octets = 1;
+            }
+            self.output.push(c);
+            octets += c.len_utf8();
+        }
+        self.output.push_str("\r\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn event(title: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> CalendarEvent {
+        CalendarEvent {
+            id: Some(ObjectId::new()),
+            title: title.to_string(),
+            description: None,
+            start_time: start,
+            end_time: end,
+            location: None,
+            is_all_day: false,
+            time_zone: None,
+            recurrence_rule: None,
+            recurrence_dates: Vec::new(),
+            excluded_dates: Vec::new(),
+            recurring_event_id: None,
+            recurrence_id: None,
+            attendees: Vec::new(),
+            color: None,
+            calendar_id: None,
+            ical_uid: None,
+            dav_name: None,
+            external_id: None,
+            synced_at: None,
+            created_at: start,
+            updated_at: start,
+            deleted_at: None,
+        }
+    }
+
+    fn round_trip(events: &[CalendarEvent]) -> Vec<ParsedEvent> {
+        let parsed = parse_calendar(&write_calendar("Work", events)).unwrap();
+        assert!(parsed.skipped.is_empty(), "{:?}", parsed.skipped);
+        parsed.events
+    }
+
+    #[test]
+    fn text_survives_a_round_trip() {
+        let mut original = event("Plan; review, ship\\done", utc(2024, 5, 1, 9, 0), utc(2024, 5, 1, 10, 0));
+        original.description = Some("First line\nSecond: line; with, separators".to_string());
+        original.location = Some("Room 1, Floor 2".to_string());
+        original.attendees = vec!["ann@example.com".to_string(), "Bob".to_string()];
+
+        let parsed = round_trip(&[original.clone()]);
+        let event = &parsed[0].event;
+        assert_eq!(parsed[0].uid, uid(&original));
+        assert_eq!(event.title, original.title);
+        assert_eq!(event.description, original.description);
+        assert_eq!(event.location, original.location);
+        assert_eq!(event.attendees, original.attendees);
+        assert_eq!((event.start_time, event.end_time), (original.start_time, original.end_time));
+    }
+
+    #[test]
+    fn recurrence_keeps_its_time_zone() {
+        let mut series = event("Stand-up", utc(2024, 3, 21, 9, 0), utc(2024, 3, 21, 9, 15));
+        series.time_zone = Some("Europe/London".to_string());
+        series.recurrence_rule = Some("FREQ=WEEKLY;UNTIL=20240502T080000Z;BYDAY=TH".to_string());
+        series.excluded_dates = vec![utc(2024, 4, 4, 8, 0)];
+        series.recurrence_dates = vec![utc(2024, 4, 5, 8, 0)];
+
+        let output = write_calendar("Work", &[series.clone()]);
+        assert!(output.contains("BEGIN:VTIMEZONE\r\nTZID:Europe/London\r\n"));
+        assert!(output.contains("DTSTART;TZID=Europe/London:20240321T090000\r\n"));
+        assert!(output.contains("EXDATE;TZID=Europe/London:20240404T090000\r\n"));
+
+        let event = &round_trip(&[series.clone()])[0].event;
+        assert_eq!(event.time_zone.as_deref(), Some("Europe/London"));
+        assert_eq!(event.start_time, series.start_time);
+        assert_eq!(event.excluded_dates, series.excluded_dates);
+        assert_eq!(event.recurrence_dates, series.recurrence_dates);
+        assert_eq!(event.rule().unwrap(), series.rule().unwrap());
+    }
+
+    #[test]
+    fn all_day_events_are_written_as_dates() {
+        let mut holiday = event("Holiday", utc(2024, 12, 24, 0, 0), utc(2024, 12, 26, 0, 0));
+        holiday.is_all_day = true;
+
+        let output = write_calendar("Work", &[holiday.clone()]);
+        assert!(output.contains("DTSTART;VALUE=DATE:20241224\r\n"));
+        assert!(output.contains("DTEND;VALUE=DATE:20241226\r\n"));
+        let event = &round_trip(&[holiday.clone()])[0].event;
+        assert!(event.is_all_day);
+        assert_eq!((event.start_time, event.end_time), (holiday.start_time, holiday.end_time));
+    }
+
+    #[test]
+    fn exceptions_share_the_series_uid() {
+        let mut series = event("Stand-up", utc(2024, 1, 1, 9, 0), utc(2024, 1, 1, 9, 15));
+        series.recurrence_rule = Some("FREQ=DAILY".to_string());
+        let mut moved = event("Stand-up (late)", utc(2024, 1, 2, 11, 0), utc(2024, 1, 2, 11, 15));
+        moved.recurring_event_id = series.id;
+        moved.recurrence_id = Some(utc(2024, 1, 2, 9, 0));
+
+        // The exception is written after its series even when listed first
+        let parsed = round_trip(&[moved, series.clone()]);
+        assert_eq!(parsed[0].uid, uid(&series));
+        assert_eq!(parsed[0].recurrence_id, None);
+        assert_eq!(parsed[1].uid, uid(&series));
+        assert_eq!(parsed[1].recurrence_id, Some(utc(2024, 1, 2, 9, 0)));
+        assert_eq!(event_id_from_uid(&parsed[1].uid), series.id);
+    }
+
+    #[test]
+    fn long_lines_are_folded_between_characters() {
+        let mut review = event("Review", utc(2024, 1, 1, 9, 0), utc(2024, 1, 1, 10, 0));
+        review.description = Some("Überprüfung der Änderungen ".repeat(10));
+
+        let output = write_calendar("Work", &[review.clone()]);
+        assert!(output.split("\r\n").all(|line| line.len() <= MAX_LINE_OCTETS));
+        assert_eq!(round_trip(&[review.clone()])[0].event.description, review.description);
+    }
+
+    #[test]
+    fn escaping_round_trips() {
+        for text in ["a;b,c", "back\\slash", "line\nbreak", "colon: fine", "trailing\\"] {
+            assert_eq!(unescape_text(&escape_text(text)), text);
+        }
+        // Carriage returns and other control characters are dropped
+        assert_eq!(escape_text("a\r\nb\u{7}c"), "a\\nbc");
+    }
+
+    #[test]
+    fn values_cannot_start_new_lines() {
+        let mut original = event("Call", utc(2024, 5, 1, 9, 0), utc(2024, 5, 1, 10, 0));
+        original.attendees = vec![
+            "ann@example.com\r\nATTENDEE:mailto:eve@example.com".to_string(),
+            "Bob\r\nX-INJECTED:1".to_string(),
+        ];
+        original.color = Some("#fff\r\nX-INJECTED:1".to_string());
+
+        let output = write_calendar("Work", &[original]);
+        assert!(!output.contains("\r\nX-INJECTED"), "{}", output);
+        assert!(!output.contains("\r\nATTENDEE:mailto:eve"), "{}", output);
+        assert_eq!(output.matches("\r\nATTENDEE").count(), 2);
+        // Raw line breaks never survive outside the line endings
+        assert!(output.split("\r\n").all(|line| !line.contains(['\r', '\n'])));
+    }
+
+    #[test]
+    fn parameters_with_separators_are_quoted() {
+        assert_eq!(quote_param("Smith, Ann"), "\"Smith, Ann\"");
+        assert_eq!(quote_param("Say \"hi\""), "Say hi");
+        let property = parse_property("ATTENDEE;CN=\"Smith; Ann: PhD\";ROLE=CHAIR:mailto:ann@example.com").unwrap();
+        assert_eq!(property.param("CN"), Some("Smith; Ann: PhD"));
+        assert_eq!(property.value, "mailto:ann@example.com");
+    }
+
+    #[test]
+    fn reads_windows_time_zone_names() {
+        let data = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:1\r\nSUMMARY:Call\r\n\
+            DTSTART;TZID=W. Europe Standard Time:20240701T100000\r\n\
+            DTEND;TZID=W. Europe Standard Time:20240701T110000\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+        let event = &parse_calendar(data).unwrap().events[0].event;
+        assert_eq!(event.time_zone.as_deref(), Some("Europe/Berlin"));
+        assert_eq!(event.start_time, utc(2024, 7, 1, 8, 0));
+    }
+
+    #[test]
+    fn unbalanced_components_are_rejected() {
+        assert!(parse_calendar("BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nEND:VCALENDAR\r\n").is_err());
+        assert!(parse_calendar("BEGIN:VCARD\r\nEND:VCARD\r\n").is_err());
+    }
+}
@@ -0,0 +1,121 @@
+//! Storage for `Idempotency-Key` requests, so that retried POSTs replay the
+//! first response instead of creating duplicates.
+
+use mongodb::{Client, Collection, bson::{doc, spec::BinarySubtype, Binary}};
+use mongodb::error::Error;
+use crate::models::datetime;
+use crate::models::idempotency::{IdempotencyRecord, StoredResponse};
+use crate::db::errors::is_duplicate_key;
+use chrono::{Duration, Utc};
+use sha2::{Digest, Sha256};
+use std::env;
+
+/// Default for the `IDEMPOTENCY_TTL_HOURS` environment variable.
+const DEFAULT_TTL_HOURS: i64 = 24;
+
+/// How long a request may hold its key before a retry may take it over,
+/// e.g. because the server restarted while handling it.
+const PENDING_TIMEOUT_MINUTES: i64 = 10;
+
+/// Longest key accepted.
+pub const MAX_KEY_LENGTH: usize = 255;
+
+/// What to do with a request carrying an idempotency key.
+pub enum Claim {
+    /// First use of the key: handle the request, then `complete` or `release` it.
+    Acquired,
+    /// The key was used before for the same request; send this response again.
+    Replay(StoredResponse),
+    /// The key was used before for a different request.
+    Mismatch,
+    /// The first request with this key is still being handled.
+    InProgress,
+}
+
+/// How long responses are kept for replay, from `IDEMPOTENCY_TTL_HOURS`.
+pub fn ttl() -> Duration {
+    let hours = env::var("IDEMPOTENCY_TTL_HOURS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|hours: &i64| *hours > 0)
+        .unwrap_or(DEFAULT_TTL_HOURS);
+    Duration::hours(hours)
+}
+
+/// Identifies a request by its method, path, query and body.
+pub fn fingerprint(method: &str, path: &str, query: &str, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    for part in [method.as_bytes(), path.as_bytes(), query.as_bytes()] {
+        hasher.update(part);
+        hasher.update([0]);
+    }
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+/// Takes the key for a request, or reports how an earlier use of it went.
+pub async fn claim(client: &Client, key: &str, fingerprint: &str) -> Result<Claim, Error> {
+    let collection = get_idempotency_collection(client);
+    let now = Utc::now();
+    let record = IdempotencyRecord {
+        key: key.to_string(),
+        fingerprint: fingerprint.to_string(),
+        response: None,
+        created_at: datetime::to_bson(now),
+        expires_at: datetime::to_bson(now + ttl()),
+    };
+
+    // Two attempts: the second one after clearing an expired or abandoned record
+    for _ in 0..2 {
+        match collection.insert_one(&record).await {
+            Ok(_) => return Ok(Claim::Acquired),
+            Err(e) if is_duplicate_key(&e) => {}
+            Err(e) => return Err(e),
+        }
+
+        let Some(existing) = collection.find_one(doc! { "_id": key }).await? else {
+            continue;
+        };
+        let abandoned = existing.response.is_none()
+            && existing.created_at < datetime::to_bson(now - Duration::minutes(PENDING_TIMEOUT_MINUTES));
+        // The TTL monitor only runs once a minute, so expired records may linger
+        if existing.expires_at <= record.created_at || abandoned {
+            collection
+                .delete_one(doc! { "_id": key, "created_at": existing.created_at })
+                .await?;
+            continue;
+        }
+
+        return Ok(match existing.response {
+            _ if existing.fingerprint != fingerprint => Claim::Mismatch,
+            Some(response) => Claim::Replay(response),
+            None => Claim::InProgress,
+        });
+    }
+    Ok(Claim::InProgress)
+}
+
+/// Stores the response to replay for a claimed key.
+pub async fn complete(client: &Client, key: &str, status: u16, headers: Vec<(String, String)>, body: Vec<u8>) -> Result<(), Error> {
+    let response = StoredResponse { status, headers, body: Binary { subtype: BinarySubtype::Generic, bytes: body } };
+    let response = mongodb::bson::to_bson(&response)?;
+    get_idempotency_collection(client)
+        .update_one(doc! { "_id": key }, doc! { "$set": { "response": response } })
+        .await?;
+    Ok(())
+}
+
+/// Gives up a claimed key so that the request can be retried, e.g. after a
+/// server error.
+pub async fn release(client: &Client, key: &str) -> Result<(), Error> {
+    get_idempotency_collection(client)
+        .delete_one(doc! { "_id": key, "response": null })
+        .await?;
+    Ok(())
+}
+
+/// Helper function to get the "idempotency_keys" collection.
+fn get_idempotency_collection(client: &Client) -> Collection<IdempotencyRecord> {
+    let db = client.database("organise");
+    db.collection::<IdempotencyRecord>("idempotency_keys")
+}
@@ -0,0 +1,883 @@
+//! Imports notes from Markdown folders (including Obsidian vaults) and
+//! Evernote ENEX exports.
+//!
+//! Parsing is synchronous and runs without the database; `import_archive`
+//! then maps folders to notebooks, resolves `[[wiki links]]`, detects
+//! duplicates and writes the notes.
+
+use mongodb::{Client, bson::{doc, oid::ObjectId}};
+use mongodb::error::Error;
+use futures_util::TryStreamExt;
+use crate::models::datetime;
+use crate::models::import::{
+    DuplicateStrategy, ImportAction, ImportFormat, ImportItem, ImportOptions, ImportReport, UnresolvedLink,
+};
+use crate::models::note::Note;
+use crate::models::notebook::Notebook;
+use crate::services::notebook_service::{self, NotebookServiceError};
+use crate::services::notes_service::{self, NotesServiceError};
+use crate::services::markdown_service;
+use thiserror::Error;
+use actix_web::HttpResponse;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Cursor, Read};
+use std::path::{Component, Path};
+use validator::Validate;
+
+/// Default for the `MAX_IMPORT_BYTES` environment variable.
+const DEFAULT_MAX_IMPORT_BYTES: usize = 100 * 1024 * 1024;
+
+/// How much larger than the upload its unpacked contents may be.
+const MAX_UNPACK_RATIO: usize = 10;
+
+/// Prefix of the links wiki links are rewritten to, followed by the note id.
+pub const NOTE_LINK_PREFIX: &str = "/notes/";
+
+#[derive(Error, Debug)]
+pub enum ImportServiceError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] Error),
+    #[error("Invalid ObjectId: {0}")]
+    InvalidObjectId(#[from] mongodb::bson::oid::Error),
+    #[error("Invalid archive: {0}")]
+    InvalidArchive(String),
+    #[error("Unsupported import format; expected a zip of Markdown files or an ENEX export")]
+    UnsupportedFormat,
+    #[error("Archive unpacks to more than {0} bytes")]
+    TooLarge(usize),
+    #[error(transparent)]
+    NotesError(#[from] NotesServiceError),
+    #[error(transparent)]
+    NotebookError(#[from] NotebookServiceError),
+}
+
+/// A note read from an archive, before it is matched against the database.
+#[derive(Debug)]
+pub struct ParsedNote {
+    /// Path inside the archive.
+    pub path: String,
+    /// Folders the note lives in, outermost first.
+    pub folders: Vec<String>,
+    pub title: String,
+    /// Other names wiki links may use for the note (Obsidian `aliases`).
+    pub aliases: Vec<String>,
+    pub content: String,
+    pub tags: Vec<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// The notes found in an archive.
+#[derive(Debug)]
+pub struct ParsedArchive {
+    pub format: ImportFormat,
+    pub notes: Vec<ParsedNote>,
+    pub warnings: Vec<String>,
+}
+
+/// Largest upload accepted for imports, from `MAX_IMPORT_BYTES`.
+pub fn max_import_size() -> usize {
+    std::env::var("MAX_IMPORT_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_IMPORT_BYTES)
+}
+
+/// Reads the notes from an uploaded file.
+///
+/// Accepts a zip of Markdown and/or ENEX files, a single ENEX export or a
+/// single Markdown file.
+pub fn parse_archive(filename: &str, data: &[u8]) -> Result<ParsedArchive, ImportServiceError> {
+    let mut archive = ParsedArchive { format: ImportFormat::Markdown, notes: Vec::new(), warnings: Vec::new() };
+    let lower = filename.to_lowercase();
+
+    if data.starts_with(b"PK\x03\x04") {
+        parse_zip(data, &mut archive)?;
+    } else if lower.ends_with(".enex") || looks_like_enex(data) {
+        let text = String::from_utf8_lossy(data);
+        parse_enex(&text, filename, &[], &mut archive)?;
+    } else if is_markdown_file(&lower) {
+        let text = String::from_utf8(data.to_vec())
+            .map_err(|_| ImportServiceError::InvalidArchive(format!("{} is not valid UTF-8", filename)))?;
+        archive.notes.push(parse_markdown(filename, &[], &text, &mut archive.warnings));
+    } else {
+        return Err(ImportServiceError::UnsupportedFormat);
+    }
+    Ok(archive)
+}
+
+/// Imports parsed notes.
+///
+/// Folders become notebooks, reusing notebooks with the same name and parent.
+/// A note is a duplicate when a note with the same title exists in its target
+/// notebook or comes earlier in the archive; identical duplicates are always
+/// skipped, others follow `options.duplicates`. With `options.dry_run` nothing
+/// is written.
+pub async fn import_archive(
+    client: &Client,
+    archive: ParsedArchive,
+    options: &ImportOptions,
+) -> Result<ImportReport, ImportServiceError> {
+    let ParsedArchive { format, notes, warnings } = archive;
+    let root_id = match options.notebook_id.as_deref() {
+        Some(notebook_id) => Some(notebook_service::get_notebook(client, notebook_id).await?.id.unwrap_or_default()),
+        None => None,
+    };
+
+    // Map every folder path to an existing or a new notebook
+    let existing_notebooks: Vec<Notebook> = notebook_service::get_notebooks_collection(client)
+        .find(doc! {})
+        .await?
+        .try_collect()
+        .await?;
+    let mut by_name: HashMap<(Option<ObjectId>, String), ObjectId> = existing_notebooks
+        .iter()
+        .filter_map(|n| Some(((n.parent_id, n.name.to_lowercase()), n.id?)))
+        .collect();
+    let folder_paths: BTreeSet<&[String]> = notes.iter().map(|n| n.folders.as_slice()).collect();
+    let mut new_notebooks: Vec<Notebook> = Vec::new();
+    let mut folder_ids: HashMap<Vec<String>, Option<ObjectId>> = HashMap::new();
+    for folders in folder_paths {
+        let mut parent_id = root_id;
+        for name in folders {
+            let name: String = name.chars().take(100).collect();
+            let key = (parent_id, name.to_lowercase());
+            let id = match by_name.get(&key) {
+                Some(id) => *id,
+                None => {
+                    let mut notebook = Notebook::new(name, parent_id);
+                    let id = ObjectId::new();
+                    notebook.id = Some(id);
+                    new_notebooks.push(notebook);
+                    by_name.insert(key, id);
+                    id
+                }
+            };
+            parent_id = Some(id);
+        }
+        folder_ids.insert(folders.to_vec(), parent_id);
+    }
+    let created_notebooks: BTreeSet<ObjectId> = new_notebooks.iter().filter_map(|n| n.id).collect();
+
+    // Decide which note every imported file maps to
+    let collection = notes_service::get_notes_collection(client);
+    let mut plans: Vec<NotePlan> = Vec::with_capacity(notes.len());
+    let mut seen: HashMap<(Option<ObjectId>, String), usize> = HashMap::new();
+    for parsed in notes {
+        let notebook_id = folder_ids.get(&parsed.folders).copied().flatten();
+        let mut note = Note::new(parsed.title.chars().take(100).collect(), parsed.content.clone());
+        note.tags = Some(parsed.tags.clone());
+        note.notebook_id = notebook_id;
+        note.created_at = parsed.created_at;
+        note.updated_at = parsed.updated_at.or(parsed.created_at);
+
+        let invalid = note.validate().err().map(|e| e.to_string().replace('\n', "; "));
+        // Exports often hold the same note more than once
+        let earlier = match invalid {
+            Some(_) => None,
+            None => seen.get(&(notebook_id, note.title.clone())).copied(),
+        };
+        let existing = match notebook_id {
+            // Notes in notebooks created by this import can't have duplicates yet
+            Some(id) if created_notebooks.contains(&id) => None,
+            _ if invalid.is_some() || earlier.is_some() => None,
+            _ => collection.find_one(doc! { "title": &note.title, "notebook_id": notebook_id, "deleted_at": null }).await?,
+        };
+        let target = match (earlier, &existing, options.duplicates) {
+            (Some(earlier), _, DuplicateStrategy::Skip | DuplicateStrategy::Overwrite) => plans[earlier].note.id,
+            (None, Some(existing), DuplicateStrategy::Skip | DuplicateStrategy::Overwrite) => existing.id,
+            _ => Some(ObjectId::new()),
+        };
+        note.id = target;
+        if invalid.is_none() {
+            seen.entry((notebook_id, note.title.clone())).or_insert(plans.len());
+        }
+        plans.push(NotePlan { parsed, note, earlier, existing, invalid });
+    }
+
+    // Rewrite wiki links now that every note has an id
+    let lookup = link_lookup(&plans);
+    let mut unresolved_links = Vec::new();
+    for plan in plans.iter_mut().filter(|plan| plan.invalid.is_none()) {
+        let (content, unresolved) = resolve_wiki_links(&plan.note.content, &lookup);
+        plan.note.content = content;
+        unresolved_links.extend(unresolved.into_iter().map(|target| UnresolvedLink { path: plan.parsed.path.clone(), target }));
+    }
+
+    let mut report = ImportReport {
+        dry_run: options.dry_run,
+        format,
+        notes_created: 0,
+        notes_overwritten: 0,
+        notes_skipped: 0,
+        notebooks_created: new_notebooks.len(),
+        items: Vec::new(),
+        unresolved_links,
+        warnings,
+    };
+
+    if !options.dry_run {
+        // Parents come before their children, since folder paths were visited in order
+        for notebook in new_notebooks {
+            notebook_service::add_notebook(client, notebook).await?;
+        }
+    }
+
+    let decisions: Vec<(ImportAction, Option<String>)> = plans.iter().map(|plan| decide(plan, &plans, options.duplicates)).collect();
+    for (plan, (action, reason)) in plans.into_iter().zip(decisions) {
+        let note_id = plan.note.id;
+
+        if !options.dry_run {
+            match action {
+                ImportAction::Create => {
+                    notes_service::add_note(client, plan.note, false).await?;
+                }
+                ImportAction::Overwrite => {
+                    let update = doc! {
+                        "$set": {
+                            "content": &plan.note.content,
+                            "tags": plan.note.tags.clone().unwrap_or_default(),
+                            "updated_at": datetime::to_bson(Utc::now())
+                        },
+                        "$inc": { "version": 1 }
+                    };
+                    collection.update_one(doc! { "_id": note_id }, update).await?;
+                }
+                ImportAction::Skip => {}
+            }
+        }
+
+        match action {
+            ImportAction::Create => report.notes_created += 1,
+            ImportAction::Overwrite => report.notes_overwritten += 1,
+            ImportAction::Skip => report.notes_skipped += 1,
+        }
+        report.items.push(ImportItem {
+            path: plan.parsed.path,
+            title: plan.parsed.title,
+            notebook: (!plan.parsed.folders.is_empty()).then(|| plan.parsed.folders.join("/")),
+            action,
+            note_id: note_id
+                .filter(|_| !options.dry_run && action != ImportAction::Skip)
+                .map(|id| id.to_hex()),
+            reason,
+        });
+    }
+
+    Ok(report)
+}
+
+/// An imported note together with the note it will become.
+struct NotePlan {
+    parsed: ParsedNote,
+    note: Note,
+    /// Index of a note with the same title and notebook earlier in the import.
+    earlier: Option<usize>,
+    /// Note with the same title already in the target notebook.
+    existing: Option<Note>,
+    /// Why the note can't be imported.
+    invalid: Option<String>,
+}
+
+/// What to do with a planned note, and why when it isn't a plain create.
+fn decide(plan: &NotePlan, plans: &[NotePlan], duplicates: DuplicateStrategy) -> (ImportAction, Option<String>) {
+    if let Some(invalid) = &plan.invalid {
+        return (ImportAction::Skip, Some(invalid.clone()));
+    }
+    if let Some(earlier) = plan.earlier.map(|index| &plans[index]) {
+        return match duplicates {
+            _ if earlier.note.content == plan.note.content => {
+                (ImportAction::Skip, Some(format!("Identical to {} in this import", earlier.parsed.path)))
+            }
+            DuplicateStrategy::Skip => (ImportAction::Skip, Some(format!("Same title as {} in this import", earlier.parsed.path))),
+            DuplicateStrategy::Overwrite => {
+                (ImportAction::Overwrite, Some(format!("Replaces {} from this import", earlier.parsed.path)))
+            }
+            DuplicateStrategy::KeepBoth => {
+                (ImportAction::Create, Some(format!("Kept next to {} from this import", earlier.parsed.path)))
+            }
+        };
+    }
+    match (&plan.existing, duplicates) {
+        (Some(existing), _) if existing.content == plan.note.content => {
+            (ImportAction::Skip, Some("Identical note already exists".to_string()))
+        }
+        (Some(_), DuplicateStrategy::Skip) => (ImportAction::Skip, Some("Note already exists".to_string())),
+        (Some(_), DuplicateStrategy::Overwrite) => (ImportAction::Overwrite, None),
+        (Some(_), DuplicateStrategy::KeepBoth) => {
+            (ImportAction::Create, Some("Kept next to an existing note with the same title".to_string()))
+        }
+        (None, _) => (ImportAction::Create, None),
+    }
+}
+
+/// Maps the names wiki links may use (lowercased path, file name, title and
+/// aliases) to note ids. Earlier notes win when names clash.
+fn link_lookup(plans: &[NotePlan]) -> HashMap<String, ObjectId> {
+    let mut lookup = HashMap::new();
+    for plan in plans.iter().filter(|plan| plan.invalid.is_none()) {
+        let Some(id) = plan.note.id else {
+            continue;
+        };
+        let path = strip_extension(&plan.parsed.path);
+        let stem = path.rsplit('/').next().unwrap_or(path);
+        let names = [path, stem, plan.parsed.title.as_str()]
+            .into_iter()
+            .chain(plan.parsed.aliases.iter().map(String::as_str));
+        for name in names {
+            lookup.entry(name.trim().to_lowercase()).or_insert(id);
+        }
+    }
+    lookup
+}
+
+/// Rewrites `[[Target]]`, `[[Target|Alias]]` and `[[Target#Heading]]` into
+/// Markdown links to the target note. Links in code are left alone, as are
+/// embeds (`![[...]]`) and links to unknown notes, which are returned.
+pub fn resolve_wiki_links(content: &str, lookup: &HashMap<String, ObjectId>) -> (String, Vec<String>) {
+    let code = markdown_service::code_ranges(content);
+    let mut resolved = String::with_capacity(content.len());
+    let mut unresolved = Vec::new();
+    let mut rest_start = 0;
+    let mut search_from = 0;
+
+    while let Some(offset) = content[search_from..].find("[[") {
+        let start = search_from + offset;
+        let Some(length) = content[start + 2..].find("]]") else {
+            break;
+        };
+        let end = start + 2 + length + 2;
+        search_from = end;
+
+        let inner = &content[start + 2..end - 2];
+        let is_embed = content[..start].ends_with('!');
+        if inner.contains('\n') || code.iter().any(|range| range.contains(&start)) {
+            search_from = start + 2;
+            continue;
+        }
+
+        let (target, alias) = match inner.split_once('|') {
+            Some((target, alias)) => (target, Some(alias.trim())),
+            None => (inner, None),
+        };
+        let name = target.split('#').next().unwrap_or(target).trim();
+        match lookup.get(&strip_extension(name).to_lowercase()) {
+            Some(id) if !is_embed => {
+                let text = alias.unwrap_or(target.trim());
+                resolved.push_str(&content[rest_start..start]);
+                resolved.push_str(&format!("[{}]({}{})", text, NOTE_LINK_PREFIX, id.to_hex()));
+                rest_start = end;
+            }
+            _ => unresolved.push(target.trim().to_string()),
+        }
+    }
+    resolved.push_str(&content[rest_start..]);
+    (resolved, unresolved)
+}
+
+/// Reads the Markdown and ENEX files in a zip archive.
+fn parse_zip(data: &[u8], archive: &mut ParsedArchive) -> Result<(), ImportServiceError> {
+    let invalid = |e: zip::result::ZipError| ImportServiceError::InvalidArchive(e.to_string());
+    let mut zip = zip::ZipArchive::new(Cursor::new(data)).map_err(invalid)?;
+    let max_unpacked = data.len().saturating_mul(MAX_UNPACK_RATIO);
+
+    // Zips of a vault usually hold a single top-level folder, which isn't a notebook
+    let mut paths = Vec::new();
+    for index in 0..zip.len() {
+        let entry = zip.by_index(index).map_err(invalid)?;
+        let path = entry.enclosed_name().map(|path| path_components(&path));
+        paths.push(path.filter(|components| !entry.is_dir() && !components.is_empty()));
+    }
+    let common_root = common_root(paths.iter().flatten().filter(|components| !is_hidden(components)));
+
+    let mut unpacked = 0usize;
+    for (index, components) in paths.into_iter().enumerate() {
+        let Some(mut components) = components else {
+            continue;
+        };
+        if is_hidden(&components) {
+            continue;
+        }
+        let path = components.join("/");
+        let lower = path.to_lowercase();
+        let is_enex = lower.ends_with(".enex");
+        if !is_enex && !is_markdown_file(&lower) {
+            archive.warnings.push(format!("Skipped {}: not a Markdown or ENEX file", path));
+            continue;
+        }
+
+        let entry = zip.by_index(index).map_err(invalid)?;
+        let mut bytes = Vec::new();
+        entry
+            .take((max_unpacked - unpacked) as u64 + 1)
+            .read_to_end(&mut bytes)
+            .map_err(|e| ImportServiceError::InvalidArchive(e.to_string()))?;
+        unpacked += bytes.len();
+        if unpacked > max_unpacked {
+            return Err(ImportServiceError::TooLarge(max_unpacked));
+        }
+        let Ok(text) = String::from_utf8(bytes) else {
+            archive.warnings.push(format!("Skipped {}: not valid UTF-8", path));
+            continue;
+        };
+
+        // What remains after dropping the root folder and the file name are notebooks
+        if common_root.is_some() {
+            components.remove(0);
+        }
+        components.pop();
+        if is_enex {
+            parse_enex(&text, &path, &components, archive)?;
+        } else {
+            let note = parse_markdown(&path, &components, &text, &mut archive.warnings);
+            archive.notes.push(note);
+        }
+    }
+    Ok(())
+}
+
+/// Reads a Markdown file, taking the title, aliases, tags and dates from its
+/// YAML front matter when present.
+fn parse_markdown(path: &str, folders: &[String], text: &str, warnings: &mut Vec<String>) -> ParsedNote {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let mut note = ParsedNote {
+        path: path.to_string(),
+        folders: folders.to_vec(),
+        title: strip_extension(file_name).to_string(),
+        aliases: Vec::new(),
+        content: text.to_string(),
+        tags: Vec::new(),
+        created_at: None,
+        updated_at: None,
+    };
+
+    let Some((front_matter, body)) = split_front_matter(text) else {
+        return note;
+    };
+    let yaml = match serde_yaml::from_str::<serde_yaml::Mapping>(front_matter) {
+        Ok(yaml) => yaml,
+        Err(e) => {
+            warnings.push(format!("Ignored invalid front matter in {}: {}", path, e));
+            return note;
+        }
+    };
+    note.content = body.to_string();
+
+    let field = |names: &[&str]| names.iter().find_map(|name| yaml.get(*name));
+    if let Some(title) = field(&["title"]).and_then(serde_yaml::Value::as_str) {
+        note.title = title.to_string();
+    }
+    note.aliases = field(&["aliases", "alias"]).map(string_list).unwrap_or_default();
+    note.tags = field(&["tags", "tag"])
+        .map(string_list)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|tag| tag.trim_start_matches('#').to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    note.created_at = field(&["created", "created_at", "date"]).and_then(parse_date);
+    note.updated_at = field(&["updated", "updated_at", "modified"]).and_then(parse_date);
+    note
+}
+
+/// Splits `---` delimited YAML front matter from the body.
+fn split_front_matter(text: &str) -> Option<(&str, &str)> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let rest = text.strip_prefix("---\n").or_else(|| text.strip_prefix("---\r\n"))?;
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if matches!(line.trim_end(), "---" | "...") {
+            let body = &rest[offset + line.len()..];
+            return Some((&rest[..offset], body.trim_start_matches(['\r', '\n'])));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+/// Reads a YAML list, or a string of comma- or space-separated values.
+fn string_list(value: &serde_yaml::Value) -> Vec<String> {
+    match value {
+        serde_yaml::Value::Sequence(items) => items
+            .iter()
+            .filter_map(|item| match item {
+                serde_yaml::Value::String(s) => Some(s.trim().to_string()),
+                serde_yaml::Value::Number(n) => Some(n.to_string()),
+                _ => None,
+            })
+            .collect(),
+        serde_yaml::Value::String(s) => s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Parses the date formats found in front matter, treating times as UTC.
+fn parse_date(value: &serde_yaml::Value) -> Option<DateTime<Utc>> {
+    let text = value.as_str()?.trim();
+    if let Ok(date) = DateTime::parse_from_rfc3339(text) {
+        return Some(date.with_timezone(&Utc));
+    }
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?.and_hms_opt(0, 0, 0))
+        .map(|date| date.and_utc())
+}
+
+/// Reads the notes of an Evernote export into a notebook named after the file.
+fn parse_enex(text: &str, path: &str, folders: &[String], archive: &mut ParsedArchive) -> Result<(), ImportServiceError> {
+    archive.format = ImportFormat::Enex;
+    let options = roxmltree::ParsingOptions { allow_dtd: true, ..Default::default() };
+    let document = roxmltree::Document::parse_with_options(text, options)
+        .map_err(|e| ImportServiceError::InvalidArchive(format!("{}: {}", path, e)))?;
+
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let mut folders = folders.to_vec();
+    folders.push(strip_extension(file_name).to_string());
+
+    for (index, node) in document.root_element().children().filter(|n| n.has_tag_name("note")).enumerate() {
+        let child_text = |name: &str| {
+            node.children()
+                .find(|n| n.has_tag_name(name))
+                .and_then(|n| n.text())
+                .map(str::trim)
+                .unwrap_or_default()
+                .to_string()
+        };
+        let title = child_text("title");
+        let note_path = format!("{}#{}", path, index + 1);
+        let (content, media) = match enml_to_markdown(&child_text("content")) {
+            Ok(converted) => converted,
+            Err(e) => {
+                archive.warnings.push(format!("Skipped {} ({}): invalid note content: {}", note_path, title, e));
+                continue;
+            }
+        };
+        if media > 0 {
+            archive.warnings.push(format!("{} ({}): {} attachment(s) not imported", note_path, title, media));
+        }
+
+        archive.notes.push(ParsedNote {
+            path: note_path,
+            folders: folders.clone(),
+            title: if title.is_empty() { "Untitled".to_string() } else { title },
+            aliases: Vec::new(),
+            content,
+            tags: node
+                .children()
+                .filter(|n| n.has_tag_name("tag"))
+                .filter_map(|n| n.text())
+                .map(|tag| tag.trim().to_string())
+                .collect(),
+            created_at: parse_enex_date(&child_text("created")),
+            updated_at: parse_enex_date(&child_text("updated")),
+        });
+    }
+    Ok(())
+}
+
+/// Parses ENEX timestamps such as `20240131T101500Z`.
+fn parse_enex_date(text: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(text, "%Y%m%dT%H%M%SZ").ok().map(|date| date.and_utc())
+}
+
+/// Converts Evernote's ENML note body to Markdown. Returns the Markdown and
+/// the number of embedded resources that were dropped.
+fn enml_to_markdown(enml: &str) -> Result<(String, usize), roxmltree::Error> {
+    // ENML is XHTML, which may use HTML entities XML doesn't define
+    let enml = ["nbsp", "mdash", "ndash", "hellip", "lsquo", "rsquo", "ldquo", "rdquo", "copy", "reg", "trade", "bull"]
+        .iter()
+        .zip(["&#160;", "&#8212;", "&#8211;", "&#8230;", "&#8216;", "&#8217;", "&#8220;", "&#8221;", "&#169;", "&#174;", "&#8482;", "&#8226;"])
+        .fold(enml.to_string(), |text, (name, code)| text.replace(&format!("&{};", name), code));
+    let options = roxmltree::ParsingOptions { allow_dtd: true, ..Default::default() };
+    let document = roxmltree::Document::parse_with_options(&enml, options)?;
+
+    let mut writer = MarkdownWriter::default();
+    writer.block(document.root_element());
+    Ok((writer.finish(), writer.media))
+}
+
+/// Accumulates Markdown while walking ENML.
+#[derive(Default)]
+struct MarkdownWriter {
+    out: String,
+    /// One entry per open list: `None` for bullets, the next number otherwise.
+    lists: Vec<Option<u32>>,
+    in_pre: bool,
+    media: usize,
+}
+
+impl MarkdownWriter {
+    fn block(&mut self, node: roxmltree::Node) {
+        for child in node.children() {
+            self.node(child);
+        }
+    }
+
+    fn node(&mut self, node: roxmltree::Node) {
+        if node.is_text() {
+            let text = node.text().unwrap_or_default();
+            if self.in_pre {
+                self.out.push_str(text);
+            } else {
+                let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                if text.starts_with(char::is_whitespace) && !self.at_line_start() && !self.out.ends_with(' ') {
+                    self.out.push(' ');
+                }
+                self.out.push_str(&collapsed);
+                if text.ends_with(char::is_whitespace) && !collapsed.is_empty() {
+                    self.out.push(' ');
+                }
+            }
+            return;
+        }
+        if !node.is_element() {
+            return;
+        }
+
+        match node.tag_name().name() {
+            "div" | "section" | "center" => {
+                self.block(node);
+                self.end_line();
+            }
+            "p" | "blockquote" => {
+                self.block(node);
+                self.end_paragraph();
+            }
+            tag @ ("h1" | "h2" | "h3" | "h4" | "h5" | "h6") => {
+                self.end_line();
+                let level = tag[1..].parse::<usize>().unwrap_or(1);
+                self.out.push_str(&"#".repeat(level));
+                self.out.push(' ');
+                self.block(node);
+                self.end_paragraph();
+            }
+            "br" => self.out.push('\n'),
+            "hr" => {
+                self.end_paragraph();
+                self.out.push_str("---\n\n");
+            }
+            "b" | "strong" => self.wrap(node, "**"),
+            "i" | "em" => self.wrap(node, "*"),
+            "s" | "strike" | "del" => self.wrap(node, "~~"),
+            "code" if !self.in_pre => self.wrap(node, "`"),
+            "a" => {
+                self.out.push('[');
+                self.block(node);
+                self.out.push_str(&format!("]({})", node.attribute("href").unwrap_or_default()));
+            }
+            "img" => {
+                let alt = node.attribute("alt").unwrap_or_default();
+                self.out.push_str(&format!("![{}]({})", alt, node.attribute("src").unwrap_or_default()));
+            }
+            "en-todo" => {
+                let checked = node.attribute("checked") == Some("true");
+                if !self.out.trim_end_matches(' ').ends_with("- ") {
+                    self.out.push_str("- ");
+                }
+                self.out.push_str(if checked { "[x] " } else { "[ ] " });
+            }
+            "en-media" => self.media += 1,
+            "pre" => {
+                self.end_paragraph();
+                self.out.push_str("```\n");
+                self.in_pre = true;
+                self.block(node);
+                self.in_pre = false;
+                self.end_line();
+                self.out.push_str("```\n\n");
+            }
+            "ul" | "ol" => {
+                self.end_line();
+                self.lists.push((node.tag_name().name() == "ol").then_some(1));
+                self.block(node);
+                self.lists.pop();
+                if self.lists.is_empty() {
+                    self.end_paragraph();
+                }
+            }
+            "li" => {
+                self.end_line();
+                let depth = self.lists.len().saturating_sub(1);
+                self.out.push_str(&"  ".repeat(depth));
+                match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        self.out.push_str(&format!("{}. ", number));
+                        *number += 1;
+                    }
+                    _ => self.out.push_str("- "),
+                }
+                self.block(node);
+                self.end_line();
+            }
+            "table" => {
+                self.end_paragraph();
+                let rows: Vec<_> = node.descendants().filter(|n| n.has_tag_name("tr")).collect();
+                for (index, row) in rows.iter().enumerate() {
+                    let cells: Vec<_> = row.children().filter(|n| n.has_tag_name("td") || n.has_tag_name("th")).collect();
+                    self.out.push('|');
+                    for cell in &cells {
+                        let mut writer = MarkdownWriter::default();
+                        writer.block(*cell);
+                        self.media += writer.media;
+                        self.out.push_str(&format!(" {} |", writer.finish().replace('\n', " ").replace('|', "\\|")));
+                    }
+                    self.out.push('\n');
+                    if index == 0 {
+                        self.out.push_str(&format!("|{}\n", " --- |".repeat(cells.len())));
+                    }
+                }
+                self.out.push('\n');
+            }
+            _ => self.block(node),
+        }
+    }
+
+    fn wrap(&mut self, node: roxmltree::Node, marker: &str) {
+        self.out.push_str(marker);
+        self.block(node);
+        self.out.push_str(marker);
+    }
+
+    fn at_line_start(&self) -> bool {
+        self.out.is_empty() || self.out.ends_with('\n')
+    }
+
+    fn end_line(&mut self) {
+        if !self.at_line_start() {
+            let trimmed = self.out.trim_end_matches(' ').len();
+            self.out.truncate(trimmed);
+            self.out.push('\n');
+        }
+    }
+
+    fn end_paragraph(&mut self) {
+        self.end_line();
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn finish(&self) -> String {
+        let mut text = self.out.trim().to_string();
+        while text.contains("\n\n\n") {
+            text = text.replace("\n\n\n", "\n\n");
+        }
+        text
+    }
+}
+
+fn looks_like_enex(data: &[u8]) -> bool {
+    let head = &data[..data.len().min(1024)];
+    String::from_utf8_lossy(head).contains("<en-export")
+}
+
+fn is_markdown_file(lower: &str) -> bool {
+    lower.ends_with(".md") || lower.ends_with(".markdown")
+}
+
+/// Files in hidden folders (`.obsidian`, `.trash`) and macOS metadata aren't notes.
+fn is_hidden(components: &[String]) -> bool {
+    components.iter().any(|c| c.starts_with('.') || c == "__MACOSX")
+}
+
+fn strip_extension(name: &str) -> &str {
+    let lower = name.to_lowercase();
+    [".md", ".markdown", ".enex"]
+        .iter()
+        .find(|ext| lower.ends_with(*ext))
+        .map_or(name, |ext| &name[..name.len() - ext.len()])
+}
+
+fn path_components(path: &Path) -> Vec<String> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The top-level folder shared by all paths, if there is one.
+fn common_root<'a>(mut paths: impl Iterator<Item = &'a Vec<String>>) -> Option<String> {
+    let first = paths.next()?;
+    let root = first.first().filter(|_| first.len() > 1)?;
+    paths
+        .all(|path| path.len() > 1 && path.first() == Some(root))
+        .then(|| root.clone())
+}
+
+// Custom function to convert ImportServiceError to HttpResponse
+pub fn error_response(error: ImportServiceError) -> HttpResponse {
+    match error {
+        ImportServiceError::DatabaseError(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+        ImportServiceError::InvalidObjectId(e) => HttpResponse::BadRequest().body(format!("Invalid ObjectId: {}", e)),
+        e @ ImportServiceError::InvalidArchive(_) => HttpResponse::BadRequest().body(e.to_string()),
+        e @ ImportServiceError::UnsupportedFormat => HttpResponse::UnsupportedMediaType().body(e.to_string()),
+        e @ ImportServiceError::TooLarge(_) => HttpResponse::PayloadTooLarge().body(e.to_string()),
+        ImportServiceError::NotesError(e) => notes_service::error_response(e),
+        ImportServiceError::NotebookError(e) => notebook_service::error_response(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(path: &str, content: &str, earlier: Option<usize>) -> NotePlan {
+        let parsed = ParsedNote {
+            path: path.to_string(),
+            folders: Vec::new(),
+            title: "Meeting".to_string(),
+            aliases: Vec::new(),
+            content: content.to_string(),
+            tags: Vec::new(),
+            created_at: None,
+            updated_at: None,
+        };
+        let note = Note::new(parsed.title.clone(), parsed.content.clone());
+        NotePlan { parsed, note, earlier, existing: None, invalid: None }
+    }
+
+    #[test]
+    fn repeated_note_in_one_export_is_skipped() {
+        let plans = vec![plan("export.enex", "Agenda", None), plan("export.enex", "Agenda", Some(0))];
+        for duplicates in [DuplicateStrategy::Skip, DuplicateStrategy::Overwrite, DuplicateStrategy::KeepBoth] {
+            assert_eq!(decide(&plans[0], &plans, duplicates).0, ImportAction::Create);
+            assert_eq!(decide(&plans[1], &plans, duplicates).0, ImportAction::Skip);
+        }
+    }
+
+    #[test]
+    fn differing_notes_with_one_title_follow_the_strategy() {
+        let plans = vec![plan("a.md", "Agenda", None), plan("b.md", "Minutes", Some(0))];
+        assert_eq!(decide(&plans[1], &plans, DuplicateStrategy::Skip).0, ImportAction::Skip);
+        assert_eq!(decide(&plans[1], &plans, DuplicateStrategy::Overwrite).0, ImportAction::Overwrite);
+        assert_eq!(decide(&plans[1], &plans, DuplicateStrategy::KeepBoth).0, ImportAction::Create);
+    }
+
+    #[test]
+    fn existing_note_is_compared_with_the_import() {
+        let mut duplicate = plan("a.md", "Agenda", None);
+        duplicate.existing = Some(Note::new("Meeting".to_string(), "Agenda".to_string()));
+        let plans = vec![duplicate];
+        assert_eq!(decide(&plans[0], &plans, DuplicateStrategy::KeepBoth).0, ImportAction::Skip);
+    }
+
+    #[test]
+    fn enex_notes_are_parsed_with_their_titles() {
+        let enex = r#"<?xml version="1.0" encoding="UTF-8"?>
+<en-export>
+  <note><title>Meeting</title><content><![CDATA[<en-note><div>Agenda</div></en-note>]]></content></note>
+  <note><title>Meeting</title><content><![CDATA[<en-note><div>Agenda</div></en-note>]]></content></note>
+</en-export>"#;
+        let archive = parse_archive("export.enex", enex.as_bytes()).unwrap();
+        assert_eq!(archive.notes.len(), 2);
+        assert!(archive.notes.iter().all(|note| note.title == "Meeting" && note.content.trim() == "Agenda"));
+    }
+}
@@ -0,0 +1,313 @@
+use ammonia::Builder;
+use pulldown_cmark::{html, Event, Options, Parser, Tag, TagEnd};
+use std::collections::HashSet;
+use std::ops::Range;
+use crate::models::note::{Note, NoteHeading, RenderedNote};
+
+/// Maximum number of characters kept in a note excerpt.
+const EXCERPT_LENGTH: usize = 200;
+
+/// Prefix of footnote ids, so that they can't shadow the page's own ids or
+/// globals.
+const FOOTNOTE_ID_PREFIX: &str = "fn-";
+
+/// CommonMark extensions enabled for notes (GitHub Flavored Markdown subset).
+fn markdown_options() -> Options {
+    Options::ENABLE_TABLES
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+}
+
+/// Builds the sanitiser used for rendered note HTML.
+///
+/// On top of ammonia's defaults this keeps the read-only checkboxes emitted for
+/// task lists and the markup pulldown-cmark uses for footnotes: its classes,
+/// and ids only for the footnotes the content defines, prefixed along with the
+/// links to them.
+fn sanitizer(footnotes: HashSet<String>) -> Builder<'static> {
+    let mut builder = Builder::default();
+    builder
+        .add_tags(["input"])
+        .add_tag_attributes("input", ["type", "checked", "disabled"])
+        .add_tag_attributes("div", ["id"])
+        .add_allowed_classes("sup", ["footnote-reference", "footnote-definition-label"])
+        .add_allowed_classes("div", ["footnote-definition"])
+        .attribute_filter(move |element, attribute, value| match (element, attribute) {
+            ("input", "type") if value != "checkbox" => None,
+            ("div", "id") => footnotes.contains(value).then(|| format!("{}{}", FOOTNOTE_ID_PREFIX, value).into()),
+            ("a", "href") => match value.strip_prefix('#') {
+                Some(label) if footnotes.contains(label) => Some(format!("#{}{}", FOOTNOTE_ID_PREFIX, label).into()),
+                _ => Some(value.into()),
+            },
+            _ => Some(value.into()),
+        });
+    builder
+}
+
+/// Renders Markdown content to sanitised HTML that is safe to embed in a page.
+pub fn render_html(content: &str) -> String {
+    let mut footnotes = HashSet::new();
+    let parser = Parser::new_ext(content, markdown_options()).inspect(|event| {
+        if let Event::Start(Tag::FootnoteDefinition(label)) = event {
+            footnotes.insert(label.to_string());
+        }
+    });
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, parser);
+    sanitizer(footnotes).clean(&unsafe_html).to_string()
+}
+
+/// Extracts a plain-text excerpt of at most `EXCERPT_LENGTH` characters.
+pub fn excerpt(content: &str) -> String {
+    let mut text = String::new();
+    // Text between inline <script>/<style> tags is not visible content
+    let mut in_raw_block = false;
+    for event in Parser::new_ext(content, markdown_options()) {
+        match event {
+            Event::InlineHtml(tag) | Event::Html(tag) => {
+                let tag = tag.to_ascii_lowercase();
+                if tag.starts_with("<script") || tag.starts_with("<style") {
+                    in_raw_block = true;
+                } else if tag.starts_with("</script") || tag.starts_with("</style") {
+                    in_raw_block = false;
+                }
+            }
+            Event::Text(_) | Event::Code(_) if in_raw_block => {}
+            Event::Text(t) | Event::Code(t) => text.push_str(&t),
+            Event::SoftBreak | Event::HardBreak | Event::End(_) => text.push(' '),
+            _ => {}
+        }
+    }
+
+    let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if collapsed.chars().count() <= EXCERPT_LENGTH {
+        return collapsed;
+    }
+
+    let truncated: String = collapsed.chars().take(EXCERPT_LENGTH).collect();
+    // Avoid cutting a word in half when there is a space to break on
+    let cut = truncated.rfind(' ').unwrap_or(truncated.len());
+    format!("{}…", truncated[..cut].trim_end())
+}
+
+/// Extracts the heading outline of a note in document order.
+pub fn outline(content: &str) -> Vec<NoteHeading> {
+    let mut headings = Vec::new();
+    let mut current: Option<NoteHeading> = None;
+
+    for event in Parser::new_ext(content, markdown_options()) {
+        match event {
+            Event::Start(Tag::Heading { level, .. }) => {
+                current = Some(NoteHeading { level: level as u8, text: String::new() });
+            }
+            Event::Text(t) | Event::Code(t) => {
+                if let Some(heading) = current.as_mut() {
+                    heading.text.push_str(&t);
+                }
+            }
+            Event::End(TagEnd::Heading(_)) => {
+                if let Some(mut heading) = current.take() {
+                    heading.text = heading.text.trim().to_string();
+                    headings.push(heading);
+                }
+            }
+            _ => {}
+        }
+    }
+    headings
+}
+
+/// A task-list item (`- [ ] ...`) found in note content.
+#[derive(Debug)]
+pub struct TaskItem {
+    pub checked: bool,
+    pub title: String,
+    /// Id of the linked todo, taken from a trailing `<!-- todo:<id> -->` marker.
+    pub todo_id: Option<String>,
+    /// Byte range of the `[ ]`/`[x]` checkbox.
+    pub checkbox: Range<usize>,
+    /// Byte offset of the end of the item's first line.
+    pub line_end: usize,
+}
+
+const TODO_MARKER_PREFIX: &str = "<!-- todo:";
+const TODO_MARKER_SUFFIX: &str = " -->";
+
+/// Formats the hidden marker that links a task-list item to a todo.
+pub fn todo_marker(todo_id: &str) -> String {
+    format!(" {}{}{}", TODO_MARKER_PREFIX, todo_id, TODO_MARKER_SUFFIX)
+}
+
+/// Finds all task-list items in the content, skipping code blocks.
+pub fn task_items(content: &str) -> Vec<TaskItem> {
+    let mut items = Vec::new();
+    for (event, range) in Parser::new_ext(content, markdown_options()).into_offset_iter() {
+        let Event::TaskListMarker(checked) = event else {
+            continue;
+        };
+        let mut line_end = content[range.end..]
+            .find('\n')
+            .map_or(content.len(), |i| range.end + i);
+        if content[..line_end].ends_with('\r') {
+            line_end -= 1;
+        }
+        let mut line = content[range.end..line_end].trim();
+
+        let mut todo_id = None;
+        if let Some(start) = line.rfind(TODO_MARKER_PREFIX) {
+            if let Some(id) = line[start + TODO_MARKER_PREFIX.len()..].strip_suffix(TODO_MARKER_SUFFIX.trim_start()) {
+                todo_id = Some(id.trim().to_string());
+                line = line[..start].trim_end();
+            }
+        }
+
+        items.push(TaskItem {
+            checked,
+            title: line.to_string(),
+            todo_id,
+            checkbox: range,
+            line_end,
+        });
+    }
+    items
+}
+
+/// Byte ranges of inline code and code blocks, where Markdown syntax such as
+/// wiki links is literal text.
+pub fn code_ranges(content: &str) -> Vec<Range<usize>> {
+    Parser::new_ext(content, markdown_options())
+        .into_offset_iter()
+        .filter_map(|(event, range)| match event {
+            Event::Code(_) | Event::Start(Tag::CodeBlock(_)) => Some(range),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Wraps a note with its rendered HTML, excerpt and outline.
+///
+/// Encrypted notes are returned with an empty rendering, since the server
+/// cannot read them.
+pub fn render_note(note: Note) -> RenderedNote {
+    if note.is_encrypted() {
+        return RenderedNote { html: String::new(), excerpt: String::new(), outline: Vec::new(), note };
+    }
+    RenderedNote {
+        html: render_html(&note.content),
+        excerpt: excerpt(&note.content),
+        outline: outline(&note.content),
+        note,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts_are_removed() {
+        let html = render_html("Hello <script>alert(1)</script>\n\n<script>\nalert(2)\n</script>");
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("alert"));
+        assert!(html.contains("Hello"));
+    }
+
+    #[test]
+    fn javascript_and_data_links_are_dropped() {
+        let html = render_html(
+            "[a](javascript:alert(1)) <a href=\"JavaScript:alert(2)\">b</a> [c](data:text/html;base64,PHNjcmlwdD4=) ![d](data:image/png;base64,AAAA)",
+        );
+        assert!(!html.to_lowercase().contains("javascript:"));
+        assert!(!html.contains("data:"));
+        let safe = render_html("[site](https://example.com)");
+        assert!(safe.contains("href=\"https://example.com\""));
+    }
+
+    #[test]
+    fn event_handler_attributes_are_dropped() {
+        let html = render_html("<img src=\"x.png\" onerror=\"alert(1)\"> <p onclick=\"alert(2)\">hi</p>");
+        assert!(!html.contains("onerror"));
+        assert!(!html.contains("onclick"));
+        assert!(html.contains("<img src=\"x.png\""));
+    }
+
+    #[test]
+    fn task_lists_render_as_read_only_checkboxes() {
+        let html = render_html("- [x] done\n- [ ] open\n\n<input type=\"text\" value=\"x\"> <input type=\"checkbox\" onclick=\"f()\">");
+        assert_eq!(html.matches("type=\"checkbox\"").count(), 3);
+        assert_eq!(html.matches("checked").count(), 1);
+        assert_eq!(html.matches("disabled").count(), 2);
+        assert!(!html.contains("text"));
+        assert!(!html.contains("onclick"));
+    }
+
+    #[test]
+    fn footnotes_keep_their_links_under_a_prefix() {
+        let html = render_html("Hi[^location].\n\n[^location]: The note.");
+        assert!(html.contains("<sup class=\"footnote-reference\"><a href=\"#fn-location\" rel=\"noopener noreferrer\">1</a></sup>"), "{}", html);
+        assert!(html.contains("<div class=\"footnote-definition\" id=\"fn-location\">"), "{}", html);
+        assert!(html.contains("<sup class=\"footnote-definition-label\">"));
+        assert!(!html.contains("id=\"location\""));
+    }
+
+    #[test]
+    fn raw_html_cant_set_ids_or_classes() {
+        let html = render_html("<div id=\"login\" class=\"admin footnote-definition\">x</div> <sup class=\"badge\">1</sup>");
+        assert!(!html.contains("id="));
+        assert!(!html.contains("admin"));
+        assert!(!html.contains("badge"));
+    }
+
+    #[test]
+    fn excerpt_is_plain_text_cut_at_a_word() {
+        assert_eq!(excerpt("# Title\n\nSome **bold** and `code`.<script>hidden()</script>"), "Title Some bold and code.");
+        let long = "word ".repeat(100);
+        let cut = excerpt(&long);
+        assert!(cut.ends_with("word…"));
+        assert!(cut.chars().count() <= EXCERPT_LENGTH + 1);
+    }
+
+    #[test]
+    fn task_items_are_found_with_their_state_and_marker() {
+        let content = "- [ ] open\n- [x] done <!-- todo:abc -->\n* [X] shouted\n- not a task";
+        let items = task_items(content);
+        let found: Vec<(bool, &str, Option<&str>)> =
+            items.iter().map(|item| (item.checked, item.title.as_str(), item.todo_id.as_deref())).collect();
+        assert_eq!(found, [(false, "open", None), (true, "done", Some("abc")), (true, "shouted", None)]);
+        assert_eq!(&content[items[0].checkbox.clone()], "[ ]");
+        assert_eq!(&content[..items[0].line_end], "- [ ] open");
+    }
+
+    #[test]
+    fn task_items_end_before_crlf() {
+        let content = "- [ ] one\r\n- [x] two <!-- todo:abc -->\r\n";
+        let items = task_items(content);
+        assert_eq!(items[0].title, "one");
+        assert_eq!(items[1].todo_id.as_deref(), Some("abc"));
+        assert!(content[..items[1].line_end].ends_with("-->"));
+        assert_eq!(&content[items[0].line_end..items[0].line_end + 2], "\r\n");
+    }
+
+    #[test]
+    fn nested_task_items_are_found() {
+        let items = task_items("- [ ] parent\n  - [x] child\n    - [ ] grandchild");
+        let titles: Vec<&str> = items.iter().map(|item| item.title.as_str()).collect();
+        assert_eq!(titles, ["parent", "child", "grandchild"]);
+        assert!(items[1].checked);
+    }
+
+    #[test]
+    fn task_items_in_code_are_ignored() {
+        let content = "```\n- [ ] fenced\n```\n\n    - [ ] indented\n\n`- [ ] inline`\n\n- [ ] real";
+        let titles: Vec<String> = task_items(content).into_iter().map(|item| item.title).collect();
+        assert_eq!(titles, ["real"]);
+    }
+
+    #[test]
+    fn outline_lists_headings_in_order() {
+        let headings = outline("# One\ntext\n## Two `code`\n```\n# not a heading\n```\n### Three");
+        let headings: Vec<(u8, &str)> = headings.iter().map(|heading| (heading.level, heading.text.as_str())).collect();
+        assert_eq!(headings, [(1, "One"), (2, "Two code"), (3, "Three")]);
+    }
+}
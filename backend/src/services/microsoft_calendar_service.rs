@@ -0,0 +1,932 @@
+//! Outlook calendars, through Microsoft Graph, as a calendar provider.
+//!
+//! Graph only tracks the changes of a calendar view, which lists the
+//! occurrences of series within a date range rather than the series. So a
+//! series is fetched whenever one of its occurrences is listed, and the
+//! occurrences Graph no longer has become its excluded dates; occurrences
+//! changed on their own come in as exceptions. Events outside the range the
+//! view had at the last full sync aren't synced. `MICROSOFT_GRAPH_API_URL`
+//! overrides the Graph endpoint, e.g. with a local mock.
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, SecondsFormat, Utc, Weekday};
+use chrono_tz::Tz;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use crate::models::calendar::CalendarEvent;
+use crate::models::calendar_sync::{CalendarProvider, PRIMARY_CALENDAR};
+use crate::models::microsoft_calendar::{
+    GraphAttendee, GraphDateTime, GraphEmailAddress, GraphEvent, GraphEventPage, GraphItemBody, GraphLocation,
+    PatternedRecurrence, RecurrencePattern, RecurrenceRange, EXCEPTION, OCCURRENCE, SERIES_MASTER,
+};
+use crate::models::recurrence::{local_to_utc, ByDay, Frequency, RecurrenceRule, Until};
+use crate::services::calendar_oauth_service::{self, CalendarOAuthServiceError, OAuthClient};
+use crate::services::calendar_sync_service::{
+    all_day_dates, check, http_client, json, CalendarSyncServiceError, RemoteCalendar, RemoteEvent, RemotePage,
+};
+use crate::services::{ical_service, recurrence_service};
+
+const DEFAULT_API_URL: &str = "https://graph.microsoft.com/v1.0";
+
+const DEFAULT_AUTHORIZATION_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/authorize";
+
+const DEFAULT_TOKEN_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/token";
+
+/// Read and write access to the user's calendars, and a refresh token.
+const SCOPE: &str = "offline_access Calendars.ReadWrite";
+
+/// Events per page, which Graph may lower.
+const PAGE_SIZE: &str = "odata.maxpagesize=100";
+
+/// Days before the first sync the calendar view starts.
+const VIEW_DAYS_BEFORE: i64 = 365;
+
+/// Days after the first sync the calendar view, and the occurrences of series
+/// checked for cancellations, end.
+const VIEW_DAYS_AFTER: i64 = 730;
+
+const UNTITLED: &str = "Untitled event";
+
+/// Day names of `daysOfWeek` and `firstDayOfWeek`.
+const WEEKDAYS: [(Weekday, &str); 7] = [
+    (Weekday::Mon, "monday"),
+    (Weekday::Tue, "tuesday"),
+    (Weekday::Wed, "wednesday"),
+    (Weekday::Thu, "thursday"),
+    (Weekday::Fri, "friday"),
+    (Weekday::Sat, "saturday"),
+    (Weekday::Sun, "sunday"),
+];
+
+/// `index` names of the ordinals relative patterns support.
+const INDEXES: [(i32, &str); 5] = [(1, "first"), (2, "second"), (3, "third"), (4, "fourth"), (-1, "last")];
+
+/// Base URL of Microsoft Graph, from `MICROSOFT_GRAPH_API_URL`.
+pub fn api_url() -> String {
+    calendar_oauth_service::url_var("MICROSOFT_GRAPH_API_URL", DEFAULT_API_URL)
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// The app's Microsoft OAuth client, from `MICROSOFT_CLIENT_ID`,
+/// `MICROSOFT_CLIENT_SECRET` and `MICROSOFT_REDIRECT_URI`.
+/// `MICROSOFT_AUTHORIZATION_URL` and `MICROSOFT_TOKEN_URL` override the
+/// Microsoft identity platform's endpoints, e.g. for a single tenant.
+pub fn oauth_client() -> Result<OAuthClient, CalendarOAuthServiceError> {
+    Ok(OAuthClient {
+        provider: CalendarProvider::Microsoft.name(),
+        client_id: calendar_oauth_service::required_var("MICROSOFT_CLIENT_ID")?,
+        client_secret: calendar_oauth_service::required_var("MICROSOFT_CLIENT_SECRET")?,
+        redirect_uri: calendar_oauth_service::required_var("MICROSOFT_REDIRECT_URI")?,
+        authorization_url: calendar_oauth_service::url_var("MICROSOFT_AUTHORIZATION_URL", DEFAULT_AUTHORIZATION_URL),
+        token_url: calendar_oauth_service::url_var("MICROSOFT_TOKEN_URL", DEFAULT_TOKEN_URL),
+        // The identity platform has no revocation endpoint
+        revocation_url: None,
+        scope: SCOPE,
+        authorization_params: &[],
+    })
+}
+
+fn title(remote: &GraphEvent) -> &str {
+    remote.subject.as_deref().filter(|subject| !subject.trim().is_empty()).unwrap_or(UNTITLED)
+}
+
+/// A listed single event or exception as the sync sees it.
+fn remote_event(remote: GraphEvent) -> Option<RemoteEvent> {
+    let id = remote.id.clone()?;
+    let occurrence_of = match remote.is_type(EXCEPTION) {
+        true => Some((remote.series_master_id.clone()?, original_start(&remote, remote.is_all_day == Some(true))?)),
+        false => None,
+    };
+    Some(RemoteEvent {
+        id,
+        occurrence_of,
+        title: title(&remote).to_string(),
+        updated: remote.last_modified_date_time,
+        event: (!remote.is_cancelled()).then(|| to_local(&remote)),
+    })
+}
+
+/// Start an occurrence has in its series. All-day occurrences start at
+/// midnight in the time zone their series was created in, and at midnight UTC
+/// here.
+fn original_start(remote: &GraphEvent, all_day: bool) -> Option<DateTime<Utc>> {
+    let start = remote
+        .original_start
+        .or_else(|| remote.start.as_ref().and_then(|start| instant(start, false).ok()))?;
+    Some(match all_day {
+        true => (start + Duration::hours(12)).date_naive().and_time(NaiveTime::MIN).and_utc(),
+        false => start,
+    })
+}
+
+/// A start or end as stored locally. All-day events keep their dates, at
+/// midnight UTC.
+fn instant(time: &GraphDateTime, all_day: bool) -> Result<DateTime<Utc>, String> {
+    if all_day {
+        return Ok(time.date_time.date().and_time(NaiveTime::MIN).and_utc());
+    }
+    let zone = ical_service::find_time_zone(&time.time_zone).ok_or_else(|| format!("Unknown time zone: {}", time.time_zone))?;
+    Ok(local_to_utc(time.date_time, zone))
+}
+
+/// Maps a Graph event to a local one, failing when it can't be represented.
+fn to_local(remote: &GraphEvent) -> Result<CalendarEvent, String> {
+    let is_all_day = remote.is_all_day == Some(true);
+    let start_time = instant(remote.start.as_ref().ok_or("Missing start")?, is_all_day)?;
+    let end_time = instant(remote.end.as_ref().ok_or("Missing end")?, is_all_day)?;
+    // Series repeat in the zone they were created in; Graph may name it in ways no IANA zone matches
+    let zone = match remote.original_start_time_zone.as_deref() {
+        Some(name) if !is_all_day => ical_service::find_time_zone(name).unwrap_or(Tz::UTC),
+        _ => Tz::UTC,
+    };
+    let recurrence_rule = match remote.recurrence.as_ref().and_then(Option::as_ref) {
+        Some(recurrence) => Some(from_pattern(recurrence)?.to_string()),
+        None => None,
+    };
+    let text = |value: Option<&String>| value.map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+    let now = Utc::now();
+    let event = CalendarEvent {
+        id: None,
+        title: title(remote).to_string(),
+        description: text(remote.body.as_ref().map(|body| &body.content)),
+        start_time,
+        end_time,
+        location: text(remote.location.as_ref().and_then(|location| location.display_name.as_ref())),
+        is_all_day,
+        time_zone: (zone != Tz::UTC).then(|| zone.name().to_string()),
+        recurrence_rule,
+        recurrence_dates: Vec::new(),
+        excluded_dates: Vec::new(),
+        recurring_event_id: None,
+        recurrence_id: None,
+        attendees: remote
+            .attendees
+            .iter()
+            .flatten()
+            .filter_map(|attendee| attendee.email_address.address.clone())
+            .filter(|address| !address.is_empty())
+            .collect(),
+        color: None,
+        calendar_id: None,
+        ical_uid: None,
+        dav_name: None,
+        external_id: None,
+        synced_at: None,
+        created_at: now,
+        updated_at: now,
+        deleted_at: None,
+    };
+    event.validate()?;
+    Ok(event)
+}
+
+/// Maps a local event to the Graph event it is pushed as, failing when Graph
+/// can't represent its recurrence.
+fn to_graph(event: &CalendarEvent) -> Result<GraphEvent, String> {
+    let tz = event.tz();
+    let time = |date_time| GraphDateTime { date_time, time_zone: tz.name().to_string() };
+    let (start, end) = match event.is_all_day {
+        true => {
+            let (start, end) = all_day_dates(event);
+            (time(start.and_time(NaiveTime::MIN)), time(end.and_time(NaiveTime::MIN)))
+        }
+        false => (
+            time(event.start_time.with_timezone(&tz).naive_local()),
+            time(event.end_time.with_timezone(&tz).naive_local()),
+        ),
+    };
+    if !event.recurrence_dates.is_empty() {
+        return Err("Outlook can't repeat on extra dates (RDATE)".to_string());
+    }
+    // Occurrences take their recurrence from their series
+    let recurrence = match event.is_exception() {
+        true => None,
+        false => Some(match event.rule()? {
+            Some(rule) => Some(to_pattern(&rule, start.date_time.date(), tz)?),
+            None => None,
+        }),
+    };
+    Ok(GraphEvent {
+        subject: Some(event.title.clone()),
+        body: Some(GraphItemBody { content_type: "text".to_string(), content: event.description.clone().unwrap_or_default() }),
+        start: Some(start),
+        end: Some(end),
+        is_all_day: Some(event.is_all_day),
+        location: Some(GraphLocation { display_name: event.location.clone() }),
+        attendees: Some(
+            event
+                .attendees
+                .iter()
+                .filter(|attendee| attendee.contains('@'))
+                .map(|attendee| GraphAttendee { email_address: GraphEmailAddress { address: Some(attendee.trim().to_string()) } })
+                .collect(),
+        ),
+        recurrence,
+        ..Default::default()
+    })
+}
+
+fn weekday_name(weekday: Weekday) -> String {
+    WEEKDAYS.iter().find(|(day, _)| *day == weekday).map(|(_, name)| name.to_string()).unwrap_or_default()
+}
+
+fn parse_weekday(name: &str) -> Result<Weekday, String> {
+    WEEKDAYS
+        .iter()
+        .find(|(_, day)| day.eq_ignore_ascii_case(name))
+        .map(|(weekday, _)| *weekday)
+        .ok_or_else(|| format!("Unknown day of week: {}", name))
+}
+
+/// Maps a rule to a Graph pattern. Graph has one pattern per frequency, so
+/// rules combining several `BY` parts, or with more than one ordinal, fail.
+fn to_pattern(rule: &RecurrenceRule, start: NaiveDate, tz: Tz) -> Result<PatternedRecurrence, String> {
+    let unsupported = || format!("Outlook can't repeat by {}", rule);
+    let days: Vec<String> = rule.by_day.iter().map(|day| weekday_name(day.weekday)).collect();
+    // One ordinal for every day, from BYDAY (2MO) or BYSETPOS (BYDAY=MO,TU;BYSETPOS=2)
+    let first_ordinal = rule.by_day.first().and_then(|day| day.ordinal);
+    let ordinal = match rule.by_set_pos.as_slice() {
+        [] if rule.by_day.iter().all(|day| day.ordinal == first_ordinal) => first_ordinal,
+        [position] if first_ordinal.is_none() && rule.by_day.iter().all(|day| day.ordinal.is_none()) => Some(*position),
+        _ => return Err(unsupported()),
+    };
+    let index = match ordinal {
+        Some(ordinal) => Some(INDEXES.iter().find(|(n, _)| *n == ordinal).map(|(_, name)| name.to_string()).ok_or_else(unsupported)?),
+        None => None,
+    };
+    let day_of_month = match rule.by_month_day.as_slice() {
+        [] => None,
+        [day] if *day > 0 => Some(*day as u32),
+        _ => return Err(unsupported()),
+    };
+    let month = match rule.by_month.as_slice() {
+        [] => None,
+        [month] => Some(*month),
+        _ => return Err(unsupported()),
+    };
+
+    let mut pattern = RecurrencePattern {
+        pattern_type: String::new(),
+        interval: rule.interval,
+        days_of_week: Vec::new(),
+        day_of_month: None,
+        month: None,
+        index: None,
+        first_day_of_week: None,
+    };
+    match rule.frequency {
+        Frequency::Daily if days.is_empty() && index.is_none() && day_of_month.is_none() && month.is_none() => {
+            pattern.pattern_type = "daily".to_string();
+        }
+        // Every weekday is a weekly pattern in Graph
+        Frequency::Daily | Frequency::Weekly
+            if (rule.frequency == Frequency::Weekly || rule.interval == 1)
+                && index.is_none()
+                && day_of_month.is_none()
+                && month.is_none() =>
+        {
+            pattern.pattern_type = "weekly".to_string();
+            pattern.days_of_week = match days.is_empty() {
+                true => vec![weekday_name(start.weekday())],
+                false => days,
+            };
+            pattern.first_day_of_week = Some(weekday_name(rule.week_start));
+        }
+        Frequency::Monthly | Frequency::Yearly => {
+            let kind = match (days.is_empty(), index) {
+                (true, None) => {
+                    pattern.day_of_month = Some(day_of_month.unwrap_or(start.day()));
+                    "absolute"
+                }
+                (false, Some(index)) if day_of_month.is_none() => {
+                    pattern.days_of_week = days;
+                    pattern.index = Some(index);
+                    "relative"
+                }
+                _ => return Err(unsupported()),
+            };
+            pattern.pattern_type = match rule.frequency {
+                Frequency::Monthly if month.is_none() => format!("{}Monthly", kind),
+                Frequency::Yearly => {
+                    pattern.month = Some(month.unwrap_or(start.month()));
+                    format!("{}Yearly", kind)
+                }
+                _ => return Err(unsupported()),
+            };
+        }
+        _ => return Err(unsupported()),
+    }
+
+    let mut range = RecurrenceRange {
+        range_type: "noEnd".to_string(),
+        start_date: start,
+        end_date: None,
+        number_of_occurrences: None,
+        recurrence_time_zone: Some(tz.name().to_string()),
+    };
+    if let Some(count) = rule.count {
+        range.range_type = "numbered".to_string();
+        range.number_of_occurrences = Some(count);
+    } else if let Some(until) = rule.until {
+        range.range_type = "endDate".to_string();
+        range.end_date = Some(match until {
+            Until::Utc(until) => until.with_timezone(&tz).date_naive(),
+            Until::Floating(until) => until.date(),
+            Until::Date(until) => until,
+        });
+    }
+    Ok(PatternedRecurrence { pattern, range })
+}
+
+/// Maps a Graph pattern to a rule.
+fn from_pattern(recurrence: &PatternedRecurrence) -> Result<RecurrenceRule, String> {
+    let pattern = &recurrence.pattern;
+    let weekdays = pattern.days_of_week.iter().map(|name| parse_weekday(name)).collect::<Result<Vec<_>, _>>()?;
+    let every = |weekdays: &[Weekday]| weekdays.iter().map(|&weekday| ByDay { ordinal: None, weekday }).collect::<Vec<_>>();
+    let ordinal = match pattern.index.as_deref() {
+        None => 1,
+        Some(index) => INDEXES
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(index))
+            .map(|(ordinal, _)| *ordinal)
+            .ok_or_else(|| format!("Unknown recurrence index: {}", index))?,
+    };
+    let day_of_month = || pattern.day_of_month.filter(|day| (1..=31).contains(day)).map(|day| day as i32).ok_or("Missing day of month");
+    let month = || pattern.month.filter(|month| (1..=12).contains(month)).ok_or("Missing month");
+
+    let mut rule = RecurrenceRule {
+        frequency: Frequency::Daily,
+        interval: pattern.interval.max(1),
+        count: None,
+        until: None,
+        by_day: Vec::new(),
+        by_month_day: Vec::new(),
+        by_month: Vec::new(),
+        by_set_pos: Vec::new(),
+        week_start: Weekday::Mon,
+    };
+    // "The first weekday" of a month is the first of any of its days
+    let relative = |rule: &mut RecurrenceRule| match weekdays.as_slice() {
+        [] => Err("Missing days of week"),
+        [weekday] => {
+            rule.by_day = vec![ByDay { ordinal: Some(ordinal), weekday: *weekday }];
+            Ok(())
+        }
+        _ => {
+            rule.by_day = every(&weekdays);
+            rule.by_set_pos = vec![ordinal];
+            Ok(())
+        }
+    };
+    match pattern.pattern_type.as_str() {
+        "daily" => {}
+        "weekly" => {
+            rule.frequency = Frequency::Weekly;
+            rule.by_day = every(&weekdays);
+            // Only changes which weeks an interval skips
+            if rule.interval > 1 {
+                rule.week_start = match pattern.first_day_of_week.as_deref() {
+                    Some(name) => parse_weekday(name)?,
+                    None => Weekday::Sun,
+                };
+            }
+        }
+        "absoluteMonthly" => {
+            rule.frequency = Frequency::Monthly;
+            rule.by_month_day = vec![day_of_month()?];
+        }
+        "relativeMonthly" => {
+            rule.frequency = Frequency::Monthly;
+            relative(&mut rule)?;
+        }
+        "absoluteYearly" => {
+            rule.frequency = Frequency::Yearly;
+            rule.by_month = vec![month()?];
+            rule.by_month_day = vec![day_of_month()?];
+        }
+        "relativeYearly" => {
+            rule.frequency = Frequency::Yearly;
+            rule.by_month = vec![month()?];
+            relative(&mut rule)?;
+        }
+        other => return Err(format!("Unsupported recurrence pattern: {}", other)),
+    }
+
+    match recurrence.range.range_type.as_str() {
+        "numbered" => rule.count = recurrence.range.number_of_occurrences.filter(|count| *count > 0),
+        "endDate" => rule.until = recurrence.range.end_date.map(Until::Date),
+        _ => {}
+    }
+    Ok(rule)
+}
+
+fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Requests to the events of one Outlook calendar.
+pub struct MicrosoftCalendar<'a> {
+    http: reqwest::Client,
+    url: String,
+    calendar_url: String,
+    access_token: &'a str,
+    /// Series fetched during this sync, which later pages needn't fetch again.
+    fetched: RefCell<HashSet<String>>,
+}
+
+impl<'a> MicrosoftCalendar<'a> {
+    /// Graph addresses the default calendar as `/me/calendar`, which the
+    /// calendar id `primary` stands for.
+    pub fn new(access_token: &'a str, calendar_id: &str) -> Self {
+        Self::at(&api_url(), access_token, calendar_id)
+    }
+
+    /// A calendar of Graph at another base URL.
+    pub(crate) fn at(api_url: &str, access_token: &'a str, calendar_id: &str) -> Self {
+        let url = api_url.to_string();
+        let calendar_url = match calendar_id {
+            PRIMARY_CALENDAR => format!("{}/me/calendar", url),
+            id => format!("{}/me/calendars/{}", url, utf8_percent_encode(id, NON_ALPHANUMERIC)),
+        };
+        MicrosoftCalendar { http: http_client(), url, calendar_url, access_token, fetched: RefCell::default() }
+    }
+
+    fn event_url(&self, event_id: &str) -> String {
+        format!("{}/me/events/{}", self.url, utf8_percent_encode(event_id, NON_ALPHANUMERIC))
+    }
+
+    /// A request with times in UTC and bodies as plain text.
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        self.http
+            .request(method, url)
+            .bearer_auth(self.access_token)
+            .header("Prefer", "outlook.timezone=\"UTC\"")
+            .header("Prefer", "outlook.body-content-type=\"text\"")
+    }
+
+    /// Occurrences and exceptions of a series overlapping `[from, to)`,
+    /// leaving out cancelled ones.
+    async fn instances(&self, series_id: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<GraphEvent>, CalendarSyncServiceError> {
+        let url = format!("{}/instances", self.event_url(series_id));
+        let mut request = self
+            .request(Method::GET, &url)
+            .query(&[("startDateTime", timestamp(from)), ("endDateTime", timestamp(to))]);
+        let mut instances = Vec::new();
+        loop {
+            let page: GraphEventPage = json(request.header("Prefer", PAGE_SIZE).send().await?).await?;
+            instances.extend(page.value.into_iter().filter(|instance| !instance.is_cancelled()));
+            match page.next_link {
+                Some(link) => request = self.request(Method::GET, &link),
+                None => return Ok(instances),
+            }
+        }
+    }
+
+    /// Fetches a series, with the occurrences Graph no longer has as its
+    /// excluded dates.
+    async fn fetch_series(&self, series_id: &str) -> Result<RemoteEvent, CalendarSyncServiceError> {
+        self.fetched.borrow_mut().insert(series_id.to_string());
+        let response = self.request(Method::GET, &self.event_url(series_id)).send().await?;
+        if matches!(response.status(), StatusCode::NOT_FOUND | StatusCode::GONE) {
+            return Ok(RemoteEvent::removed(series_id.to_string(), UNTITLED));
+        }
+        let remote: GraphEvent = json(response).await?;
+        let Some(mut series) = remote_event(remote) else {
+            return Ok(RemoteEvent::removed(series_id.to_string(), UNTITLED));
+        };
+        if let Some(Ok(event)) = &mut series.event {
+            if event.is_recurring() {
+                event.excluded_dates = self.cancelled_occurrences(series_id, event).await?;
+            }
+        }
+        Ok(series)
+    }
+
+    /// Original starts of the occurrences the recurrence of a series has but
+    /// Graph doesn't list, up to the end of the calendar view.
+    async fn cancelled_occurrences(&self, series_id: &str, series: &CalendarEvent) -> Result<Vec<DateTime<Utc>>, CalendarSyncServiceError> {
+        let end = Utc::now() + Duration::days(VIEW_DAYS_AFTER);
+        let expected: Vec<DateTime<Utc>> = recurrence_service::occurrences(series, series.start_time, end)
+            .into_iter()
+            .filter_map(|occurrence| occurrence.original_start_time)
+            .collect();
+        let Some(last) = expected.last() else {
+            return Ok(Vec::new());
+        };
+        let listed: HashSet<DateTime<Utc>> = self
+            .instances(series_id, series.start_time, *last + Duration::seconds(1))
+            .await?
+            .iter()
+            .filter_map(|instance| original_start(instance, series.is_all_day))
+            .collect();
+        Ok(expected.into_iter().filter(|start| !listed.contains(start)).collect())
+    }
+
+    /// Deletes the occurrences excluded from a series here, which is how
+    /// Graph cancels them.
+    async fn cancel_excluded(&self, series_id: &str, series: &CalendarEvent) -> Result<(), CalendarSyncServiceError> {
+        for start in &series.excluded_dates {
+            if let Some(occurrence_id) = self.occurrence_id(series_id, series, *start).await? {
+                self.delete(&occurrence_id).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RemoteCalendar for MicrosoftCalendar<'_> {
+    /// Pages of the calendar view's delta query, following its `nextLink`s;
+    /// the `deltaLink` of the last page serves as the sync token.
+    async fn changes(&self, sync_token: Option<&str>, page_token: Option<&str>) -> Result<RemotePage, CalendarSyncServiceError> {
+        let request = match page_token.or(sync_token) {
+            Some(link) => self.request(Method::GET, link),
+            None => {
+                let now = Utc::now();
+                self.request(Method::GET, &format!("{}/calendarView/delta", self.calendar_url)).query(&[
+                    ("startDateTime", timestamp(now - Duration::days(VIEW_DAYS_BEFORE))),
+                    ("endDateTime", timestamp(now + Duration::days(VIEW_DAYS_AFTER))),
+                ])
+            }
+        };
+        let response = request.header("Prefer", PAGE_SIZE).send().await?;
+        let page: GraphEventPage = match response.status() {
+            StatusCode::GONE => return Err(CalendarSyncServiceError::SyncTokenExpired),
+            _ => json(response).await?,
+        };
+
+        let mut events = Vec::new();
+        for remote in page.value {
+            let series_id = match (remote.is_type(OCCURRENCE), remote.is_type(SERIES_MASTER)) {
+                (true, _) => remote.series_master_id.clone(),
+                (_, true) => remote.id.clone(),
+                _ => {
+                    events.extend(remote_event(remote));
+                    continue;
+                }
+            };
+            if let Some(series_id) = series_id.filter(|id| !self.fetched.borrow().contains(id)) {
+                events.push(self.fetch_series(&series_id).await?);
+            }
+        }
+        Ok(RemotePage { events, next_page: page.next_link, sync_token: page.delta_link })
+    }
+
+    /// Graph lists a deleted series, and a cancelled occurrence, by the ids of
+    /// occurrences.
+    async fn series(&self, series_id: &str) -> Result<Option<RemoteEvent>, CalendarSyncServiceError> {
+        self.fetch_series(series_id).await.map(Some)
+    }
+
+    /// The calendar view around the time of the sync.
+    fn listed_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let now = Utc::now();
+        Some((now - Duration::days(VIEW_DAYS_BEFORE), now + Duration::days(VIEW_DAYS_AFTER)))
+    }
+
+    async fn insert(&self, event: &CalendarEvent) -> Result<String, CalendarSyncServiceError> {
+        let body = to_graph(event).map_err(CalendarSyncServiceError::Unsupported)?;
+        let response = self.request(Method::POST, &format!("{}/events", self.calendar_url)).json(&body).send().await?;
+        let remote: GraphEvent = json(response).await?;
+        let id = remote
+            .id
+            .ok_or_else(|| CalendarSyncServiceError::ApiError("Graph returned an event without an id".to_string()))?;
+        self.cancel_excluded(&id, event).await?;
+        Ok(id)
+    }
+
+    async fn update(&self, id: &str, event: &CalendarEvent) -> Result<bool, CalendarSyncServiceError> {
+        let body = to_graph(event).map_err(CalendarSyncServiceError::Unsupported)?;
+        let response = self.request(Method::PATCH, &self.event_url(id)).json(&body).send().await?;
+        if matches!(response.status(), StatusCode::NOT_FOUND | StatusCode::GONE) {
+            return Ok(false);
+        }
+        check(response).await?;
+        if !event.is_exception() {
+            self.cancel_excluded(id, event).await?;
+        }
+        Ok(true)
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), CalendarSyncServiceError> {
+        let response = self.request(Method::DELETE, &self.event_url(id)).send().await?;
+        match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::GONE => Ok(()),
+            _ => check(response).await.map(|_| ()),
+        }
+    }
+
+    async fn occurrence_id(
+        &self,
+        series_id: &str,
+        series: &CalendarEvent,
+        start: DateTime<Utc>,
+    ) -> Result<Option<String>, CalendarSyncServiceError> {
+        let instances = self.instances(series_id, start - Duration::days(1), start + Duration::days(1)).await?;
+        Ok(instances
+            .into_iter()
+            .find(|instance| original_start(instance, series.is_all_day) == Some(start))
+            .and_then(|instance| instance.id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::ServerHandle;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Access token the mock accepts.
+    const TOKEN: &str = "test-token";
+
+    /// What the mock Graph holds.
+    #[derive(Default)]
+    struct MockState {
+        url: String,
+        /// Pages of a delta query without a token.
+        pages: Vec<Vec<Value>>,
+        /// Events listed with the current delta token.
+        changes: Vec<Value>,
+        delta_token: String,
+        /// Series by id.
+        series: HashMap<String, Value>,
+        /// Occurrences and exceptions of series by series id.
+        instances: HashMap<String, Vec<Value>>,
+        /// Requests received, as "METHOD path".
+        requests: Vec<String>,
+    }
+
+    /// A stand-in for Graph on a local port, serving the default calendar.
+    struct MockGraph {
+        url: String,
+        state: Arc<Mutex<MockState>>,
+        handle: ServerHandle,
+    }
+
+    impl MockGraph {
+        fn start(state: MockState) -> Self {
+            let state = Arc::new(Mutex::new(state));
+            let data = web::Data::from(state.clone());
+            let server = HttpServer::new(move || App::new().app_data(data.clone()).default_service(web::to(respond)))
+                .workers(1)
+                .bind(("127.0.0.1", 0))
+                .expect("a local port");
+            let url = format!("http://{}", server.addrs()[0]);
+            // Links to further pages point back at the mock
+            state.lock().unwrap().url = url.clone();
+            let server = server.run();
+            let handle = server.handle();
+            actix_web::rt::spawn(server);
+            MockGraph { url, state, handle }
+        }
+
+        fn calendar(&self) -> MicrosoftCalendar<'static> {
+            MicrosoftCalendar::at(&self.url, TOKEN, PRIMARY_CALENDAR)
+        }
+
+        fn requests(&self) -> Vec<String> {
+            self.state.lock().unwrap().requests.clone()
+        }
+
+        async fn stop(self) {
+            self.handle.stop(false).await;
+        }
+    }
+
+    fn time(at: &str) -> Value {
+        json!({ "dateTime": at, "timeZone": "UTC" })
+    }
+
+    /// A single event as delta lists it.
+    fn single(id: &str, subject: &str) -> Value {
+        json!({
+            "id": id,
+            "type": "singleInstance",
+            "subject": subject,
+            "start": time("2024-07-01T09:00:00"),
+            "end": time("2024-07-01T10:00:00"),
+            "originalStartTimeZone": "UTC",
+            "lastModifiedDateTime": "2024-06-01T00:00:00Z"
+        })
+    }
+
+    /// A weekly series on Mondays from 1 July 2024, four times.
+    fn weekly_series(id: &str) -> Value {
+        json!({
+            "id": id,
+            "type": "seriesMaster",
+            "subject": "Standup",
+            "start": time("2024-07-01T10:00:00"),
+            "end": time("2024-07-01T10:30:00"),
+            "originalStartTimeZone": "UTC",
+            "recurrence": {
+                "pattern": { "type": "weekly", "interval": 1, "daysOfWeek": ["monday"], "firstDayOfWeek": "monday" },
+                "range": { "type": "numbered", "startDate": "2024-07-01", "numberOfOccurrences": 4 }
+            }
+        })
+    }
+
+    fn instance(id: &str, event_type: &str, series_id: &str, original_start: &str, start: &str) -> Value {
+        json!({
+            "id": id,
+            "type": event_type,
+            "seriesMasterId": series_id,
+            "subject": "Standup",
+            "originalStart": original_start,
+            "start": time(start),
+            "end": time(&start.replace(":00:00", ":30:00")),
+            "originalStartTimeZone": "UTC"
+        })
+    }
+
+    fn utc(at: &str) -> DateTime<Utc> {
+        at.parse().unwrap()
+    }
+
+    async fn respond(req: HttpRequest, state: web::Data<Mutex<MockState>>) -> HttpResponse {
+        let mut state = state.lock().unwrap();
+        state.requests.push(format!("{} {}", req.method(), req.path()));
+        let authorization = req.headers().get("Authorization").and_then(|value| value.to_str().ok());
+        if authorization != Some(&format!("Bearer {}", TOKEN)) {
+            return HttpResponse::Unauthorized().finish();
+        }
+        let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).unwrap().into_inner();
+        let delta_url = format!("{}/me/calendar/calendarView/delta", state.url);
+
+        if req.path() == "/me/calendar/calendarView/delta" {
+            if let Some(token) = query.get("$deltatoken") {
+                if *token != state.delta_token {
+                    return HttpResponse::Gone().json(json!({ "error": { "code": "SyncStateNotFound" } }));
+                }
+                let link = format!("{}?$deltatoken={}", delta_url, state.delta_token);
+                return HttpResponse::Ok().json(json!({ "value": state.changes, "@odata.deltaLink": link }));
+            }
+            if !query.contains_key("startDateTime") && !query.contains_key("$skiptoken") {
+                return HttpResponse::BadRequest().finish();
+            }
+            let page: usize = query.get("$skiptoken").map(|page| page.parse().unwrap()).unwrap_or(0);
+            let link = match page + 1 < state.pages.len() {
+                true => json!({ "@odata.nextLink": format!("{}?$skiptoken={}", delta_url, page + 1) }),
+                false => json!({ "@odata.deltaLink": format!("{}?$deltatoken={}", delta_url, state.delta_token) }),
+            };
+            let mut body = link;
+            body["value"] = json!(state.pages[page]);
+            return HttpResponse::Ok().json(body);
+        }
+
+        let Some(path) = req.path().strip_prefix("/me/events/") else {
+            return HttpResponse::NotFound().finish();
+        };
+        match path.split_once('/') {
+            Some((series_id, "instances")) => {
+                let (from, to) = (utc(&query["startDateTime"]), utc(&query["endDateTime"]));
+                let instances: Vec<&Value> = state
+                    .instances
+                    .get(series_id)
+                    .into_iter()
+                    .flatten()
+                    .filter(|instance| (from..to).contains(&utc(instance["originalStart"].as_str().unwrap())))
+                    .collect();
+                HttpResponse::Ok().json(json!({ "value": instances }))
+            }
+            None => match state.series.get(path) {
+                Some(series) => HttpResponse::Ok().json(series),
+                None => HttpResponse::NotFound().json(json!({ "error": { "code": "ErrorItemNotFound" } })),
+            },
+            _ => HttpResponse::NotFound().finish(),
+        }
+    }
+
+    fn standup() -> MockState {
+        MockState {
+            delta_token: "delta-1".to_string(),
+            series: HashMap::from([("standup".to_string(), weekly_series("standup"))]),
+            instances: HashMap::from([(
+                "standup".to_string(),
+                vec![
+                    instance("standup1", OCCURRENCE, "standup", "2024-07-01T10:00:00Z", "2024-07-01T10:00:00"),
+                    instance("standup2", OCCURRENCE, "standup", "2024-07-08T10:00:00Z", "2024-07-08T10:00:00"),
+                    // Moved to the afternoon; the 22nd was cancelled
+                    instance("standup3", EXCEPTION, "standup", "2024-07-15T10:00:00Z", "2024-07-15T14:00:00"),
+                ],
+            )]),
+            ..Default::default()
+        }
+    }
+
+    #[actix_web::test]
+    async fn delta_pages_follow_next_links_and_end_with_the_delta_link() {
+        let graph = MockGraph::start(MockState {
+            pages: vec![vec![single("one", "One"), single("two", "Two")], vec![single("three", "Three")]],
+            changes: vec![json!({ "id": "two", "@removed": { "reason": "deleted" } })],
+            delta_token: "delta-1".to_string(),
+            ..Default::default()
+        });
+        let calendar = graph.calendar();
+
+        let first = calendar.changes(None, None).await.unwrap();
+        assert_eq!(first.events.iter().map(|event| event.id.as_str()).collect::<Vec<_>>(), ["one", "two"]);
+        assert!(first.sync_token.is_none());
+        let last = calendar.changes(None, first.next_page.as_deref()).await.unwrap();
+        assert_eq!(last.events[0].id, "three");
+        assert_eq!(last.events[0].title, "Three");
+        assert!(last.next_page.is_none());
+        let delta_link = last.sync_token.unwrap();
+        assert!(delta_link.ends_with("$deltatoken=delta-1"));
+
+        let changes = calendar.changes(Some(&delta_link), None).await.unwrap();
+        assert_eq!(changes.events.len(), 1);
+        assert_eq!(changes.events[0].id, "two");
+        assert!(changes.events[0].event.is_none());
+        graph.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn an_expired_delta_link_asks_for_a_full_sync() {
+        let graph = MockGraph::start(MockState { pages: vec![vec![]], delta_token: "delta-2".to_string(), ..Default::default() });
+        let stale = format!("{}/me/calendar/calendarView/delta?$deltatoken=delta-1", graph.url);
+        let result = graph.calendar().changes(Some(&stale), None).await;
+        assert!(matches!(result, Err(CalendarSyncServiceError::SyncTokenExpired)));
+        graph.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn listed_occurrences_fetch_their_series_once() {
+        let mut state = standup();
+        state.pages = vec![
+            vec![
+                instance("standup1", OCCURRENCE, "standup", "2024-07-01T10:00:00Z", "2024-07-01T10:00:00"),
+                instance("standup2", OCCURRENCE, "standup", "2024-07-08T10:00:00Z", "2024-07-08T10:00:00"),
+            ],
+            vec![instance("standup3", EXCEPTION, "standup", "2024-07-15T10:00:00Z", "2024-07-15T14:00:00")],
+        ];
+        let graph = MockGraph::start(state);
+        let calendar = graph.calendar();
+
+        let first = calendar.changes(None, None).await.unwrap();
+        assert_eq!(first.events.len(), 1);
+        let series = first.events[0].event.as_ref().unwrap().as_ref().unwrap();
+        assert_eq!(first.events[0].id, "standup");
+        assert_eq!(series.recurrence_rule.as_deref(), Some("FREQ=WEEKLY;COUNT=4;BYDAY=MO"));
+        // The occurrence Graph no longer lists was cancelled
+        assert_eq!(series.excluded_dates, [utc("2024-07-22T10:00:00Z")]);
+
+        let last = calendar.changes(None, first.next_page.as_deref()).await.unwrap();
+        assert_eq!(last.events.len(), 1);
+        let exception = &last.events[0];
+        assert_eq!(exception.id, "standup3");
+        assert_eq!(exception.occurrence_of, Some(("standup".to_string(), utc("2024-07-15T10:00:00Z"))));
+        let moved = exception.event.as_ref().unwrap().as_ref().unwrap();
+        assert_eq!(moved.start_time, utc("2024-07-15T14:00:00Z"));
+
+        let fetches = graph.requests().iter().filter(|request| *request == "GET /me/events/standup").count();
+        assert_eq!(fetches, 1);
+        graph.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn a_missing_series_is_removed() {
+        let graph = MockGraph::start(standup());
+        let removed = graph.calendar().series("gone").await.unwrap().unwrap();
+        assert_eq!(removed.id, "gone");
+        assert!(removed.event.is_none());
+        graph.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn occurrences_are_found_by_their_original_start() {
+        let mut state = standup();
+        let all_day = json!({
+            "id": "holiday",
+            "type": "seriesMaster",
+            "isAllDay": true,
+            "start": time("2024-07-01T00:00:00"),
+            "end": time("2024-07-02T00:00:00"),
+            "recurrence": {
+                "pattern": { "type": "weekly", "interval": 1, "daysOfWeek": ["monday"] },
+                "range": { "type": "numbered", "startDate": "2024-07-01", "numberOfOccurrences": 2 }
+            }
+        });
+        // Created in Berlin, so Graph reports midnight there
+        let mut occurrence = instance("holiday2", OCCURRENCE, "holiday", "2024-07-07T22:00:00Z", "2024-07-08T00:00:00");
+        occurrence["isAllDay"] = json!(true);
+        state.instances.insert("holiday".to_string(), vec![occurrence]);
+        let graph = MockGraph::start(state);
+        let calendar = graph.calendar();
+
+        let standup = to_local(&serde_json::from_value(weekly_series("standup")).unwrap()).unwrap();
+        let moved = calendar.occurrence_id("standup", &standup, utc("2024-07-15T10:00:00Z")).await.unwrap();
+        assert_eq!(moved.as_deref(), Some("standup3"));
+        let cancelled = calendar.occurrence_id("standup", &standup, utc("2024-07-22T10:00:00Z")).await.unwrap();
+        assert_eq!(cancelled, None);
+
+        let holiday = to_local(&serde_json::from_value(all_day).unwrap()).unwrap();
+        let second = calendar.occurrence_id("holiday", &holiday, utc("2024-07-08T00:00:00Z")).await.unwrap();
+        assert_eq!(second.as_deref(), Some("holiday2"));
+        graph.stop().await;
+    }
+}
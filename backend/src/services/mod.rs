@@ -1,3 +1,22 @@
 pub mod todo_service;
 pub mod notes_service;
 pub mod calendar_service;
+pub mod calendar_change_service;
+pub mod recurrence_service;
+pub mod ical_service;
+pub mod calendar_feed_service;
+pub mod caldav_service;
+pub mod calendar_oauth_service;
+pub mod calendar_sync_service;
+pub mod google_calendar_service;
+pub mod microsoft_calendar_service;
+pub mod markdown_service;
+pub mod notebook_service;
+pub mod attachment_service;
+pub mod template_service;
+pub mod collab_service;
+pub mod import_service;
+pub mod export_service;
+pub mod trash_service;
+pub mod bulk_service;
+pub mod idempotency_service;
@@ -0,0 +1,653 @@
+use mongodb::{Client, ClientSession, bson::{doc, oid::ObjectId, Bson, Document}};
+use mongodb::error::Error;
+use mongodb::options::ReturnDocument;
+use futures_util::TryStreamExt;
+use crate::db::errors::is_duplicate_key;
+use crate::dto::preconditions;
+use crate::models::datetime;
+use crate::models::note::Note;
+use crate::models::notebook::{Notebook, NotebookDeleteMode, NotebookSummary};
+use crate::services::notes_service;
+use thiserror::Error;
+use actix_web::HttpResponse;
+use chrono::Utc;
+use std::collections::HashMap;
+use validator::Validate;
+
+/// Name of the notebook created on demand to collect orphaned notes.
+const DEFAULT_NOTEBOOK_NAME: &str = "Inbox";
+
+#[derive(Error, Debug)]
+pub enum NotebookServiceError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] Error),
+    #[error("Invalid ObjectId: {0}")]
+    InvalidObjectId(#[from] mongodb::bson::oid::Error),
+    #[error("Notebook not found")]
+    NotebookNotFound,
+    #[error("Note not found")]
+    NoteNotFound,
+    #[error("Validation error: {0}")]
+    ValidationError(validator::ValidationErrors),
+    #[error("Invalid move: {0}")]
+    InvalidMove(String),
+    #[error("The default notebook cannot be deleted")]
+    DefaultNotebook,
+    #[error("Notebook is not empty: {notes} notes and {notebooks} notebooks would be deleted")]
+    NotEmpty { notes: u64, notebooks: u64 },
+    #[error("Note has been modified")]
+    VersionMismatch(Box<Note>),
+}
+
+/// Retrieves all notebooks with their note and child notebook counts.
+///
+/// Notebooks are returned flat, ordered by position; clients build the tree
+/// from `parent_id`.
+pub async fn get_all_notebooks(client: &Client) -> Result<Vec<NotebookSummary>, NotebookServiceError> {
+    let notebooks = load_notebooks(client).await?;
+
+    let pipeline = vec![
+        doc! { "$match": { "notebook_id": { "$ne": Bson::Null }, "deleted_at": null } },
+        doc! { "$group": { "_id": "$notebook_id", "count": { "$sum": 1 } } },
+    ];
+    let mut cursor = get_notes_documents(client).aggregate(pipeline).await?;
+    let mut note_counts = HashMap::new();
+    while let Some(group) = cursor.try_next().await? {
+        if let Ok(id) = group.get_object_id("_id") {
+            let count = group.get_i32("count").map(i64::from).or_else(|_| group.get_i64("count")).unwrap_or(0);
+            note_counts.insert(id, count as u64);
+        }
+    }
+
+    let mut child_counts: HashMap<ObjectId, u64> = HashMap::new();
+    for parent_id in notebooks.iter().filter_map(|n| n.parent_id) {
+        *child_counts.entry(parent_id).or_default() += 1;
+    }
+
+    Ok(notebooks
+        .into_iter()
+        .map(|notebook| {
+            let id = notebook.id.unwrap_or_default();
+            NotebookSummary {
+                note_count: note_counts.get(&id).copied().unwrap_or(0),
+                notebook_count: child_counts.get(&id).copied().unwrap_or(0),
+                notebook,
+            }
+        })
+        .collect())
+}
+
+/// Retrieves a single notebook by its id.
+pub async fn get_notebook(client: &Client, notebook_id: &str) -> Result<Notebook, NotebookServiceError> {
+    let object_id = ObjectId::parse_str(notebook_id)?;
+    find_notebook(client, object_id).await
+}
+
+/// Retrieves a single notebook with its note and child notebook counts.
+pub async fn get_notebook_summary(client: &Client, notebook_id: &str) -> Result<NotebookSummary, NotebookServiceError> {
+    let notebook = get_notebook(client, notebook_id).await?;
+    Ok(summarize(client, notebook).await?)
+}
+
+/// Retrieves the notes of a notebook in their manual order.
+pub async fn get_notebook_notes(client: &Client, notebook_id: &str) -> Result<Vec<Note>, NotebookServiceError> {
+    let object_id = ObjectId::parse_str(notebook_id)?;
+    find_notebook(client, object_id).await?;
+
+    let cursor = notes_service::get_notes_collection(client)
+        .find(doc! { "notebook_id": object_id, "deleted_at": null })
+        .sort(doc! { "position": 1, "created_at": 1 })
+        .await?;
+    Ok(cursor.try_collect().await?)
+}
+
+/// Inserts a new notebook at the end of its siblings, keeping an id already set.
+pub async fn add_notebook(client: &Client, mut notebook: Notebook) -> Result<Notebook, NotebookServiceError> {
+    if let Err(e) = notebook.validate() {
+        return Err(NotebookServiceError::ValidationError(e));
+    }
+    if let Some(parent_id) = notebook.parent_id {
+        find_notebook(client, parent_id).await?;
+    }
+
+    let collection = get_notebooks_collection(client);
+    let siblings = collection.count_documents(doc! { "parent_id": notebook.parent_id }).await?;
+    notebook.id.get_or_insert_with(ObjectId::new);
+    notebook.position = siblings as i32;
+    notebook.is_default = false;
+    notebook.created_at = Some(Utc::now().to_rfc3339());
+    notebook.updated_at = Some(Utc::now().to_rfc3339());
+    collection.insert_one(&notebook).await?;
+    Ok(notebook)
+}
+
+/// Renames an existing notebook.
+pub async fn rename_notebook(client: &Client, notebook_id: &str, name: String) -> Result<NotebookSummary, NotebookServiceError> {
+    let object_id = ObjectId::parse_str(notebook_id)?;
+    let mut notebook = find_notebook(client, object_id).await?;
+    notebook.name = name;
+    if let Err(e) = notebook.validate() {
+        return Err(NotebookServiceError::ValidationError(e));
+    }
+
+    let update = doc! {
+        "$set": { "name": notebook.name, "updated_at": Utc::now().to_rfc3339() }
+    };
+    let notebook = get_notebooks_collection(client)
+        .find_one_and_update(doc! { "_id": object_id }, update)
+        .return_document(ReturnDocument::After)
+        .await?
+        .ok_or(NotebookServiceError::NotebookNotFound)?;
+    Ok(summarize(client, notebook).await?)
+}
+
+/// Moves a notebook under a new parent (or to the top level) at the given position.
+///
+/// Moving a notebook into itself or one of its descendants is rejected, as is
+/// nesting the default notebook.
+pub async fn move_notebook(
+    client: &Client,
+    notebook_id: &str,
+    parent_id: Option<&str>,
+    position: Option<i32>,
+) -> Result<NotebookSummary, NotebookServiceError> {
+    let object_id = ObjectId::parse_str(notebook_id)?;
+    let parent_id = parent_id.map(ObjectId::parse_str).transpose()?;
+
+    let notebooks = load_notebooks(client).await?;
+    let notebook = notebooks
+        .iter()
+        .find(|n| n.id == Some(object_id))
+        .ok_or(NotebookServiceError::NotebookNotFound)?;
+    if let Some(parent_id) = parent_id {
+        if notebook.is_default {
+            return Err(NotebookServiceError::InvalidMove("The default notebook stays at the top level".to_string()));
+        }
+        if !notebooks.iter().any(|n| n.id == Some(parent_id)) {
+            return Err(NotebookServiceError::NotebookNotFound);
+        }
+        if subtree_ids(&notebooks, object_id).contains(&parent_id) {
+            return Err(NotebookServiceError::InvalidMove(
+                "A notebook cannot be moved into itself or one of its descendants".to_string(),
+            ));
+        }
+    }
+
+    let collection = get_notebooks_collection(client);
+    // Close the gap left behind among the old siblings
+    collection
+        .update_many(
+            doc! { "parent_id": notebook.parent_id, "position": { "$gt": notebook.position }, "_id": { "$ne": object_id } },
+            doc! { "$inc": { "position": -1 } },
+        )
+        .await?;
+
+    let siblings = collection
+        .count_documents(doc! { "parent_id": parent_id, "_id": { "$ne": object_id } })
+        .await? as i32;
+    let position = position.unwrap_or(siblings).clamp(0, siblings);
+    collection
+        .update_many(
+            doc! { "parent_id": parent_id, "position": { "$gte": position }, "_id": { "$ne": object_id } },
+            doc! { "$inc": { "position": 1 } },
+        )
+        .await?;
+
+    let update = doc! {
+        "$set": { "parent_id": parent_id, "position": position, "updated_at": Utc::now().to_rfc3339() }
+    };
+    let notebook = collection
+        .find_one_and_update(doc! { "_id": object_id }, update)
+        .return_document(ReturnDocument::After)
+        .await?
+        .ok_or(NotebookServiceError::NotebookNotFound)?;
+    Ok(summarize(client, notebook).await?)
+}
+
+/// Moves a note into a notebook (or out of all notebooks) at the given position.
+///
+/// With `expected_versions` (from `If-Match`), the move only applies to one of
+/// those versions of the note.
+pub async fn move_note(
+    client: &Client,
+    note_id: &str,
+    notebook_id: Option<&str>,
+    position: Option<i32>,
+    expected_versions: Option<&[i64]>,
+) -> Result<Note, NotebookServiceError> {
+    let note_object_id = ObjectId::parse_str(note_id)?;
+    let notebook_id = notebook_id.map(ObjectId::parse_str).transpose()?;
+    if let Some(notebook_id) = notebook_id {
+        find_notebook(client, notebook_id).await?;
+    }
+
+    let notes = notes_service::get_notes_collection(client);
+    let mut filter = doc! { "_id": note_object_id, "deleted_at": null };
+    let note = notes
+        .find_one(filter.clone())
+        .await?
+        .ok_or(NotebookServiceError::NoteNotFound)?;
+    // Check the version before shifting the note's siblings around
+    if let Some(expected) = expected_versions {
+        if !expected.contains(&note.version) {
+            return Err(NotebookServiceError::VersionMismatch(Box::new(note)));
+        }
+        filter.insert("version", note.version);
+    }
+
+    let mut session = client.start_session().await?;
+    let fields = make_room_for_note(client, &mut session, &note, notebook_id, position).await?;
+    let moved = notes
+        .find_one_and_update(filter, doc! { "$set": fields, "$inc": { "version": 1 } })
+        .return_document(ReturnDocument::After)
+        .await?;
+    match moved {
+        Some(note) => Ok(note),
+        // Changed or removed since it was loaded
+        None => match notes.find_one(doc! { "_id": note_object_id, "deleted_at": null }).await? {
+            Some(note) => Err(NotebookServiceError::VersionMismatch(Box::new(note))),
+            None => Err(NotebookServiceError::NoteNotFound),
+        },
+    }
+}
+
+/// Closes the gap a note leaves among the notes of its notebook and makes room
+/// for it at `position` in `notebook_id` (appending by default). Returns the
+/// fields that put the note there, for the caller to set.
+pub(crate) async fn make_room_for_note(
+    client: &Client,
+    session: &mut ClientSession,
+    note: &Note,
+    notebook_id: Option<ObjectId>,
+    position: Option<i32>,
+) -> Result<Document, Error> {
+    let notes = notes_service::get_notes_collection(client);
+    if let (Some(old_notebook), Some(old_position)) = (note.notebook_id, note.position) {
+        notes
+            .update_many(
+                doc! { "notebook_id": old_notebook, "position": { "$gt": old_position }, "_id": { "$ne": note.id } },
+                doc! { "$inc": { "position": -1 } },
+            )
+            .session(&mut *session)
+            .await?;
+    }
+
+    let mut fields = doc! { "notebook_id": notebook_id, "updated_at": datetime::to_bson(Utc::now()) };
+    match notebook_id {
+        Some(notebook_id) => {
+            let siblings = notes
+                .count_documents(doc! { "notebook_id": notebook_id, "_id": { "$ne": note.id }, "deleted_at": null })
+                .session(&mut *session)
+                .await? as i32;
+            let position = position.unwrap_or(siblings).clamp(0, siblings);
+            notes
+                .update_many(
+                    doc! { "notebook_id": notebook_id, "position": { "$gte": position }, "_id": { "$ne": note.id } },
+                    doc! { "$inc": { "position": 1 } },
+                )
+                .session(&mut *session)
+                .await?;
+            fields.insert("position", position);
+        }
+        None => {
+            fields.insert("position", Bson::Null);
+        }
+    }
+    Ok(fields)
+}
+
+/// Removes a notebook.
+///
+/// In `Move` mode its notes go to the default notebook and its child notebooks
+/// move up to its parent. In `Recursive` mode the whole subtree is deleted and
+/// its notes go to the trash, which must be confirmed explicitly when anything
+/// would be lost.
+pub async fn remove_notebook(
+    client: &Client,
+    notebook_id: &str,
+    mode: NotebookDeleteMode,
+    confirm: bool,
+) -> Result<(), NotebookServiceError> {
+    let object_id = ObjectId::parse_str(notebook_id)?;
+    let notebooks = load_notebooks(client).await?;
+    let notebook = notebooks
+        .iter()
+        .find(|n| n.id == Some(object_id))
+        .ok_or(NotebookServiceError::NotebookNotFound)?;
+    if notebook.is_default {
+        return Err(NotebookServiceError::DefaultNotebook);
+    }
+
+    let collection = get_notebooks_collection(client);
+    match mode {
+        NotebookDeleteMode::Move => {
+            let note_count = notes_service::get_notes_collection(client)
+                .count_documents(doc! { "notebook_id": object_id })
+                .await?;
+            if note_count > 0 {
+                let default_id = ensure_default_notebook(client).await?;
+                let offset = notes_service::get_notes_collection(client)
+                    .count_documents(doc! { "notebook_id": default_id, "deleted_at": null })
+                    .await? as i32;
+                // Append the moved notes after the default notebook's own notes
+                let update = vec![doc! {
+                    "$set": {
+                        "notebook_id": default_id,
+                        "position": { "$add": [{ "$ifNull": ["$position", 0] }, offset] },
+                        "updated_at": datetime::to_bson(Utc::now()),
+                        "version": { "$add": [{ "$ifNull": ["$version", 0] }, 1] }
+                    }
+                }];
+                notes_service::get_notes_collection(client)
+                    .update_many(doc! { "notebook_id": object_id }, update)
+                    .await?;
+            }
+
+            // Close the gap first, so the children follow the last sibling
+            close_gap(client, notebook).await?;
+            let offset = collection
+                .count_documents(doc! { "parent_id": notebook.parent_id, "_id": { "$ne": object_id } })
+                .await? as i32;
+            let update = vec![doc! {
+                "$set": {
+                    "parent_id": notebook.parent_id,
+                    "position": { "$add": ["$position", offset] },
+                    "updated_at": Utc::now().to_rfc3339()
+                }
+            }];
+            collection.update_many(doc! { "parent_id": object_id }, update).await?;
+            collection.delete_one(doc! { "_id": object_id }).await?;
+        }
+        NotebookDeleteMode::Recursive => {
+            let subtree = subtree_ids(&notebooks, object_id);
+            if notebooks.iter().any(|n| n.is_default && n.id.is_some_and(|id| subtree.contains(&id))) {
+                return Err(NotebookServiceError::DefaultNotebook);
+            }
+
+            let notes = notes_service::get_notes_collection(client)
+                .count_documents(doc! { "notebook_id": { "$in": &subtree }, "deleted_at": null })
+                .await?;
+            let descendants = subtree.len() as u64 - 1;
+            if !confirm && (notes > 0 || descendants > 0) {
+                return Err(NotebookServiceError::NotEmpty { notes, notebooks: descendants });
+            }
+
+            notes_service::trash_notes_in_notebooks(client, &subtree).await?;
+            collection.delete_many(doc! { "_id": { "$in": &subtree } }).await?;
+            close_gap(client, notebook).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves up the siblings after a notebook that is going away.
+async fn close_gap(client: &Client, notebook: &Notebook) -> Result<(), Error> {
+    get_notebooks_collection(client)
+        .update_many(
+            doc! { "parent_id": notebook.parent_id, "position": { "$gt": notebook.position }, "_id": { "$ne": notebook.id } },
+            doc! { "$inc": { "position": -1 } },
+        )
+        .await?;
+    Ok(())
+}
+
+/// Returns the id of the default notebook, creating it if it doesn't exist yet.
+pub async fn ensure_default_notebook(client: &Client) -> Result<ObjectId, NotebookServiceError> {
+    let collection = get_notebooks_collection(client);
+    let now = Utc::now().to_rfc3339();
+    let update = doc! {
+        "$setOnInsert": {
+            "name": DEFAULT_NOTEBOOK_NAME,
+            "parent_id": Bson::Null,
+            "position": 0,
+            "created_at": &now,
+            "updated_at": &now
+        }
+    };
+    let upserted = collection
+        .find_one_and_update(doc! { "is_default": true }, update)
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .await;
+    let notebook = match upserted {
+        Ok(notebook) => notebook,
+        // A concurrent request created it first; the unique index kept it to one
+        Err(e) if is_duplicate_key(&e) => collection.find_one(doc! { "is_default": true }).await?,
+        Err(e) => return Err(e.into()),
+    };
+    notebook.and_then(|notebook| notebook.id).ok_or(NotebookServiceError::NotebookNotFound)
+}
+
+/// Returns the given notebook id together with the ids of all its descendants.
+fn subtree_ids(notebooks: &[Notebook], root: ObjectId) -> Vec<ObjectId> {
+    let mut ids = vec![root];
+    let mut index = 0;
+    while index < ids.len() {
+        let current = ids[index];
+        ids.extend(
+            notebooks
+                .iter()
+                .filter(|n| n.parent_id == Some(current))
+                .filter_map(|n| n.id),
+        );
+        index += 1;
+    }
+    ids
+}
+
+/// Counts the live notes and child notebooks of a notebook.
+async fn summarize(client: &Client, notebook: Notebook) -> Result<NotebookSummary, Error> {
+    let id = notebook.id.unwrap_or_default();
+    let note_count = notes_service::get_notes_collection(client)
+        .count_documents(doc! { "notebook_id": id, "deleted_at": null })
+        .await?;
+    let notebook_count = get_notebooks_collection(client).count_documents(doc! { "parent_id": id }).await?;
+    Ok(NotebookSummary { notebook, note_count, notebook_count })
+}
+
+/// Looks up a notebook by id.
+async fn find_notebook(client: &Client, notebook_id: ObjectId) -> Result<Notebook, NotebookServiceError> {
+    get_notebooks_collection(client)
+        .find_one(doc! { "_id": notebook_id })
+        .await?
+        .ok_or(NotebookServiceError::NotebookNotFound)
+}
+
+/// Loads every notebook, ordered by position.
+async fn load_notebooks(client: &Client) -> Result<Vec<Notebook>, Error> {
+    let cursor = get_notebooks_collection(client)
+        .find(doc! {})
+        .sort(doc! { "position": 1, "name": 1 })
+        .await?;
+    cursor.try_collect().await
+}
+
+/// Helper function to get the "notebooks" collection.
+pub(crate) fn get_notebooks_collection(client: &Client) -> mongodb::Collection<Notebook> {
+    let db = client.database("organise");
+    db.collection::<Notebook>("notebooks")
+}
+
+/// Helper function to get the "notes" collection as raw documents, for aggregations.
+fn get_notes_documents(client: &Client) -> mongodb::Collection<Document> {
+    let db = client.database("organise");
+    db.collection::<Document>("notes")
+}
+
+// Custom function to convert NotebookServiceError to HttpResponse
+pub fn error_response(error: NotebookServiceError) -> HttpResponse {
+    match error {
+        NotebookServiceError::DatabaseError(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+        NotebookServiceError::InvalidObjectId(e) => HttpResponse::BadRequest().body(format!("Invalid ObjectId: {}", e)),
+        NotebookServiceError::NotebookNotFound => HttpResponse::NotFound().body("Notebook not found"),
+        NotebookServiceError::NoteNotFound => HttpResponse::NotFound().body("Note not found"),
+        NotebookServiceError::ValidationError(e) => HttpResponse::BadRequest().json(e),
+        NotebookServiceError::InvalidMove(e) => HttpResponse::BadRequest().body(format!("Invalid move: {}", e)),
+        e @ NotebookServiceError::DefaultNotebook => HttpResponse::BadRequest().body(e.to_string()),
+        e @ NotebookServiceError::NotEmpty { .. } => HttpResponse::Conflict().body(e.to_string()),
+        NotebookServiceError::VersionMismatch(note) => HttpResponse::PreconditionFailed()
+            .insert_header(preconditions::etag(note.version))
+            .json(note),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn test_client() -> Client {
+        let uri = std::env::var("MONGODB_TEST_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
+        let client = Client::with_uri_str(uri).await.unwrap();
+        crate::db::indexes::ensure_indexes(&client).await.unwrap();
+        client
+    }
+
+    async fn add(client: &Client, name: &str, parent_id: Option<ObjectId>) -> ObjectId {
+        let notebook = add_notebook(client, Notebook::new(name.to_string(), parent_id)).await.unwrap();
+        notebook.id.unwrap()
+    }
+
+    /// A top-level notebook of its own for one test.
+    async fn test_root(client: &Client) -> ObjectId {
+        add(client, &format!("test-{}", ObjectId::new().to_hex()), None).await
+    }
+
+    /// Names of the children of a notebook in their order, with positions.
+    async fn children(client: &Client, parent_id: ObjectId) -> Vec<(String, i32)> {
+        let cursor = get_notebooks_collection(client)
+            .find(doc! { "parent_id": parent_id })
+            .sort(doc! { "position": 1 })
+            .await
+            .unwrap();
+        let notebooks: Vec<Notebook> = cursor.try_collect().await.unwrap();
+        notebooks.into_iter().map(|notebook| (notebook.name, notebook.position)).collect()
+    }
+
+    fn ordered(names: &[&str]) -> Vec<(String, i32)> {
+        names.iter().enumerate().map(|(position, name)| (name.to_string(), position as i32)).collect()
+    }
+
+    async fn add_note(client: &Client, notebook_id: ObjectId) -> ObjectId {
+        let mut note = Note::new("Note".to_string(), String::new());
+        note.notebook_id = Some(notebook_id);
+        notes_service::add_note(client, note, false).await.unwrap().id.unwrap()
+    }
+
+    async fn cleanup(client: &Client, root: ObjectId) {
+        let notebooks = load_notebooks(client).await.unwrap();
+        let subtree = subtree_ids(&notebooks, root);
+        get_notebooks_collection(client).delete_many(doc! { "_id": { "$in": &subtree } }).await.unwrap();
+        notes_service::get_notes_collection(client)
+            .delete_many(doc! { "notebook_id": { "$in": &subtree } })
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn move_mode_appends_children_after_the_remaining_siblings() {
+        let client = test_client().await;
+        let root = test_root(&client).await;
+        add(&client, "A", Some(root)).await;
+        let b = add(&client, "B", Some(root)).await;
+        add(&client, "C", Some(root)).await;
+        add(&client, "B1", Some(b)).await;
+        add(&client, "B2", Some(b)).await;
+        let note = add_note(&client, b).await;
+
+        remove_notebook(&client, &b.to_hex(), NotebookDeleteMode::Move, false).await.unwrap();
+        assert_eq!(children(&client, root).await, ordered(&["A", "C", "B1", "B2"]));
+        let default_id = ensure_default_notebook(&client).await.unwrap();
+        let moved = notes_service::get_notes_collection(&client).find_one(doc! { "_id": note }).await.unwrap().unwrap();
+        assert_eq!(moved.notebook_id, Some(default_id));
+
+        notes_service::get_notes_collection(&client).delete_one(doc! { "_id": note }).await.unwrap();
+        cleanup(&client, root).await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn recursive_mode_needs_confirmation_and_trashes_notes() {
+        let client = test_client().await;
+        let root = test_root(&client).await;
+        let x = add(&client, "X", Some(root)).await;
+        add(&client, "Z", Some(root)).await;
+        let y = add(&client, "Y", Some(x)).await;
+        let note = add_note(&client, y).await;
+
+        let refused = remove_notebook(&client, &x.to_hex(), NotebookDeleteMode::Recursive, false).await;
+        assert!(matches!(refused, Err(NotebookServiceError::NotEmpty { notes: 1, notebooks: 1 })));
+        assert_eq!(children(&client, root).await, ordered(&["X", "Z"]));
+
+        remove_notebook(&client, &x.to_hex(), NotebookDeleteMode::Recursive, true).await.unwrap();
+        assert_eq!(children(&client, root).await, ordered(&["Z"]));
+        assert!(matches!(get_notebook(&client, &y.to_hex()).await, Err(NotebookServiceError::NotebookNotFound)));
+        let trashed = notes_service::get_notes_collection(&client).find_one(doc! { "_id": note }).await.unwrap().unwrap();
+        assert!(trashed.deleted_at.is_some());
+
+        cleanup(&client, root).await;
+        notes_service::get_notes_collection(&client).delete_one(doc! { "_id": note }).await.unwrap();
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn the_default_notebook_is_kept_at_the_top_level() {
+        let client = test_client().await;
+        let root = test_root(&client).await;
+        let default_id = ensure_default_notebook(&client).await.unwrap().to_hex();
+
+        for mode in [NotebookDeleteMode::Move, NotebookDeleteMode::Recursive] {
+            let result = remove_notebook(&client, &default_id, mode, true).await;
+            assert!(matches!(result, Err(NotebookServiceError::DefaultNotebook)));
+        }
+        let nested = move_notebook(&client, &default_id, Some(&root.to_hex()), None).await;
+        assert!(matches!(nested, Err(NotebookServiceError::InvalidMove(_))));
+        assert_eq!(get_notebook(&client, &default_id).await.unwrap().parent_id, None);
+
+        cleanup(&client, root).await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn moved_notebooks_keep_both_sides_in_order() {
+        let client = test_client().await;
+        let root = test_root(&client).await;
+        let other = test_root(&client).await;
+        let a = add(&client, "A", Some(root)).await;
+        add(&client, "B", Some(root)).await;
+        let c = add(&client, "C", Some(root)).await;
+
+        move_notebook(&client, &c.to_hex(), Some(&root.to_hex()), Some(0)).await.unwrap();
+        assert_eq!(children(&client, root).await, ordered(&["C", "A", "B"]));
+        move_notebook(&client, &a.to_hex(), Some(&other.to_hex()), None).await.unwrap();
+        assert_eq!(children(&client, root).await, ordered(&["C", "B"]));
+        assert_eq!(children(&client, other).await, ordered(&["A"]));
+        // Positions past the end are clamped
+        move_notebook(&client, &c.to_hex(), Some(&root.to_hex()), Some(10)).await.unwrap();
+        assert_eq!(children(&client, root).await, ordered(&["B", "C"]));
+        let cycle = move_notebook(&client, &root.to_hex(), Some(&c.to_hex()), None).await;
+        assert!(matches!(cycle, Err(NotebookServiceError::InvalidMove(_))));
+
+        cleanup(&client, root).await;
+        cleanup(&client, other).await;
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+    async fn moving_a_stale_note_changes_nothing() {
+        let client = test_client().await;
+        let root = test_root(&client).await;
+        let other = test_root(&client).await;
+        let note_id = add_note(&client, root).await;
+        let note = notes_service::get_note_by_id(&client, &note_id.to_hex()).await.unwrap();
+
+        let stale = move_note(&client, &note_id.to_hex(), Some(&other.to_hex()), None, Some(&[note.version + 1])).await;
+        assert!(matches!(stale, Err(NotebookServiceError::VersionMismatch(current)) if current.version == note.version));
+        let moved = move_note(&client, &note_id.to_hex(), Some(&other.to_hex()), None, Some(&[note.version])).await.unwrap();
+        assert_eq!((moved.notebook_id, moved.version), (Some(other), note.version + 1));
+
+        notes_service::get_notes_collection(&client).delete_one(doc! { "_id": note_id }).await.unwrap();
+        cleanup(&client, root).await;
+        cleanup(&client, other).await;
+    }
+}
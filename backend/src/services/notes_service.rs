@@ -1,7 +1,14 @@
-use mongodb::{Client, bson::{doc, oid::ObjectId}};
+use mongodb::{Client, bson::{doc, oid::ObjectId, Bson, Document}};
+use mongodb::options::{Collation, CollationStrength, ReturnDocument};
 use mongodb::error::Error;
 use futures_util::TryStreamExt;
-use crate::models::note::Note;
+use crate::db::errors::is_duplicate_key;
+use crate::models::attachment::AttachmentOwner;
+use crate::models::datetime;
+use crate::models::note::{KeyRotation, Note, NoteListQuery, NotePage, NoteSort, SortOrder};
+use crate::models::todo::Todo;
+use crate::dto::preconditions;
+use crate::services::{attachment_service, markdown_service, notebook_service, todo_service};
 use thiserror::Error;
 use actix_web::HttpResponse;
 use chrono::Utc;
@@ -15,101 +22,619 @@ pub enum NotesServiceError {
     InvalidObjectId(#[from] mongodb::bson::oid::Error),
     #[error("Note not found")]
     NoteNotFound,
+    #[error("Notebook not found")]
+    NotebookNotFound,
     #[error("Validation error: {0}")]
     ValidationError(validator::ValidationErrors),
+    #[error("Invalid pagination cursor")]
+    InvalidCursor,
+    #[error("Pagination cursor belongs to a different sort order")]
+    CursorMismatch,
+    #[error("Encrypted notes do not support {0}")]
+    EncryptedNote(&'static str),
+    #[error("Note is not encrypted with key {0}")]
+    KeyMismatch(String),
+    #[error("Note has been modified")]
+    VersionMismatch(Box<Note>),
 }
 
-/// Retrieves all Note documents from the MongoDB "notes" collection.
-pub async fn get_all_notes(client: &Client) -> Result<Vec<Note>, NotesServiceError> {
+/// Largest page size accepted when listing notes.
+const MAX_PAGE_SIZE: u32 = 200;
+
+/// Retrieves Note documents from the MongoDB "notes" collection.
+///
+/// Pinned notes always come first, followed by the requested sort order.
+/// Pagination is keyset based: the cursor encodes the sort key of the last
+/// note on the previous page.
+pub async fn get_all_notes(client: &Client, query: &NoteListQuery) -> Result<NotePage, NotesServiceError> {
+    let collection = get_notes_collection(client);
+    let (field, direction) = sort_key(query);
+
+    // Trashed notes are only listed by the trash bin
+    let mut conditions = vec![doc! { "deleted_at": null }];
+    if let Some(pinned) = query.pinned {
+        conditions.push(flag_filter("is_pinned", pinned));
+    }
+    if let Some(favourite) = query.favourite {
+        conditions.push(flag_filter("is_favourite", favourite));
+    }
+    if let Some(key_id) = &query.key_id {
+        conditions.push(doc! { "encryption.key_id": key_id });
+    }
+    if let Some(cursor) = &query.cursor {
+        let (pinned, value, id) = decode_cursor(cursor, query)?;
+        let keys = [
+            ("is_pinned", pinned, -1),
+            (field, value, direction),
+            ("_id", Bson::ObjectId(id), direction),
+        ];
+        conditions.push(after_cursor(&keys));
+    }
+    let filter = doc! { "$and": conditions };
+
+    let limit = query.limit.map(|limit| limit.clamp(1, MAX_PAGE_SIZE) as usize);
+    let mut find = collection
+        .find(filter)
+        .sort(doc! { "is_pinned": -1, field: direction, "_id": direction });
+    if query.sort == NoteSort::Title {
+        // Case-insensitive, so "apple" and "Banana" sort alphabetically
+        find = find.collation(title_collation());
+    }
+    if let Some(limit) = limit {
+        // Fetch one extra note to know whether another page follows
+        find = find.limit(limit as i64 + 1);
+    }
+    let mut notes = collect_notes(find.await?).await?;
+
+    let mut next_cursor = None;
+    if let Some(limit) = limit {
+        if notes.len() > limit {
+            notes.truncate(limit);
+            next_cursor = notes.last().map(|note| encode_cursor(note, query));
+        }
+    }
+    Ok(NotePage { notes, next_cursor })
+}
+
+/// Retrieves a single Note document by its id.
+pub async fn get_note_by_id(client: &Client, note_id: &str) -> Result<Note, NotesServiceError> {
     let collection = get_notes_collection(client);
-    let cursor = collection.find(doc! {}).await?;
-    collect_notes(cursor).await.map_err(NotesServiceError::from)
+    let object_id = ObjectId::parse_str(note_id)?;
+    collection
+        .find_one(doc! { "_id": object_id, "deleted_at": null })
+        .await?
+        .ok_or(NotesServiceError::NoteNotFound)
 }
 
 /// Inserts a new Note document into the MongoDB "notes" collection.
-pub async fn add_note(client: &Client, mut note: Note) -> Result<(), NotesServiceError> {
+///
+/// An id and timestamps already set on the note are kept. When
+/// `extract_todos` is set, or the note opts in itself, unchecked task-list
+/// items are turned into linked todos.
+pub async fn add_note(client: &Client, mut note: Note, extract_todos: bool) -> Result<Note, NotesServiceError> {
     if let Err(e) = note.validate() {
         return Err(NotesServiceError::ValidationError(e));
     }
 
+    let collection = get_notes_collection(client);
+    if let Some(notebook_id) = note.notebook_id {
+        let notebooks = notebook_service::get_notebooks_collection(client);
+        if notebooks.count_documents(doc! { "_id": notebook_id }).await? == 0 {
+            return Err(NotesServiceError::NotebookNotFound);
+        }
+        // New notes go to the end of the notebook
+        note.position = Some(collection.count_documents(doc! { "notebook_id": notebook_id, "deleted_at": null }).await? as i32);
+    }
+
+    // Imports assign ids up front so notes can link to each other
+    let note_id = *note.id.get_or_insert_with(ObjectId::new);
+    if note.is_encrypted() {
+        if extract_todos || note.extract_todos == Some(true) {
+            return Err(NotesServiceError::EncryptedNote("todo extraction"));
+        }
+    } else if extract_todos || note.extract_todos == Some(true) {
+        note.content = sync_linked_todos(client, note_id, &note.title, &note.content).await?;
+    }
+    note.created_at.get_or_insert_with(Utc::now);
+    note.updated_at.get_or_insert_with(Utc::now);
+    collection.insert_one(&note).await?;
+    Ok(note)
+}
+
+/// Retrieves the daily note for a date (YYYY-MM-DD), if it exists.
+pub async fn find_daily_note(client: &Client, date: &str) -> Result<Option<Note>, NotesServiceError> {
+    let collection = get_notes_collection(client);
+    Ok(collection.find_one(doc! { "daily_date": date }).await?)
+}
+
+/// Inserts a daily note unless one already exists for the date.
+///
+/// Relies on the unique index on `daily_date`, so concurrent requests for the
+/// same day return the same note. Returns whether the note was created.
+pub async fn find_or_create_daily_note(client: &Client, date: &str, mut note: Note) -> Result<(Note, bool), NotesServiceError> {
+    if let Err(e) = note.validate() {
+        return Err(NotesServiceError::ValidationError(e));
+    }
+    if let Some(existing) = find_daily_note(client, date).await? {
+        return Ok((existing, false));
+    }
+
     let collection = get_notes_collection(client);
     note.id = Some(ObjectId::new());
-    note.created_at = Some(Utc::now().to_rfc3339());
-    note.updated_at = Some(Utc::now().to_rfc3339());
-    collection.insert_one(note).await?;
-    Ok(())
+    note.daily_date = Some(date.to_string());
+    note.created_at = Some(Utc::now());
+    note.updated_at = Some(Utc::now());
+    match collection.insert_one(&note).await {
+        Ok(_) => Ok((note, true)),
+        Err(e) if is_duplicate_key(&e) => find_daily_note(client, date)
+            .await?
+            .map(|existing| (existing, false))
+            .ok_or(NotesServiceError::NoteNotFound),
+        Err(e) => Err(e.into()),
+    }
 }
 
 /// Updates an existing Note document in the MongoDB "notes" collection.
-pub async fn update_note(client: &Client, note_id: &str, mut updated_note: Note) -> Result<(), NotesServiceError> {
+///
+/// Linked todos are kept in sync with the note's task-list items when
+/// `extract_todos` is set or the note has opted in. With `expected_versions`
+/// the update only applies to one of those versions. A note without
+/// `encryption` keeps the stored encryption with `keep_encryption`, and is
+/// stored decrypted otherwise. Returns the updated note.
+pub async fn update_note(
+    client: &Client,
+    note_id: &str,
+    mut updated_note: Note,
+    extract_todos: bool,
+    expected_versions: Option<&[i64]>,
+    keep_encryption: bool,
+) -> Result<Note, NotesServiceError> {
+    let collection = get_notes_collection(client);
+    let object_id = ObjectId::parse_str(note_id)?;
+    let (existing_note, filter) = find_for_write(client, object_id, expected_versions).await?;
+    if keep_encryption && updated_note.encryption.is_none() {
+        updated_note.encryption = existing_note.encryption;
+    }
     if let Err(e) = updated_note.validate() {
         return Err(NotesServiceError::ValidationError(e));
     }
+    let wants_todos = extract_todos || updated_note.extract_todos.or(existing_note.extract_todos) == Some(true);
+    if updated_note.is_encrypted() {
+        if extract_todos || updated_note.extract_todos == Some(true) {
+            return Err(NotesServiceError::EncryptedNote("todo extraction"));
+        }
+        // The server can no longer read the task list, so stop syncing it
+        updated_note.extract_todos = Some(false);
+    } else if wants_todos {
+        updated_note.content = sync_linked_todos(client, object_id, &updated_note.title, &updated_note.content).await?;
+    }
+
+    let mut fields = doc! { 
+        "title": updated_note.title, 
+        "content": updated_note.content,
+        "updated_at": datetime::to_bson(Utc::now()),
+        "tags": updated_note.tags,
+        "is_archived": updated_note.is_archived
+    };
+    if let Some(flag) = updated_note.extract_todos {
+        fields.insert("extract_todos", flag);
+    }
+    // Without encryption the note is stored as plain text
+    let update = match updated_note.encryption {
+        Some(encryption) => {
+            fields.insert("encryption", mongodb::bson::to_bson(&encryption).unwrap_or_default());
+            doc! { "$set": fields, "$inc": { "version": 1 } }
+        }
+        None => doc! { "$set": fields, "$unset": { "encryption": "" }, "$inc": { "version": 1 } },
+    };
+    let note = match collection.find_one_and_update(filter, update).return_document(ReturnDocument::After).await? {
+        Some(note) => note,
+        None => return Err(write_conflict(client, object_id).await),
+    };
+    if note.is_encrypted() {
+        purge_linked_todos(client, object_id).await?;
+    }
+    Ok(note)
+}
+
+/// Permanently deletes the todos extracted from a note, whose titles and
+/// descriptions would otherwise keep the plain text of an encrypted note.
+async fn purge_linked_todos(client: &Client, note_id: ObjectId) -> Result<(), Error> {
+    let todos: Vec<Todo> = todo_service::get_todo_collection(client)
+        .find(doc! { "note_id": note_id })
+        .await?
+        .try_collect()
+        .await?;
+    for todo_id in todos.into_iter().filter_map(|todo| todo.id) {
+        todo_service::purge_todo(client, todo_id).await?;
+    }
+    Ok(())
+}
+
+/// Rewraps an encrypted note's data key with a new key.
+///
+/// Only the key metadata changes; the ciphertext is left untouched. Fails
+/// with `KeyMismatch` if the note is not currently wrapped with `from_key_id`,
+/// and with `expected_versions` only applies to one of those versions.
+pub async fn rotate_note_key(
+    client: &Client,
+    note_id: &str,
+    rotation: KeyRotation,
+    expected_versions: Option<&[i64]>,
+) -> Result<Note, NotesServiceError> {
+    if rotation.key_id.is_empty() || rotation.key_id.len() > 100 {
+        let mut errors = validator::ValidationErrors::new();
+        errors.add("key_id", validator::ValidationError::new("length"));
+        return Err(NotesServiceError::ValidationError(errors));
+    }
 
     let collection = get_notes_collection(client);
     let object_id = ObjectId::parse_str(note_id)?;
-    let filter = doc! { "_id": object_id };
-    
-    // Check if note exists
-    let existing_note = collection.find_one(filter.clone()).await?;
-    if existing_note.is_none() {
-        return Err(NotesServiceError::NoteNotFound);
+    let (note, mut filter) = find_for_write(client, object_id, expected_versions).await?;
+    let Some(mut encryption) = note.encryption.filter(|encryption| encryption.key_id == rotation.from_key_id) else {
+        return Err(NotesServiceError::KeyMismatch(rotation.from_key_id));
+    };
+    encryption.key_id = rotation.key_id;
+    encryption.wrapped_key = rotation.wrapped_key;
+    if let Err(message) = encryption.validate() {
+        let mut errors = validator::ValidationErrors::new();
+        let mut error = validator::ValidationError::new("encryption");
+        error.message = Some(message.into());
+        errors.add("encryption", error);
+        return Err(NotesServiceError::ValidationError(errors));
     }
 
-    updated_note.updated_at = Some(Utc::now().to_rfc3339());
-    let update = doc! { 
-        "$set": { 
-            "title": updated_note.title, 
-            "content": updated_note.content,
-            "updated_at": updated_note.updated_at,
-            "tags": updated_note.tags,
-            "is_archived": updated_note.is_archived
-        } 
+    filter.insert("encryption.key_id", &rotation.from_key_id);
+    let update = doc! {
+        "$set": {
+            "encryption.key_id": encryption.key_id,
+            "encryption.wrapped_key": encryption.wrapped_key,
+            "updated_at": datetime::to_bson(Utc::now())
+        },
+        "$inc": { "version": 1 }
     };
-    collection.update_one(filter, update).await?;
-    Ok(())
+    match collection.find_one_and_update(filter, update).return_document(ReturnDocument::After).await? {
+        Some(note) => Ok(note),
+        None if expected_versions.is_some() => Err(write_conflict(client, object_id).await),
+        None => Err(NotesServiceError::KeyMismatch(rotation.from_key_id)),
+    }
 }
 
-/// Removes an existing Note document from the MongoDB "notes" collection.
-pub async fn remove_note(client: &Client, note_id: &str) -> Result<(), NotesServiceError> {
+/// Moves an existing note to the trash.
+///
+/// The note keeps its attachments until it is permanently deleted. A trashed
+/// daily note stops being the note for its date, so a new one can be created;
+/// the date is kept aside in `trashed_daily_date` for restoring it.
+pub async fn remove_note(client: &Client, note_id: &str, expected_versions: Option<&[i64]>) -> Result<(), NotesServiceError> {
     let collection = get_notes_collection(client);
     let object_id = ObjectId::parse_str(note_id)?;
-    let filter = doc! { "_id": object_id };
-    
-    // Check if note exists
-    let existing_note = collection.find_one(filter.clone()).await?;
-    if existing_note.is_none() {
-        return Err(NotesServiceError::NoteNotFound);
+    let (_, filter) = find_for_write(client, object_id, expected_versions).await?;
+    let update = doc! {
+        "$set": { "deleted_at": datetime::to_bson(Utc::now()) },
+        "$rename": { "daily_date": "trashed_daily_date" },
+        "$inc": { "version": 1 }
+    };
+    if collection.update_one(filter, update).await?.matched_count == 0 {
+        return Err(write_conflict(client, object_id).await);
     }
-
-    collection.delete_one(filter).await?;
     Ok(())
 }
 
+/// Permanently deletes a note and releases its attachments; their blobs are
+/// deleted by the next attachment garbage collection.
+pub(crate) async fn purge_note(client: &Client, note_id: ObjectId) -> Result<bool, Error> {
+    let result = get_notes_collection(client).delete_one(doc! { "_id": note_id }).await?;
+    attachment_service::detach_owner(client, AttachmentOwner::Note, note_id).await?;
+    Ok(result.deleted_count > 0)
+}
+
 /// Toggles the archive status of a note
-pub async fn toggle_archive(client: &Client, note_id: &str) -> Result<(), NotesServiceError> {
+pub async fn toggle_archive(client: &Client, note_id: &str, expected_versions: Option<&[i64]>) -> Result<Note, NotesServiceError> {
     let collection = get_notes_collection(client);
     let object_id = ObjectId::parse_str(note_id)?;
-    let filter = doc! { "_id": object_id };
-    
-    // Check if note exists
-    let existing_note = collection.find_one(filter.clone()).await?;
-    if existing_note.is_none() {
-        return Err(NotesServiceError::NoteNotFound);
-    }
+    let (_, filter) = find_for_write(client, object_id, expected_versions).await?;
 
     let update = doc! { 
         "$set": { 
             "is_archived": true,
-            "updated_at": Utc::now().to_rfc3339()
-        } 
+            "updated_at": datetime::to_bson(Utc::now())
+        },
+        "$inc": { "version": 1 }
+    };
+    match collection.find_one_and_update(filter, update).return_document(ReturnDocument::After).await? {
+        Some(note) => Ok(note),
+        None => Err(write_conflict(client, object_id).await),
+    }
+}
+
+/// Pins or unpins a note.
+pub async fn set_pinned(client: &Client, note_id: &str, pinned: bool, expected_versions: Option<&[i64]>) -> Result<Note, NotesServiceError> {
+    set_flag(client, note_id, "is_pinned", pinned, expected_versions).await
+}
+
+/// Marks or unmarks a note as a favourite.
+pub async fn set_favourite(
+    client: &Client,
+    note_id: &str,
+    favourite: bool,
+    expected_versions: Option<&[i64]>,
+) -> Result<Note, NotesServiceError> {
+    set_flag(client, note_id, "is_favourite", favourite, expected_versions).await
+}
+
+async fn set_flag(
+    client: &Client,
+    note_id: &str,
+    field: &str,
+    value: bool,
+    expected_versions: Option<&[i64]>,
+) -> Result<Note, NotesServiceError> {
+    let collection = get_notes_collection(client);
+    let object_id = ObjectId::parse_str(note_id)?;
+    let (_, filter) = find_for_write(client, object_id, expected_versions).await?;
+    let update = doc! {
+        "$set": { field: value, "updated_at": datetime::to_bson(Utc::now()) },
+        "$inc": { "version": 1 }
+    };
+    match collection.find_one_and_update(filter, update).return_document(ReturnDocument::After).await? {
+        Some(note) => Ok(note),
+        None => Err(write_conflict(client, object_id).await),
+    }
+}
+
+/// Creates or updates the todos linked to the task-list items of a note.
+///
+/// Items carrying a link marker update their todo's title and completion;
+/// unchecked items without one get a new todo and a marker. Returns the note
+/// content with the new markers inserted.
+pub(crate) async fn sync_linked_todos(
+    client: &Client,
+    note_id: ObjectId,
+    note_title: &str,
+    content: &str,
+) -> Result<String, NotesServiceError> {
+    let todos = todo_service::get_todo_collection(client);
+    let mut synced = content.to_string();
+
+    // Walk backwards so inserted markers don't shift offsets still to be visited
+    for item in markdown_service::task_items(content).into_iter().rev() {
+        let title: String = item.title.chars().take(100).collect();
+        if title.is_empty() {
+            continue;
+        }
+        let now = Utc::now().to_rfc3339();
+
+        match item.todo_id.as_deref().and_then(|id| ObjectId::parse_str(id).ok()) {
+            Some(todo_id) => {
+                let filter = doc! {
+                    "_id": todo_id,
+                    "note_id": note_id,
+                    "deleted_at": null,
+                    "$or": [
+                        { "title": { "$ne": &title } },
+                        { "completed": { "$ne": item.checked } }
+                    ]
+                };
+                let update = doc! {
+                    "$set": { "title": &title, "completed": item.checked, "updated_at": now },
+                    "$inc": { "version": 1 }
+                };
+                todos.update_one(filter, update).await?;
+            }
+            None if !item.checked => {
+                let todo_id = ObjectId::new();
+                let todo = Todo {
+                    id: Some(todo_id),
+                    title,
+                    description: format!("From note \"{}\"", note_title),
+                    completed: false,
+                    priority: "medium".to_string(),
+                    created_at: now.clone(),
+                    updated_at: Some(now),
+                    note_id: Some(note_id),
+                    project: None,
+                    tags: Some(Vec::new()),
+                    is_archived: Some(false),
+                    deleted_at: None,
+                    version: 1,
+                };
+                todos.insert_one(todo).await?;
+                synced.insert_str(item.line_end, &markdown_service::todo_marker(&todo_id.to_hex()));
+            }
+            None => {}
+        }
+    }
+    Ok(synced)
+}
+
+/// Ticks or unticks the task-list item linked to a todo in its source note.
+///
+/// Does nothing if the note or the linked item no longer exists.
+pub async fn set_linked_task_state(
+    client: &Client,
+    note_id: ObjectId,
+    todo_id: ObjectId,
+    checked: bool,
+) -> Result<(), Error> {
+    let collection = get_notes_collection(client);
+    let filter = doc! { "_id": note_id, "deleted_at": null };
+    let Some(note) = collection.find_one(filter.clone()).await?.filter(|note| !note.is_encrypted()) else {
+        return Ok(());
+    };
+
+    let todo_hex = todo_id.to_hex();
+    let item = markdown_service::task_items(&note.content)
+        .into_iter()
+        .find(|item| item.todo_id.as_deref() == Some(todo_hex.as_str()));
+    let Some(item) = item.filter(|item| item.checked != checked) else {
+        return Ok(());
+    };
+
+    let mut content = note.content;
+    content.replace_range(item.checkbox, if checked { "[x]" } else { "[ ]" });
+    let update = doc! {
+        "$set": { "content": content, "updated_at": datetime::to_bson(Utc::now()) },
+        "$inc": { "version": 1 }
     };
     collection.update_one(filter, update).await?;
     Ok(())
 }
 
+/// Moves every note filed in one of the given notebooks to the trash.
+pub(crate) async fn trash_notes_in_notebooks(client: &Client, notebook_ids: &[ObjectId]) -> Result<u64, Error> {
+    let filter = doc! { "notebook_id": { "$in": notebook_ids }, "deleted_at": null };
+    let update = doc! {
+        "$set": { "deleted_at": datetime::to_bson(Utc::now()) },
+        "$rename": { "daily_date": "trashed_daily_date" },
+        "$inc": { "version": 1 }
+    };
+    let result = get_notes_collection(client).update_many(filter, update).await?;
+    Ok(result.modified_count)
+}
+
+/// Collation used when sorting and paginating by title.
+pub(crate) fn title_collation() -> Collation {
+    Collation::builder()
+        .locale("en")
+        .strength(CollationStrength::Secondary)
+        .build()
+}
+
+/// Field and direction the notes list is sorted by.
+fn sort_key(query: &NoteListQuery) -> (&'static str, i32) {
+    let (field, default_order) = match query.sort {
+        NoteSort::Updated => ("updated_at", SortOrder::Desc),
+        NoteSort::Created => ("created_at", SortOrder::Desc),
+        NoteSort::Title => ("title", SortOrder::Asc),
+        NoteSort::Manual => ("position", SortOrder::Asc),
+    };
+    match query.order.unwrap_or(default_order) {
+        SortOrder::Asc => (field, 1),
+        SortOrder::Desc => (field, -1),
+    }
+}
+
+/// Filter for a boolean flag, treating a missing flag as `false`.
+fn flag_filter(field: &str, value: bool) -> Document {
+    if value {
+        doc! { field: true }
+    } else {
+        doc! { field: { "$ne": true } }
+    }
+}
+
+/// Matches documents sorting strictly after the given sort key values.
+///
+/// `keys` holds (field, value, direction) in sort priority order; the result
+/// is the usual lexicographic expansion (a > x) OR (a = x AND b > y) ...
+fn after_cursor(keys: &[(&str, Bson, i32)]) -> Document {
+    let branches: Vec<Document> = (0..keys.len())
+        .map(|i| {
+            let mut clause: Vec<Document> = keys[..i]
+                .iter()
+                .map(|(field, value, _)| doc! { *field: value.clone() })
+                .collect();
+            let (field, value, direction) = &keys[i];
+            clause.push(strictly_after(field, value, *direction));
+            doc! { "$and": clause }
+        })
+        .collect();
+    doc! { "$or": branches }
+}
+
+/// Matches values strictly after `value`. Missing values sort first in
+/// ascending order and last in descending order, as MongoDB sorts them.
+fn strictly_after(field: &str, value: &Bson, direction: i32) -> Document {
+    match (value, direction > 0) {
+        (Bson::Null, true) => doc! { field: { "$ne": Bson::Null } },
+        (Bson::Null, false) => doc! { "_id": { "$exists": false } },
+        (value, true) => doc! { field: { "$gt": value.clone() } },
+        (value, false) => doc! { "$or": [{ field: { "$lt": value.clone() } }, { field: Bson::Null }] },
+    }
+}
+
+/// Encodes the sort key of a note, and the order it was listed in, as an
+/// opaque pagination cursor.
+fn encode_cursor(note: &Note, query: &NoteListQuery) -> String {
+    let (field, direction) = sort_key(query);
+    let value = match query.sort {
+        NoteSort::Updated => note.updated_at.map(datetime::to_bson).map_or(Bson::Null, Bson::DateTime),
+        NoteSort::Created => note.created_at.map(datetime::to_bson).map_or(Bson::Null, Bson::DateTime),
+        NoteSort::Title => Bson::String(note.title.clone()),
+        NoteSort::Manual => note.position.map_or(Bson::Null, Bson::Int32),
+    };
+    let cursor = doc! {
+        "p": note.is_pinned.map_or(Bson::Null, Bson::Boolean),
+        "v": value,
+        "id": note.id,
+        "s": field,
+        "d": direction,
+    };
+    let mut bytes = Vec::new();
+    // Writing a document into a Vec cannot fail
+    let _ = cursor.to_writer(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Decodes a cursor into the pinned flag, sort value and id it was made from.
+/// A cursor only continues a listing in the order it was made for.
+fn decode_cursor(cursor: &str, query: &NoteListQuery) -> Result<(Bson, Bson, ObjectId), NotesServiceError> {
+    let (field, direction) = sort_key(query);
+    let document = hex::decode(cursor)
+        .ok()
+        .and_then(|bytes| Document::from_reader(bytes.as_slice()).ok())
+        .ok_or(NotesServiceError::InvalidCursor)?;
+    let (Some(pinned), Some(value), Ok(id), Ok(sort), Ok(order)) = (
+        document.get("p"),
+        document.get("v"),
+        document.get_object_id("id"),
+        document.get_str("s"),
+        document.get_i32("d"),
+    ) else {
+        return Err(NotesServiceError::InvalidCursor);
+    };
+    if sort != field || order != direction {
+        return Err(NotesServiceError::CursorMismatch);
+    }
+    Ok((pinned.clone(), value.clone(), id))
+}
+
+/// Loads a live note for a conditional write.
+///
+/// Fails with `VersionMismatch` unless the note is at one of the expected
+/// versions. The returned filter only matches the note at the version that
+/// was loaded, so a concurrent write in between is detected too.
+async fn find_for_write(
+    client: &Client,
+    note_id: ObjectId,
+    expected_versions: Option<&[i64]>,
+) -> Result<(Note, Document), NotesServiceError> {
+    let mut filter = doc! { "_id": note_id, "deleted_at": null };
+    let note = get_notes_collection(client)
+        .find_one(filter.clone())
+        .await?
+        .ok_or(NotesServiceError::NoteNotFound)?;
+    if let Some(expected) = expected_versions {
+        if !expected.contains(&note.version) {
+            return Err(NotesServiceError::VersionMismatch(Box::new(note)));
+        }
+        filter.insert("version", note.version);
+    }
+    Ok((note, filter))
+}
+
+/// Error for a conditional write that matched nothing: the note changed or
+/// was removed after it was loaded.
+async fn write_conflict(client: &Client, note_id: ObjectId) -> NotesServiceError {
+    match get_notes_collection(client).find_one(doc! { "_id": note_id, "deleted_at": null }).await {
+        Ok(Some(note)) => NotesServiceError::VersionMismatch(Box::new(note)),
+        Ok(None) => NotesServiceError::NoteNotFound,
+        Err(e) => e.into(),
+    }
+}
+
 /// Helper function to get the "notes" collection.
-fn get_notes_collection(client: &Client) -> mongodb::Collection<Note> {
+pub(crate) fn get_notes_collection(client: &Client) -> mongodb::Collection<Note> {
     let db = client.database("organise");
     db.collection::<Note>("notes")
 }
@@ -129,6 +654,171 @@ pub fn error_response(error: NotesServiceError) -> HttpResponse {
         NotesServiceError::DatabaseError(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
         NotesServiceError::InvalidObjectId(e) => HttpResponse::BadRequest().body(format!("Invalid ObjectId: {}", e)),
         NotesServiceError::NoteNotFound => HttpResponse::NotFound().body("Note not found"),
+        NotesServiceError::NotebookNotFound => HttpResponse::NotFound().body("Notebook not found"),
         NotesServiceError::ValidationError(e) => HttpResponse::BadRequest().json(e),
+        NotesServiceError::InvalidCursor => HttpResponse::BadRequest().body("Invalid pagination cursor"),
+        e @ NotesServiceError::CursorMismatch => HttpResponse::BadRequest().body(e.to_string()),
+        e @ NotesServiceError::EncryptedNote(_) => HttpResponse::UnprocessableEntity().body(e.to_string()),
+        e @ NotesServiceError::KeyMismatch(_) => HttpResponse::Conflict().body(e.to_string()),
+        NotesServiceError::VersionMismatch(note) => HttpResponse::PreconditionFailed()
+            .insert_header(preconditions::etag(note.version))
+            .json(note),
+    }
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering;
+
+    /// Evaluates the subset of query operators `after_cursor` produces.
+    fn matches(filter: &Document, document: &Document) -> bool {
+        filter.iter().all(|(key, condition)| match key.as_str() {
+            "$and" => condition.as_array().unwrap().iter().all(|c| matches(c.as_document().unwrap(), document)),
+            "$or" => condition.as_array().unwrap().iter().any(|c| matches(c.as_document().unwrap(), document)),
+            field => {
+                let value = document.get(field).unwrap_or(&Bson::Null);
+                match condition.as_document() {
+                    Some(operators) => operators.iter().all(|(operator, operand)| match operator.as_str() {
+                        "$gt" => same_type(value, operand) && compare(value, operand) == Ordering::Greater,
+                        "$lt" => same_type(value, operand) && compare(value, operand) == Ordering::Less,
+                        "$ne" => value != operand,
+                        "$exists" => document.contains_key(field) == operand.as_bool().unwrap(),
+                        other => panic!("unsupported operator {}", other),
+                    }),
+                    None => value == condition,
+                }
+            }
+        })
+    }
+
+    fn same_type(a: &Bson, b: &Bson) -> bool {
+        a.element_type() == b.element_type()
+    }
+
+    /// MongoDB's ordering for the values used in sort keys; null sorts first.
+    fn compare(a: &Bson, b: &Bson) -> Ordering {
+        match (a, b) {
+            (Bson::Null, Bson::Null) => Ordering::Equal,
+            (Bson::Null, _) => Ordering::Less,
+            (_, Bson::Null) => Ordering::Greater,
+            (Bson::Boolean(a), Bson::Boolean(b)) => a.cmp(b),
+            (Bson::DateTime(a), Bson::DateTime(b)) => a.cmp(b),
+            (Bson::String(a), Bson::String(b)) => a.cmp(b),
+            (Bson::Int32(a), Bson::Int32(b)) => a.cmp(b),
+            (Bson::ObjectId(a), Bson::ObjectId(b)) => a.cmp(b),
+            _ => panic!("mixed types in a sort key"),
+        }
+    }
+
+    /// Checks that, from every document, the cursor filter matches exactly
+    /// the documents sorted after it.
+    fn assert_pages_in_order(mut documents: Vec<Document>, field: &str, direction: i32) {
+        let key = |document: &Document, name: &str| document.get(name).cloned().unwrap_or(Bson::Null);
+        // Pinned notes come first whichever way the list is ordered
+        documents.sort_by(|a, b| {
+            let order = compare(&key(a, field), &key(b, field)).then_with(|| compare(&key(a, "_id"), &key(b, "_id")));
+            compare(&key(b, "is_pinned"), &key(a, "is_pinned")).then(if direction < 0 { order.reverse() } else { order })
+        });
+
+        for (position, last) in documents.iter().enumerate() {
+            let keys = [
+                ("is_pinned", key(last, "is_pinned"), -1),
+                (field, key(last, field), direction),
+                ("_id", key(last, "_id"), direction),
+            ];
+            let filter = after_cursor(&keys);
+            let after: Vec<&Document> = documents.iter().filter(|document| matches(&filter, document)).collect();
+            let expected: Vec<&Document> = documents[position + 1..].iter().collect();
+            assert_eq!(after, expected, "after {:?}", last);
+        }
     }
-} 
\ No newline at end of file
+
+    fn note(pinned: Option<bool>, field: &str, value: Option<Bson>) -> Document {
+        let mut document = doc! { "_id": ObjectId::new() };
+        if let Some(pinned) = pinned {
+            document.insert("is_pinned", pinned);
+        }
+        if let Some(value) = value {
+            document.insert(field, value);
+        }
+        document
+    }
+
+    #[test]
+    fn cursor_pages_by_date_with_ties_and_missing_values() {
+        let date = |millis| Some(Bson::DateTime(mongodb::bson::DateTime::from_millis(millis)));
+        let documents = vec![
+            note(Some(true), "updated_at", date(1_000)),
+            note(Some(false), "updated_at", date(3_000)),
+            note(Some(false), "updated_at", date(3_000)),
+            note(Some(false), "updated_at", None),
+            note(None, "updated_at", date(2_000)),
+            note(Some(true), "updated_at", None),
+            note(Some(false), "updated_at", date(1_000)),
+        ];
+        assert_pages_in_order(documents.clone(), "updated_at", -1);
+        assert_pages_in_order(documents, "updated_at", 1);
+    }
+
+    #[test]
+    fn cursor_pages_by_position() {
+        let documents = vec![
+            note(Some(false), "position", Some(Bson::Int32(2))),
+            note(Some(false), "position", Some(Bson::Int32(0))),
+            note(Some(false), "position", None),
+            note(Some(false), "position", Some(Bson::Int32(2))),
+            note(Some(true), "position", Some(Bson::Int32(5))),
+        ];
+        assert_pages_in_order(documents.clone(), "position", 1);
+        assert_pages_in_order(documents, "position", -1);
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let mut note = Note::new("Groceries".to_string(), String::new());
+        note.id = Some(ObjectId::new());
+        note.is_pinned = Some(true);
+
+        let by_title = NoteListQuery { sort: NoteSort::Title, ..Default::default() };
+        let (pinned, value, id) = decode_cursor(&encode_cursor(&note, &by_title), &by_title).unwrap();
+        assert_eq!(pinned, Bson::Boolean(true));
+        assert_eq!(value, Bson::String("Groceries".to_string()));
+        assert_eq!(Some(id), note.id);
+        // Notes without the sort value encode it as null
+        let manual = NoteListQuery { sort: NoteSort::Manual, ..Default::default() };
+        let (_, value, _) = decode_cursor(&encode_cursor(&note, &manual), &manual).unwrap();
+        assert_eq!(value, Bson::Null);
+    }
+
+    #[test]
+    fn malformed_cursors_are_rejected() {
+        let query = NoteListQuery::default();
+        for cursor in ["not hex".to_string(), "00ff".to_string(), hex::encode(b"\x05\x00\x00\x00\x00")] {
+            assert!(matches!(decode_cursor(&cursor, &query), Err(NotesServiceError::InvalidCursor)));
+        }
+    }
+
+    #[test]
+    fn cursors_only_continue_the_order_they_were_made_for() {
+        let mut note = Note::new("Groceries".to_string(), String::new());
+        note.id = Some(ObjectId::new());
+        let by_title = NoteListQuery { sort: NoteSort::Title, ..Default::default() };
+        let cursor = encode_cursor(&note, &by_title);
+
+        let explicit = NoteListQuery { sort: NoteSort::Title, order: Some(SortOrder::Asc), ..Default::default() };
+        assert!(decode_cursor(&cursor, &explicit).is_ok());
+        let reversed = NoteListQuery { sort: NoteSort::Title, order: Some(SortOrder::Desc), ..Default::default() };
+        assert!(matches!(decode_cursor(&cursor, &reversed), Err(NotesServiceError::CursorMismatch)));
+        assert!(matches!(decode_cursor(&cursor, &NoteListQuery::default()), Err(NotesServiceError::CursorMismatch)));
+    }
+
+    #[test]
+    fn sort_defaults_to_newest_first_and_titles_ascending() {
+        let query = NoteListQuery::default();
+        assert_eq!(sort_key(&query), ("updated_at", -1));
+        let query = NoteListQuery { sort: NoteSort::Title, ..NoteListQuery::default() };
+        assert_eq!(sort_key(&query), ("title", 1));
+        let query = NoteListQuery { sort: NoteSort::Title, order: Some(SortOrder::Desc), ..NoteListQuery::default() };
+        assert_eq!(sort_key(&query), ("title", -1));
+    }
+}
@@ -0,0 +1,429 @@
+//! Expands recurring calendar events into the concrete occurrences that fall
+//! within a date range.
+//!
+//! Rules are expanded in the event's time zone, so a weekly 09:00 meeting stays
+//! at 09:00 local time across daylight saving changes. The event's own start is
+//! always the first occurrence and counts towards `COUNT`, as in RFC 5545.
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, Utc};
+use chrono_tz::Tz;
+use crate::models::calendar::{instance_id, CalendarEvent, EventOccurrence};
+use crate::models::recurrence::{local_to_utc, ByDay, Frequency, RecurrenceRule, Until};
+
+/// Most occurrences of one series returned for a single date range.
+pub const MAX_OCCURRENCES: usize = 1000;
+
+/// Most recurrence periods (days, weeks, months or years) examined for one
+/// series, so that rules which rarely or never match still terminate.
+const MAX_PERIODS: u32 = 50_000;
+
+/// Occurrences of an event overlapping `[start, end)`, in start order.
+///
+/// A single event, or an exception to a series, yields itself when it
+/// overlaps the range. An event whose stored rule no longer parses is treated
+/// as a single event. Occurrences replaced by exceptions are still produced;
+/// leaving them out is up to the caller.
+pub fn occurrences(event: &CalendarEvent, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<EventOccurrence> {
+    let rule = event.rule().ok().flatten();
+    if rule.is_none() && event.recurrence_dates.is_empty() {
+        if event.start_time < end && event.end_time > start {
+            return vec![as_occurrence(event)];
+        }
+        return Vec::new();
+    }
+
+    // An occurrence overlaps the range when it starts before its end and ends after its start
+    let duration = event.end_time - event.start_time;
+    let after = start - duration;
+    let mut starts = match &rule {
+        Some(rule) => rule_starts(rule, event.start_time, event.tz(), after, end),
+        None if event.start_time > after && event.start_time < end => vec![event.start_time],
+        None => Vec::new(),
+    };
+    starts.extend(event.recurrence_dates.iter().filter(|date| **date > after && **date < end));
+    starts.sort();
+    starts.dedup();
+    starts.retain(|date| !event.excluded_dates.contains(date));
+    starts.truncate(MAX_OCCURRENCES);
+
+    let series_id = event.id.unwrap_or_default();
+    starts
+        .into_iter()
+        .map(|start| EventOccurrence {
+            event: copy_event(event, start),
+            instance_id: instance_id(series_id, start),
+            recurring_event_id: Some(series_id),
+            original_start_time: Some(start),
+        })
+        .collect()
+}
+
+/// The occurrence of a series that originally started at `start`, if the
+/// series has one.
+pub fn occurrence_at(series: &CalendarEvent, start: DateTime<Utc>) -> Option<EventOccurrence> {
+    occurrences(series, start, start + Duration::milliseconds(1))
+        .into_iter()
+        .find(|occurrence| occurrence.original_start_time == Some(start))
+}
+
+/// A single event or an exception as an occurrence; exceptions take the id
+/// of the occurrence they replace.
+pub fn as_occurrence(event: &CalendarEvent) -> EventOccurrence {
+    match (event.recurring_event_id, event.recurrence_id) {
+        (Some(series_id), Some(start)) => EventOccurrence {
+            event: event.clone(),
+            instance_id: instance_id(series_id, start),
+            recurring_event_id: Some(series_id),
+            original_start_time: Some(start),
+        },
+        _ => EventOccurrence {
+            event: event.clone(),
+            instance_id: event.id.map(|id| id.to_hex()).unwrap_or_default(),
+            recurring_event_id: None,
+            original_start_time: None,
+        },
+    }
+}
+
+/// Start times produced by a rule that lie strictly between `after` and `before`.
+fn rule_starts(
+    rule: &RecurrenceRule,
+    dtstart: DateTime<Utc>,
+    tz: Tz,
+    after: DateTime<Utc>,
+    before: DateTime<Utc>,
+) -> Vec<DateTime<Utc>> {
+    let local = dtstart.with_timezone(&tz).naive_local();
+    let rule = with_defaults(rule, local.date());
+    let until = rule.until.map(|until| match until {
+        Until::Utc(until) => until,
+        Until::Floating(until) => local_to_utc(until, tz),
+        Until::Date(until) => local_to_utc(until.and_time(NaiveTime::MIN) + Duration::days(1), tz) - Duration::seconds(1),
+    });
+    let last_date = before.with_timezone(&tz).date_naive();
+
+    let mut starts = Vec::new();
+    if dtstart > after && dtstart < before {
+        starts.push(dtstart);
+    }
+    let mut emitted = 1;
+    // Without a COUNT, the periods before the range can be skipped
+    let first_period = match rule.count {
+        Some(_) => 0,
+        None => periods_between(&rule, local.date(), after.with_timezone(&tz).date_naive()).saturating_sub(1),
+    };
+
+    for period in first_period..first_period.saturating_add(MAX_PERIODS) {
+        let Some((period_start, dates)) = period_dates(&rule, local.date(), period) else {
+            break;
+        };
+        if period_start > last_date {
+            break;
+        }
+        for date in dates {
+            let start = local_to_utc(date.and_time(local.time()), tz);
+            if start <= dtstart {
+                continue;
+            }
+            if until.is_some_and(|until| start > until) || rule.count.is_some_and(|count| emitted >= count) {
+                return starts;
+            }
+            emitted += 1;
+            if start >= before {
+                return starts;
+            }
+            if start > after {
+                starts.push(start);
+                if starts.len() >= MAX_OCCURRENCES {
+                    return starts;
+                }
+            }
+        }
+    }
+    starts
+}
+
+/// Fills in the parts of a rule that RFC 5545 takes from the event's start.
+fn with_defaults(rule: &RecurrenceRule, start: NaiveDate) -> RecurrenceRule {
+    let mut rule = rule.clone();
+    if rule.by_day.is_empty() && rule.by_month_day.is_empty() {
+        match rule.frequency {
+            Frequency::Yearly => {
+                if rule.by_month.is_empty() {
+                    rule.by_month = vec![start.month()];
+                }
+                rule.by_month_day = vec![start.day() as i32];
+            }
+            Frequency::Monthly => rule.by_month_day = vec![start.day() as i32],
+            Frequency::Weekly => rule.by_day = vec![ByDay { ordinal: None, weekday: start.weekday() }],
+            Frequency::Daily => {}
+        }
+    }
+    rule
+}
+
+/// Number of whole intervals between the period of `start` and that of `date`.
+fn periods_between(rule: &RecurrenceRule, start: NaiveDate, date: NaiveDate) -> u32 {
+    if date <= start {
+        return 0;
+    }
+    let units = match rule.frequency {
+        Frequency::Daily => (date - start).num_days(),
+        Frequency::Weekly => (date - week_start(start, rule)).num_days() / 7,
+        Frequency::Monthly => month_index(date) - month_index(start),
+        Frequency::Yearly => i64::from(date.year() - start.year()),
+    };
+    u32::try_from(units / i64::from(rule.interval)).unwrap_or(u32::MAX)
+}
+
+/// First day of the `period`th period of the rule and the dates it produces,
+/// or `None` once dates run out of range.
+fn period_dates(rule: &RecurrenceRule, start: NaiveDate, period: u32) -> Option<(NaiveDate, Vec<NaiveDate>)> {
+    let steps = i64::from(period) * i64::from(rule.interval);
+    let (period_start, span, candidates) = match rule.frequency {
+        Frequency::Daily => {
+            let day = start.checked_add_signed(Duration::try_days(steps)?)?;
+            (day, (day, day), vec![day])
+        }
+        Frequency::Weekly => {
+            let first = week_start(start, rule).checked_add_signed(Duration::try_weeks(steps)?)?;
+            let days = (0..7).filter_map(|offset| first.checked_add_signed(Duration::days(offset))).collect();
+            (first, (first, first), days)
+        }
+        Frequency::Monthly => {
+            let index = month_index(start).checked_add(steps)?;
+            let first = NaiveDate::from_ymd_opt(i32::try_from(index.div_euclid(12)).ok()?, index.rem_euclid(12) as u32 + 1, 1)?;
+            let last = last_of_month(first)?;
+            (first, (first, last), days_between(first, last))
+        }
+        Frequency::Yearly => {
+            let year = i32::try_from(i64::from(start.year()).checked_add(steps)?).ok()?;
+            let first = NaiveDate::from_ymd_opt(year, 1, 1)?;
+            let last = NaiveDate::from_ymd_opt(year, 12, 31)?;
+            (first, (first, last), days_between(first, last))
+        }
+    };
+
+    let mut dates: Vec<NaiveDate> = candidates
+        .into_iter()
+        .filter(|date| rule.by_month.is_empty() || rule.by_month.contains(&date.month()))
+        .filter(|date| rule.by_month_day.is_empty() || rule.by_month_day.iter().any(|day| matches_month_day(*date, *day)))
+        .filter(|date| rule.by_day.is_empty() || matches_by_day(rule, *date, span))
+        .collect();
+
+    if !rule.by_set_pos.is_empty() {
+        let count = dates.len() as i32;
+        let mut selected: Vec<NaiveDate> = rule
+            .by_set_pos
+            .iter()
+            .filter_map(|position| {
+                let index = if *position > 0 { position - 1 } else { count + position };
+                usize::try_from(index).ok().and_then(|index| dates.get(index).copied())
+            })
+            .collect();
+        selected.sort();
+        selected.dedup();
+        dates = selected;
+    }
+    Some((period_start, dates))
+}
+
+/// Whether `date` is one of the `BYDAY` days. Numbered days count within the
+/// month for monthly rules and yearly rules with `BYMONTH`, and within the
+/// year otherwise.
+fn matches_by_day(rule: &RecurrenceRule, date: NaiveDate, span: (NaiveDate, NaiveDate)) -> bool {
+    let (first, last) = match rule.frequency {
+        Frequency::Yearly if !rule.by_month.is_empty() => {
+            let first = date.with_day(1).unwrap_or(date);
+            (first, last_of_month(first).unwrap_or(date))
+        }
+        _ => span,
+    };
+    let from_start = (date - first).num_days() as i32 / 7 + 1;
+    let from_end = -((last - date).num_days() as i32 / 7 + 1);
+    rule.by_day.iter().any(|day| {
+        day.weekday == date.weekday()
+            && day.ordinal.is_none_or(|ordinal| ordinal == from_start || ordinal == from_end)
+    })
+}
+
+/// Whether `date` is the given `BYMONTHDAY`; negative days count from the end of the month.
+fn matches_month_day(date: NaiveDate, day: i32) -> bool {
+    if day > 0 {
+        return date.day() as i32 == day;
+    }
+    let length = last_of_month(date).map(|last| last.day() as i32).unwrap_or(31);
+    date.day() as i32 == length + day + 1
+}
+
+fn week_start(date: NaiveDate, rule: &RecurrenceRule) -> NaiveDate {
+    let offset = date.weekday().days_since(rule.week_start);
+    date - Duration::days(i64::from(offset))
+}
+
+fn month_index(date: NaiveDate) -> i64 {
+    i64::from(date.year()) * 12 + i64::from(date.month0())
+}
+
+fn last_of_month(date: NaiveDate) -> Option<NaiveDate> {
+    let (year, month) = if date.month() == 12 { (date.year() + 1, 1) } else { (date.year(), date.month() + 1) };
+    NaiveDate::from_ymd_opt(year, month, 1)?.pred_opt()
+}
+
+fn days_between(first: NaiveDate, last: NaiveDate) -> Vec<NaiveDate> {
+    first.iter_days().take_while(|day| *day <= last).collect()
+}
+
+/// The event moved to start at `start`, keeping its duration.
+fn copy_event(event: &CalendarEvent, start: DateTime<Utc>) -> CalendarEvent {
+    let mut copy = event.clone();
+    copy.start_time = start;
+    copy.end_time = start + (event.end_time - event.start_time);
+    copy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use mongodb::bson::oid::ObjectId;
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn event(start: DateTime<Utc>, time_zone: Option<&str>, rule: Option<&str>) -> CalendarEvent {
+        CalendarEvent {
+            id: Some(ObjectId::new()),
+            title: "Stand-up".to_string(),
+            description: None,
+            start_time: start,
+            end_time: start + Duration::minutes(30),
+            location: None,
+            is_all_day: false,
+            time_zone: time_zone.map(str::to_string),
+            recurrence_rule: rule.map(str::to_string),
+            recurrence_dates: Vec::new(),
+            excluded_dates: Vec::new(),
+            recurring_event_id: None,
+            recurrence_id: None,
+            attendees: Vec::new(),
+            color: None,
+            calendar_id: None,
+            ical_uid: None,
+            dav_name: None,
+            external_id: None,
+            synced_at: None,
+            created_at: start,
+            updated_at: start,
+            deleted_at: None,
+        }
+    }
+
+    fn starts(event: &CalendarEvent, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        occurrences(event, start, end).into_iter().map(|occurrence| occurrence.event.start_time).collect()
+    }
+
+    #[test]
+    fn keeps_local_time_across_daylight_saving_changes() {
+        // 09:00 in London is 09:00 UTC until 31 March 2024 and 08:00 UTC after
+        let series = event(utc(2024, 3, 21, 9, 0), Some("Europe/London"), Some("FREQ=WEEKLY"));
+        assert_eq!(
+            starts(&series, utc(2024, 3, 1, 0, 0), utc(2024, 4, 12, 0, 0)),
+            vec![utc(2024, 3, 21, 9, 0), utc(2024, 3, 28, 9, 0), utc(2024, 4, 4, 8, 0), utc(2024, 4, 11, 8, 0)]
+        );
+    }
+
+    #[test]
+    fn skipped_local_times_move_forward() {
+        // 01:30 doesn't exist in London on 31 March 2024
+        let series = event(utc(2024, 3, 30, 1, 30), Some("Europe/London"), Some("FREQ=DAILY;COUNT=3"));
+        assert_eq!(
+            starts(&series, utc(2024, 3, 30, 0, 0), utc(2024, 4, 2, 0, 0)),
+            vec![utc(2024, 3, 30, 1, 30), utc(2024, 3, 31, 1, 30), utc(2024, 4, 1, 0, 30)]
+        );
+    }
+
+    #[test]
+    fn count_includes_the_first_occurrence() {
+        let series = event(utc(2024, 1, 1, 10, 0), None, Some("FREQ=DAILY;COUNT=3"));
+        assert_eq!(starts(&series, utc(2024, 1, 1, 0, 0), utc(2025, 1, 1, 0, 0)).len(), 3);
+        // Counted from the series' start, not from the range
+        assert_eq!(starts(&series, utc(2024, 1, 2, 12, 0), utc(2025, 1, 1, 0, 0)), vec![utc(2024, 1, 3, 10, 0)]);
+    }
+
+    #[test]
+    fn until_is_inclusive() {
+        let series = event(utc(2024, 1, 1, 10, 0), None, Some("FREQ=DAILY;UNTIL=20240103T100000Z"));
+        assert_eq!(starts(&series, utc(2024, 1, 1, 0, 0), utc(2024, 2, 1, 0, 0)).len(), 3);
+        // A date UNTIL covers the whole day in the event's zone
+        let series = event(utc(2024, 1, 1, 22, 0), Some("Europe/Berlin"), Some("FREQ=DAILY;UNTIL=20240103"));
+        assert_eq!(
+            starts(&series, utc(2024, 1, 1, 0, 0), utc(2024, 2, 1, 0, 0)),
+            vec![utc(2024, 1, 1, 22, 0), utc(2024, 1, 2, 22, 0), utc(2024, 1, 3, 22, 0)]
+        );
+    }
+
+    #[test]
+    fn excluded_dates_are_left_out_and_extra_dates_added() {
+        let mut series = event(utc(2024, 1, 1, 10, 0), None, Some("FREQ=DAILY;COUNT=4"));
+        series.excluded_dates = vec![utc(2024, 1, 2, 10, 0), utc(2024, 1, 5, 15, 0)];
+        series.recurrence_dates = vec![utc(2024, 1, 5, 15, 0), utc(2024, 1, 6, 15, 0)];
+        assert_eq!(
+            starts(&series, utc(2024, 1, 1, 0, 0), utc(2024, 2, 1, 0, 0)),
+            vec![utc(2024, 1, 1, 10, 0), utc(2024, 1, 3, 10, 0), utc(2024, 1, 4, 10, 0), utc(2024, 1, 6, 15, 0)]
+        );
+    }
+
+    #[test]
+    fn excluded_first_occurrence_still_counts() {
+        let mut series = event(utc(2024, 1, 1, 10, 0), None, Some("FREQ=DAILY;COUNT=2"));
+        series.excluded_dates = vec![utc(2024, 1, 1, 10, 0)];
+        assert_eq!(starts(&series, utc(2024, 1, 1, 0, 0), utc(2024, 2, 1, 0, 0)), vec![utc(2024, 1, 2, 10, 0)]);
+    }
+
+    #[test]
+    fn occurrences_overlapping_the_range_start_are_included() {
+        let series = event(utc(2024, 1, 1, 10, 0), None, Some("FREQ=DAILY"));
+        assert_eq!(starts(&series, utc(2024, 1, 3, 10, 15), utc(2024, 1, 4, 0, 0)), vec![utc(2024, 1, 3, 10, 0)]);
+    }
+
+    #[test]
+    fn numbered_weekdays_count_from_the_end_of_the_month() {
+        let series = event(utc(2024, 1, 26, 16, 0), None, Some("FREQ=MONTHLY;BYDAY=-1FR;COUNT=3"));
+        assert_eq!(
+            starts(&series, utc(2024, 1, 1, 0, 0), utc(2025, 1, 1, 0, 0)),
+            vec![utc(2024, 1, 26, 16, 0), utc(2024, 2, 23, 16, 0), utc(2024, 3, 29, 16, 0)]
+        );
+    }
+
+    #[test]
+    fn monthly_rules_skip_months_without_the_day() {
+        let series = event(utc(2024, 1, 31, 9, 0), None, Some("FREQ=MONTHLY;COUNT=3"));
+        assert_eq!(
+            starts(&series, utc(2024, 1, 1, 0, 0), utc(2025, 1, 1, 0, 0)),
+            vec![utc(2024, 1, 31, 9, 0), utc(2024, 3, 31, 9, 0), utc(2024, 5, 31, 9, 0)]
+        );
+    }
+
+    #[test]
+    fn endless_rules_are_bounded() {
+        let series = event(utc(2000, 1, 1, 0, 0), None, Some("FREQ=DAILY"));
+        let found = occurrences(&series, utc(2000, 1, 1, 0, 0), utc(2100, 1, 1, 0, 0));
+        assert_eq!(found.len(), MAX_OCCURRENCES);
+        // Rules that never match still terminate
+        let never = event(utc(2024, 1, 1, 9, 0), None, Some("FREQ=YEARLY;BYMONTH=2;BYMONTHDAY=30"));
+        assert_eq!(starts(&never, utc(2024, 1, 1, 0, 0), utc(9999, 1, 1, 0, 0)), vec![utc(2024, 1, 1, 9, 0)]);
+    }
+
+    #[test]
+    fn occurrences_have_stable_instance_ids() {
+        let series = event(utc(2024, 1, 1, 10, 0), None, Some("FREQ=WEEKLY"));
+        let first = occurrences(&series, utc(2024, 1, 1, 0, 0), utc(2024, 2, 1, 0, 0));
+        let later = occurrences(&series, utc(2024, 1, 10, 0, 0), utc(2024, 2, 1, 0, 0));
+        assert_eq!(first[2].instance_id, later[0].instance_id);
+        assert_eq!(first[2].instance_id, instance_id(series.id.unwrap(), utc(2024, 1, 15, 10, 0)));
+        assert!(occurrence_at(&series, utc(2024, 1, 15, 10, 0)).is_some());
+        assert!(occurrence_at(&series, utc(2024, 1, 16, 10, 0)).is_none());
+    }
+}
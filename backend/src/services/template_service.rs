@@ -0,0 +1,301 @@
+use mongodb::{Client, bson::{doc, oid::ObjectId}};
+use mongodb::error::Error;
+use mongodb::options::ReturnDocument;
+use futures_util::TryStreamExt;
+use crate::models::note::Note;
+use crate::models::template::{NoteTemplate, TemplateValues};
+use crate::services::notes_service::{self, NotesServiceError};
+use thiserror::Error;
+use actix_web::HttpResponse;
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use std::collections::HashMap;
+use validator::Validate;
+
+/// Title used for daily notes when no daily template exists.
+const DEFAULT_DAILY_TITLE: &str = "{{date}}";
+
+/// Content used for daily notes when no daily template exists.
+const DEFAULT_DAILY_CONTENT: &str = "# {{weekday}}, {{date}}\n\n";
+
+#[derive(Error, Debug)]
+pub enum TemplateServiceError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] Error),
+    #[error("Invalid ObjectId: {0}")]
+    InvalidObjectId(#[from] mongodb::bson::oid::Error),
+    #[error("Template not found")]
+    TemplateNotFound,
+    #[error("Validation error: {0}")]
+    ValidationError(validator::ValidationErrors),
+    #[error("Missing values for prompts: {}", .0.join(", "))]
+    MissingValues(Vec<String>),
+    #[error("Invalid time zone: {0}")]
+    InvalidTimeZone(String),
+    #[error(transparent)]
+    NotesError(#[from] NotesServiceError),
+}
+
+/// Retrieves all note templates.
+pub async fn get_all_templates(client: &Client) -> Result<Vec<NoteTemplate>, TemplateServiceError> {
+    let collection = get_templates_collection(client);
+    let cursor = collection.find(doc! {}).sort(doc! { "name": 1 }).await?;
+    Ok(cursor.try_collect().await?)
+}
+
+/// Retrieves a single note template by its id.
+pub async fn get_template(client: &Client, template_id: &str) -> Result<NoteTemplate, TemplateServiceError> {
+    let object_id = ObjectId::parse_str(template_id)?;
+    get_templates_collection(client)
+        .find_one(doc! { "_id": object_id })
+        .await?
+        .ok_or(TemplateServiceError::TemplateNotFound)
+}
+
+/// Inserts a new note template and returns it.
+pub async fn add_template(client: &Client, mut template: NoteTemplate) -> Result<NoteTemplate, TemplateServiceError> {
+    if let Err(e) = template.validate() {
+        return Err(TemplateServiceError::ValidationError(e));
+    }
+
+    let collection = get_templates_collection(client);
+    if template.is_daily {
+        clear_daily_flag(client).await?;
+    }
+    template.id = Some(ObjectId::new());
+    template.created_at = Some(Utc::now().to_rfc3339());
+    template.updated_at = Some(Utc::now().to_rfc3339());
+    collection.insert_one(&template).await?;
+    Ok(template)
+}
+
+/// Updates an existing note template and returns it.
+pub async fn update_template(client: &Client, template_id: &str, updated: NoteTemplate) -> Result<NoteTemplate, TemplateServiceError> {
+    if let Err(e) = updated.validate() {
+        return Err(TemplateServiceError::ValidationError(e));
+    }
+
+    let collection = get_templates_collection(client);
+    let object_id = ObjectId::parse_str(template_id)?;
+    let filter = doc! { "_id": object_id };
+    if collection.find_one(filter.clone()).await?.is_none() {
+        return Err(TemplateServiceError::TemplateNotFound);
+    }
+    if updated.is_daily {
+        clear_daily_flag(client).await?;
+    }
+
+    let prompts = mongodb::bson::to_bson(&updated.prompts).unwrap_or_default();
+    let update = doc! {
+        "$set": {
+            "name": updated.name,
+            "title": updated.title,
+            "content": updated.content,
+            "tags": updated.tags,
+            "prompts": prompts,
+            "is_daily": updated.is_daily,
+            "updated_at": Utc::now().to_rfc3339()
+        }
+    };
+    collection
+        .find_one_and_update(filter, update)
+        .return_document(ReturnDocument::After)
+        .await?
+        .ok_or(TemplateServiceError::TemplateNotFound)
+}
+
+/// Removes an existing note template.
+pub async fn remove_template(client: &Client, template_id: &str) -> Result<(), TemplateServiceError> {
+    let object_id = ObjectId::parse_str(template_id)?;
+    let result = get_templates_collection(client).delete_one(doc! { "_id": object_id }).await?;
+    if result.deleted_count == 0 {
+        return Err(TemplateServiceError::TemplateNotFound);
+    }
+    Ok(())
+}
+
+/// Creates a note from a template, expanding its placeholders.
+pub async fn create_note_from_template(
+    client: &Client,
+    template_id: &str,
+    values: TemplateValues,
+) -> Result<Note, TemplateServiceError> {
+    let template = get_template(client, template_id).await?;
+    let tz = parse_time_zone(values.tz.as_deref())?;
+    let now = Utc::now().with_timezone(&tz);
+    let title = values.title.unwrap_or_else(|| template.name.clone());
+
+    let mut variables = builtin_variables(now, now.date_naive(), &title);
+    let mut missing = Vec::new();
+    for prompt in &template.prompts {
+        let value = values
+            .values
+            .get(&prompt.name)
+            .filter(|v| !v.trim().is_empty())
+            .or(prompt.default.as_ref());
+        match value {
+            Some(value) => {
+                variables.insert(prompt.name.clone(), value.clone());
+            }
+            None => missing.push(prompt.name.clone()),
+        }
+    }
+    if !missing.is_empty() {
+        return Err(TemplateServiceError::MissingValues(missing));
+    }
+
+    let mut note = Note::new(
+        expand_placeholders(&template.title, &variables),
+        expand_placeholders(&template.content, &variables),
+    );
+    note.tags = Some(template.tags.unwrap_or_default());
+    note.notebook_id = values.notebook_id.as_deref().map(ObjectId::parse_str).transpose()?;
+    Ok(notes_service::add_note(client, note, false).await?)
+}
+
+/// Returns the daily note for the given date, creating it if it doesn't exist.
+///
+/// The date defaults to today in the given time zone. New daily notes use the
+/// template flagged as daily, or `template_id` when given. Returns whether the
+/// note was created by this call.
+pub async fn get_or_create_daily_note(
+    client: &Client,
+    date: Option<NaiveDate>,
+    tz: Option<&str>,
+    template_id: Option<&str>,
+) -> Result<(Note, bool), TemplateServiceError> {
+    let tz = parse_time_zone(tz)?;
+    let now = Utc::now().with_timezone(&tz);
+    let date = date.unwrap_or_else(|| now.date_naive());
+    let key = date.format("%Y-%m-%d").to_string();
+
+    if let Some(note) = notes_service::find_daily_note(client, &key).await? {
+        return Ok((note, false));
+    }
+
+    let template = match template_id {
+        Some(template_id) => Some(get_template(client, template_id).await?),
+        None => get_templates_collection(client).find_one(doc! { "is_daily": true }).await?,
+    };
+    let (title, content, tags, prompts) = match template {
+        Some(t) => (t.title, t.content, t.tags, t.prompts),
+        None => (DEFAULT_DAILY_TITLE.to_string(), DEFAULT_DAILY_CONTENT.to_string(), None, Vec::new()),
+    };
+
+    // Nobody is around to answer prompts, so fall back to their defaults
+    let mut variables = builtin_variables(now, date, &key);
+    for prompt in prompts {
+        variables.insert(prompt.name, prompt.default.unwrap_or_default());
+    }
+
+    let mut note = Note::new(
+        expand_placeholders(&title, &variables),
+        expand_placeholders(&content, &variables),
+    );
+    note.tags = Some(tags.unwrap_or_default());
+    Ok(notes_service::find_or_create_daily_note(client, &key, note).await?)
+}
+
+/// Replaces `{{name}}` placeholders with their values.
+///
+/// Whitespace inside the braces is ignored; unknown placeholders are kept as-is.
+pub fn expand_placeholders(text: &str, variables: &HashMap<String, String>) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            break;
+        };
+        expanded.push_str(&rest[..start]);
+        match variables.get(after[..end].trim()) {
+            Some(value) => expanded.push_str(value),
+            None => expanded.push_str(&rest[start..start + end + 4]),
+        }
+        rest = &after[end + 2..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Values of the built-in placeholders.
+fn builtin_variables(now: DateTime<Tz>, date: NaiveDate, title: &str) -> HashMap<String, String> {
+    HashMap::from([
+        ("date".to_string(), date.format("%Y-%m-%d").to_string()),
+        ("weekday".to_string(), date.format("%A").to_string()),
+        ("time".to_string(), now.format("%H:%M").to_string()),
+        ("datetime".to_string(), format!("{} {}", date.format("%Y-%m-%d"), now.format("%H:%M"))),
+        ("title".to_string(), title.to_string()),
+    ])
+}
+
+/// Parses an IANA time zone name, defaulting to UTC.
+fn parse_time_zone(tz: Option<&str>) -> Result<Tz, TemplateServiceError> {
+    match tz {
+        Some(name) => name
+            .parse::<Tz>()
+            .map_err(|_| TemplateServiceError::InvalidTimeZone(name.to_string())),
+        None => Ok(Tz::UTC),
+    }
+}
+
+/// Ensures only one template is flagged as the daily template.
+async fn clear_daily_flag(client: &Client) -> Result<(), Error> {
+    get_templates_collection(client)
+        .update_many(doc! { "is_daily": true }, doc! { "$set": { "is_daily": false } })
+        .await?;
+    Ok(())
+}
+
+/// Helper function to get the "note_templates" collection.
+fn get_templates_collection(client: &Client) -> mongodb::Collection<NoteTemplate> {
+    let db = client.database("organise");
+    db.collection::<NoteTemplate>("note_templates")
+}
+
+// Custom function to convert TemplateServiceError to HttpResponse
+pub fn error_response(error: TemplateServiceError) -> HttpResponse {
+    match error {
+        TemplateServiceError::DatabaseError(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+        TemplateServiceError::InvalidObjectId(e) => HttpResponse::BadRequest().body(format!("Invalid ObjectId: {}", e)),
+        TemplateServiceError::TemplateNotFound => HttpResponse::NotFound().body("Template not found"),
+        TemplateServiceError::ValidationError(e) => HttpResponse::BadRequest().json(e),
+        e @ TemplateServiceError::MissingValues(_) => HttpResponse::BadRequest().body(e.to_string()),
+        e @ TemplateServiceError::InvalidTimeZone(_) => HttpResponse::BadRequest().body(e.to_string()),
+        TemplateServiceError::NotesError(e) => notes_service::error_response(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> HashMap<String, String> {
+        HashMap::from([
+            ("date".to_string(), "2024-03-01".to_string()),
+            ("title".to_string(), "Standup {{date}}".to_string()),
+        ])
+    }
+
+    #[test]
+    fn known_placeholders_are_replaced() {
+        assert_eq!(expand_placeholders("# {{date}} / {{ date }}", &variables()), "# 2024-03-01 / 2024-03-01");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_kept() {
+        assert_eq!(expand_placeholders("{{ attendees }} on {{date}}", &variables()), "{{ attendees }} on 2024-03-01");
+    }
+
+    #[test]
+    fn values_are_inserted_verbatim() {
+        // A value that looks like a placeholder isn't expanded again
+        assert_eq!(expand_placeholders("{{title}}", &variables()), "Standup {{date}}");
+    }
+
+    #[test]
+    fn unterminated_braces_are_left_alone() {
+        assert_eq!(expand_placeholders("{{date}} {{date", &variables()), "2024-03-01 {{date");
+        assert_eq!(expand_placeholders("{ {date} }", &variables()), "{ {date} }");
+    }
+}
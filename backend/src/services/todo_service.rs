@@ -1,7 +1,12 @@
-use mongodb::{Client, bson::{doc, oid::ObjectId}};
+use mongodb::{Client, bson::{doc, oid::ObjectId, Document}};
 use mongodb::error::Error;
+use mongodb::options::ReturnDocument;
 use futures_util::TryStreamExt;
+use crate::models::attachment::AttachmentOwner;
+use crate::models::datetime;
 use crate::models::todo::Todo;
+use crate::dto::preconditions;
+use crate::services::{attachment_service, notes_service};
 use thiserror::Error;
 use actix_web::HttpResponse;
 use chrono::Utc;
@@ -17,17 +22,28 @@ pub enum TodoServiceError {
     TodoNotFound,
     #[error("Validation error: {0}")]
     ValidationError(validator::ValidationErrors),
+    #[error("Todo has been modified")]
+    VersionMismatch(Box<Todo>),
 }
 
-/// Retrieves all Todo documents from the MongoDB "todos" collection.
+/// Retrieves all Todo documents from the MongoDB "todos" collection, except trashed ones.
 pub async fn get_all_todos(client: &Client) -> Result<Vec<Todo>, TodoServiceError> {
     let collection = get_todo_collection(client);
-    let cursor = collection.find(doc! {}).await?;
+    let cursor = collection.find(doc! { "deleted_at": null }).await?;
     collect_todos(cursor).await.map_err(TodoServiceError::from)
 }
 
-/// Inserts a new Todo document into the MongoDB "todos" collection.
-pub async fn add_todo(client: &Client, mut todo: Todo) -> Result<(), TodoServiceError> {
+/// Retrieves a single Todo document by its ID, unless it is trashed.
+pub async fn get_todo_by_id(client: &Client, todo_id: &str) -> Result<Todo, TodoServiceError> {
+    let object_id = ObjectId::parse_str(todo_id)?;
+    get_todo_collection(client)
+        .find_one(doc! { "_id": object_id, "deleted_at": null })
+        .await?
+        .ok_or(TodoServiceError::TodoNotFound)
+}
+
+/// Inserts a new Todo document into the MongoDB "todos" collection and returns it.
+pub async fn add_todo(client: &Client, mut todo: Todo) -> Result<Todo, TodoServiceError> {
     if let Err(e) = todo.validate() {
         return Err(TodoServiceError::ValidationError(e));
     }
@@ -36,81 +52,145 @@ pub async fn add_todo(client: &Client, mut todo: Todo) -> Result<(), TodoService
     todo.id = Some(ObjectId::new());
     todo.created_at = Utc::now().to_rfc3339();
     todo.updated_at = Some(Utc::now().to_rfc3339());
-    collection.insert_one(todo).await?;
-    Ok(())
+    collection.insert_one(&todo).await?;
+    Ok(todo)
 }
 
 /// Updates an existing Todo document in the MongoDB "todos" collection.
-pub async fn update_todo(client: &Client, todo_id: &str, mut updated_todo: Todo) -> Result<(), TodoServiceError> {
+///
+/// With `expected_versions` the update only applies to one of those versions.
+/// The project and tags are kept unless the update carries them. Returns the
+/// updated todo.
+pub async fn update_todo(
+    client: &Client,
+    todo_id: &str,
+    mut updated_todo: Todo,
+    expected_versions: Option<&[i64]>,
+) -> Result<Todo, TodoServiceError> {
     if let Err(e) = updated_todo.validate() {
         return Err(TodoServiceError::ValidationError(e));
     }
 
-    
     let collection = get_todo_collection(client);
     let object_id = ObjectId::parse_str(todo_id)?;
-    let filter = doc! { "_id": object_id };
-    
-    // Check if todo exists
-    let existing_todo = collection.find_one(filter.clone()).await?;
-    if existing_todo.is_none() {
-        return Err(TodoServiceError::TodoNotFound);
-    }
+    let (existing_todo, filter) = find_for_write(client, object_id, expected_versions).await?;
 
+    let completed = updated_todo.completed;
     updated_todo.updated_at = Some(Utc::now().to_rfc3339());
-    let update = doc! { 
-        "$set": { 
-            "title": updated_todo.title, 
-            "description": updated_todo.description, 
-            "completed": updated_todo.completed, 
-            "priority": updated_todo.priority,
-            "updated_at": updated_todo.updated_at
-        } 
+    let mut fields = doc! { 
+        "title": updated_todo.title, 
+        "description": updated_todo.description, 
+        "completed": updated_todo.completed, 
+        "priority": updated_todo.priority,
+        "updated_at": updated_todo.updated_at
     };
-    collection.update_one(filter, update).await?;
-    Ok(())
+    if let Some(project) = updated_todo.project {
+        fields.insert("project", project);
+    }
+    if let Some(tags) = updated_todo.tags {
+        fields.insert("tags", tags);
+    }
+    let update = doc! { "$set": fields, "$inc": { "version": 1 } };
+    let Some(todo) = collection.find_one_and_update(filter, update).return_document(ReturnDocument::After).await? else {
+        return Err(write_conflict(client, object_id).await);
+    };
+
+    // Mirror the completion state onto the checkbox in the source note
+    if let Some(note_id) = existing_todo.note_id {
+        notes_service::set_linked_task_state(client, note_id, object_id, completed).await?;
+    }
+    Ok(todo)
 }
 
-/// Removes an existing Todo document from the MongoDB "todos" collection.
-pub async fn remove_todo(client: &Client, todo_id: &str) -> Result<(), TodoServiceError> {
+/// Moves an existing todo to the trash. It keeps its attachments until it is
+/// permanently deleted.
+pub async fn remove_todo(client: &Client, todo_id: &str, expected_versions: Option<&[i64]>) -> Result<(), TodoServiceError> {
     let collection = get_todo_collection(client);
     let object_id = ObjectId::parse_str(todo_id)?;
-    let filter = doc! { "_id": object_id };
-    
-    // Check if todo exists
-    let existing_todo = collection.find_one(filter.clone()).await?;
-    if existing_todo.is_none() {
-        return Err(TodoServiceError::TodoNotFound);
+    let (_, filter) = find_for_write(client, object_id, expected_versions).await?;
+    let update = doc! {
+        "$set": { "deleted_at": datetime::to_bson(Utc::now()) },
+        "$inc": { "version": 1 }
+    };
+    if collection.update_one(filter, update).await?.matched_count == 0 {
+        return Err(write_conflict(client, object_id).await);
     }
-
-    collection.delete_one(filter).await?;
     Ok(())
 }
 
+/// Permanently deletes a todo and releases its attachments; their blobs are
+/// deleted by the next attachment garbage collection.
+pub(crate) async fn purge_todo(client: &Client, todo_id: ObjectId) -> Result<bool, Error> {
+    let result = get_todo_collection(client).delete_one(doc! { "_id": todo_id }).await?;
+    attachment_service::detach_owner(client, AttachmentOwner::Todo, todo_id).await?;
+    Ok(result.deleted_count > 0)
+}
+
 /// Sets the completion status of an existing Todo document in the MongoDB "todos" collection.
-pub async fn set_todo_completion(client: &Client, todo_id: &str) -> Result<(), TodoServiceError> {
+///
+/// Returns the updated todo.
+pub async fn set_todo_completion(
+    client: &Client,
+    todo_id: &str,
+    expected_versions: Option<&[i64]>,
+) -> Result<Todo, TodoServiceError> {
     let collection = get_todo_collection(client);
     let object_id = ObjectId::parse_str(todo_id)?;
-    let filter = doc! { "_id": object_id };
-    
-    // Check if todo exists
-    let existing_todo = collection.find_one(filter.clone()).await?;
-    if existing_todo.is_none() {
-        return Err(TodoServiceError::TodoNotFound);
-    }
+    let (existing_todo, filter) = find_for_write(client, object_id, expected_versions).await?;
 
     let update = doc! { 
         "$set": { 
             "completed": true,
             "updated_at": Utc::now().to_rfc3339()
-        } 
+        },
+        "$inc": { "version": 1 }
     };
-    collection.update_one(filter, update).await?;
-    Ok(())
+    let Some(todo) = collection.find_one_and_update(filter, update).return_document(ReturnDocument::After).await? else {
+        return Err(write_conflict(client, object_id).await);
+    };
+
+    if let Some(note_id) = existing_todo.note_id {
+        notes_service::set_linked_task_state(client, note_id, object_id, true).await?;
+    }
+    Ok(todo)
+}
+
+/// Loads a live todo for a conditional write.
+///
+/// Fails with `VersionMismatch` unless the todo is at one of the expected
+/// versions. The returned filter only matches the todo at the version that
+/// was loaded, so a concurrent write in between is detected too.
+async fn find_for_write(
+    client: &Client,
+    todo_id: ObjectId,
+    expected_versions: Option<&[i64]>,
+) -> Result<(Todo, Document), TodoServiceError> {
+    let mut filter = doc! { "_id": todo_id, "deleted_at": null };
+    let todo = get_todo_collection(client)
+        .find_one(filter.clone())
+        .await?
+        .ok_or(TodoServiceError::TodoNotFound)?;
+    if let Some(expected) = expected_versions {
+        if !expected.contains(&todo.version) {
+            return Err(TodoServiceError::VersionMismatch(Box::new(todo)));
+        }
+        filter.insert("version", todo.version);
+    }
+    Ok((todo, filter))
+}
+
+/// Error for a conditional write that matched nothing: the todo changed or
+/// was removed after it was loaded.
+async fn write_conflict(client: &Client, todo_id: ObjectId) -> TodoServiceError {
+    match get_todo_collection(client).find_one(doc! { "_id": todo_id, "deleted_at": null }).await {
+        Ok(Some(todo)) => TodoServiceError::VersionMismatch(Box::new(todo)),
+        Ok(None) => TodoServiceError::TodoNotFound,
+        Err(e) => e.into(),
+    }
 }
 
 /// Helper function to get the "todos" collection.
-fn get_todo_collection(client: &Client) -> mongodb::Collection<Todo> {
+pub(crate) fn get_todo_collection(client: &Client) -> mongodb::Collection<Todo> {
     let db = client.database("organise");
     db.collection::<Todo>("todos")
 }
@@ -131,5 +211,8 @@ pub fn error_response(error: TodoServiceError) -> HttpResponse {
         TodoServiceError::InvalidObjectId(e) => HttpResponse::BadRequest().body(format!("Invalid ObjectId: {}", e)),
         TodoServiceError::TodoNotFound => HttpResponse::NotFound().body("Todo not found"),
         TodoServiceError::ValidationError(e) => HttpResponse::BadRequest().json(e),
+        TodoServiceError::VersionMismatch(todo) => HttpResponse::PreconditionFailed()
+            .insert_header(preconditions::etag(todo.version))
+            .json(todo),
     }
 }
@@ -0,0 +1,303 @@
+//! Trash bin for notes, todos and calendar events.
+//!
+//! Deleting any of them only sets `deleted_at`; the document stays out of
+//! every listing until it is restored, purged by hand, or purged for good once
+//! the retention period is over.
+
+use mongodb::{Client, Collection, bson::{doc, oid::ObjectId, Bson, Document}};
+use mongodb::error::Error;
+use futures_util::TryStreamExt;
+use std::collections::HashSet;
+use crate::db::errors::is_duplicate_key;
+use crate::models::datetime;
+use crate::models::trash::{PurgeSummary, TrashItem, TrashKind};
+use crate::services::notebook_service::{self, NotebookServiceError};
+use crate::services::{calendar_change_service, notes_service, todo_service};
+use thiserror::Error;
+use actix_web::HttpResponse;
+use chrono::{DateTime, Duration, Utc};
+use std::env;
+
+/// Default for the `TRASH_RETENTION_DAYS` environment variable.
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+
+#[derive(Error, Debug)]
+pub enum TrashServiceError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] Error),
+    #[error("Invalid ObjectId: {0}")]
+    InvalidObjectId(#[from] mongodb::bson::oid::Error),
+    #[error("Item not found in trash")]
+    ItemNotFound,
+    #[error("{0}")]
+    Conflict(String),
+    #[error(transparent)]
+    NotebookError(#[from] NotebookServiceError),
+}
+
+/// How long trashed items are kept, from `TRASH_RETENTION_DAYS`.
+pub fn retention_period() -> Duration {
+    let days = env::var("TRASH_RETENTION_DAYS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|days: &i64| *days >= 0)
+        .unwrap_or(DEFAULT_RETENTION_DAYS);
+    Duration::days(days)
+}
+
+/// Lists trashed items, most recently deleted first.
+pub async fn get_trash(client: &Client, kind: Option<TrashKind>) -> Result<Vec<TrashItem>, TrashServiceError> {
+    let kinds = match kind {
+        Some(kind) => vec![kind],
+        None => vec![TrashKind::Note, TrashKind::Todo, TrashKind::Event],
+    };
+    let retention = retention_period();
+
+    let mut items = Vec::new();
+    for kind in kinds {
+        let hidden = trashed_series(client, kind).await?;
+        let mut cursor = get_collection(client, kind)
+            .find(doc! { "deleted_at": { "$ne": Bson::Null } })
+            .projection(doc! { "title": 1, "deleted_at": 1, "encryption": 1, "recurring_event_id": 1 })
+            .await?;
+        while let Some(document) = cursor.try_next().await? {
+            let (Ok(id), Some(deleted_at)) = (document.get_object_id("_id"), deleted_at(&document)) else {
+                continue;
+            };
+            if listed_with_series(&document, &hidden) {
+                continue;
+            }
+            // Encrypted titles are ciphertext, which is no use in a listing
+            let title = match document.get_document("encryption") {
+                Ok(_) => "Encrypted note".to_string(),
+                Err(_) => document.get_str("title").unwrap_or_default().to_string(),
+            };
+            items.push(TrashItem { kind, id: id.to_hex(), title, deleted_at, purge_at: deleted_at + retention });
+        }
+    }
+    items.sort_by_key(|item| std::cmp::Reverse(item.deleted_at));
+    Ok(items)
+}
+
+/// Takes an item out of the trash.
+///
+/// A note whose notebook was deleted in the meantime is restored into the
+/// default notebook. Restored notes go to the end of their notebook, and a
+/// daily note gets its date back unless another note took it meanwhile. An
+/// exception to a recurring event brings its occurrence back.
+pub async fn restore(client: &Client, kind: TrashKind, id: &str) -> Result<(), TrashServiceError> {
+    let object_id = ObjectId::parse_str(id)?;
+    let filter = doc! { "_id": object_id, "deleted_at": { "$ne": Bson::Null } };
+    let collection = get_collection(client, kind);
+    let Some(document) = collection.find_one(filter.clone()).await? else {
+        return Err(TrashServiceError::ItemNotFound);
+    };
+    let series_id = document.get_object_id("recurring_event_id").ok();
+    if series_id.is_some() && listed_with_series(&document, &trashed_series(client, kind).await?) {
+        return Err(TrashServiceError::ItemNotFound);
+    }
+
+    // Todos keep their timestamps as RFC 3339 strings
+    let updated_at = match kind {
+        TrashKind::Todo => Bson::String(Utc::now().to_rfc3339()),
+        TrashKind::Note | TrashKind::Event => Bson::DateTime(datetime::to_bson(Utc::now())),
+    };
+    let mut fields = doc! { "updated_at": updated_at };
+    if kind == TrashKind::Note {
+        if let Ok(notebook_id) = document.get_object_id("notebook_id") {
+            let notebook_exists = notebook_service::get_notebooks_collection(client)
+                .count_documents(doc! { "_id": notebook_id })
+                .await?
+                > 0;
+            let notebook_id = match notebook_exists {
+                true => notebook_id,
+                false => notebook_service::ensure_default_notebook(client).await?,
+            };
+            let position = notes_service::get_notes_collection(client)
+                .count_documents(doc! { "notebook_id": notebook_id, "deleted_at": null })
+                .await? as i32;
+            fields.insert("notebook_id", notebook_id);
+            fields.insert("position", position);
+        }
+        if let Ok(date) = document.get_str("trashed_daily_date") {
+            let taken = collection.count_documents(doc! { "daily_date": date, "deleted_at": null }).await? > 0;
+            if !taken {
+                fields.insert("daily_date", date);
+            }
+        }
+    }
+
+    let mut update = doc! { "$set": fields.clone(), "$unset": { "deleted_at": "", "trashed_daily_date": "" } };
+    if kind != TrashKind::Event {
+        update.insert("$inc", doc! { "version": 1 });
+    }
+    match collection.update_one(filter.clone(), update.clone()).await {
+        Ok(_) => {}
+        // Another note took the date after all
+        Err(e) if is_duplicate_key(&e) && fields.contains_key("daily_date") => {
+            fields.remove("daily_date");
+            update.insert("$set", fields);
+            collection.update_one(filter, update.clone()).await?;
+        }
+        Err(e) if is_duplicate_key(&e) => {
+            return Err(TrashServiceError::Conflict("Another item has taken its place since it was deleted".to_string()));
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    if let (Some(series_id), Ok(start)) = (series_id, document.get_datetime("recurrence_id")) {
+        let update = doc! {
+            "$pull": { "excluded_dates": start },
+            "$set": { "updated_at": datetime::to_bson(Utc::now()) }
+        };
+        collection.update_one(doc! { "_id": series_id, "deleted_at": null }, update).await?;
+        calendar_change_service::record_changes(client, &[series_id]).await?;
+        return Ok(());
+    }
+
+    // Exceptions to a recurring event were trashed along with it
+    if kind == TrashKind::Event {
+        if let Some(deleted_at) = document.get("deleted_at") {
+            collection
+                .update_many(doc! { "recurring_event_id": object_id, "deleted_at": deleted_at }, update)
+                .await?;
+        }
+        calendar_change_service::record_changes(client, &[object_id]).await?;
+    }
+    Ok(())
+}
+
+/// Permanently deletes a trashed item.
+///
+/// Attachments of notes and todos are released; their blobs are deleted by
+/// the next attachment garbage collection.
+pub async fn purge(client: &Client, kind: TrashKind, id: &str) -> Result<(), TrashServiceError> {
+    let object_id = ObjectId::parse_str(id)?;
+    let filter = doc! { "_id": object_id, "deleted_at": { "$ne": Bson::Null } };
+    if get_collection(client, kind).count_documents(filter).await? == 0 {
+        return Err(TrashServiceError::ItemNotFound);
+    }
+    purge_one(client, kind, object_id).await?;
+    Ok(())
+}
+
+/// Permanently deletes everything in the trash.
+pub async fn empty_trash(client: &Client) -> Result<PurgeSummary, TrashServiceError> {
+    Ok(purge_matching(client, doc! { "deleted_at": { "$ne": Bson::Null } }).await?)
+}
+
+/// Permanently deletes the items that have been in the trash for longer than
+/// the retention period.
+pub async fn purge_expired(client: &Client) -> Result<PurgeSummary, TrashServiceError> {
+    let cutoff = datetime::to_bson(Utc::now() - retention_period());
+    Ok(purge_matching(client, doc! { "deleted_at": { "$lte": cutoff } }).await?)
+}
+
+/// Purges every item matching the filter, one at a time so that attachments
+/// are released for each of them.
+async fn purge_matching(client: &Client, filter: Document) -> Result<PurgeSummary, Error> {
+    let mut summary = PurgeSummary::default();
+    for kind in [TrashKind::Note, TrashKind::Todo, TrashKind::Event] {
+        // Exceptions to a trashed series are purged with it
+        let hidden = trashed_series(client, kind).await?;
+        let ids: Vec<ObjectId> = get_collection(client, kind)
+            .find(filter.clone())
+            .projection(doc! { "_id": 1, "recurring_event_id": 1 })
+            .await?
+            .try_collect::<Vec<Document>>()
+            .await?
+            .into_iter()
+            .filter(|document| !listed_with_series(document, &hidden))
+            .filter_map(|document| document.get_object_id("_id").ok())
+            .collect();
+
+        let mut purged = 0;
+        for id in ids {
+            if purge_one(client, kind, id).await? {
+                purged += 1;
+            }
+        }
+        match kind {
+            TrashKind::Note => summary.notes = purged,
+            TrashKind::Todo => summary.todos = purged,
+            TrashKind::Event => summary.events = purged,
+        }
+    }
+    Ok(summary)
+}
+
+async fn purge_one(client: &Client, kind: TrashKind, id: ObjectId) -> Result<bool, Error> {
+    match kind {
+        TrashKind::Note => notes_service::purge_note(client, id).await,
+        TrashKind::Todo => todo_service::purge_todo(client, id).await,
+        TrashKind::Event => {
+            calendar_change_service::record_purge(client, &[id]).await?;
+            let collection = get_collection(client, kind);
+            let result = collection.delete_one(doc! { "_id": id }).await?;
+            collection.delete_many(doc! { "recurring_event_id": id }).await?;
+            Ok(result.deleted_count > 0)
+        }
+    }
+}
+
+/// Ids of the trashed recurring events, whose exceptions are listed,
+/// restored and purged as part of them.
+async fn trashed_series(client: &Client, kind: TrashKind) -> Result<HashSet<ObjectId>, Error> {
+    if kind != TrashKind::Event {
+        return Ok(HashSet::new());
+    }
+    let filter = doc! { "deleted_at": { "$ne": Bson::Null }, "recurring_event_id": Bson::Null };
+    let documents: Vec<Document> = get_collection(client, kind).find(filter).projection(doc! { "_id": 1 }).await?.try_collect().await?;
+    Ok(documents.iter().filter_map(|document| document.get_object_id("_id").ok()).collect())
+}
+
+/// Whether a trashed document is an exception to one of the trashed series
+/// rather than an item of its own. Exceptions removed from a live series are
+/// items of their own.
+fn listed_with_series(document: &Document, trashed_series: &HashSet<ObjectId>) -> bool {
+    document.get_object_id("recurring_event_id").is_ok_and(|series_id| trashed_series.contains(&series_id))
+}
+
+/// Reads `deleted_at`, which is a BSON datetime.
+fn deleted_at(document: &Document) -> Option<DateTime<Utc>> {
+    let date = document.get_datetime("deleted_at").ok()?;
+    DateTime::<Utc>::from_timestamp_millis(date.timestamp_millis())
+}
+
+/// Helper function to get the collection holding a kind of trashable document.
+fn get_collection(client: &Client, kind: TrashKind) -> Collection<Document> {
+    let db = client.database("organise");
+    match kind {
+        TrashKind::Note => db.collection::<Document>("notes"),
+        TrashKind::Todo => db.collection::<Document>("todos"),
+        TrashKind::Event => db.collection::<Document>("calendar_events"),
+    }
+}
+
+// Custom function to convert TrashServiceError to HttpResponse
+pub fn error_response(error: TrashServiceError) -> HttpResponse {
+    match error {
+        TrashServiceError::DatabaseError(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+        TrashServiceError::InvalidObjectId(e) => HttpResponse::BadRequest().body(format!("Invalid ObjectId: {}", e)),
+        TrashServiceError::ItemNotFound => HttpResponse::NotFound().body("Item not found in trash"),
+        TrashServiceError::Conflict(message) => HttpResponse::Conflict().body(message),
+        TrashServiceError::NotebookError(e) => notebook_service::error_response(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exceptions_are_listed_with_their_trashed_series_only() {
+        let series_id = ObjectId::new();
+        let trashed = HashSet::from([series_id]);
+        let exception = doc! { "_id": ObjectId::new(), "recurring_event_id": series_id };
+        let single = doc! { "_id": ObjectId::new() };
+
+        assert!(listed_with_series(&exception, &trashed));
+        assert!(!listed_with_series(&exception, &HashSet::new()));
+        assert!(!listed_with_series(&single, &trashed));
+    }
+}
@@ -0,0 +1,267 @@
+//! CalDAV over HTTP: PROPFIND and REPORT bodies, entity tags and
+//! sync-collection, against the routes the server mounts.
+//!
+//! Each test works in a calendar of its own. All but the authentication test
+//! need a MongoDB at `MONGODB_TEST_URI`.
+
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::test::{self, TestRequest};
+use actix_web::{web, App};
+use backend::db::indexes;
+use backend::routes::{self, auth::ApiUsers};
+use base64::Engine;
+use mongodb::bson::oid::ObjectId;
+use mongodb::Client;
+use roxmltree::Document;
+
+const DAV: &str = "DAV:";
+const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+
+async fn test_client() -> Client {
+    let uri = std::env::var("MONGODB_TEST_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
+    let client = Client::with_uri_str(uri).await.expect("MongoDB client");
+    indexes::ensure_indexes(&client).await.expect("indexes");
+    client
+}
+
+macro_rules! app {
+    ($client:expr) => {
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new($client))
+                .app_data(web::Data::new(ApiUsers::parse("alice:secret")))
+                .configure(routes::init_routes),
+        )
+        .await
+    };
+}
+
+fn request(method: &str, uri: &str) -> TestRequest {
+    let credentials = base64::engine::general_purpose::STANDARD.encode("alice:secret");
+    TestRequest::default()
+        .method(Method::from_bytes(method.as_bytes()).unwrap())
+        .uri(uri)
+        .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+}
+
+fn calendar() -> String {
+    format!("/caldav/calendars/test-{}/", ObjectId::new().to_hex())
+}
+
+fn event(uid: &str, summary: &str) -> String {
+    format!(
+        "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//Test//EN\r\nBEGIN:VEVENT\r\nUID:{}\r\nSUMMARY:{}\r\n\
+         DTSTART:20240701T100000Z\r\nDTEND:20240701T110000Z\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+        uid, summary
+    )
+}
+
+/// A `response` element of a multistatus.
+struct DavResponse {
+    href: String,
+    /// Status of the response, or of its first propstat.
+    status: String,
+    /// Names and text of the props found.
+    props: Vec<(String, String)>,
+}
+
+impl DavResponse {
+    fn prop(&self, name: &str) -> Option<&str> {
+        self.props.iter().find(|(prop, _)| prop == name).map(|(_, value)| value.as_str())
+    }
+}
+
+fn responses(xml: &str) -> Vec<DavResponse> {
+    let document = Document::parse(xml).expect("multistatus XML");
+    let root = document.root_element();
+    assert!(root.has_tag_name((DAV, "multistatus")));
+    root.children()
+        .filter(|node| node.has_tag_name((DAV, "response")))
+        .map(|response| {
+            let text = |node: roxmltree::Node, name: &str| {
+                node.descendants().find(|child| child.has_tag_name((DAV, name))).and_then(|child| child.text()).unwrap_or_default().to_string()
+            };
+            let props = response
+                .descendants()
+                .filter(|node| node.has_tag_name((DAV, "propstat")) && text(*node, "status").contains("200"))
+                .flat_map(|propstat| propstat.descendants().filter(|node| node.has_tag_name((DAV, "prop"))).collect::<Vec<_>>())
+                .flat_map(|prop| prop.children().filter(|node| node.is_element()))
+                .map(|prop| (prop.tag_name().name().to_string(), prop.text().unwrap_or_default().to_string()))
+                .collect();
+            DavResponse { href: text(response, "href"), status: text(response, "status"), props }
+        })
+        .collect()
+}
+
+fn sync_token(xml: &str) -> String {
+    let document = Document::parse(xml).expect("multistatus XML");
+    let token = document.root_element().children().find(|node| node.has_tag_name((DAV, "sync-token")));
+    token.and_then(|node| node.text()).expect("sync token").to_string()
+}
+
+fn sync_collection(token: &str) -> String {
+    format!(
+        r#"<?xml version="1.0"?><D:sync-collection xmlns:D="DAV:"><D:sync-token>{}</D:sync-token><D:sync-level>1</D:sync-level><D:prop><D:getetag/></D:prop></D:sync-collection>"#,
+        token
+    )
+}
+
+#[actix_web::test]
+async fn requests_without_credentials_are_challenged() {
+    let client = Client::with_uri_str("mongodb://localhost:27017").await.unwrap();
+    let app = app!(client);
+    let request = TestRequest::default().method(Method::from_bytes(b"PROPFIND").unwrap()).uri("/caldav/").to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(response.headers().contains_key(header::WWW_AUTHENTICATE));
+
+    let request = TestRequest::put().uri("/caldav/calendars/local/a.ics").set_payload(event("a", "Lunch")).to_request();
+    assert_eq!(test::call_service(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+#[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+async fn propfind_describes_the_calendar_and_its_events() {
+    let app = app!(test_client().await);
+    let calendar = calendar();
+    let put = request("PUT", &format!("{}lunch.ics", calendar)).set_payload(event("lunch", "Lunch"));
+    assert_eq!(test::call_service(&app, put.to_request()).await.status(), StatusCode::CREATED);
+
+    let body = r#"<?xml version="1.0"?><D:propfind xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav"><D:prop><D:resourcetype/><D:getetag/><D:sync-token/><C:supported-calendar-component-set/></D:prop></D:propfind>"#;
+    let propfind = request("PROPFIND", &calendar).insert_header(("Depth", "1")).set_payload(body);
+    let response = test::call_service(&app, propfind.to_request()).await;
+    assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+    let xml = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    let listed = responses(&xml);
+
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0].href, calendar);
+    assert!(listed[0].prop("sync-token").unwrap().starts_with("urn:organise:caldav:change:"));
+    let document = Document::parse(&xml).unwrap();
+    assert!(document.descendants().any(|node| node.has_tag_name((CALDAV, "calendar"))));
+    assert!(document.descendants().any(|node| node.has_tag_name((CALDAV, "comp")) && node.attribute("name") == Some("VEVENT")));
+
+    assert_eq!(listed[1].href, format!("{}lunch.ics", calendar));
+    let etag = listed[1].prop("getetag").unwrap();
+    let get = test::call_service(&app, request("GET", &listed[1].href).to_request()).await;
+    assert_eq!(get.headers().get(header::ETAG).unwrap().to_str().unwrap(), etag);
+
+    let depth_zero = request("PROPFIND", &calendar).insert_header(("Depth", "0")).set_payload(body);
+    let xml = String::from_utf8(test::call_and_read_body(&app, depth_zero.to_request()).await.to_vec()).unwrap();
+    assert_eq!(responses(&xml).len(), 1);
+}
+
+#[actix_web::test]
+#[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+async fn writes_are_conditional_on_the_entity_tag() {
+    let app = app!(test_client().await);
+    let href = format!("{}standup.ics", calendar());
+    let create = || request("PUT", &href).insert_header((header::IF_NONE_MATCH, "*"));
+    assert_eq!(test::call_service(&app, create().set_payload(event("standup", "Standup")).to_request()).await.status(), StatusCode::CREATED);
+    assert_eq!(
+        test::call_service(&app, create().set_payload(event("standup", "Standup")).to_request()).await.status(),
+        StatusCode::PRECONDITION_FAILED
+    );
+
+    let get = test::call_service(&app, request("GET", &href).to_request()).await;
+    let etag = get.headers().get(header::ETAG).unwrap().to_str().unwrap().to_string();
+    let stale = request("PUT", &href).insert_header((header::IF_MATCH, "\"stale\"")).set_payload(event("standup", "Moved"));
+    assert_eq!(test::call_service(&app, stale.to_request()).await.status(), StatusCode::PRECONDITION_FAILED);
+    let current = request("PUT", &href).insert_header((header::IF_MATCH, etag.as_str())).set_payload(event("standup", "Moved"));
+    assert_eq!(test::call_service(&app, current.to_request()).await.status(), StatusCode::NO_CONTENT);
+
+    let get = test::call_service(&app, request("GET", &href).to_request()).await;
+    let changed = get.headers().get(header::ETAG).unwrap().to_str().unwrap().to_string();
+    assert_ne!(changed, etag);
+    let body = String::from_utf8(test::read_body(get).await.to_vec()).unwrap();
+    assert!(body.contains("SUMMARY:Moved"));
+
+    let delete = request("DELETE", &href).insert_header((header::IF_MATCH, etag.as_str()));
+    assert_eq!(test::call_service(&app, delete.to_request()).await.status(), StatusCode::PRECONDITION_FAILED);
+    let delete = request("DELETE", &href).insert_header((header::IF_MATCH, changed.as_str()));
+    assert_eq!(test::call_service(&app, delete.to_request()).await.status(), StatusCode::NO_CONTENT);
+    assert_eq!(test::call_service(&app, request("GET", &href).to_request()).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+#[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+async fn reports_query_and_fetch_events() {
+    let app = app!(test_client().await);
+    let calendar = calendar();
+    let put = request("PUT", &format!("{}review.ics", calendar)).set_payload(event("review", "Review"));
+    assert_eq!(test::call_service(&app, put.to_request()).await.status(), StatusCode::CREATED);
+
+    let query = |start: &str, end: &str| {
+        format!(
+            r#"<C:calendar-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav"><D:prop><D:getetag/></D:prop><C:filter><C:comp-filter name="VCALENDAR"><C:comp-filter name="VEVENT"><C:time-range start="{}" end="{}"/></C:comp-filter></C:comp-filter></C:filter></C:calendar-query>"#,
+            start, end
+        )
+    };
+    let during = request("REPORT", &calendar).set_payload(query("20240701T000000Z", "20240702T000000Z"));
+    let xml = String::from_utf8(test::call_and_read_body(&app, during.to_request()).await.to_vec()).unwrap();
+    assert_eq!(responses(&xml).len(), 1);
+    let after = request("REPORT", &calendar).set_payload(query("20240801T000000Z", "20240802T000000Z"));
+    let xml = String::from_utf8(test::call_and_read_body(&app, after.to_request()).await.to_vec()).unwrap();
+    assert!(responses(&xml).is_empty());
+
+    let multiget = format!(
+        r#"<C:calendar-multiget xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav"><D:prop><D:getetag/><C:calendar-data/></D:prop><D:href>{0}review.ics</D:href><D:href>{0}missing.ics</D:href></C:calendar-multiget>"#,
+        calendar
+    );
+    let response = test::call_service(&app, request("REPORT", &calendar).set_payload(multiget).to_request()).await;
+    assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+    let xml = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    let listed = responses(&xml);
+    assert_eq!(listed.len(), 2);
+    assert!(listed[0].prop("calendar-data").unwrap().contains("SUMMARY:Review\r\n"));
+    assert_eq!(listed[1].href, format!("{}missing.ics", calendar));
+    assert!(listed[1].status.contains("404"));
+
+    let unsupported = request("REPORT", &calendar).set_payload(r#"<D:expand-property xmlns:D="DAV:"/>"#);
+    assert_eq!(test::call_service(&app, unsupported.to_request()).await.status(), StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+#[ignore = "needs MongoDB at MONGODB_TEST_URI"]
+async fn sync_collection_reports_each_change_once() {
+    let app = app!(test_client().await);
+    let calendar = calendar();
+    let sync = |token: &str| request("REPORT", &calendar).set_payload(sync_collection(token)).to_request();
+
+    let xml = String::from_utf8(test::call_and_read_body(&app, sync("")).await.to_vec()).unwrap();
+    assert!(responses(&xml).is_empty());
+    let initial = sync_token(&xml);
+
+    for name in ["a", "b"] {
+        let put = request("PUT", &format!("{}{}.ics", calendar, name)).set_payload(event(name, name));
+        assert_eq!(test::call_service(&app, put.to_request()).await.status(), StatusCode::CREATED);
+    }
+    let xml = String::from_utf8(test::call_and_read_body(&app, sync(&initial)).await.to_vec()).unwrap();
+    let mut hrefs: Vec<String> = responses(&xml).into_iter().map(|response| response.href).collect();
+    hrefs.sort();
+    assert_eq!(hrefs, [format!("{}a.ics", calendar), format!("{}b.ics", calendar)]);
+    let created = sync_token(&xml);
+    assert_ne!(created, initial);
+
+    let xml = String::from_utf8(test::call_and_read_body(&app, sync(&created)).await.to_vec()).unwrap();
+    assert!(responses(&xml).is_empty());
+    assert_eq!(sync_token(&xml), created);
+
+    let delete = request("DELETE", &format!("{}a.ics", calendar));
+    assert_eq!(test::call_service(&app, delete.to_request()).await.status(), StatusCode::NO_CONTENT);
+    let put = request("PUT", &format!("{}b.ics", calendar)).set_payload(event("b", "b, moved"));
+    assert_eq!(test::call_service(&app, put.to_request()).await.status(), StatusCode::NO_CONTENT);
+    let xml = String::from_utf8(test::call_and_read_body(&app, sync(&created)).await.to_vec()).unwrap();
+    let mut changes = responses(&xml);
+    changes.sort_by(|a, b| a.href.cmp(&b.href));
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0].href, format!("{}a.ics", calendar));
+    assert!(changes[0].status.contains("404"));
+    assert_eq!(changes[1].href, format!("{}b.ics", calendar));
+    assert!(changes[1].prop("getetag").is_some());
+
+    let response = test::call_service(&app, sync("urn:organise:caldav:change:999999999999")).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let xml = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    assert!(Document::parse(&xml).unwrap().descendants().any(|node| node.has_tag_name((DAV, "valid-sync-token"))));
+}
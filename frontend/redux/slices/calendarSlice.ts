@@ -42,7 +42,7 @@ export const fetchEvents = createAsyncThunk(
   "calendar/fetchEvents",
   async ({ start, end }: { start: string; end: string }, { rejectWithValue }) => {
     try {
-      const response = await axios.get(`http://localhost:8080/api/calendar?start=${start}&end=${end}`);
+      const response = await axios.get(`http://localhost:8080/api/calendar/events?start=${encodeURIComponent(start)}&end=${encodeURIComponent(end)}`);
       return response.data;
     } catch (error) {
       const err = error as AxiosError<ApiErrorResponse>;
@@ -56,7 +56,7 @@ export const addEvent = createAsyncThunk(
   "calendar/addEvent",
   async (event: Omit<CalendarEvent, "id" | "created_at" | "updated_at">, { rejectWithValue }) => {
     try {
-      const response = await axios.post("http://localhost:8080/api/calendar", event);
+      const response = await axios.post("http://localhost:8080/api/calendar/events", event);
       return response.data;
     } catch (error) {
       const err = error as AxiosError<ApiErrorResponse>;
@@ -70,7 +70,7 @@ export const updateEvent = createAsyncThunk(
   "calendar/updateEvent",
   async (event: Partial<CalendarEvent> & { id: string }, { rejectWithValue }) => {
     try {
-      const response = await axios.put(`http://localhost:8080/api/calendar/${event.id}`, event);
+      const response = await axios.put(`http://localhost:8080/api/calendar/events/${event.id}`, event);
       return response.data;
     } catch (error) {
       const err = error as AxiosError<ApiErrorResponse>;
@@ -84,7 +84,7 @@ export const deleteEvent = createAsyncThunk(
   "calendar/deleteEvent",
   async (id: string, { rejectWithValue }) => {
     try {
-      await axios.delete(`http://localhost:8080/api/calendar/${id}`);
+      await axios.delete(`http://localhost:8080/api/calendar/events/${id}`);
       return id;
     } catch (error) {
       const err = error as AxiosError<ApiErrorResponse>;
@@ -29,8 +29,8 @@ const API_BASE_URL = process.env.REACT_APP_API_BASE_URL ?? 'http://localhost:808
 // Async thunk to fetch tasks from the backend
 export const fetchTasks = createAsyncThunk('tasks/fetchTasks', async () => {
   const response = await axios.get(`${API_BASE_URL}/todos`);
-  const tasks = (response.data as Array<{ _id: { $oid: string }; title: string; description: string; completed: boolean; priority: string; created_at: string }>).map((task) => ({
-    id: task._id.$oid,
+  const tasks = (response.data as Array<{ id: string; title: string; description: string; completed: boolean; priority: string; created_at: string }>).map((task) => ({
+    id: task.id,
     title: task.title,
     description: task.description,
     completed: task.completed,